@@ -0,0 +1,132 @@
+//! Compares the throughput of QUIC and ObfsUdp links, by sending a burst of N2R messages from a client to its relay over each.
+//!
+//! Run with `cargo bench --bench links`.
+
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use earendil::{config::ConfigFile, daemon::Daemon, socket::Socket};
+use sosistab2_obfsudp::ObfsUdpSecret;
+
+/// How many messages each burst sends.
+const BURST_LEN: usize = 10_000;
+
+/// How big each message is, in bytes.
+const MESSAGE_SIZE: usize = 1024;
+
+fn daemon_from_json(json: serde_json::Value) -> Daemon {
+    let cfg: ConfigFile = serde_json::from_value(json).unwrap();
+    Daemon::init(cfg).unwrap()
+}
+
+/// A relay and a client linked to it by the given in_route and out_route.
+fn linked_pair(
+    name: &str,
+    control_ports: (u16, u16),
+    in_route: serde_json::Value,
+    out_route: impl FnOnce(String) -> serde_json::Value,
+) -> (Daemon, Daemon) {
+    let relay = daemon_from_json(serde_json::json!({
+        "identity_seed": format!("{name}-bench-relay"),
+        "control_listen": format!("127.0.0.1:{}", control_ports.0),
+        "in_routes": {"main": in_route},
+    }));
+    let relay_fp = relay.identity().public().fingerprint().to_string();
+    let client = daemon_from_json(serde_json::json!({
+        "identity_seed": format!("{name}-bench-client"),
+        "control_listen": format!("127.0.0.1:{}", control_ports.1),
+        "out_routes": {"relay": out_route(relay_fp)},
+    }));
+    (relay, client)
+}
+
+/// Sends a burst of messages from the client to the relay, printing how many arrived and how fast.
+fn bench_burst(name: &str, relay: &Daemon, client: &Daemon) {
+    let relay_skt = Socket::bind_n2r(relay, relay.identity(), None);
+    let client_skt = Socket::bind_n2r(client, client.identity(), None);
+    let msg = Bytes::from(vec![0u8; MESSAGE_SIZE]);
+    smolscale::block_on(async {
+        // the link and the relay graph take a while to come up, so wait until a message gets through
+        let deadline = Instant::now() + Duration::from_secs(120);
+        loop {
+            assert!(
+                Instant::now() < deadline,
+                "{name}: the client never reached the relay"
+            );
+            let _ = client_skt
+                .send_to(msg.clone(), relay_skt.local_endpoint())
+                .await;
+            if let Ok(Some(_)) = relay_skt.recv_from_timeout(Duration::from_secs(1)).await {
+                break;
+            }
+        }
+        while let Ok(Some(_)) = relay_skt.recv_from_timeout(Duration::from_secs(1)).await {}
+
+        let start = Instant::now();
+        for _ in 0..BURST_LEN {
+            client_skt
+                .send_to(msg.clone(), relay_skt.local_endpoint())
+                .await
+                .unwrap();
+        }
+        let mut received = 0;
+        let mut last_received = start;
+        while let Ok(Some(_)) = relay_skt.recv_from_timeout(Duration::from_secs(2)).await {
+            received += 1;
+            last_received = Instant::now();
+            if received == BURST_LEN {
+                break;
+            }
+        }
+        let elapsed = last_received - start;
+        println!(
+            "{name}: {received}/{BURST_LEN} messages of {MESSAGE_SIZE} bytes in {elapsed:?}, {:.1} Mbps",
+            (received * MESSAGE_SIZE * 8) as f64 / elapsed.as_secs_f64() / 1_000_000.0
+        );
+    });
+}
+
+fn main() {
+    let _ = env_logger::try_init();
+
+    let secret = "obfsudp-bench";
+    let cookie = ObfsUdpSecret::from_bytes(*blake3::hash(secret.as_bytes()).as_bytes()).to_public();
+    let (relay, client) = linked_pair(
+        "obfsudp",
+        (11311, 11312),
+        serde_json::json!({"protocol": "obfsudp", "listen": "127.0.0.1:12311", "secret": secret}),
+        |fingerprint| {
+            serde_json::json!({
+                "protocol": "obfsudp",
+                "fingerprint": fingerprint,
+                "connect": "127.0.0.1:12311",
+                "cookie": hex::encode(cookie.as_bytes()),
+            })
+        },
+    );
+    bench_burst("obfsudp", &relay, &client);
+
+    // neighbors don't check the certificate, so a throwaway self-signed one does
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let cert_path =
+        std::env::temp_dir().join(format!("earendil-quic-bench-{}.crt", std::process::id()));
+    let key_path =
+        std::env::temp_dir().join(format!("earendil-quic-bench-{}.key", std::process::id()));
+    std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+    std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+    let (relay, client) = linked_pair(
+        "quic",
+        (11313, 11314),
+        serde_json::json!({"protocol": "quic", "listen": "127.0.0.1:12312", "cert": cert_path, "key": key_path}),
+        |fingerprint| {
+            serde_json::json!({
+                "protocol": "quic",
+                "fingerprint": fingerprint,
+                "connect": "127.0.0.1:12312",
+            })
+        },
+    );
+    bench_burst("quic", &relay, &client);
+    let _ = std::fs::remove_file(cert_path);
+    let _ = std::fs::remove_file(key_path);
+}
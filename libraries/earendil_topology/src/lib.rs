@@ -24,6 +24,9 @@ pub struct RelayGraph {
     documents: IndexMap<(u64, u64), AdjacencyDescriptor>,
 }
 
+/// How many nodes a search for a path longer than the shortest one visits before giving up, so that it takes bounded time however large the graph is.
+const MAX_SEARCH_STEPS: usize = 10_000;
+
 // Update the AdjacencyError enum with more specific cases
 #[derive(thiserror::Error, Debug)]
 pub enum AdjacencyError {
@@ -128,12 +131,115 @@ impl RelayGraph {
             .map(|v| v.1.clone())
     }
 
-    /// Finds the shortest path between two Fingerprints.
+    /// Finds the shortest path between two Fingerprints. Among paths of equal length, the one whose narrowest link has the most bandwidth wins.
     /// Returns a Vec of Fingerprint instances representing the shortest path or None if no path exists.
     pub fn find_shortest_path(
         &self,
         start_fp: &Fingerprint,
         end_fp: &Fingerprint,
+    ) -> Option<Vec<Fingerprint>> {
+        self.find_path(start_fp, end_fp, &HashSet::new(), 0)
+    }
+
+    /// Finds the shortest path between two Fingerprints that doesn't go through any of the `excluded` ones, and has at least `min_relays` nodes between its ends.
+    pub fn find_path(
+        &self,
+        start_fp: &Fingerprint,
+        end_fp: &Fingerprint,
+        excluded: &HashSet<Fingerprint>,
+        min_relays: usize,
+    ) -> Option<Vec<Fingerprint>> {
+        if excluded.contains(end_fp) {
+            return None;
+        }
+        let shortest = self.bfs_path(start_fp, end_fp, excluded)?;
+        if shortest.len() >= min_relays + 2 {
+            return Some(shortest);
+        }
+        // the breadth-first search only finds the shortest path, so search for longer ones one length at a time
+        const MAX_DETOUR: usize = 2;
+        let start_id = self.id(start_fp)?;
+        let end_id = self.id(end_fp)?;
+        let hops_to_end = self.hops_to(end_id, excluded);
+        let mut steps_left = MAX_SEARCH_STEPS;
+        (min_relays + 2..=min_relays + 2 + MAX_DETOUR).find_map(|len| {
+            let mut path = vec![start_id];
+            self.extend_path_to_len(
+                &mut path,
+                end_id,
+                len,
+                excluded,
+                &hops_to_end,
+                &mut steps_left,
+            )
+            .then(|| path.iter().map(|id| self.id_to_fp[id]).collect())
+        })
+    }
+
+    /// Extends `path` depth-first into a loop-free path of exactly `len` nodes that ends at `end_id`, returning whether one was found. Neighbors too far from the end to make it in time are skipped, and the search gives up once it has taken `steps_left` steps.
+    fn extend_path_to_len(
+        &self,
+        path: &mut Vec<u64>,
+        end_id: u64,
+        len: usize,
+        excluded: &HashSet<Fingerprint>,
+        hops_to_end: &HashMap<u64, usize>,
+        steps_left: &mut usize,
+    ) -> bool {
+        if *steps_left == 0 {
+            return false;
+        }
+        *steps_left -= 1;
+        let current_id = *path.last().expect("paths start with the start node");
+        if path.len() == len {
+            return current_id == end_id;
+        }
+        if current_id == end_id {
+            return false;
+        }
+        for &neighbor_id in self.adjacency.get(&current_id).into_iter().flatten() {
+            if path.contains(&neighbor_id) || excluded.contains(&self.id_to_fp[&neighbor_id]) {
+                continue;
+            }
+            match hops_to_end.get(&neighbor_id) {
+                Some(hops) if path.len() + 1 + hops <= len => {}
+                _ => continue,
+            }
+            path.push(neighbor_id);
+            if self.extend_path_to_len(path, end_id, len, excluded, hops_to_end, steps_left) {
+                return true;
+            }
+            path.pop();
+        }
+        false
+    }
+
+    /// How many hops away from `end_id` each node is, not going through any of the `excluded` ones. Nodes that can't reach it at all are left out.
+    fn hops_to(&self, end_id: u64, excluded: &HashSet<Fingerprint>) -> HashMap<u64, usize> {
+        let mut hops = HashMap::new();
+        let mut queue = VecDeque::new();
+        hops.insert(end_id, 0);
+        queue.push_back(end_id);
+        while let Some(current_id) = queue.pop_front() {
+            let next_hops = hops[&current_id] + 1;
+            for &neighbor_id in self.adjacency.get(&current_id).into_iter().flatten() {
+                if hops.contains_key(&neighbor_id)
+                    || excluded.contains(&self.id_to_fp[&neighbor_id])
+                {
+                    continue;
+                }
+                hops.insert(neighbor_id, next_hops);
+                queue.push_back(neighbor_id);
+            }
+        }
+        hops
+    }
+
+    fn bfs_path(
+        &self,
+        start_fp: &Fingerprint,
+        end_fp: &Fingerprint,
+        excluded: &HashSet<Fingerprint>,
     ) -> Option<Vec<Fingerprint>> {
         let start_id = self.id(start_fp)?;
         let end_id = self.id(end_fp)?;
@@ -141,9 +247,12 @@ impl RelayGraph {
         let mut visited = HashSet::new();
         let mut queue = VecDeque::new();
         let mut path = HashMap::new();
+        // hop count and bottleneck bandwidth of the best path found to each node
+        let mut best: HashMap<u64, (usize, u32)> = HashMap::new();
 
         visited.insert(start_id);
         queue.push_back(start_id);
+        best.insert(start_id, (0, u32::MAX));
 
         while let Some(current_id) = queue.pop_front() {
             if current_id == end_id {
@@ -160,11 +269,21 @@ impl RelayGraph {
                 return Some(result);
             }
 
+            // nodes come off the queue one hop count at a time, so the best path to the current node is final by now
+            let (hops, bottleneck) = best[&current_id];
             for neighbor_id in self.adjacency.get(&current_id)?.iter() {
+                if excluded.contains(&self.id_to_fp[neighbor_id]) {
+                    continue;
+                }
+                let bottleneck = bottleneck.min(self.link_bandwidth(current_id, *neighbor_id));
                 if !visited.contains(neighbor_id) {
                     visited.insert(*neighbor_id);
                     path.insert(*neighbor_id, current_id);
+                    best.insert(*neighbor_id, (hops + 1, bottleneck));
                     queue.push_back(*neighbor_id);
+                } else if best[neighbor_id].0 == hops + 1 && best[neighbor_id].1 < bottleneck {
+                    path.insert(*neighbor_id, current_id);
+                    best.insert(*neighbor_id, (hops + 1, bottleneck));
                 }
             }
         }
@@ -172,6 +291,19 @@ impl RelayGraph {
         None
     }
 
+    /// The bandwidth of the link between two adjacent nodes, in kbps, with unlimited links counting as the widest possible.
+    fn link_bandwidth(&self, a: u64, b: u64) -> u32 {
+        let key = if self.id_to_fp[&a] < self.id_to_fp[&b] {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        match self.documents.get(&key).map_or(0, |adj| adj.bandwidth_kbps) {
+            0 => u32::MAX,
+            kbps => kbps,
+        }
+    }
+
     // removes all information more than ROUTE_TIMEOUT ago
     fn cleanup(&mut self) {
         const ROUTE_TIMEOUT: u64 = 60 * 60; // e.g., 1 hour in seconds
@@ -288,15 +420,32 @@ pub struct AdjacencyDescriptor {
     pub right_sig: Bytes,
 
     pub unix_timestamp: u64,
+
+    /// The bandwidth both sides agreed the link carries, in kbps. Zero means unlimited, and is what nodes that predate this field send.
+    #[serde(default)]
+    pub bandwidth_kbps: u32,
 }
 
 impl AdjacencyDescriptor {
     /// The value that the signatures are supposed to be computed against.
     pub fn to_sign(&self) -> blake3::Hash {
-        let mut this = self.clone();
-        this.left_sig = Bytes::new();
-        this.right_sig = Bytes::new();
-        blake3::keyed_hash(b"adjacency_descriptor____________", &this.stdcode())
+        let to_sign = if self.bandwidth_kbps == 0 {
+            // laid out like descriptors from before the bandwidth field, so that their signatures stay valid
+            (
+                self.left,
+                self.right,
+                Bytes::new(),
+                Bytes::new(),
+                self.unix_timestamp,
+            )
+                .stdcode()
+        } else {
+            let mut this = self.clone();
+            this.left_sig = Bytes::new();
+            this.right_sig = Bytes::new();
+            this.stdcode()
+        };
+        blake3::keyed_hash(b"adjacency_descriptor____________", &to_sign)
     }
 }
 
@@ -338,3 +487,99 @@ impl IdentityDescriptor {
         blake3::keyed_hash(b"identity_descriptor_____________", &this.stdcode())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect(graph: &mut RelayGraph, a: &IdentitySecret, b: &IdentitySecret) {
+        let (left, right) = if a.public().fingerprint() < b.public().fingerprint() {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        let mut adj = AdjacencyDescriptor {
+            left: left.public().fingerprint(),
+            right: right.public().fingerprint(),
+            left_sig: Bytes::new(),
+            right_sig: Bytes::new(),
+            unix_timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            bandwidth_kbps: 0,
+        };
+        adj.left_sig = left.sign(adj.to_sign().as_bytes());
+        adj.right_sig = right.sign(adj.to_sign().as_bytes());
+        graph.insert_adjacency(adj).unwrap();
+    }
+
+    #[test]
+    fn test_find_path() {
+        // a - x - d is the shortest path, with a - b - c - d going around x
+        let [a, b, c, d, x] = std::array::from_fn(|_| IdentitySecret::generate());
+        let mut graph = RelayGraph::new();
+        for isk in [&a, &b, &c, &d, &x] {
+            graph
+                .insert_identity(IdentityDescriptor::new(isk, &OnionSecret::generate(), true))
+                .unwrap();
+        }
+        connect(&mut graph, &a, &x);
+        connect(&mut graph, &x, &d);
+        connect(&mut graph, &a, &b);
+        connect(&mut graph, &b, &c);
+        connect(&mut graph, &c, &d);
+        let fp = |isk: &IdentitySecret| isk.public().fingerprint();
+        let around_x = vec![fp(&a), fp(&b), fp(&c), fp(&d)];
+
+        assert_eq!(
+            graph.find_shortest_path(&fp(&a), &fp(&d)),
+            Some(vec![fp(&a), fp(&x), fp(&d)])
+        );
+
+        // Testing that excluded nodes are never on the path
+        let excluded: HashSet<_> = [fp(&x)].into();
+        assert_eq!(
+            graph.find_path(&fp(&a), &fp(&d), &excluded, 0),
+            Some(around_x.clone())
+        );
+        assert_eq!(graph.find_path(&fp(&a), &fp(&x), &excluded, 0), None);
+        let excluded: HashSet<_> = [fp(&x), fp(&c)].into();
+        assert_eq!(graph.find_path(&fp(&a), &fp(&d), &excluded, 0), None);
+
+        // Testing that paths are lengthened to go through enough relays
+        assert_eq!(
+            graph.find_path(&fp(&a), &fp(&d), &HashSet::new(), 2),
+            Some(around_x)
+        );
+        assert_eq!(graph.find_path(&fp(&a), &fp(&d), &HashSet::new(), 3), None);
+    }
+
+    #[test]
+    fn test_find_path_gives_up() {
+        // every node is connected to every other, so there are too many loop-free paths to try them all
+        let isks: Vec<IdentitySecret> = (0..20).map(|_| IdentitySecret::generate()).collect();
+        let mut graph = RelayGraph::new();
+        for isk in isks.iter() {
+            graph
+                .insert_identity(IdentityDescriptor::new(isk, &OnionSecret::generate(), true))
+                .unwrap();
+        }
+        for (i, a) in isks.iter().enumerate() {
+            for b in isks[i + 1..].iter() {
+                connect(&mut graph, a, b);
+            }
+        }
+        let start = isks[0].public().fingerprint();
+        let end = isks[1].public().fingerprint();
+
+        // Testing that a path through more relays than there are nodes isn't searched for exhaustively
+        assert_eq!(graph.find_path(&start, &end, &HashSet::new(), 30), None);
+        assert_eq!(
+            graph
+                .find_path(&start, &end, &HashSet::new(), 3)
+                .map(|path| path.len()),
+            Some(5)
+        );
+    }
+}
@@ -1,4 +1,6 @@
-use crate::socket::Endpoint;
+use std::path::PathBuf;
+
+use crate::{control_protocol::EventKind, socket::Endpoint};
 use clap::{arg, Subcommand};
 use earendil_crypt::Fingerprint;
 use earendil_packet::Dock;
@@ -32,6 +34,9 @@ pub enum ControlCommands {
         #[arg(long)]
         /// fingerprint of rendezvous point. Specify this if you are the haven server.
         rendezvous: Option<Fingerprint>,
+        #[arg(long)]
+        /// socket tunables as YAML, e.g. "{crypt_session_ttl: 600}"
+        config: Option<String>,
     },
 
     /// Prints the fingerprint and dock of a socket
@@ -40,9 +45,69 @@ pub enum ControlCommands {
         skt_id: String,
     },
 
+    /// Prints daemon events as they happen, one JSON object per line
+    Events {
+        #[arg(long)]
+        /// only print events of this kind; can be given several times
+        kind: Vec<EventKind>,
+    },
+
+    /// Prints the events the daemon logged recently, one JSON object per line
+    EventLog {
+        #[arg(long, default_value_t = 0)]
+        /// only print events with this sequence number or later
+        from: u64,
+        #[arg(long, default_value_t = 100)]
+        limit: usize,
+    },
+
+    /// Measures the round-trip time to a node
+    Ping {
+        #[arg(long)]
+        /// fingerprint of the node
+        fp: Fingerprint,
+    },
+
+    /// Prints the relays on the route to a node, probing each in turn over its own route
+    TraceRoute {
+        /// fingerprint of the node
+        fp: Fingerprint,
+    },
+
+    /// Lists the bound sockets
+    ListSkts,
+
+    /// Unbinds a socket
+    CloseSkt {
+        #[arg(long)]
+        skt_id: String,
+    },
+
+    /// Reloads the in_routes and out_routes from the daemon's config file
+    ReloadConfig,
+
+    /// Prints statistics of the messages a socket received
+    SocketStats {
+        #[arg(long)]
+        skt_id: String,
+    },
+
     /// Prints the information of all hosted havens
     HavensInfo,
 
+    /// Measures the round-trip time to a destination running haven-echo-server
+    TestEcho {
+        #[arg(long)]
+        /// tag for the socket to use
+        skt_id: String,
+        #[arg(short, long)]
+        /// destination fingerprint::dock
+        dest: Endpoint,
+        #[arg(long, default_value_t = 64)]
+        /// size of the test message in bytes
+        payload_size: usize,
+    },
+
     /// Sends a message using a given socket to a destination.
     SendMsg {
         #[arg(long)]
@@ -63,6 +128,84 @@ pub enum ControlCommands {
         skt_id: String,
     },
 
+    /// Captures all messages sent and received by a socket into a PCAP-NG file.
+    EnableDebugPcap {
+        #[arg(long)]
+        /// tag for the socket to capture
+        skt_id: String,
+        #[arg(long)]
+        /// path of the capture file
+        path: PathBuf,
+    },
+
+    /// Stops capturing the messages of a socket.
+    DisableDebugPcap {
+        #[arg(long)]
+        /// tag for the socket being captured
+        skt_id: String,
+    },
+
+    /// Starts listening on a new in_route.
+    AddInRoute {
+        #[arg(long)]
+        /// name of the in_route
+        name: String,
+        #[arg(long)]
+        /// in_route config as YAML, e.g. "{protocol: obfsudp, listen: 0.0.0.0:19999, secret: hello}"
+        config: String,
+    },
+
+    /// Stops an in_route that was added with add-in-route.
+    RemoveInRoute {
+        #[arg(long)]
+        /// name of the in_route
+        name: String,
+    },
+
+    /// Starts connecting to a new neighbor, until the daemon restarts.
+    AddPeer {
+        #[arg(long)]
+        /// out_route config as YAML, e.g. "{protocol: obfsudp, fingerprint: ..., connect: 1.2.3.4:19999, cookie: ...}"
+        config: String,
+    },
+
+    /// Disconnects from a neighbor that was added with add-peer.
+    RemovePeer {
+        #[arg(long)]
+        /// fingerprint of the neighbor
+        fp: Fingerprint,
+    },
+
+    /// Moves a haven socket to another rendezvous point.
+    SetHavenRendezvous {
+        #[arg(long)]
+        /// tag for the haven socket
+        skt_id: String,
+        #[arg(long)]
+        /// fingerprint of the new rendezvous point. Leave out to stop registering with any.
+        rendezvous: Option<Fingerprint>,
+    },
+
+    /// Pre-announces a source route to the relays along it.
+    AnnounceRoute {
+        #[arg(long, value_delimiter = ',')]
+        /// comma-separated fingerprints of the hops, in order
+        route: Vec<Fingerprint>,
+        #[arg(long, default_value_t = 60)]
+        /// how many seconds the reservation lasts
+        ttl: u64,
+    },
+
+    /// Binds a haven socket that echoes every message back to its sender, printing statistics every 10 seconds. Timed echoes, as sent by `test-echo`, count toward the average response time.
+    HavenEchoServer {
+        #[arg(long)]
+        /// dock to serve on
+        dock: Dock,
+        #[arg(long)]
+        /// fingerprint of the rendezvous point to register with
+        rendezvous: Fingerprint,
+    },
+
     /// Send a GlobalRpc request to a destination.
     GlobalRpc {
         #[arg(long)]
@@ -90,6 +233,35 @@ pub enum ControlCommands {
         key: Fingerprint,
     },
 
+    /// Deletes a haven's rendezvous locator from the dht. The haven's identity must be known to the daemon.
+    RemoveRendezvous {
+        #[arg(short, long)]
+        key: Fingerprint,
+    },
+
+    /// Looks up many rendezvous haven locators at once.
+    BatchGetRendezvous {
+        /// fingerprints of the havens to look up
+        #[arg(required = true)]
+        keys: Vec<Fingerprint>,
+    },
+
+    /// Lists the locators this node stores whose fingerprints are closest to a key, closest first.
+    NearestRendezvous {
+        #[arg(short, long)]
+        key: Fingerprint,
+        /// how many locators to list at most
+        #[arg(short, long, default_value_t = 8)]
+        count: usize,
+    },
+
+    /// Lists the havens whose locators a relay stores in the DHT.
+    ListHavens {
+        #[arg(long)]
+        /// fingerprint of the relay to query
+        relay: Fingerprint,
+    },
+
     /// Insert and get a randomly generated HavenLocator.
     RendezvousHavenTest,
 
@@ -101,4 +273,37 @@ pub enum ControlCommands {
 
     /// Dumps my own routes.
     MyRoutes,
+
+    /// Prints the onion path a socket's messages to a destination would take.
+    GetPath {
+        #[arg(long)]
+        /// tag for the socket
+        skt_id: String,
+        #[arg(long)]
+        /// fingerprint of the destination
+        dest: Fingerprint,
+    },
+
+    /// Makes a socket route its messages according to a profile from the config file
+    SetRouteProfile {
+        #[arg(long)]
+        /// tag for the socket
+        skt_id: String,
+        #[arg(long)]
+        /// name of the profile; leave out to route without constraints
+        profile: Option<String>,
+    },
+
+    /// Prints statistics of the DHT lookups of this node.
+    DhtDebug,
+
+    /// Benchmarks inserting into and looking up from the DHT.
+    BenchDht {
+        #[arg(long, default_value_t = 10)]
+        /// how many insert-and-lookup rounds to run
+        iterations: u16,
+    },
+
+    /// Prints the onion traffic exchanged with each neighbor.
+    BandwidthStats,
 }
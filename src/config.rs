@@ -1,16 +1,25 @@
-use std::{collections::BTreeMap, io::Write, net::SocketAddr, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
 
 use anyhow::Context;
 use earendil_crypt::{Fingerprint, IdentitySecret};
 use earendil_packet::Dock;
+use itertools::Itertools;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 use std::fs::OpenOptions;
+use thiserror::Error;
 
 use crate::socket::Endpoint;
 
 /// A YAML-serializable configuration file
-#[derive(Serialize, Deserialize, Clone)]
+#[serde_as]
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
 pub struct ConfigFile {
     /// Seed of the long-term identity. Must be long and difficult to guess!
     ///
@@ -21,6 +30,12 @@ pub struct ConfigFile {
     /// Where to listen for the local control protocol.
     #[serde(default = "default_control_listen")]
     pub control_listen: SocketAddr,
+    /// Where to serve `/metrics` in the Prometheus text format, if anywhere.
+    #[serde(default)]
+    pub metrics_listen: Option<SocketAddr>,
+    /// How many traffic-generating control protocol calls per second the daemon accepts, with bursts of up to a second's worth. Calls beyond that fail with a rate-limit error.
+    #[serde(default = "default_rate_limit_calls_per_second")]
+    pub rate_limit_calls_per_second: f64,
 
     /// List of all listeners for incoming connections
     #[serde(default)]
@@ -39,6 +54,221 @@ pub struct ConfigFile {
     /// List of all haven configs
     #[serde(default)]
     pub havens: Vec<HavenForwardConfig>,
+    /// DHT key under which relays announce themselves, so that nodes that know no relays yet can bootstrap. Defaults to a well-known key.
+    #[serde_as(as = "Option<serde_with::DisplayFromStr>")]
+    #[serde(default)]
+    #[schemars(with = "Option<String>")]
+    pub bootstrap_key: Option<Fingerprint>,
+    /// How many seconds an anonymous peer's reply blocks can go unused before the peer is probed, and forgotten if it doesn't answer.
+    #[serde(default = "default_idle_probe_secs")]
+    pub idle_probe_secs: u64,
+    /// How many seconds a haven locator stays valid after the haven made it. Havens refresh their locators every half minute, so older ones in the DHT belong to havens that went away.
+    #[serde(default = "default_locator_ttl_secs")]
+    pub locator_ttl_secs: u64,
+    /// How many seconds the reply blocks that anonymous peers send stay usable. Relays forget their nonces when they restart, so old reply blocks are likely to lead nowhere.
+    #[serde(default = "default_reply_block_ttl_secs")]
+    pub reply_block_ttl_secs: u64,
+    /// Where to keep an on-disk cache of looked-up haven locators, which stand in when the network fails to answer a lookup, even after a restart. Without one, locators are only cached in memory.
+    #[serde(default)]
+    pub dht_cache_path: Option<PathBuf>,
+    /// How many DHT inserts per minute this node stores for any one haven identity, with bursts of up to a minute's worth. Inserts beyond that are refused, so that no one can flood this node's shard of the DHT.
+    #[serde(default = "default_dht_inserts_per_minute")]
+    pub dht_inserts_per_minute: u32,
+    /// How many DHT inserts per minute this node stores for haven identities it doesn't store a locator for yet, all together, with bursts of up to a minute's worth. Identities cost nothing to make, so this is what bounds how fast the shard can grow.
+    #[serde(default = "default_dht_new_keys_per_minute")]
+    pub dht_new_keys_per_minute: u32,
+    /// Tunables of the connections to neighbors.
+    #[serde(default)]
+    pub link_connection: LinkConnectionConfig,
+    /// Named constraints on the routes that sockets and havens send through. Sockets that don't name one route like an empty profile.
+    #[serde(default)]
+    pub profiles: BTreeMap<String, RouteProfile>,
+}
+
+impl ConfigFile {
+    /// Reads, parses and validates a YAML config file, substituting `${VAR}` references to environment variables in its string values.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(path).context("cannot read config file")?;
+        let mut json: serde_json::Value =
+            serde_yaml::from_str(&raw).context("syntax error in config file")?;
+        interpolate_env_values(&mut json)?;
+        let config: ConfigFile = serde_json::from_value(json)?;
+        if let Err(errors) = config.validate() {
+            anyhow::bail!(
+                "invalid config file:\n{}",
+                errors.iter().map(|e| format!("- {e}")).join("\n")
+            );
+        }
+        Ok(config)
+    }
+
+    /// Returns a JSON Schema describing the config file, for editors and CI to validate it against.
+    pub fn json_schema() -> serde_json::Value {
+        serde_json::to_value(schemars::schema_for!(ConfigFile))
+            .expect("JSON schemas always serialize")
+    }
+
+    /// Checks for mistakes that would otherwise only surface at runtime, returning all of them at once.
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = vec![];
+
+        let listens: Vec<(&String, (&str, SocketAddr))> = self
+            .in_routes
+            .iter()
+            .filter_map(|(name, config)| Some((name, config.listen()?)))
+            .collect();
+        for (i, (first, (first_proto, first_listen))) in listens.iter().enumerate() {
+            for (second, (second_proto, second_listen)) in &listens[i + 1..] {
+                // an unspecified address also takes the port on every specific address
+                let conflicting = first_proto == second_proto
+                    && first_listen.port() == second_listen.port()
+                    && first_listen.port() != 0
+                    && (first_listen.ip() == second_listen.ip()
+                        || first_listen.ip().is_unspecified()
+                        || second_listen.ip().is_unspecified());
+                if conflicting {
+                    errors.push(ConfigError::ConflictingListen {
+                        first: first.to_string(),
+                        second: second.to_string(),
+                        port: first_listen.port(),
+                    });
+                }
+            }
+        }
+
+        let mut out_route_fingerprints: BTreeMap<Fingerprint, &String> = BTreeMap::new();
+        for (name, config) in self.out_routes.iter() {
+            let fingerprint = config.fingerprint();
+            if let Some(first) = out_route_fingerprints.insert(fingerprint, name) {
+                errors.push(ConfigError::DuplicateFingerprint {
+                    first: first.clone(),
+                    second: name.clone(),
+                    fingerprint,
+                });
+            }
+        }
+
+        for (i, first) in self.havens.iter().enumerate() {
+            for second in &self.havens[i + 1..] {
+                if let (Some(first_dock), Some(second_dock)) =
+                    (first.handler.listen_dock(), second.handler.listen_dock())
+                {
+                    if first.identity == second.identity && first_dock == second_dock {
+                        errors.push(ConfigError::ConflictingHavenDock { dock: first_dock });
+                    }
+                }
+            }
+        }
+
+        for haven in &self.havens {
+            if let Some(profile) = &haven.route_profile {
+                if !self.profiles.contains_key(profile) {
+                    errors.push(ConfigError::UnknownProfile(profile.clone()));
+                }
+            }
+        }
+
+        if self.idle_probe_secs == 0 {
+            errors.push(ConfigError::OutOfRange {
+                field: "idle_probe_secs",
+                expected: "at least 1",
+            });
+        }
+        if self.locator_ttl_secs == 0 {
+            errors.push(ConfigError::OutOfRange {
+                field: "locator_ttl_secs",
+                expected: "at least 1",
+            });
+        }
+        if self.reply_block_ttl_secs == 0 {
+            errors.push(ConfigError::OutOfRange {
+                field: "reply_block_ttl_secs",
+                expected: "at least 1",
+            });
+        }
+        if self.dht_inserts_per_minute == 0 {
+            errors.push(ConfigError::OutOfRange {
+                field: "dht_inserts_per_minute",
+                expected: "at least 1",
+            });
+        }
+        if self.dht_new_keys_per_minute == 0 {
+            errors.push(ConfigError::OutOfRange {
+                field: "dht_new_keys_per_minute",
+                expected: "at least 1",
+            });
+        }
+        if self.rate_limit_calls_per_second.is_nan() || self.rate_limit_calls_per_second <= 0.0 {
+            errors.push(ConfigError::OutOfRange {
+                field: "rate_limit_calls_per_second",
+                expected: "positive",
+            });
+        }
+        if self.link_connection.channel_capacity == 0 {
+            errors.push(ConfigError::OutOfRange {
+                field: "link_connection.channel_capacity",
+                expected: "at least 1",
+            });
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// A mistake in a config file, found by [ConfigFile::validate].
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("in_routes {first} and {second} both listen on port {port}")]
+    ConflictingListen {
+        first: String,
+        second: String,
+        port: u16,
+    },
+    #[error("out_routes {first} and {second} both connect to {fingerprint}")]
+    DuplicateFingerprint {
+        first: String,
+        second: String,
+        fingerprint: Fingerprint,
+    },
+    #[error("two havens with the same identity both listen on dock {dock}")]
+    ConflictingHavenDock { dock: Dock },
+    #[error("route profile {0} is used but not defined in profiles")]
+    UnknownProfile(String),
+    #[error("environment variable {0} is referenced in the config file but not set")]
+    MissingEnvVar(String),
+    #[error("{field} must be {expected}")]
+    OutOfRange {
+        field: &'static str,
+        expected: &'static str,
+    },
+}
+
+fn default_idle_probe_secs() -> u64 {
+    300
+}
+
+fn default_locator_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_reply_block_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_dht_inserts_per_minute() -> u32 {
+    10
+}
+
+fn default_dht_new_keys_per_minute() -> u32 {
+    600
+}
+
+fn default_rate_limit_calls_per_second() -> f64 {
+    1000.0
 }
 
 fn default_control_listen() -> SocketAddr {
@@ -46,100 +276,425 @@ fn default_control_listen() -> SocketAddr {
 }
 
 #[serde_as]
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
 #[serde(tag = "protocol", rename_all = "snake_case")]
 pub enum InRouteConfig {
+    /// Accepts obfuscated UDP connections from neighbors.
     Obfsudp {
+        /// address to listen on
         #[serde_as(as = "serde_with::DisplayFromStr")]
+        #[schemars(with = "String")]
         listen: SocketAddr,
+        /// secret that neighbors' cookies are derived from
         secret: String,
     },
+    /// Accepts plain TCP connections from neighbors, for networks that block UDP. Neighbors are still authenticated by the link protocol, but the traffic isn't disguised.
+    Tcp {
+        /// address to listen on
+        #[serde_as(as = "serde_with::DisplayFromStr")]
+        #[schemars(with = "String")]
+        listen: SocketAddr,
+        /// PEM file with a certificate chain and its private key; if given, connections must be wrapped in TLS
+        #[serde(default)]
+        tls_cert: Option<PathBuf>,
+    },
+    /// Accepts QUIC connections from neighbors.
+    Quic {
+        /// address to listen on
+        #[serde_as(as = "serde_with::DisplayFromStr")]
+        #[schemars(with = "String")]
+        listen: SocketAddr,
+        /// PEM file with the certificate chain to present; neighbors don't check it, since the link protocol authenticates us anyway
+        cert: PathBuf,
+        /// PEM file with the certificate's private key
+        key: PathBuf,
+    },
+    /// Accepts WebSocket connections from neighbors, which can pass through CDNs and reverse proxies that support WebSocket.
+    #[serde(rename = "websocket")]
+    WebSocket {
+        /// address to listen on
+        #[serde_as(as = "serde_with::DisplayFromStr")]
+        #[schemars(with = "String")]
+        listen: SocketAddr,
+        /// HTTP path that WebSocket upgrades are accepted at, such as `/earendil`
+        path: String,
+        /// PEM file with a certificate chain and its private key; if given, neighbors connect with `wss://` rather than `ws://`
+        #[serde(default)]
+        tls_cert: Option<PathBuf>,
+    },
+    /// Accepts connections from other processes on the same machine through a Unix domain socket. Not available on Windows.
+    UnixSocket {
+        /// where to create the socket; a stale socket left there by a previous run is replaced
+        path: PathBuf,
+    },
+    /// Accepts neighbors through a transport that the embedding program registered with [crate::daemon::DaemonBuilder::register_transport].
+    Custom {
+        /// name of the registered transport
+        transport: String,
+        /// settings passed to the transport as they are
+        #[serde(default)]
+        config: serde_json::Value,
+    },
+}
+
+impl InRouteConfig {
+    /// The address this in_route listens on, and the transport protocol it listens with. Unix sockets and custom transports don't listen on an address.
+    pub fn listen(&self) -> Option<(&'static str, SocketAddr)> {
+        match self {
+            InRouteConfig::Obfsudp { listen, .. } | InRouteConfig::Quic { listen, .. } => {
+                Some(("udp", *listen))
+            }
+            InRouteConfig::Tcp { listen, .. } | InRouteConfig::WebSocket { listen, .. } => {
+                Some(("tcp", *listen))
+            }
+            InRouteConfig::UnixSocket { .. } | InRouteConfig::Custom { .. } => None,
+        }
+    }
 }
 
 #[serde_as]
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
 #[serde(tag = "protocol", rename_all = "snake_case")]
 pub enum OutRouteConfig {
+    /// Connects to a neighbor over obfuscated UDP.
     Obfsudp {
+        /// fingerprint of the neighbor
         #[serde_as(as = "serde_with::DisplayFromStr")]
+        #[schemars(with = "String")]
         fingerprint: Fingerprint,
+        /// address of the neighbor's in_route
         #[serde_as(as = "serde_with::DisplayFromStr")]
+        #[schemars(with = "String")]
         connect: SocketAddr,
+        /// hex-encoded cookie of the neighbor's in_route
         #[serde_as(as = "serde_with::hex::Hex")]
+        #[schemars(with = "String")]
         cookie: [u8; 32],
+        /// the most bandwidth, in kbps, to forward for this neighbor, overriding `link_connection.bandwidth_kbps`. If the neighbor also limits the link, the lower of the two limits applies.
+        #[serde(default)]
+        bandwidth_limit_kbps: Option<u32>,
+        /// how strongly to prefer this link when routes through several neighbors are equally short, from 0 (lowest) to 255 (highest)
+        #[serde(default = "default_priority")]
+        priority: u8,
+    },
+    /// Connects to a neighbor's TCP in_route.
+    Tcp {
+        /// fingerprint of the neighbor
+        #[serde_as(as = "serde_with::DisplayFromStr")]
+        #[schemars(with = "String")]
+        fingerprint: Fingerprint,
+        /// host and port of the neighbor's in_route
+        connect: String,
+        /// whether the in_route expects TLS; its certificate isn't checked, since the link protocol authenticates the neighbor anyway
+        #[serde(default)]
+        tls: bool,
+        /// the most bandwidth, in kbps, to forward for this neighbor, overriding `link_connection.bandwidth_kbps`. If the neighbor also limits the link, the lower of the two limits applies.
+        #[serde(default)]
+        bandwidth_limit_kbps: Option<u32>,
+        /// how strongly to prefer this link when routes through several neighbors are equally short, from 0 (lowest) to 255 (highest)
+        #[serde(default = "default_priority")]
+        priority: u8,
     },
+    /// Connects to a neighbor's QUIC in_route.
+    Quic {
+        /// fingerprint of the neighbor
+        #[serde_as(as = "serde_with::DisplayFromStr")]
+        #[schemars(with = "String")]
+        fingerprint: Fingerprint,
+        /// address of the neighbor's in_route
+        #[serde_as(as = "serde_with::DisplayFromStr")]
+        #[schemars(with = "String")]
+        connect: SocketAddr,
+        /// the most bandwidth, in kbps, to forward for this neighbor, overriding `link_connection.bandwidth_kbps`. If the neighbor also limits the link, the lower of the two limits applies.
+        #[serde(default)]
+        bandwidth_limit_kbps: Option<u32>,
+        /// how strongly to prefer this link when routes through several neighbors are equally short, from 0 (lowest) to 255 (highest)
+        #[serde(default = "default_priority")]
+        priority: u8,
+    },
+    /// Connects to a neighbor's WebSocket in_route, possibly through a CDN or reverse proxy.
+    #[serde(rename = "websocket")]
+    WebSocket {
+        /// fingerprint of the neighbor
+        #[serde_as(as = "serde_with::DisplayFromStr")]
+        #[schemars(with = "String")]
+        fingerprint: Fingerprint,
+        /// `ws://` or `wss://` URL of the neighbor's in_route. Certificates of `wss://` servers aren't checked, since the link protocol authenticates the neighbor anyway.
+        url: String,
+        /// the most bandwidth, in kbps, to forward for this neighbor, overriding `link_connection.bandwidth_kbps`. If the neighbor also limits the link, the lower of the two limits applies.
+        #[serde(default)]
+        bandwidth_limit_kbps: Option<u32>,
+        /// how strongly to prefer this link when routes through several neighbors are equally short, from 0 (lowest) to 255 (highest)
+        #[serde(default = "default_priority")]
+        priority: u8,
+    },
+    /// Connects to a neighbor's Unix socket in_route on the same machine. Not available on Windows.
+    UnixSocket {
+        /// fingerprint of the neighbor
+        #[serde_as(as = "serde_with::DisplayFromStr")]
+        #[schemars(with = "String")]
+        fingerprint: Fingerprint,
+        /// path of the neighbor's socket
+        path: PathBuf,
+        /// the most bandwidth, in kbps, to forward for this neighbor, overriding `link_connection.bandwidth_kbps`. If the neighbor also limits the link, the lower of the two limits applies.
+        #[serde(default)]
+        bandwidth_limit_kbps: Option<u32>,
+        /// how strongly to prefer this link when routes through several neighbors are equally short, from 0 (lowest) to 255 (highest)
+        #[serde(default = "default_priority")]
+        priority: u8,
+    },
+    /// Connects to a neighbor through a transport that the embedding program registered with [crate::daemon::DaemonBuilder::register_transport].
+    Custom {
+        /// fingerprint of the neighbor
+        #[serde_as(as = "serde_with::DisplayFromStr")]
+        #[schemars(with = "String")]
+        fingerprint: Fingerprint,
+        /// name of the registered transport
+        transport: String,
+        /// settings passed to the transport as they are
+        #[serde(default)]
+        config: serde_json::Value,
+        /// the most bandwidth, in kbps, to forward for this neighbor, overriding `link_connection.bandwidth_kbps`. If the neighbor also limits the link, the lower of the two limits applies.
+        #[serde(default)]
+        bandwidth_limit_kbps: Option<u32>,
+        /// how strongly to prefer this link when routes through several neighbors are equally short, from 0 (lowest) to 255 (highest)
+        #[serde(default = "default_priority")]
+        priority: u8,
+    },
+}
+
+impl OutRouteConfig {
+    /// The fingerprint of the neighbor this out_route connects to.
+    pub fn fingerprint(&self) -> Fingerprint {
+        match self {
+            OutRouteConfig::Obfsudp { fingerprint, .. }
+            | OutRouteConfig::Tcp { fingerprint, .. }
+            | OutRouteConfig::Quic { fingerprint, .. }
+            | OutRouteConfig::WebSocket { fingerprint, .. }
+            | OutRouteConfig::UnixSocket { fingerprint, .. }
+            | OutRouteConfig::Custom { fingerprint, .. } => *fingerprint,
+        }
+    }
 }
 
+/// The priority of links that don't configure one, including those of neighbors that connected to us.
+pub const DEFAULT_PRIORITY: u8 = 128;
+
+fn default_priority() -> u8 {
+    DEFAULT_PRIORITY
+}
+
+/// Tunables of a connection to a neighbor. Missing fields take their default values.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default)]
+pub struct LinkConnectionConfig {
+    /// how many onion packets can wait to be sent, or to be processed after being received, before more are dropped
+    pub channel_capacity: usize,
+    /// the most bandwidth, in kbps, that this node forwards for each neighbor; zero means no limit
+    pub bandwidth_kbps: u32,
+}
+
+impl Default for LinkConnectionConfig {
+    fn default() -> Self {
+        Self {
+            channel_capacity: 100,
+            bandwidth_kbps: 0,
+        }
+    }
+}
+
+/// Constraints on the routes a socket's messages take. Missing fields take their default values, which don't constrain routes at all.
 #[serde_as]
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct RouteProfile {
+    /// among equally short routes, go through the neighbor with the lowest round-trip time, rather than the one with the highest-priority link
+    pub prefer_low_latency: bool,
+    /// the fewest relays a route must go through between us and the destination, taking a longer route if the shortest has fewer
+    pub require_relay_count: usize,
+    /// nodes that routes must never go through
+    #[serde_as(as = "Vec<serde_with::DisplayFromStr>")]
+    #[schemars(with = "Vec<String>")]
+    pub exclude_fingerprints: Vec<Fingerprint>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
 #[serde(rename_all = "snake_case")]
 pub struct UdpForwardConfig {
+    /// local address to accept UDP traffic on
     pub listen: SocketAddr,
+    /// haven endpoint to forward it to, as fingerprint:dock
     #[serde_as(as = "serde_with::DisplayFromStr")]
+    #[schemars(with = "String")]
     pub remote: Endpoint,
 }
 
 #[serde_as]
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
 #[serde(rename_all = "snake_case")]
 pub struct TcpForwardConfig {
+    /// local address to accept TCP traffic on
     pub listen: SocketAddr,
+    /// haven endpoint to forward it to, as fingerprint:dock
     #[serde_as(as = "serde_with::DisplayFromStr")]
+    #[schemars(with = "String")]
     pub remote: Endpoint,
 }
 
 #[serde_as]
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
 #[serde(rename_all = "snake_case")]
 pub struct Socks5 {
+    /// local address to accept SOCKS5 connections on
     pub listen: SocketAddr,
+    /// what to do with connections to addresses that aren't havens
     pub fallback: Fallback,
 }
 
 #[serde_as]
-#[derive(Serialize, Deserialize, Clone, Copy)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy)]
 #[serde(rename_all = "snake_case")]
 pub enum Fallback {
+    /// refuse the connection
     Block,
+    /// connect directly, without going through Earendil
     PassThrough,
+    /// go through a haven running a simple proxy
     SimpleProxy {
+        /// endpoint of the simple proxy, as fingerprint:dock
         #[serde_as(as = "serde_with::DisplayFromStr")]
+        #[schemars(with = "String")]
         remote: Endpoint,
     },
 }
 
 #[serde_as]
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub struct HavenForwardConfig {
+    /// long-term identity of the haven
     #[serde(flatten)]
     pub identity: Identity,
+    /// fingerprint of the relay the haven registers with
     #[serde_as(as = "serde_with::DisplayFromStr")]
+    #[schemars(with = "String")]
     pub rendezvous: Fingerprint,
+    /// what the haven serves
     pub handler: ForwardHandler,
+    /// Shell command to run whenever the haven fails, e.g. because its upstream can't be reached. It gets the haven's fingerprint in `$EARENDIL_HAVEN_FP` and the failure in `$EARENDIL_FAIL_REASON`. While the haven keeps failing, the command runs less and less often, down to once an hour.
+    #[serde(default)]
+    pub on_fail_command: Option<String>,
+    /// Name of the entry in `profiles` constraining the routes of the haven's replies.
+    #[serde(default)]
+    pub route_profile: Option<String>,
+    /// Fingerprints of the only clients allowed to connect, which must bind their sockets with those identities. Empty allows anyone.
+    #[serde(default)]
+    #[serde_as(as = "Vec<serde_with::DisplayFromStr>")]
+    #[schemars(with = "Vec<String>")]
+    pub allowed_clients: Vec<Fingerprint>,
 }
 
 #[serde_as]
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ForwardHandler {
+    /// Forwards UDP traffic to a local service.
     UdpService {
+        /// dock the haven listens on
         listen_dock: Dock,
+        /// address of the service
         upstream: SocketAddr,
     },
+    /// Forwards TCP traffic to a local service.
     TcpService {
+        /// dock the haven listens on
         listen_dock: Dock,
+        /// address of the service
         upstream: SocketAddr,
     },
+    /// Proxies connections to arbitrary addresses, for SOCKS5 clients falling back to it.
     SimpleProxy {
+        /// dock the haven listens on
         listen_dock: Dock,
     },
+    /// Accepts HTTP/1.1 CONNECT requests, relaying each to a TCP connection to the requested host.
+    HttpConnectProxy {
+        /// dock the haven listens on
+        listen_dock: Dock,
+        /// domains that may be connected to, along with their subdomains; every other host is refused
+        allowed_domains: Vec<String>,
+    },
+    /// Resolves DNS queries that arrive as haven datagrams, by asking an upstream resolver.
+    DnsResolver {
+        /// dock the haven listens on
+        #[serde(default = "default_dns_dock")]
+        listen_dock: Dock,
+        /// address of the upstream resolver
+        #[serde(default = "default_dns_upstream")]
+        #[serde_as(as = "serde_with::DisplayFromStr")]
+        #[schemars(with = "String")]
+        upstream: SocketAddr,
+    },
+    /// Runs a local DNS server over UDP that passes each query on to a `dns_resolver` haven, so lookups don't reveal who makes them. Queries go out under a fresh identity rather than the haven's, and nothing is registered at the rendezvous.
+    DnsProxy {
+        /// local address to accept DNS queries on
+        #[serde_as(as = "serde_with::DisplayFromStr")]
+        #[schemars(with = "String")]
+        listen: SocketAddr,
+        /// fingerprint of the resolver haven
+        #[serde_as(as = "serde_with::DisplayFromStr")]
+        #[schemars(with = "String")]
+        resolver_haven: Fingerprint,
+        /// dock the resolver haven listens on
+        #[serde(default = "default_dns_dock")]
+        resolver_dock: Dock,
+    },
+    /// Runs a local SOCKS5 proxy that tunnels CONNECT and UDP ASSOCIATE requests to havens, under a fresh client identity for every connection unless `use_haven_identity` is set. Nothing is registered at the rendezvous.
+    Socks5Proxy {
+        /// local address to accept SOCKS5 clients on
+        #[serde_as(as = "serde_with::DisplayFromStr")]
+        #[schemars(with = "String")]
+        listen: SocketAddr,
+        /// the haven each destination IP address stands for, with the destination port as the haven's dock. `<fingerprint>.haven` names reach havens without an entry here.
+        #[serde(default)]
+        #[serde_as(as = "BTreeMap<serde_with::DisplayFromStr, serde_with::DisplayFromStr>")]
+        #[schemars(with = "BTreeMap<String, String>")]
+        routes: BTreeMap<IpAddr, Fingerprint>,
+        /// reach havens under the haven's identity, so that havens that allow only certain clients can let the proxy in, at the cost of letting them link all of its connections together
+        #[serde(default)]
+        use_haven_identity: bool,
+    },
+}
+
+fn default_dns_dock() -> Dock {
+    53
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+fn default_dns_upstream() -> SocketAddr {
+    "1.1.1.1:53".parse().unwrap()
+}
+
+impl ForwardHandler {
+    /// The dock the haven listens on, if it serves anything over earendil.
+    pub fn listen_dock(&self) -> Option<Dock> {
+        match self {
+            ForwardHandler::UdpService { listen_dock, .. }
+            | ForwardHandler::TcpService { listen_dock, .. }
+            | ForwardHandler::SimpleProxy { listen_dock }
+            | ForwardHandler::HttpConnectProxy { listen_dock, .. }
+            | ForwardHandler::DnsResolver { listen_dock, .. } => Some(*listen_dock),
+            ForwardHandler::Socks5Proxy { .. } | ForwardHandler::DnsProxy { .. } => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
 /// A configuration for an identity, specified either as a human-readable seed that will be passed through a KDF, or a file that stores the raw binary bytes of the identity secret.
 #[serde(rename_all = "snake_case")]
 pub enum Identity {
+    /// seed that the identity is derived from
     IdentitySeed(String),
+    /// file holding the identity, created if it doesn't exist
     IdentityFile(PathBuf),
 }
 
@@ -180,3 +735,180 @@ impl Identity {
         }
     }
 }
+
+/// Substitutes environment variables in every string value of a parsed config file, but not in keys. This happens after parsing, so that values can hold any characters without changing the structure of the file.
+fn interpolate_env_values(value: &mut serde_json::Value) -> Result<(), ConfigError> {
+    match value {
+        serde_json::Value::String(s) => *s = interpolate_env(s)?,
+        serde_json::Value::Array(items) => {
+            for item in items {
+                interpolate_env_values(item)?;
+            }
+        }
+        serde_json::Value::Object(fields) => {
+            for field in fields.values_mut() {
+                interpolate_env_values(field)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Replaces every `${VAR}` in a config string value with the value of the environment variable `VAR`, so that secrets need not be stored in the file itself.
+pub fn interpolate_env(raw: &str) -> Result<String, ConfigError> {
+    let mut out = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start + 2..].find('}') else {
+            break;
+        };
+        let name = &rest[start + 2..start + 2 + len];
+        let value =
+            std::env::var(name).map_err(|_| ConfigError::MissingEnvVar(name.to_string()))?;
+        out.push_str(&rest[..start]);
+        out.push_str(&value);
+        rest = &rest[start + 2 + len + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interpolate_env() {
+        std::env::set_var("EARENDIL_TEST_SEED", "correct horse");
+        assert_eq!(
+            interpolate_env("identity_seed: ${EARENDIL_TEST_SEED}\nsecret: ${EARENDIL_TEST_SEED}!")
+                .unwrap(),
+            "identity_seed: correct horse\nsecret: correct horse!"
+        );
+        assert_eq!(interpolate_env("cost: $5 {x}").unwrap(), "cost: $5 {x}");
+        assert!(matches!(
+            interpolate_env("secret: ${EARENDIL_TEST_UNSET}"),
+            Err(ConfigError::MissingEnvVar(name)) if name == "EARENDIL_TEST_UNSET"
+        ));
+
+        // Testing that values are substituted after parsing, so they can't change the structure of the file
+        std::env::set_var("EARENDIL_TEST_TRICKY", "a # b: c\nd: e");
+        let mut json: serde_json::Value = serde_yaml::from_str(
+            "# ${EARENDIL_TEST_UNSET}\nsecret: ${EARENDIL_TEST_TRICKY}\nlist: [\"${EARENDIL_TEST_SEED}\"]",
+        )
+        .unwrap();
+        interpolate_env_values(&mut json).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"secret": "a # b: c\nd: e", "list": ["correct horse"]})
+        );
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let schema = ConfigFile::json_schema();
+        assert_eq!(
+            schema["properties"]["control_listen"]["description"],
+            "Where to listen for the local control protocol."
+        );
+        assert!(schema["definitions"]["OutRouteConfig"]
+            .to_string()
+            .contains("hex-encoded cookie"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let peer = IdentitySecret::generate()
+            .public()
+            .fingerprint()
+            .to_string();
+        let haven = |dock: Dock| {
+            serde_json::json!({
+                "identity_seed": "haven",
+                "rendezvous": peer,
+                "handler": {"type": "simple_proxy", "listen_dock": dock},
+            })
+        };
+        let out_route = serde_json::json!({
+            "protocol": "obfsudp",
+            "fingerprint": peer,
+            "connect": "10.0.0.1:1234",
+            "cookie": hex::encode([0u8; 32]),
+        });
+        let mut config: ConfigFile = serde_json::from_value(serde_json::json!({
+            "in_routes": {
+                "a": {"protocol": "obfsudp", "listen": "0.0.0.0:1000", "secret": "a"},
+                "b": {"protocol": "obfsudp", "listen": "127.0.0.1:1001", "secret": "b"},
+            },
+            "out_routes": {"x": out_route},
+            "havens": [haven(1), haven(2)],
+        }))
+        .unwrap();
+        assert!(config.validate().is_ok());
+
+        // Testing that every mistake is reported at once
+        config.in_routes.insert(
+            "c".into(),
+            InRouteConfig::Obfsudp {
+                listen: "127.0.0.1:1000".parse().unwrap(),
+                secret: "c".into(),
+            },
+        );
+        // a TCP listener doesn't take the port from a UDP one
+        config.in_routes.insert(
+            "d".into(),
+            InRouteConfig::Tcp {
+                listen: "0.0.0.0:1000".parse().unwrap(),
+                tls_cert: None,
+            },
+        );
+        config.out_routes.insert(
+            "y".into(),
+            serde_json::from_value(serde_json::json!({
+                "protocol": "tcp",
+                "fingerprint": peer,
+                "connect": "relay.example.com:1000",
+            }))
+            .unwrap(),
+        );
+        config
+            .havens
+            .push(serde_json::from_value(haven(2)).unwrap());
+        config.link_connection.channel_capacity = 0;
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors.len(), 4);
+        assert!(matches!(
+            &errors[0],
+            ConfigError::ConflictingListen { first, second, port: 1000 } if first == "a" && second == "c"
+        ));
+        assert!(matches!(
+            &errors[1],
+            ConfigError::DuplicateFingerprint { first, second, .. } if first == "x" && second == "y"
+        ));
+        assert!(matches!(
+            &errors[2],
+            ConfigError::ConflictingHavenDock { dock: 2 }
+        ));
+        assert!(matches!(
+            &errors[3],
+            ConfigError::OutOfRange {
+                field: "link_connection.channel_capacity",
+                ..
+            }
+        ));
+
+        // Testing that havens can only use defined route profiles
+        config.havens[0].route_profile = Some("strict".into());
+        assert!(config
+            .validate()
+            .unwrap_err()
+            .iter()
+            .any(|e| matches!(e, ConfigError::UnknownProfile(profile) if profile == "strict")));
+        config
+            .profiles
+            .insert("strict".into(), RouteProfile::default());
+        // defining the profile leaves only the mistakes from before
+        assert_eq!(config.validate().unwrap_err().len(), errors.len());
+    }
+}
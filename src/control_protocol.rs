@@ -1,5 +1,6 @@
 use crate::commands::ControlCommands;
-use crate::socket::Endpoint;
+use crate::config::{InRouteConfig, OutRouteConfig};
+use crate::socket::{Endpoint, HavenSocketConfig, SocketKind, SocketStats};
 use crate::{daemon::ControlProtErr, haven_util::HavenLocator};
 use anyhow::Context;
 use async_trait::async_trait;
@@ -9,12 +10,17 @@ use earendil_packet::{
     crypt::{OnionPublic, OnionSecret},
     Dock, PacketConstructError,
 };
+use itertools::Itertools;
 use nanorpc::nanorpc_derive;
 use nanorpc_http::client::HttpRpcTransport;
+use parking_lot::Mutex;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 use std::marker::Send;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{net::SocketAddr, str::FromStr};
 use thiserror::Error;
 
@@ -36,13 +42,36 @@ pub async fn main_control(
             anon_id,
             dock,
             rendezvous,
+            config,
         } => {
-            client.bind_haven(skt_id, anon_id, dock, rendezvous).await?;
+            let config: Option<HavenSocketConfig> = config
+                .map(|config| serde_yaml::from_str(&config))
+                .transpose()
+                .context("haven socket config not valid YAML")?;
+            client
+                .bind_haven(skt_id, anon_id, dock, rendezvous, config)
+                .await?;
         }
         ControlCommands::SktInfo { skt_id } => {
             let skt_info = client.skt_info(skt_id).await??;
             println!("{skt_info}")
         }
+        ControlCommands::ReloadConfig => {
+            client.reload_config().await??;
+            println!("config reloaded");
+        }
+        ControlCommands::SocketStats { skt_id } => {
+            let stats = client.socket_stats(skt_id).await??;
+            println!("{}", serde_yaml::to_string(&stats)?);
+        }
+        ControlCommands::TestEcho {
+            skt_id,
+            dest,
+            payload_size,
+        } => {
+            let rtt = client.send_test_echo(skt_id, dest, payload_size).await??;
+            println!("echo from {dest} in {rtt:?}");
+        }
         ControlCommands::SendMsg {
             skt_id: socket_id,
             dest: destination,
@@ -62,6 +91,91 @@ pub async fn main_control(
                 Err(e) => println!("error receiving message: {e}"),
             }
         }
+        ControlCommands::Events { kind } => {
+            let subscription_id = client.subscribe_events(EventFilter { kinds: kind }).await?;
+            loop {
+                for event in client.poll_events(subscription_id.clone()).await?? {
+                    println!("{}", serde_json::to_string(&event)?);
+                }
+            }
+        }
+        ControlCommands::EventLog { from, limit } => {
+            for event in client.read_event_log(from, limit).await? {
+                println!("{}", serde_json::to_string(&event)?);
+            }
+        }
+        ControlCommands::Ping { fp } => {
+            let rtt = client.ping(fp).await??;
+            println!("reply from {fp} in {rtt:?}");
+        }
+        ControlCommands::TraceRoute { fp } => {
+            let hops = client.trace_route(fp).await??;
+            for (i, hop) in hops.iter().enumerate() {
+                println!("{} {hop}", i + 1);
+            }
+            if hops.last() != Some(&fp) {
+                println!("{} * (no reply)", hops.len() + 1);
+            }
+        }
+        ControlCommands::ListSkts => {
+            for entry in client.list_sockets().await? {
+                println!(
+                    "{}: {:?} socket at {}, bound at {}",
+                    entry.socket_id, entry.kind, entry.endpoint, entry.created_at
+                );
+            }
+        }
+        ControlCommands::CloseSkt { skt_id } => {
+            client.close_socket(skt_id).await??;
+        }
+        ControlCommands::EnableDebugPcap { skt_id, path } => {
+            client.enable_debug_pcap(skt_id, path).await??;
+        }
+        ControlCommands::DisableDebugPcap { skt_id } => {
+            client.disable_debug_pcap(skt_id).await??;
+        }
+        ControlCommands::ListHavens { relay } => {
+            for locator in client.list_havens(relay).await?? {
+                let fp = locator.identity_pk.fingerprint();
+                if locator.rendezvous_backups.is_empty() {
+                    println!("{} -> {}", fp, locator.rendezvous_point);
+                } else {
+                    println!(
+                        "{} -> {} (backups: {})",
+                        fp,
+                        locator.rendezvous_point,
+                        locator.rendezvous_backups.iter().join(", ")
+                    );
+                }
+            }
+        }
+        ControlCommands::AddInRoute { name, config } => {
+            let config: InRouteConfig =
+                serde_yaml::from_str(&config).context("in_route config not valid YAML")?;
+            client.add_in_route(name, config).await??;
+        }
+        ControlCommands::RemoveInRoute { name } => {
+            client.remove_in_route(name).await??;
+        }
+        ControlCommands::AddPeer { config } => {
+            let config: OutRouteConfig =
+                serde_yaml::from_str(&config).context("out_route config not valid YAML")?;
+            client.add_peer(config).await??;
+        }
+        ControlCommands::RemovePeer { fp } => {
+            client.remove_peer(fp).await??;
+        }
+        ControlCommands::SetHavenRendezvous { skt_id, rendezvous } => {
+            client.set_haven_rendezvous(skt_id, rendezvous).await??;
+        }
+        ControlCommands::AnnounceRoute { route, ttl } => {
+            client
+                .announce_route(route, Duration::from_secs(ttl))
+                .await??;
+        }
+        ControlCommands::HavenEchoServer { dock, rendezvous } => {
+            haven_echo_server(client, dock, rendezvous).await?;
+        }
         ControlCommands::GlobalRpc {
             id,
             dest: destination,
@@ -102,6 +216,23 @@ pub async fn main_control(
                 println!("No haven locator found for fingerprint {key}")
             }
         }
+        ControlCommands::RemoveRendezvous { key } => {
+            client.remove_rendezvous(key).await??;
+        }
+        ControlCommands::BatchGetRendezvous { keys } => {
+            for (key, locator) in client.batch_get_rendezvous(keys).await?? {
+                if let Some(locator) = locator {
+                    println!("{key}: {:?}", locator);
+                } else {
+                    println!("{key}: no haven locator found");
+                }
+            }
+        }
+        ControlCommands::NearestRendezvous { key, count } => {
+            for locator in client.nearest_rendezvous(key, count).await?? {
+                println!("{}: {:?}", locator.identity_pk.fingerprint(), locator);
+            }
+        }
         ControlCommands::RendezvousHavenTest => {
             let mut fingerprint_bytes = [0; 20];
             rand::thread_rng().fill_bytes(&mut fingerprint_bytes);
@@ -129,6 +260,47 @@ pub async fn main_control(
             let routes = client.my_routes().await?;
             println!("{}", serde_yaml::to_string(&routes)?);
         }
+        ControlCommands::GetPath { skt_id, dest } => {
+            let path = client.get_path_for_socket(skt_id, dest).await??;
+            println!("{}", path.iter().join(" -> "));
+        }
+        ControlCommands::SetRouteProfile { skt_id, profile } => {
+            client.set_route_profile(skt_id, profile).await??;
+        }
+        ControlCommands::DhtDebug => {
+            let stats = client.dht_debug().await?;
+            println!("{}", serde_yaml::to_string(&stats)?);
+        }
+        ControlCommands::BenchDht { iterations } => {
+            let result = client.bench_dht(iterations).await??;
+            println!("{}", serde_yaml::to_string(&result)?);
+        }
+        ControlCommands::BandwidthStats => {
+            let stats = client.bandwidth_stats().await?;
+            println!(
+                "in the last {}s: {} bytes sent, {} bytes received",
+                stats.measurement_window_secs, stats.total_bytes_sent, stats.total_bytes_received
+            );
+            for neigh in stats.per_neighbor {
+                let idle = neigh
+                    .idle_secs
+                    .map_or("no packets yet".into(), |idle| format!("idle {idle}s"));
+                println!(
+                    "{}: {} bytes sent, {} bytes received, up {}s, {}",
+                    neigh.fingerprint,
+                    neigh.bytes_sent,
+                    neigh.bytes_received,
+                    neigh.uptime_secs,
+                    idle
+                );
+            }
+            for haven in stats.per_haven {
+                println!(
+                    "haven {}: {} bytes sent, {} bytes received in the last minute",
+                    haven.fingerprint, haven.bytes_sent, haven.bytes_received
+                );
+            }
+        }
         ControlCommands::HavensInfo => {
             let havens_info = client.havens_info().await?;
             for info in havens_info {
@@ -139,6 +311,78 @@ pub async fn main_control(
     Ok(())
 }
 
+/// Starts test echoes that carry the sender's UNIX time in microseconds, as a big-endian u64 right after the tag. The tag ends with the version of this format.
+pub(crate) const TIMED_ECHO_TAG: &[u8] = b"earendil-echo-v1";
+
+/// Returns the send time that a timed test echo carries, in UNIX microseconds, or `None` for any other message.
+fn timed_echo_sent_micros(msg: &[u8]) -> Option<u64> {
+    let timestamp = msg.strip_prefix(TIMED_ECHO_TAG)?.get(..8)?;
+    Some(u64::from_be_bytes(timestamp.try_into().ok()?))
+}
+
+#[derive(Default)]
+struct EchoStats {
+    messages: u64,
+    bytes: u64,
+    timed_messages: u64,
+    total_response_time: Duration,
+}
+
+async fn haven_echo_server(
+    client: ControlClient,
+    dock: Dock,
+    rendezvous: Fingerprint,
+) -> anyhow::Result<()> {
+    let skt_id = format!("haven-echo-server-{dock}");
+    client
+        .bind_haven(skt_id.clone(), None, Some(dock), Some(rendezvous), None)
+        .await?;
+    let endpoint = client.skt_info(skt_id.clone()).await??;
+    println!("echoing on {endpoint}");
+
+    let stats = Arc::new(Mutex::new(EchoStats::default()));
+    let _printer = smolscale::spawn({
+        let stats = stats.clone();
+        async move {
+            loop {
+                smol::Timer::after(Duration::from_secs(10)).await;
+                let stats = stats.lock();
+                let avg_response_time = if stats.timed_messages > 0 {
+                    stats.total_response_time / stats.timed_messages as u32
+                } else {
+                    Duration::ZERO
+                };
+                println!(
+                    "received {} messages ({} bytes); average response time {:?}",
+                    stats.messages, stats.bytes, avg_response_time
+                );
+            }
+        }
+    });
+
+    loop {
+        let (msg, src) = client.recv_message(skt_id.clone()).await??;
+        {
+            let mut stats = stats.lock();
+            stats.messages += 1;
+            stats.bytes += msg.len() as u64;
+            if let Some(sent_micros) = timed_echo_sent_micros(&msg) {
+                let now_micros = SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as u64;
+                stats.timed_messages += 1;
+                stats.total_response_time +=
+                    Duration::from_micros(now_micros.saturating_sub(sent_micros));
+            }
+        }
+        client
+            .send_message(SendMessageArgs {
+                socket_id: skt_id.clone(),
+                destination: src,
+                content: msg,
+            })
+            .await??;
+    }
+}
+
 #[nanorpc_derive]
 #[async_trait]
 pub trait ControlProtocol {
@@ -150,31 +394,149 @@ pub trait ControlProtocol {
         anon_id: Option<String>,
         dock: Option<Dock>,
         rendezvous_point: Option<Fingerprint>,
+        config: Option<HavenSocketConfig>,
     );
 
     async fn skt_info(&self, skt_id: String) -> Result<Endpoint, ControlProtErr>;
 
+    /// Reloads the config file the daemon was started with, starting and stopping in_routes and out_routes to match it. Other fields can't change without a restart.
+    async fn reload_config(&self) -> Result<(), ReloadError>;
+
+    /// Returns statistics of the messages a socket received.
+    async fn socket_stats(&self, skt_id: String) -> Result<SocketStats, ControlProtErr>;
+
     async fn havens_info(&self) -> Vec<(String, String)>;
 
+    /// Sends `payload_size` bytes to a destination running `haven-echo-server` and returns the time until they are echoed back. Results are cached for a minute per destination and payload size.
+    async fn send_test_echo(
+        &self,
+        socket_id: String,
+        destination: Endpoint,
+        payload_size: usize,
+    ) -> Result<Duration, ControlProtErr>;
+
     async fn send_message(&self, args: SendMessageArgs) -> Result<(), ControlProtErr>;
 
     async fn recv_message(&self, socket_id: String) -> Result<(Bytes, Endpoint), ControlProtErr>;
 
+    /// Starts collecting the daemon events that pass the filter, returning the id to poll them with. Subscriptions that go unpolled for 5 minutes are dropped.
+    async fn subscribe_events(&self, filter: EventFilter) -> String;
+
+    /// Waits up to 20 seconds for events on a subscription, returning every one that arrived since the last poll. Returns no events if none arrived in time.
+    async fn poll_events(
+        &self,
+        subscription_id: String,
+    ) -> Result<Vec<DaemonEvent>, ControlProtErr>;
+
+    /// Returns the first `limit` events that the daemon logged with sequence numbers of `from_seq` or later, oldest first. Only the latest 10000 events are kept; passing one more than the sequence number of the last event returned pages through the rest.
+    async fn read_event_log(&self, from_seq: u64, limit: usize) -> Vec<LoggedEvent>;
+
+    /// Lists the sockets bound through the control protocol.
+    async fn list_sockets(&self) -> Vec<SocketEntry>;
+
+    /// Unbinds a socket, after waiting for the messages it already sent to leave.
+    async fn close_socket(&self, socket_id: String) -> Result<(), ControlProtErr>;
+
+    /// Starts writing every message sent or received through the given socket into a PCAP-NG file at `path`.
+    async fn enable_debug_pcap(
+        &self,
+        socket_id: String,
+        path: PathBuf,
+    ) -> Result<(), ControlProtErr>;
+
+    /// Stops a capture started with `enable_debug_pcap` and closes its file.
+    async fn disable_debug_pcap(&self, socket_id: String) -> Result<(), ControlProtErr>;
+
+    /// Lists the haven locators stored by a relay's shard of the DHT.
+    async fn list_havens(&self, relay: Fingerprint) -> Result<Vec<HavenLocator>, GlobalRpcError>;
+
+    /// Starts listening on a new in_route without restarting the daemon.
+    async fn add_in_route(&self, name: String, config: InRouteConfig)
+        -> Result<(), ControlProtErr>;
+
+    /// Stops an in_route added with `add_in_route`, closing every connection accepted through it.
+    async fn remove_in_route(&self, name: String) -> Result<(), ControlProtErr>;
+
+    /// Starts connecting to a new neighbor without restarting the daemon. The neighbor is forgotten on restart.
+    async fn add_peer(&self, config: OutRouteConfig) -> Result<(), ControlProtErr>;
+
+    /// Disconnects from a neighbor added with `add_peer`.
+    async fn remove_peer(&self, fp: Fingerprint) -> Result<(), ControlProtErr>;
+
+    /// Moves a live haven socket to another rendezvous relay, or takes it off every rendezvous relay with `None`.
+    async fn set_haven_rendezvous(
+        &self,
+        socket_id: String,
+        rendezvous_fp: Option<Fingerprint>,
+    ) -> Result<(), ControlProtErr>;
+
+    /// Measures the round-trip time to a node: over the link for neighbors, or with a GlobalRpc ping through onion routes otherwise. A GlobalRpc ping goes out even if earlier calls to the node kept failing, so that it can close the node's circuit breaker again.
+    async fn ping(&self, fp: Fingerprint) -> Result<Duration, ControlProtErr>;
+
+    /// Probes each relay on the route this node would send to a node through, returning those that answered up to the first that didn't. The node is reachable if it's the last one returned. Each relay is probed over its own onion route, so this shows which relays on the route are reachable, not the path that any one packet took.
+    async fn trace_route(&self, fp: Fingerprint) -> Result<Vec<Fingerprint>, ControlProtErr>;
+
     async fn send_global_rpc(
         &self,
         args: GlobalRpcArgs,
     ) -> Result<serde_json::Value, GlobalRpcError>;
 
+    /// Tells every relay on the route that traffic will flow along it for the next `ttl`, so that they keep their links along it up meanwhile. Relays that don't support reservations ignore it.
+    async fn announce_route(
+        &self,
+        route: Vec<Fingerprint>,
+        ttl: Duration,
+    ) -> Result<(), ControlProtErr>;
+
     async fn graph_dump(&self, human: bool) -> String;
 
     async fn my_routes(&self) -> serde_json::Value;
 
+    /// Returns the onion path that a socket's messages to the given destination would take. Haven sockets reach havens through their rendezvous relay, so that's the destination to ask about for them.
+    async fn get_path_for_socket(
+        &self,
+        socket_id: String,
+        destination: Fingerprint,
+    ) -> Result<Vec<Fingerprint>, ControlProtErr>;
+
+    /// Makes a socket route its messages according to a profile in the config file, or without constraints given `None`.
+    async fn set_route_profile(
+        &self,
+        socket_id: String,
+        profile: Option<String>,
+    ) -> Result<(), ControlProtErr>;
+
+    /// Returns the onion traffic exchanged with currently connected neighbors during the current measurement window, in total and per neighbor.
+    async fn bandwidth_stats(&self) -> BandwidthStats;
+
+    /// Returns statistics of this node's DHT lookups.
+    async fn dht_debug(&self) -> DhtStats;
+
+    /// Benchmarks the DHT end to end, by inserting and looking up a locator for a fresh identity `iterations` times.
+    async fn bench_dht(&self, iterations: u16) -> Result<DhtBenchResult, DhtError>;
+
     async fn insert_rendezvous(&self, locator: HavenLocator) -> Result<(), DhtError>;
 
     async fn get_rendezvous(
         &self,
         fingerprint: Fingerprint,
     ) -> Result<Option<HavenLocator>, DhtError>;
+
+    /// Deletes the locator of a haven from the DHT. The haven's identity must be one this daemon has: the global identity, a configured haven's, or an anonymous identity a haven socket was bound with.
+    async fn remove_rendezvous(&self, fingerprint: Fingerprint) -> Result<(), DhtError>;
+
+    /// Returns up to `k` of the locators stored on this node whose fingerprints are XOR-closest to `fingerprint`, closest first. Only this node's shard and cache are searched, not the whole DHT.
+    async fn nearest_rendezvous(
+        &self,
+        fingerprint: Fingerprint,
+        k: usize,
+    ) -> Result<Vec<HavenLocator>, DhtError>;
+
+    /// Looks up many rendezvous haven locators, a few at a time. Fingerprints whose lookups fail or time out map to `None`. Batches that are too large are refused with [DhtError::BatchTooLarge].
+    async fn batch_get_rendezvous(
+        &self,
+        fingerprints: Vec<Fingerprint>,
+    ) -> Result<Vec<(Fingerprint, Option<HavenLocator>)>, DhtError>;
 }
 
 #[derive(Error, Serialize, Deserialize, Debug)]
@@ -191,12 +553,30 @@ pub enum SendMessageError {
     NoAnonId,
 }
 
+#[derive(Error, Serialize, Deserialize, Debug)]
+pub enum ReloadError {
+    #[error("the daemon was not started from a config file")]
+    NoConfigFile,
+    #[error("could not load config file: {0}")]
+    BadConfigFile(String),
+    #[error("the {0} field cannot be changed without restarting the daemon")]
+    ImmutableField(String),
+    #[error("could not start in_route {0}")]
+    InRouteError(String),
+}
+
 #[derive(Error, Serialize, Deserialize, Debug)]
 pub enum DhtError {
     #[error("failed to verify descriptor retrieved from DHT")]
     VerifyFailed,
     #[error("network failed: {0}")]
     NetworkFailure(String),
+    #[error("this daemon doesn't have the identity secret of this haven")]
+    UnknownIdentity,
+    #[error("too many DHT requests; try again later")]
+    RateLimited,
+    #[error("a batch lookup can ask for at most {0} locators")]
+    BatchTooLarge(usize),
 }
 
 #[serde_as]
@@ -219,8 +599,179 @@ pub struct GlobalRpcArgs {
     pub args: Vec<serde_json::Value>,
 }
 
+/// A snapshot of the statistics of a node's DHT lookups.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DhtStats {
+    pub total_lookups: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub network_successes: u64,
+    pub network_failures: u64,
+    /// network lookups that timed out; these are also counted in `network_failures`
+    pub network_timeouts: u64,
+    /// counts of all network lookups taking under 100ms, 500ms, 1s, 5s, and longer, in that order
+    pub latency_histogram: [u64; 5],
+    /// average duration of the latest lookups that went to the network
+    pub avg_lookup_ms: f64,
+    /// 99th percentile duration of the latest lookups that went to the network
+    pub p99_lookup_ms: f64,
+}
+
+/// The outcome of a DHT benchmark.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DhtBenchResult {
+    pub avg_insert_ms: f64,
+    pub avg_lookup_ms: f64,
+    pub p99_insert_ms: f64,
+    pub p99_lookup_ms: f64,
+    /// fraction of the rounds in which the inserted locator could not be looked up
+    pub failure_rate: f64,
+}
+
+/// Something that happened in the daemon, as delivered to event subscriptions.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum DaemonEvent {
+    NeighborConnected(Fingerprint),
+    NeighborDisconnected(Fingerprint),
+    /// a haven registered with this node as its rendezvous point
+    HavenRegistered(Fingerprint),
+    /// a haven locator was stored in this node's shard of the DHT
+    DhtInsert(Fingerprint),
+    PacketDropped {
+        reason: DropReason,
+    },
+    /// onion packets were peeled and passed on to a neighbor; one event sums up the packets forwarded to that neighbor over about a second
+    PacketForwarded {
+        next_hop: Fingerprint,
+        count: u64,
+    },
+    /// a DHT lookup finished, either from the cache or the network
+    DhtLookup {
+        fingerprint: Fingerprint,
+        found: bool,
+    },
+    /// a neighbor's in_route config changed since it was last checked, so out_routes to it may need updating
+    NeighborConfigChanged(Fingerprint),
+}
+
+impl DaemonEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            DaemonEvent::NeighborConnected(_) => EventKind::NeighborConnected,
+            DaemonEvent::NeighborDisconnected(_) => EventKind::NeighborDisconnected,
+            DaemonEvent::HavenRegistered(_) => EventKind::HavenRegistered,
+            DaemonEvent::DhtInsert(_) => EventKind::DhtInsert,
+            DaemonEvent::PacketDropped { .. } => EventKind::PacketDropped,
+            DaemonEvent::PacketForwarded { .. } => EventKind::PacketForwarded,
+            DaemonEvent::DhtLookup { .. } => EventKind::DhtLookup,
+            DaemonEvent::NeighborConfigChanged(_) => EventKind::NeighborConfigChanged,
+        }
+    }
+}
+
+/// Why the daemon dropped a packet.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// the packet could not be peeled with our onion key
+    BadPacket,
+    /// the packet was to be forwarded to a node that isn't our neighbor
+    NoNextHop,
+    /// a reply arrived through a reply block that we have no record of
+    NoDegarbler,
+    /// a message arrived for a dock that no socket is bound to
+    NoSocket,
+    /// a queue of packets waiting to be processed was full, or the socket it was for was backpressured
+    QueueFull,
+}
+
+/// The kinds of [DaemonEvent], for filtering subscriptions.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum EventKind {
+    NeighborConnected,
+    NeighborDisconnected,
+    HavenRegistered,
+    DhtInsert,
+    PacketDropped,
+    PacketForwarded,
+    DhtLookup,
+    NeighborConfigChanged,
+}
+
+/// A [DaemonEvent] as kept in the daemon's event log.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LoggedEvent {
+    /// numbers the events in the order they were logged, starting from 0 when the daemon starts
+    pub seq: u64,
+    /// when the event happened, in milliseconds since the UNIX epoch
+    pub timestamp_ms: u64,
+    pub kind: EventKind,
+    /// the event itself, as JSON
+    pub payload: serde_json::Value,
+}
+
+/// Which events a subscription delivers. No kinds at all means every kind.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct EventFilter {
+    pub kinds: Vec<EventKind>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &DaemonEvent) -> bool {
+        self.kinds.is_empty() || self.kinds.contains(&event.kind())
+    }
+}
+
+/// A socket bound through the control protocol.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SocketEntry {
+    pub socket_id: String,
+    pub endpoint: Endpoint,
+    pub kind: SocketKind,
+    /// UNIX timestamp of when the socket was bound
+    pub created_at: u64,
+}
+
+/// Onion traffic exchanged with all connected neighbors since the counters last restarted. Counters restart every minute, giving a rolling rate.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BandwidthStats {
+    pub total_bytes_sent: u64,
+    pub total_bytes_received: u64,
+    /// how long ago the counters restarted, i.e. the period the byte counts cover
+    pub measurement_window_secs: u64,
+    pub per_neighbor: Vec<NeighborBandwidth>,
+    /// haven traffic over the last minute, whatever `measurement_window_secs` is
+    pub per_haven: Vec<HavenBandwidth>,
+}
+
+/// Plaintext bytes that haven sessions exchanged over the last minute, by the fingerprint of the haven: our own for havens we serve, the remote one for havens we talk to.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HavenBandwidth {
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    pub fingerprint: Fingerprint,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// Onion traffic exchanged with a neighbor over its current connection during the measurement window, and how lively that connection is.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NeighborBandwidth {
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    pub fingerprint: Fingerprint,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// how long the connection has been established
+    pub uptime_secs: u64,
+    /// how long ago a packet was last sent or received; `None` if none ever was
+    pub idle_secs: Option<u64>,
+}
+
 #[derive(Error, Serialize, Deserialize, Debug)]
 pub enum GlobalRpcError {
     #[error("error sending GlobalRpc request")]
     SendError,
+    #[error("destination failed too many times recently; not trying again yet")]
+    CircuitOpen,
 }
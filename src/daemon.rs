@@ -1,18 +1,31 @@
 pub(crate) mod context;
 mod control_protocol_impl;
+mod debug_pcap;
+mod event_log;
+pub(crate) mod events;
+mod framed_pipe;
 
 pub(crate) mod dht;
+mod dht_cache;
 mod gossip;
+pub(crate) mod haven_bandwidth;
 mod inout_route;
 mod link_connection;
 mod link_protocol;
+pub(crate) mod metrics;
 mod neightable;
 mod peel_forward;
+mod peer_probe;
+mod quic_pipe;
+mod reload;
 mod reply_block_store;
 mod rrb_balance;
-mod socks5;
+pub(crate) mod socks5;
 mod tcp_forward;
+mod tls;
+pub(crate) mod token_bucket;
 mod udp_forward;
+mod ws_pipe;
 
 use bytes::Bytes;
 use clone_macro::clone;
@@ -20,7 +33,7 @@ use earendil_crypt::{Fingerprint, IdentitySecret};
 use earendil_packet::ForwardInstruction;
 
 use earendil_topology::RelayGraph;
-use futures_util::{stream::FuturesUnordered, StreamExt, TryFutureExt};
+use futures_util::TryFutureExt;
 use moka::sync::Cache;
 use nanorpc::{JrpcRequest, RpcService};
 use nanorpc_http::server::HttpRpcServer;
@@ -31,24 +44,37 @@ use stdcode::StdcodeSerializeExt;
 
 use std::thread::available_parallelism;
 
-use std::{sync::Arc, time::Duration};
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::socket::Endpoint;
+use crate::transport::{EarendilTransport, TransportRegistry};
 use crate::{config::ConfigFile, global_rpc::GLOBAL_RPC_DOCK};
+use crate::{control_protocol::SendMessageError, global_rpc::GlobalRpcService};
 use crate::{
-    config::{InRouteConfig, OutRouteConfig},
-    control_protocol::ControlService,
+    control_protocol::{ControlService, ReloadError},
     daemon::{
+        dht::{dht_announce_self, dht_discover_relays, DHT_PERSISTENT_CACHE},
         gossip::gossip_loop,
-        inout_route::{in_route_obfsudp, out_route_obfsudp, InRouteContext, OutRouteContext},
+        haven_bandwidth::HAVEN_BANDWIDTH,
+        reload::start_configured_routes,
     },
 };
-use crate::{control_protocol::SendMessageError, global_rpc::GlobalRpcService};
-use crate::{daemon::context::DaemonContext, global_rpc::server::GlobalRpcImpl};
+use crate::{
+    daemon::context::DaemonContext,
+    global_rpc::server::{garbage_collect_channels, GlobalRpcImpl},
+};
 use crate::{daemon::context::NEIGH_TABLE, socket::n2r_socket::N2rSocket};
 use crate::{
     daemon::{
-        peel_forward::peel_forward_loop, socks5::socks5_loop, tcp_forward::tcp_forward_loop,
+        metrics::metrics_loop,
+        peel_forward::{emit_forward_events, peel_forward_loop, FORWARD_EVENT_INTERVAL},
+        peer_probe::peer_probe_loop,
+        socks5::socks5_loop,
+        tcp_forward::tcp_forward_loop,
         udp_forward::udp_forward_loop,
     },
     log_error,
@@ -60,7 +86,13 @@ use crate::{
 
 pub use self::control_protocol_impl::ControlProtErr;
 
-use self::{context::GLOBAL_IDENTITY, control_protocol_impl::ControlProtocolImpl};
+use self::{
+    context::{
+        BANDWIDTH_WINDOW, BANDWIDTH_WINDOW_START, CONFIG_PATH, GLOBAL_IDENTITY, IN_ROUTE_FAILURES,
+        TRANSPORTS,
+    },
+    control_protocol_impl::ControlProtocolImpl,
+};
 
 pub struct Daemon {
     pub(crate) ctx: DaemonContext,
@@ -70,18 +102,55 @@ pub struct Daemon {
 impl Daemon {
     /// Initializes the daemon and starts all background loops
     pub fn init(config: ConfigFile) -> anyhow::Result<Daemon> {
-        let ctx = DaemonContext::new(config);
+        DaemonBuilder::new(config).build()
+    }
+
+    pub fn identity(&self) -> IdentitySecret {
+        *self.ctx.get(GLOBAL_IDENTITY)
+    }
+
+    /// Remembers the file the config was loaded from, so that it can be reloaded through the control protocol.
+    pub fn set_config_path(&self, path: PathBuf) {
+        let _ = self.ctx.get(CONFIG_PATH).set(path);
+    }
+
+    /// Switches the in_routes and out_routes over to those of a new config, which must otherwise be the same as the running one.
+    pub async fn reload_config(&self, config: ConfigFile) -> Result<(), ReloadError> {
+        reload::reload_config(&self.ctx, config).await
+    }
+}
+
+/// Sets up a daemon with additions that can't go in a config file, such as custom transports.
+pub struct DaemonBuilder {
+    config: ConfigFile,
+    transports: TransportRegistry,
+}
+
+impl DaemonBuilder {
+    pub fn new(config: ConfigFile) -> Self {
+        Self {
+            config,
+            transports: TransportRegistry::default(),
+        }
+    }
+
+    /// Makes a transport available to in_routes and out_routes with `protocol: custom`, under its [EarendilTransport::name].
+    pub fn register_transport(mut self, transport: impl EarendilTransport) -> Self {
+        self.transports.register(transport);
+        self
+    }
+
+    /// Initializes the daemon and starts all background loops
+    pub fn build(self) -> anyhow::Result<Daemon> {
+        let ctx = DaemonContext::new(self.config);
+        let _ = ctx.get(TRANSPORTS).set(self.transports);
         let context = ctx.clone();
         log::info!("starting background task for main_daemon");
         let task = Immortal::spawn(async move {
             main_daemon(context).await.unwrap();
             panic!("daemon failed to start!")
         });
-        Ok(Self { ctx, _task: task })
-    }
-
-    pub fn identity(&self) -> IdentitySecret {
-        *self.ctx.get(GLOBAL_IDENTITY)
+        Ok(Daemon { ctx, _task: task })
     }
 }
 
@@ -98,11 +167,34 @@ pub async fn main_daemon(ctx: DaemonContext) -> anyhow::Result<()> {
         )
     });
 
+    // opening the persistent DHT cache purges stale locators, so it's done at startup rather than on the first lookup
+    ctx.get(DHT_PERSISTENT_CACHE);
+
     // Run the loops
     let _table_gc = Immortal::spawn(clone!([ctx], async move {
         loop {
             smol::Timer::after(Duration::from_secs(60)).await;
             ctx.get(NEIGH_TABLE).garbage_collect();
+            ctx.get(HAVEN_BANDWIDTH).garbage_collect();
+            garbage_collect_channels(&ctx);
+        }
+    }));
+
+    let _forward_events = Immortal::spawn(clone!([ctx], async move {
+        loop {
+            smol::Timer::after(FORWARD_EVENT_INTERVAL).await;
+            emit_forward_events(&ctx);
+        }
+    }));
+
+    // traffic counters restart periodically, so that bandwidth_stats reflects recent rates rather than lifetime totals
+    let _bandwidth_window = Immortal::spawn(clone!([ctx], async move {
+        loop {
+            smol::Timer::after(BANDWIDTH_WINDOW).await;
+            *ctx.get(BANDWIDTH_WINDOW_START).lock() = Instant::now();
+            for neigh in ctx.get(NEIGH_TABLE).all_neighs() {
+                neigh.reset_traffic_stats();
+            }
         }
     }));
 
@@ -141,6 +233,33 @@ pub async fn main_daemon(ctx: DaemonContext) -> anyhow::Result<()> {
             .map_err(log_error("rendezvous_forward_loop"))),
     );
 
+    let _peer_probe_loop = Immortal::respawn(
+        RespawnStrategy::Immediate,
+        clone!([ctx], move || peer_probe_loop(ctx.clone())
+            .map_err(log_error("peer_probe_loop"))),
+    );
+
+    // relays periodically announce themselves, so that nodes without a relay graph can bootstrap
+    let _announce_self_loop = (!ctx.init().in_routes.is_empty()).then(|| {
+        Immortal::spawn(clone!([ctx], async move {
+            loop {
+                dht_announce_self(&ctx).await;
+                smol::Timer::after(Duration::from_secs(120)).await;
+            }
+        }))
+    });
+
+    // every node looks up the announced relays, which is how one with no relay graph yet finds relays beyond its neighbors
+    let _discover_relays_loop = Immortal::spawn(clone!([ctx], async move {
+        loop {
+            match dht_discover_relays(&ctx).await {
+                Ok(count) => log::debug!("found {count} relays announced in the DHT"),
+                Err(e) => log::debug!("could not look up announced relays: {e}"),
+            }
+            smol::Timer::after(Duration::from_secs(120)).await;
+        }
+    }));
+
     let _haven_loops: Vec<Immortal> = ctx
         .init()
         .havens
@@ -197,46 +316,18 @@ pub async fn main_daemon(ctx: DaemonContext) -> anyhow::Result<()> {
         )
     });
 
-    let mut route_tasks = FuturesUnordered::new();
-
-    // For every in_routes block, spawn a task to handle incoming stuff
-    for (in_route_name, config) in ctx.init().in_routes.iter() {
-        let context = InRouteContext {
-            in_route_name: in_route_name.clone(),
-            daemon_ctx: ctx.clone(),
-        };
-        match config.clone() {
-            InRouteConfig::Obfsudp { listen, secret } => {
-                route_tasks.push(smolscale::spawn(in_route_obfsudp(context, listen, secret)));
-            }
-        }
-    }
-
-    // For every out_routes block, spawn a task to handle outgoing stuff
-    for (out_route_name, config) in ctx.init().out_routes.iter() {
-        match config {
-            OutRouteConfig::Obfsudp {
-                fingerprint,
-                connect,
-                cookie,
-            } => {
-                let context = OutRouteContext {
-                    out_route_name: out_route_name.clone(),
-                    remote_fingerprint: *fingerprint,
-                    daemon_ctx: ctx.clone(),
-                };
-                route_tasks.push(smolscale::spawn(out_route_obfsudp(
-                    context, *connect, *cookie,
-                )));
-            }
-        }
-    }
+    let _metrics_loop = ctx.init().metrics_listen.map(|listen| {
+        Immortal::respawn(
+            RespawnStrategy::Immediate,
+            clone!([ctx], move || metrics_loop(ctx.clone(), listen)
+                .map_err(log_error("metrics_loop"))),
+        )
+    });
 
-    // Join all the tasks. If any of the tasks terminate with an error, that's fatal!
-    while let Some(next) = route_tasks.next().await {
-        next?;
-    }
-    Ok(())
+    // Start the in_routes and out_routes. If an in_route can't listen, or later stops with an error, that's fatal! Afterwards, they change only when the config is reloaded.
+    start_configured_routes(&ctx).await?;
+    let err = ctx.get(IN_ROUTE_FAILURES).1.recv().await?;
+    Err(err)
 }
 
 /// Loop that handles the control protocol
@@ -254,16 +345,26 @@ async fn global_rpc_loop(ctx: DaemonContext) -> anyhow::Result<()> {
         *ctx.get(GLOBAL_IDENTITY),
         Some(GLOBAL_RPC_DOCK),
     ));
-    let service = Arc::new(GlobalRpcService(GlobalRpcImpl::new(ctx)));
     let group: TaskReaper<anyhow::Result<()>> = TaskReaper::new();
 
     loop {
         let socket = socket.clone();
         if let Ok((req, endpoint)) = socket.recv_from().await {
-            let service = service.clone();
+            // pubsub subscriptions are made for the endpoint that asked
+            let service = GlobalRpcService(GlobalRpcImpl::new(ctx.clone(), endpoint));
             group.attach(smolscale::spawn(async move {
-                let req: JrpcRequest = serde_json::from_str(&String::from_utf8(req.to_vec())?)?;
+                let mut req: serde_json::Value =
+                    serde_json::from_str(&String::from_utf8(req.to_vec())?)?;
+                // notifications have no id, and must not be responded to
+                let is_notification = req.get("id").is_none();
+                if is_notification {
+                    req["id"] = 0.into();
+                }
+                let req: JrpcRequest = serde_json::from_value(req)?;
                 let resp = service.respond_raw(req).await;
+                if is_notification {
+                    return Ok(());
+                }
                 socket
                     .send_to(
                         Bytes::from(serde_json::to_string(&resp)?.into_bytes()),
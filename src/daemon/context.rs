@@ -1,5 +1,8 @@
 use std::{
+    collections::HashSet,
     ops::Deref,
+    path::PathBuf,
+    sync::OnceLock,
     time::{Duration, Instant},
 };
 
@@ -15,18 +18,28 @@ use itertools::Itertools;
 use moka::sync::{Cache, CacheBuilder};
 use once_cell::sync::Lazy;
 use parking_lot::{Mutex, RwLock};
-use smol::channel::Sender;
 
 use crate::{
-    config::ConfigFile, control_protocol::SendMessageError, daemon::route_to_instructs,
-    socket::Endpoint,
+    config::{ConfigFile, RouteProfile, DEFAULT_PRIORITY},
+    control_protocol::SendMessageError,
+    daemon::route_to_instructs,
+    socket::{n2r_socket::RecvQueue, Endpoint},
+    transport::TransportRegistry,
 };
 
 use super::{
-    neightable::NeighTable, reply_block_store::ReplyBlockStore, rrb_balance::replenish_rrb,
+    events::EVENTS,
+    inout_route::{InRouteHandle, OutRouteHandle},
+    link_connection::LinkConnection,
+    neightable::NeighTable,
+    reload::ConfiguredRoutes,
+    reply_block_store::{ReplyBlockStore, SharedReplyBlockStore},
+    rrb_balance::replenish_rrb,
 };
 
 pub type DaemonContext = anyctx::AnyCtx<ConfigFile>;
+
+/// A piece of daemon-wide state. The function is only run the first time the field is accessed through [DaemonContext::get], so fields can depend on each other and on the config without any particular initialization order, and subsystems that are never used are never initialized.
 pub type CtxField<T> = fn(&DaemonContext) -> T;
 
 pub static GLOBAL_IDENTITY: CtxField<IdentitySecret> = |ctx| {
@@ -42,10 +55,43 @@ pub static GLOBAL_IDENTITY: CtxField<IdentitySecret> = |ctx| {
 
 pub static GLOBAL_ONION_SK: CtxField<OnionSecret> = |_| OnionSecret::generate();
 pub static RELAY_GRAPH: CtxField<RwLock<RelayGraph>> = |_| RwLock::new(RelayGraph::new());
-pub static ANON_DESTS: CtxField<Mutex<ReplyBlockStore>> = |_| Mutex::new(ReplyBlockStore::new());
-pub static NEIGH_TABLE: CtxField<NeighTable> = |_| NeighTable::new();
-pub static SOCKET_RECV_QUEUES: CtxField<DashMap<Endpoint, Sender<(Message, Fingerprint)>>> =
-    |_| Default::default();
+pub static ANON_DESTS: CtxField<SharedReplyBlockStore> = |ctx| {
+    SharedReplyBlockStore::new(
+        ReplyBlockStore::new().set_ttl(Duration::from_secs(ctx.init().reply_block_ttl_secs)),
+    )
+};
+pub static NEIGH_TABLE: CtxField<NeighTable> =
+    |ctx| NeighTable::with_events(ctx.get(EVENTS).clone());
+/// In-routes added at runtime through the control protocol, keyed by name.
+pub static IN_ROUTES: CtxField<DashMap<String, InRouteHandle>> = |_| Default::default();
+/// Out-routes added at runtime through the control protocol, keyed by the neighbor they connect to.
+pub static OUT_ROUTES: CtxField<DashMap<Fingerprint, OutRouteHandle>> = |_| Default::default();
+/// Errors of in_routes that stopped on their own rather than being removed, which bring down the daemon.
+pub static IN_ROUTE_FAILURES: CtxField<(
+    smol::channel::Sender<anyhow::Error>,
+    smol::channel::Receiver<anyhow::Error>,
+)> = |_| smol::channel::unbounded();
+/// The in_routes and out_routes of the config file, which change when it's reloaded.
+pub static CONFIGURED_ROUTES: CtxField<smol::lock::Mutex<ConfiguredRoutes>> =
+    |ctx| smol::lock::Mutex::new(ConfiguredRoutes::new(ctx));
+/// Where the config was loaded from, if it came from a file.
+pub static CONFIG_PATH: CtxField<OnceLock<PathBuf>> = |_| OnceLock::new();
+/// The custom transports the daemon was built with, set once when it starts.
+pub static TRANSPORTS: CtxField<OnceLock<TransportRegistry>> = |_| OnceLock::new();
+/// The route profiles of the sockets that set one, by their local endpoint.
+pub static ROUTE_PROFILES: CtxField<DashMap<Endpoint, RouteProfile>> = |_| Default::default();
+pub static SOCKET_RECV_QUEUES: CtxField<DashMap<Endpoint, RecvQueue>> = |_| Default::default();
+/// How often the per-connection traffic counters behind `bandwidth_stats` restart.
+pub const BANDWIDTH_WINDOW: Duration = Duration::from_secs(60);
+/// When the per-connection traffic counters last restarted.
+pub static BANDWIDTH_WINDOW_START: CtxField<Mutex<Instant>> = |_| Mutex::new(Instant::now());
+
+/// Round-trip times of recent test echoes, by destination and payload size, since bigger payloads take longer.
+pub static ECHO_RTT_CACHE: CtxField<Cache<(Endpoint, usize), Duration>> = |_| {
+    CacheBuilder::default()
+        .time_to_live(Duration::from_secs(60))
+        .build()
+};
 pub static DEGARBLERS: CtxField<Cache<u64, ReplyDegarbler>> = |_| {
     CacheBuilder::default()
         .time_to_live(Duration::from_secs(60))
@@ -69,7 +115,7 @@ pub async fn send_n2r(
 
     let src_anon = &src_idsk != ctx.get(GLOBAL_IDENTITY);
 
-    let maybe_reply_block = ctx.get(ANON_DESTS).lock().pop(&dst_fp);
+    let maybe_reply_block = ctx.get(ANON_DESTS).pop(&dst_fp);
     if let Some(reply_block) = maybe_reply_block {
         if src_anon {
             return Err(SendMessageError::NoAnonId);
@@ -78,11 +124,12 @@ pub async fn send_n2r(
         let raw_packet = RawPacket::new_reply(&reply_block, inner, &src_idsk)?;
         ctx.get(NEIGH_TABLE).inject_asif_incoming(raw_packet).await;
     } else {
-        let route = ctx
-            .get(RELAY_GRAPH)
-            .read()
-            .find_shortest_path(&ctx.get(GLOBAL_IDENTITY).public().fingerprint(), &dst_fp)
-            .ok_or(SendMessageError::NoRoute(dst_fp))?;
+        let profile = ctx
+            .get(ROUTE_PROFILES)
+            .get(&Endpoint::new(src_idsk.public().fingerprint(), src_dock))
+            .map(|profile| profile.clone())
+            .unwrap_or_default();
+        let route = route_to(ctx, dst_fp, &profile)?;
         let instructs = {
             let graph = ctx.get(RELAY_GRAPH).read();
             route_to_instructs(route, &graph)
@@ -113,6 +160,62 @@ pub async fn send_n2r(
     Ok(())
 }
 
+/// Returns the onion path, starting with ourselves, that N2R messages to the given destination take when there's no reply block to use, following the constraints of the given route profile.
+pub fn route_to(
+    ctx: &DaemonContext,
+    dst_fp: Fingerprint,
+    profile: &RouteProfile,
+) -> Result<Vec<Fingerprint>, SendMessageError> {
+    let my_fp = ctx.get(GLOBAL_IDENTITY).public().fingerprint();
+    let graph = ctx.get(RELAY_GRAPH).read();
+    let mut excluded: HashSet<Fingerprint> = profile.exclude_fingerprints.iter().copied().collect();
+    let shortest = graph
+        .find_path(&my_fp, &dst_fp, &excluded, profile.require_relay_count)
+        .ok_or(SendMessageError::NoRoute(dst_fp))?;
+    let Some(first_hop) = shortest.get(1) else {
+        return Ok(shortest);
+    };
+    // among equally short routes, go through the neighbor with the highest-priority link, or with the lowest latency if the profile prefers that
+    let score = |conn: &LinkConnection| {
+        if profile.prefer_low_latency {
+            conn.last_rtt()
+                .map_or(0, |rtt| u64::MAX - rtt.as_micros() as u64)
+        } else {
+            conn.priority() as u64
+        }
+    };
+    let first_hop_score = ctx.get(NEIGH_TABLE).lookup(first_hop).map_or(
+        if profile.prefer_low_latency {
+            0
+        } else {
+            DEFAULT_PRIORITY as u64
+        },
+        |conn| score(&conn),
+    );
+    excluded.insert(my_fp);
+    let preferred = ctx
+        .get(NEIGH_TABLE)
+        .all_neighs()
+        .into_iter()
+        .filter(|conn| {
+            score(conn) > first_hop_score && !excluded.contains(&conn.remote_idpk().fingerprint())
+        })
+        .sorted_by_key(|conn| std::cmp::Reverse(score(conn)))
+        .find_map(|conn| {
+            let rest = graph.find_path(
+                &conn.remote_idpk().fingerprint(),
+                &dst_fp,
+                &excluded,
+                profile.require_relay_count.saturating_sub(1),
+            )?;
+            (rest.len() + 1 == shortest.len()).then_some(rest)
+        });
+    Ok(match preferred {
+        Some(rest) => std::iter::once(my_fp).chain(rest).collect(),
+        None => shortest,
+    })
+}
+
 /// Send a batch of reply blocks to the given N2R destination.
 pub async fn send_reply_blocks(
     ctx: &DaemonContext,
@@ -127,11 +230,15 @@ pub async fn send_reply_blocks(
 
     log::trace!("sending a batch of {count} reply blocks to {dst_fp}");
 
-    let route = ctx
-        .get(RELAY_GRAPH)
-        .read()
-        .find_shortest_path(&ctx.get(GLOBAL_IDENTITY).public().fingerprint(), &dst_fp)
-        .ok_or(SendMessageError::NoRoute(dst_fp))?;
+    // reply blocks carry the replies to every socket of an anonymous identity, so use the profile of one of them, always the same one
+    let profile = ctx
+        .get(ROUTE_PROFILES)
+        .iter()
+        .filter(|entry| entry.key().fingerprint == my_anon_isk.public().fingerprint())
+        .min_by_key(|entry| entry.key().dock)
+        .map(|entry| entry.value().clone())
+        .unwrap_or_default();
+    let route = route_to(ctx, dst_fp, &profile)?;
     let their_opk = ctx
         .get(RELAY_GRAPH)
         .read()
@@ -143,7 +250,12 @@ pub async fn send_reply_blocks(
     let reverse_route = ctx
         .get(RELAY_GRAPH)
         .read()
-        .find_shortest_path(&dst_fp, &ctx.get(GLOBAL_IDENTITY).public().fingerprint())
+        .find_path(
+            &dst_fp,
+            &ctx.get(GLOBAL_IDENTITY).public().fingerprint(),
+            &profile.exclude_fingerprints.iter().copied().collect(),
+            profile.require_relay_count,
+        )
         .ok_or(SendMessageError::NoRoute(dst_fp))?;
     let reverse_instructs = route_to_instructs(reverse_route, ctx.get(RELAY_GRAPH).read().deref())?;
 
@@ -1,14 +1,22 @@
-use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
+use async_broadcast::{RecvError, TryRecvError};
 use async_trait::async_trait;
 use bytes::Bytes;
 use dashmap::DashMap;
 use earendil_crypt::{Fingerprint, IdentitySecret};
 use earendil_packet::Dock;
+use futures_util::{stream::FuturesUnordered, StreamExt};
 use itertools::Itertools;
 use moka::sync::Cache;
 use nanorpc::RpcTransport;
 use parking_lot::Mutex;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use smol_timeout::TimeoutExt;
@@ -16,36 +24,117 @@ use sosistab2_obfsudp::ObfsUdpSecret;
 use thiserror::Error;
 
 use crate::{
-    config::InRouteConfig,
-    control_protocol::{ControlProtocol, DhtError, GlobalRpcArgs, GlobalRpcError, SendMessageArgs},
+    config::{ConfigFile, InRouteConfig, OutRouteConfig, RouteProfile},
+    control_protocol::{
+        BandwidthStats, ControlProtocol, DaemonEvent, DhtBenchResult, DhtError, DhtStats,
+        EventFilter, GlobalRpcArgs, GlobalRpcError, HavenBandwidth, LoggedEvent, NeighborBandwidth,
+        ReloadError, SendMessageArgs, SocketEntry, TIMED_ECHO_TAG,
+    },
     daemon::{
-        context::{NEIGH_TABLE, RELAY_GRAPH},
+        context::{
+            BANDWIDTH_WINDOW_START, CONFIGURED_ROUTES, CONFIG_PATH, ECHO_RTT_CACHE, IN_ROUTES,
+            NEIGH_TABLE, OUT_ROUTES, RELAY_GRAPH, ROUTE_PROFILES,
+        },
+        events::EVENTS,
+        haven_bandwidth::HAVEN_BANDWIDTH,
+        inout_route::{start_in_route, start_out_route},
+        reload::{reload_config, stop_in_route},
         DaemonContext,
     },
-    global_rpc::transport::GlobalRpcTransport,
+    global_rpc::{
+        transport::GlobalRpcTransport, GlobalRpcClient, RouteReservation, DHT_LIST_PAGE_SIZE,
+    },
     haven_util::HavenLocator,
-    socket::{Endpoint, Socket, SocketRecvError, SocketSendError},
+    socket::{Endpoint, HavenSocketConfig, Socket, SocketRecvError, SocketSendError, SocketStats},
 };
 
 use super::{
-    context::GLOBAL_IDENTITY,
-    dht::{dht_get, dht_insert},
+    context::{route_to, GLOBAL_IDENTITY},
+    debug_pcap::{DebugPcap, Direction},
+    dht::{
+        dht_batch_get, dht_bench, dht_get, dht_get_nearest, dht_get_stats, dht_insert, dht_remove,
+        DHT_GET_TIMEOUT,
+    },
+    token_bucket::TokenBucket,
 };
 
+/// How long `poll_events` waits for the first event.
+const EVENT_POLL_TIMEOUT: Duration = Duration::from_secs(20);
+
+struct EventSubscription {
+    filter: EventFilter,
+    recv: async_broadcast::Receiver<DaemonEvent>,
+}
+
 pub struct ControlProtocolImpl {
     anon_identities: Arc<Mutex<AnonIdentities>>,
     sockets: DashMap<String, Socket>,
+    /// when each socket in `sockets` was bound, as a UNIX timestamp
+    socket_created_at: DashMap<String, u64>,
+    pcaps: DashMap<String, DebugPcap>,
+    event_subscriptions: Cache<String, Arc<smol::lock::Mutex<EventSubscription>>>,
+    /// shared by every caller, since the HTTP control listener can't tell callers apart
+    call_limiter: Mutex<TokenBucket>,
     ctx: DaemonContext,
 }
 
 impl ControlProtocolImpl {
     pub fn new(ctx: DaemonContext) -> Self {
         Self {
+            call_limiter: Mutex::new(TokenBucket::new(ctx.init().rate_limit_calls_per_second)),
             ctx,
             sockets: DashMap::new(),
+            socket_created_at: DashMap::new(),
+            pcaps: DashMap::new(),
+            event_subscriptions: Cache::builder()
+                .time_to_idle(Duration::from_secs(5 * 60))
+                .build(),
             anon_identities: Arc::new(Mutex::new(AnonIdentities::new())),
         }
     }
+
+    /// Finds the identity secret with the given fingerprint among those havens bound through this daemon could be using.
+    fn find_identity(&self, fingerprint: Fingerprint) -> Option<IdentitySecret> {
+        let global = *self.ctx.get(GLOBAL_IDENTITY);
+        let configured = self
+            .ctx
+            .init()
+            .havens
+            .iter()
+            .filter_map(|haven| haven.identity.actualize().ok());
+        let anonymous = self.anon_identities.lock().all();
+        std::iter::once(global)
+            .chain(configured)
+            .chain(anonymous)
+            .find(|isk| isk.public().fingerprint() == fingerprint)
+    }
+
+    /// Takes a token for a call that generates network traffic, failing if the caller is over the configured rate.
+    fn check_rate_limit(&self) -> Result<(), ControlProtErr> {
+        if self.call_limiter.lock().try_take(1) {
+            Ok(())
+        } else {
+            Err(ControlProtErr::RateLimited)
+        }
+    }
+
+    /// Like [Self::check_rate_limit], for the DHT calls, which take a token for each locator they insert, look up or remove.
+    fn check_dht_rate_limit(&self, locators: usize) -> Result<(), DhtError> {
+        if self.call_limiter.lock().try_take(locators) {
+            Ok(())
+        } else {
+            Err(DhtError::RateLimited)
+        }
+    }
+
+    fn insert_socket(&self, socket_id: String, socket: Socket) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.socket_created_at.insert(socket_id.clone(), now);
+        self.sockets.insert(socket_id, socket);
+    }
 }
 
 #[async_trait]
@@ -55,7 +144,7 @@ impl ControlProtocol for ControlProtocolImpl {
             .map(|id| self.anon_identities.lock().get(&id))
             .unwrap_or_else(|| *self.ctx.get(GLOBAL_IDENTITY));
         let socket = Socket::bind_n2r_internal(self.ctx.clone(), anon_id, dock);
-        self.sockets.insert(socket_id, socket);
+        self.insert_socket(socket_id, socket);
     }
 
     async fn bind_haven(
@@ -64,12 +153,19 @@ impl ControlProtocol for ControlProtocolImpl {
         anon_id: Option<String>,
         dock: Option<Dock>,
         rendezvous_point: Option<Fingerprint>,
+        config: Option<HavenSocketConfig>,
     ) {
         let isk = anon_id
             .map(|id| self.anon_identities.lock().get(&id))
             .unwrap_or_else(|| *self.ctx.get(GLOBAL_IDENTITY));
-        let socket = Socket::bind_haven_internal(self.ctx.clone(), isk, dock, rendezvous_point);
-        self.sockets.insert(socket_id, socket);
+        let socket = Socket::bind_haven_internal_with_config(
+            self.ctx.clone(),
+            isk,
+            dock,
+            rendezvous_point.into_iter().collect(),
+            config.unwrap_or_default(),
+        );
+        self.insert_socket(socket_id, socket);
     }
 
     async fn skt_info(&self, skt_id: String) -> Result<Endpoint, ControlProtErr> {
@@ -80,6 +176,14 @@ impl ControlProtocol for ControlProtocolImpl {
         }
     }
 
+    async fn socket_stats(&self, skt_id: String) -> Result<SocketStats, ControlProtErr> {
+        if let Some(skt) = self.sockets.get(&skt_id) {
+            Ok(skt.socket_stats())
+        } else {
+            Err(ControlProtErr::NoSocket)
+        }
+    }
+
     async fn havens_info(&self) -> Vec<(String, String)> {
         self.ctx
             .init()
@@ -111,32 +215,304 @@ impl ControlProtocol for ControlProtocolImpl {
                         "SimpleProxy".to_string(),
                         fp.to_string() + ":" + &listen_dock.to_string(),
                     ),
+                    crate::config::ForwardHandler::HttpConnectProxy { listen_dock, .. } => (
+                        "HttpConnectProxy".to_string(),
+                        fp.to_string() + ":" + &listen_dock.to_string(),
+                    ),
+                    crate::config::ForwardHandler::DnsResolver { listen_dock, .. } => (
+                        "DnsResolver".to_string(),
+                        fp.to_string() + ":" + &listen_dock.to_string(),
+                    ),
+                    crate::config::ForwardHandler::DnsProxy { listen, .. } => {
+                        ("DnsProxy".to_string(), listen.to_string())
+                    }
+                    crate::config::ForwardHandler::Socks5Proxy { listen, .. } => {
+                        ("Socks5Proxy".to_string(), listen.to_string())
+                    }
                 }
             })
             .collect()
     }
 
+    async fn send_test_echo(
+        &self,
+        socket_id: String,
+        destination: Endpoint,
+        payload_size: usize,
+    ) -> Result<Duration, ControlProtErr> {
+        self.check_rate_limit()?;
+        if let Some(rtt) = self
+            .ctx
+            .get(ECHO_RTT_CACHE)
+            .get(&(destination, payload_size))
+        {
+            return Ok(rtt);
+        }
+        let socket = self
+            .sockets
+            .get(&socket_id)
+            .ok_or(ControlProtErr::NoSocket)?
+            .clone();
+        let mut payload = vec![0u8; payload_size];
+        rand::thread_rng().fill_bytes(&mut payload);
+        // the echo server reads a tagged timestamp to track its response times, if the payload has room for one
+        let tag_len = TIMED_ECHO_TAG.len();
+        if let Some(header) = payload.get_mut(..tag_len + 8) {
+            let now_micros = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_micros() as u64;
+            header[..tag_len].copy_from_slice(TIMED_ECHO_TAG);
+            header[tag_len..].copy_from_slice(&now_micros.to_be_bytes());
+        }
+        let payload = Bytes::from(payload);
+
+        let start = Instant::now();
+        socket.send_to(payload.clone(), destination).await?;
+        async {
+            loop {
+                let (msg, src) = socket.recv_from().await?;
+                if src == destination && msg == payload {
+                    return Ok::<_, ControlProtErr>(());
+                }
+                log::debug!("ignoring non-echo message from {src} while waiting for echo");
+            }
+        }
+        .timeout(Duration::from_secs(30))
+        .await
+        .ok_or(ControlProtErr::EchoTimeout)??;
+        let rtt = start.elapsed();
+        self.ctx
+            .get(ECHO_RTT_CACHE)
+            .insert((destination, payload_size), rtt);
+        Ok(rtt)
+    }
+
     async fn send_message(&self, args: SendMessageArgs) -> Result<(), ControlProtErr> {
-        if let Some(socket) = self.sockets.get(&args.socket_id) {
-            socket.send_to(args.content, args.destination).await?;
-            Ok(())
-        } else {
-            Err(ControlProtErr::NoSocket)
+        self.check_rate_limit()?;
+        // clone the socket out, so that the map isn't locked while sending
+        let socket = self
+            .sockets
+            .get(&args.socket_id)
+            .map(|s| s.clone())
+            .ok_or(ControlProtErr::NoSocket)?;
+        if let Some(pcap) = self.pcaps.get(&args.socket_id) {
+            pcap.record(Direction::Outbound, args.destination, &args.content);
         }
+        socket.send_to(args.content, args.destination).await?;
+        Ok(())
     }
 
     async fn recv_message(&self, socket_id: String) -> Result<(Bytes, Endpoint), ControlProtErr> {
-        if let Some(socket) = self.sockets.get(&socket_id) {
-            let recvd = socket.recv_from().await?;
-            Ok(recvd)
-        } else {
-            Err(ControlProtErr::NoSocket)
+        // holding the map's lock until a message arrives would block close_socket on the same socket
+        let socket = self
+            .sockets
+            .get(&socket_id)
+            .map(|s| s.clone())
+            .ok_or(ControlProtErr::NoSocket)?;
+        let recvd = socket.recv_from().await?;
+        if let Some(pcap) = self.pcaps.get(&socket_id) {
+            pcap.record(Direction::Inbound, recvd.1, &recvd.0);
+        }
+        Ok(recvd)
+    }
+
+    async fn enable_debug_pcap(
+        &self,
+        socket_id: String,
+        path: PathBuf,
+    ) -> Result<(), ControlProtErr> {
+        if !self.sockets.contains_key(&socket_id) {
+            return Err(ControlProtErr::NoSocket);
+        }
+        let pcap = DebugPcap::create(&path, &socket_id)
+            .map_err(|e| ControlProtErr::PcapError(e.to_string()))?;
+        self.pcaps.insert(socket_id, pcap);
+        Ok(())
+    }
+
+    async fn subscribe_events(&self, filter: EventFilter) -> String {
+        let subscription_id = hex::encode(rand::random::<[u8; 16]>());
+        let subscription = EventSubscription {
+            filter,
+            recv: self.ctx.get(EVENTS).subscribe(),
+        };
+        self.event_subscriptions.insert(
+            subscription_id.clone(),
+            Arc::new(smol::lock::Mutex::new(subscription)),
+        );
+        subscription_id
+    }
+
+    async fn poll_events(
+        &self,
+        subscription_id: String,
+    ) -> Result<Vec<DaemonEvent>, ControlProtErr> {
+        let subscription = self
+            .event_subscriptions
+            .get(&subscription_id)
+            .ok_or(ControlProtErr::NoSubscription)?;
+        let mut subscription = subscription.lock().await;
+        let deadline = Instant::now() + EVENT_POLL_TIMEOUT;
+        let mut events = vec![];
+        while events.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match subscription.recv.recv().timeout(remaining).await {
+                None | Some(Err(RecvError::Closed)) => break,
+                Some(Err(RecvError::Overflowed(missed))) => {
+                    log::debug!("event subscription {subscription_id} missed {missed} events")
+                }
+                Some(Ok(event)) => events.push(event),
+            }
+            // take whatever else already arrived, without waiting
+            loop {
+                match subscription.recv.try_recv() {
+                    Ok(event) => events.push(event),
+                    Err(TryRecvError::Overflowed(_)) => continue,
+                    Err(_) => break,
+                }
+            }
+            events.retain(|event| subscription.filter.matches(event));
+        }
+        Ok(events)
+    }
+
+    async fn read_event_log(&self, from_seq: u64, limit: usize) -> Vec<LoggedEvent> {
+        self.ctx.get(EVENTS).log().read_events(from_seq, limit)
+    }
+
+    async fn list_sockets(&self) -> Vec<SocketEntry> {
+        self.sockets
+            .iter()
+            .map(|entry| SocketEntry {
+                socket_id: entry.key().clone(),
+                endpoint: entry.value().local_endpoint(),
+                kind: entry.value().kind(),
+                created_at: self
+                    .socket_created_at
+                    .get(entry.key())
+                    .map_or(0, |created_at| *created_at),
+            })
+            .sorted_by(|a, b| a.socket_id.cmp(&b.socket_id))
+            .collect()
+    }
+
+    async fn close_socket(&self, socket_id: String) -> Result<(), ControlProtErr> {
+        let (_, socket) = self
+            .sockets
+            .remove(&socket_id)
+            .ok_or(ControlProtErr::NoSocket)?;
+        self.socket_created_at.remove(&socket_id);
+        self.pcaps.remove(&socket_id);
+        socket.close().await?;
+        Ok(())
+    }
+
+    async fn disable_debug_pcap(&self, socket_id: String) -> Result<(), ControlProtErr> {
+        // dropping the capture closes its file
+        self.pcaps.remove(&socket_id);
+        Ok(())
+    }
+
+    async fn add_in_route(
+        &self,
+        name: String,
+        config: InRouteConfig,
+    ) -> Result<(), ControlProtErr> {
+        let configured = self.ctx.get(CONFIGURED_ROUTES).lock().await;
+        if configured.config.in_routes.contains_key(&name)
+            || self.ctx.get(IN_ROUTES).contains_key(&name)
+        {
+            return Err(ControlProtErr::InRouteError(format!(
+                "in_route {name} already exists"
+            )));
+        }
+        let handle = start_in_route(&self.ctx, &name, config)
+            .await
+            .map_err(|e| ControlProtErr::InRouteError(e.to_string()))?;
+        self.ctx.get(IN_ROUTES).insert(name, handle);
+        Ok(())
+    }
+
+    async fn remove_in_route(&self, name: String) -> Result<(), ControlProtErr> {
+        let (_, handle) = self
+            .ctx
+            .get(IN_ROUTES)
+            .remove(&name)
+            .ok_or(ControlProtErr::NoInRoute)?;
+        stop_in_route(&self.ctx, handle).await;
+        Ok(())
+    }
+
+    async fn add_peer(&self, config: OutRouteConfig) -> Result<(), ControlProtErr> {
+        let fingerprint = config.fingerprint();
+        let configured = self.ctx.get(CONFIGURED_ROUTES).lock().await;
+        if configured
+            .config
+            .out_routes
+            .values()
+            .any(|route| route.fingerprint() == fingerprint)
+            || self.ctx.get(OUT_ROUTES).contains_key(&fingerprint)
+        {
+            return Err(ControlProtErr::PeerExists);
+        }
+        let handle = start_out_route(&self.ctx, &format!("peer-{fingerprint}"), config);
+        self.ctx.get(OUT_ROUTES).insert(fingerprint, handle);
+        Ok(())
+    }
+
+    async fn remove_peer(&self, fp: Fingerprint) -> Result<(), ControlProtErr> {
+        let (_, handle) = self
+            .ctx
+            .get(OUT_ROUTES)
+            .remove(&fp)
+            .ok_or(ControlProtErr::NoPeer)?;
+        handle.task.cancel().await;
+        self.ctx.get(NEIGH_TABLE).remove(&fp);
+        Ok(())
+    }
+
+    async fn reload_config(&self) -> Result<(), ReloadError> {
+        let path = self
+            .ctx
+            .get(CONFIG_PATH)
+            .get()
+            .ok_or(ReloadError::NoConfigFile)?;
+        let config =
+            ConfigFile::load(path).map_err(|e| ReloadError::BadConfigFile(format!("{e:?}")))?;
+        reload_config(&self.ctx, config).await
+    }
+
+    async fn set_haven_rendezvous(
+        &self,
+        socket_id: String,
+        rendezvous_fp: Option<Fingerprint>,
+    ) -> Result<(), ControlProtErr> {
+        if let Some(fp) = rendezvous_fp {
+            let is_relay = self
+                .ctx
+                .get(RELAY_GRAPH)
+                .read()
+                .identity(&fp)
+                .is_some_and(|id| id.is_relay);
+            if !is_relay {
+                return Err(ControlProtErr::InvalidRelayFingerprint);
+            }
         }
+        // moving the haven talks to relays, which mustn't keep the socket map locked
+        let socket = self
+            .sockets
+            .get(&socket_id)
+            .map(|s| s.clone())
+            .ok_or(ControlProtErr::NoSocket)?;
+        socket.set_rendezvous_point(rendezvous_fp).await?;
+        Ok(())
     }
 
     async fn my_routes(&self) -> serde_json::Value {
-        let lala: BTreeMap<String, serde_json::Value> = self
-            .ctx.init()
+        let configured = self.ctx.get(CONFIGURED_ROUTES).lock().await;
+        let lala: BTreeMap<String, serde_json::Value> = configured
+            .config
             .in_routes
             .iter()
             .map(|(k, v)| match v {
@@ -152,11 +528,193 @@ impl ControlProtocol for ControlProtocolImpl {
                         }),
                     )
                 }
+                InRouteConfig::Tcp { listen, tls_cert } => (
+                    k.clone(),
+                    json!( {
+                        "fingerprint": format!("{}", self.ctx.get(GLOBAL_IDENTITY).public().fingerprint()),
+                        "connect": format!("<YOUR_IP>:{}", listen.port()),
+                        "tls": tls_cert.is_some(),
+                    }),
+                ),
+                InRouteConfig::Quic { listen, .. } => (
+                    k.clone(),
+                    json!( {
+                        "fingerprint": format!("{}", self.ctx.get(GLOBAL_IDENTITY).public().fingerprint()),
+                        "connect": format!("<YOUR_IP>:{}", listen.port()),
+                    }),
+                ),
+                InRouteConfig::WebSocket {
+                    listen,
+                    path,
+                    tls_cert,
+                } => (
+                    k.clone(),
+                    json!( {
+                        "fingerprint": format!("{}", self.ctx.get(GLOBAL_IDENTITY).public().fingerprint()),
+                        "url": format!(
+                            "{}://<YOUR_HOST>:{}{}",
+                            if tls_cert.is_some() { "wss" } else { "ws" },
+                            listen.port(),
+                            path
+                        ),
+                    }),
+                ),
+                InRouteConfig::UnixSocket { path } => (
+                    k.clone(),
+                    json!( {
+                        "fingerprint": format!("{}", self.ctx.get(GLOBAL_IDENTITY).public().fingerprint()),
+                        "path": path,
+                    }),
+                ),
+                // custom transports decide for themselves how they're dialed
+                InRouteConfig::Custom { transport, .. } => (
+                    k.clone(),
+                    json!( {
+                        "fingerprint": format!("{}", self.ctx.get(GLOBAL_IDENTITY).public().fingerprint()),
+                        "transport": transport,
+                    }),
+                ),
             })
             .collect();
         serde_json::to_value(lala).unwrap()
     }
 
+    async fn get_path_for_socket(
+        &self,
+        socket_id: String,
+        destination: Fingerprint,
+    ) -> Result<Vec<Fingerprint>, ControlProtErr> {
+        let endpoint = self
+            .sockets
+            .get(&socket_id)
+            .ok_or(ControlProtErr::NoSocket)?
+            .local_endpoint();
+        let profile = self
+            .ctx
+            .get(ROUTE_PROFILES)
+            .get(&endpoint)
+            .map(|profile| profile.clone())
+            .unwrap_or_default();
+        let path = route_to(&self.ctx, destination, &profile).map_err(SocketSendError::from)?;
+        Ok(path)
+    }
+
+    async fn set_route_profile(
+        &self,
+        socket_id: String,
+        profile: Option<String>,
+    ) -> Result<(), ControlProtErr> {
+        let profile = profile
+            .map(|name| {
+                self.ctx
+                    .init()
+                    .profiles
+                    .get(&name)
+                    .cloned()
+                    .ok_or(ControlProtErr::NoProfile(name))
+            })
+            .transpose()?;
+        self.sockets
+            .get(&socket_id)
+            .ok_or(ControlProtErr::NoSocket)?
+            .set_route_profile(profile);
+        Ok(())
+    }
+
+    async fn dht_debug(&self) -> DhtStats {
+        dht_get_stats(&self.ctx)
+    }
+
+    async fn bench_dht(&self, iterations: u16) -> Result<DhtBenchResult, DhtError> {
+        dht_bench(&self.ctx, iterations).await
+    }
+
+    async fn bandwidth_stats(&self) -> BandwidthStats {
+        let measurement_window_secs = self
+            .ctx
+            .get(BANDWIDTH_WINDOW_START)
+            .lock()
+            .elapsed()
+            .as_secs();
+        let per_neighbor: Vec<NeighborBandwidth> = self
+            .ctx
+            .get(NEIGH_TABLE)
+            .all_neighs()
+            .into_iter()
+            .map(|neigh| {
+                let (bytes_sent, bytes_received) = neigh.traffic_stats();
+                NeighborBandwidth {
+                    fingerprint: neigh.remote_idpk().fingerprint(),
+                    bytes_sent,
+                    bytes_received,
+                    uptime_secs: neigh.uptime().as_secs(),
+                    idle_secs: neigh
+                        .last_activity()
+                        .map(|last_activity| last_activity.elapsed().as_secs()),
+                }
+            })
+            .collect();
+        let haven_bandwidth = self.ctx.get(HAVEN_BANDWIDTH);
+        BandwidthStats {
+            total_bytes_sent: per_neighbor.iter().map(|neigh| neigh.bytes_sent).sum(),
+            total_bytes_received: per_neighbor.iter().map(|neigh| neigh.bytes_received).sum(),
+            measurement_window_secs,
+            per_neighbor,
+            per_haven: haven_bandwidth
+                .havens()
+                .into_iter()
+                .map(|fingerprint| {
+                    let (bytes_sent, bytes_received) =
+                        haven_bandwidth.bytes_per_minute(fingerprint);
+                    HavenBandwidth {
+                        fingerprint,
+                        bytes_sent,
+                        bytes_received,
+                    }
+                })
+                .filter(|haven| haven.bytes_sent > 0 || haven.bytes_received > 0)
+                .collect(),
+        }
+    }
+
+    async fn announce_route(
+        &self,
+        route: Vec<Fingerprint>,
+        ttl: Duration,
+    ) -> Result<(), ControlProtErr> {
+        self.check_rate_limit()?;
+        let expires_at = (SystemTime::now() + ttl)
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        // signed with a throwaway key, so that the relays don't learn whose route it is
+        let reservation =
+            RouteReservation::new(&IdentitySecret::generate(), route.clone(), expires_at);
+        let mut gatherer: FuturesUnordered<_> = route
+            .into_iter()
+            .map(|hop| {
+                let reservation = reservation.clone();
+                async move {
+                    let gclient = GlobalRpcClient(GlobalRpcTransport::new(
+                        self.ctx.clone(),
+                        IdentitySecret::generate(),
+                        hop,
+                    ));
+                    (hop, gclient.reserve_route(reservation).await)
+                }
+            })
+            .collect();
+        // reservations are best-effort, so failures only get logged
+        while let Some((hop, res)) = gatherer.next().await {
+            match res {
+                Ok(Ok(())) => log::debug!("{hop} accepted route reservation"),
+                Ok(Err(err)) => log::debug!("{hop} rejected route reservation: {err}"),
+                Err(err) => log::debug!("could not reserve route at {hop}: {err}"),
+            }
+        }
+        Ok(())
+    }
+
     async fn graph_dump(&self, human: bool) -> String {
         let my_fp = self
             .ctx
@@ -267,6 +825,61 @@ impl ControlProtocol for ControlProtocolImpl {
         }
     }
 
+    async fn ping(&self, fp: Fingerprint) -> Result<Duration, ControlProtErr> {
+        self.check_rate_limit()?;
+        const PING_TIMEOUT: Duration = Duration::from_secs(10);
+        if let Some(conn) = self.ctx.get(NEIGH_TABLE).lookup(&fp) {
+            return conn
+                .measure_latency()
+                .timeout(PING_TIMEOUT)
+                .await
+                .ok_or(ControlProtErr::PingTimeout)?
+                .map_err(|e| ControlProtErr::PingFailed(e.to_string()));
+        }
+        let transport = GlobalRpcTransport::new(self.ctx.clone(), IdentitySecret::generate(), fp);
+        // an operator's ping always goes out, even to a node that recently failed to answer, so it also recovers the node's circuit
+        transport.reset_circuit();
+        let gclient = GlobalRpcClient(transport);
+        let nonce: u64 = rand::random();
+        let start = Instant::now();
+        let echoed = gclient
+            .ping(nonce)
+            .timeout(PING_TIMEOUT)
+            .await
+            .ok_or(ControlProtErr::PingTimeout)?
+            .map_err(|e| ControlProtErr::PingFailed(e.to_string()))?;
+        if echoed != nonce {
+            return Err(ControlProtErr::PingFailed(
+                "reply does not match the ping".into(),
+            ));
+        }
+        Ok(start.elapsed())
+    }
+
+    async fn trace_route(&self, fp: Fingerprint) -> Result<Vec<Fingerprint>, ControlProtErr> {
+        self.check_rate_limit()?;
+        const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+        let route =
+            route_to(&self.ctx, fp, &RouteProfile::default()).map_err(SocketSendError::from)?;
+        // onion packets can't expire partway, so each hop of the route is probed directly over its own route, nearest first
+        let mut answered = vec![];
+        for (i, hop) in route.into_iter().enumerate().skip(1) {
+            let gclient = GlobalRpcClient(GlobalRpcTransport::new(
+                self.ctx.clone(),
+                IdentitySecret::generate(),
+                hop,
+            ));
+            match gclient.hop_report().timeout(PROBE_TIMEOUT).await {
+                Some(Ok(reported)) if reported == hop => answered.push(hop),
+                _ => {
+                    log::debug!("hop {i} ({hop}) did not answer the trace-route probe");
+                    break;
+                }
+            }
+        }
+        Ok(answered)
+    }
+
     async fn send_global_rpc(
         &self,
         send_args: GlobalRpcArgs,
@@ -281,7 +894,7 @@ impl ControlProtocol for ControlProtocolImpl {
             .await
             .map_err(|e| {
                 log::warn!("send_global_rpc failed with {:?}", e);
-                GlobalRpcError::SendError
+                e.downcast().unwrap_or(GlobalRpcError::SendError)
             })? {
             res.map_err(|e| {
                 log::warn!("send_global_rpc failed with {:?}", e);
@@ -293,7 +906,45 @@ impl ControlProtocol for ControlProtocolImpl {
         Ok(res)
     }
 
+    async fn list_havens(&self, relay: Fingerprint) -> Result<Vec<HavenLocator>, GlobalRpcError> {
+        let gclient = GlobalRpcClient(GlobalRpcTransport::new(
+            self.ctx.clone(),
+            IdentitySecret::generate(),
+            relay,
+        ));
+        let mut havens: Vec<HavenLocator> = vec![];
+        loop {
+            let after = havens
+                .last()
+                .map(|locator| locator.identity_pk.fingerprint());
+            let page = gclient
+                .dht_list(after, DHT_LIST_PAGE_SIZE)
+                .await
+                .map_err(|e| {
+                    log::warn!("list_havens failed with {:?}", e);
+                    match e {
+                        crate::global_rpc::GlobalRpcError::Transport(e) => {
+                            e.downcast().unwrap_or(GlobalRpcError::SendError)
+                        }
+                        _ => GlobalRpcError::SendError,
+                    }
+                })?;
+            let is_last = page.len() < DHT_LIST_PAGE_SIZE;
+            // a relay that doesn't page forward would otherwise keep us asking forever
+            let last = page.last().map(|locator| locator.identity_pk.fingerprint());
+            if last.is_none() || last <= after {
+                break;
+            }
+            havens.extend(page);
+            if is_last {
+                break;
+            }
+        }
+        Ok(havens)
+    }
+
     async fn insert_rendezvous(&self, locator: HavenLocator) -> Result<(), DhtError> {
+        self.check_dht_rate_limit(1)?;
         dht_insert(&self.ctx, locator).await;
         Ok(())
     }
@@ -302,8 +953,9 @@ impl ControlProtocol for ControlProtocolImpl {
         &self,
         fingerprint: Fingerprint,
     ) -> Result<Option<HavenLocator>, DhtError> {
+        self.check_dht_rate_limit(1)?;
         dht_get(&self.ctx, fingerprint)
-            .timeout(Duration::from_secs(30))
+            .timeout(DHT_GET_TIMEOUT)
             .await
             .map_or(
                 Err(DhtError::NetworkFailure(
@@ -312,6 +964,35 @@ impl ControlProtocol for ControlProtocolImpl {
                 |res| res,
             )
     }
+
+    async fn remove_rendezvous(&self, fingerprint: Fingerprint) -> Result<(), DhtError> {
+        self.check_dht_rate_limit(1)?;
+        let isk = self
+            .find_identity(fingerprint)
+            .ok_or(DhtError::UnknownIdentity)?;
+        dht_remove(&self.ctx, isk).await;
+        Ok(())
+    }
+
+    async fn nearest_rendezvous(
+        &self,
+        fingerprint: Fingerprint,
+        k: usize,
+    ) -> Result<Vec<HavenLocator>, DhtError> {
+        self.check_dht_rate_limit(1)?;
+        Ok(dht_get_nearest(&self.ctx, fingerprint, k))
+    }
+
+    async fn batch_get_rendezvous(
+        &self,
+        fingerprints: Vec<Fingerprint>,
+    ) -> Result<Vec<(Fingerprint, Option<HavenLocator>)>, DhtError> {
+        self.check_dht_rate_limit(fingerprints.len())?;
+        Ok(dht_batch_get(&self.ctx, fingerprints)
+            .await?
+            .into_iter()
+            .collect())
+    }
 }
 
 struct AnonIdentities {
@@ -327,6 +1008,11 @@ impl AnonIdentities {
         Self { map }
     }
 
+    /// Returns every anonymous identity handed out and not yet expired.
+    pub fn all(&self) -> Vec<IdentitySecret> {
+        self.map.iter().map(|(_, isk)| isk).collect()
+    }
+
     pub fn get(&mut self, id: &str) -> IdentitySecret {
         let pseudo_secret = blake3::hash(id.as_bytes());
         self.map
@@ -344,4 +1030,88 @@ pub enum ControlProtErr {
         "No socket exists for this socket_id! Bind a socket to this id before trying to use it ^_^"
     )]
     NoSocket,
+    #[error("could not open capture file: {0}")]
+    PcapError(String),
+    #[error("could not start in_route: {0}")]
+    InRouteError(String),
+    #[error("no in_route with this name was added at runtime")]
+    NoInRoute,
+    #[error("an out_route to this peer already exists")]
+    PeerExists,
+    #[error("no peer with this fingerprint was added at runtime")]
+    NoPeer,
+    #[error("the given fingerprint is not a relay in the relay graph")]
+    InvalidRelayFingerprint,
+    #[error("no echo received from the destination in time")]
+    EchoTimeout,
+    #[error("no route profile named {0} in the config file")]
+    NoProfile(String),
+    #[error("no event subscription with this id; it may have expired")]
+    NoSubscription,
+    #[error("no answer to the ping in time")]
+    PingTimeout,
+    #[error("ping failed: {0}")]
+    PingFailed(String),
+    #[error("too many control protocol calls; slow down")]
+    RateLimited,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let config: ConfigFile = serde_json::from_value(json!({
+            "identity_seed": "rate-limit-test",
+            "control_listen": "127.0.0.1:0",
+            "rate_limit_calls_per_second": 100.0,
+        }))
+        .unwrap();
+        let control = ControlProtocolImpl::new(DaemonContext::new(config));
+        let destination = Endpoint::new(IdentitySecret::generate().public().fingerprint(), 0);
+        let limited = smol::block_on(async {
+            let mut limited = 0;
+            for _ in 0..10000 {
+                let args = SendMessageArgs {
+                    socket_id: "nonexistent".into(),
+                    destination,
+                    content: Bytes::new(),
+                };
+                if let Err(ControlProtErr::RateLimited) = control.send_message(args).await {
+                    limited += 1;
+                }
+            }
+            limited
+        });
+        assert!(
+            limited > 9000,
+            "only {limited} of 10000 calls were rate-limited"
+        );
+    }
+    #[test]
+    fn test_close_socket_during_recv() {
+        // Testing that closing a socket doesn't wait for a receive pending on it
+        let config: ConfigFile = serde_json::from_value(json!({
+            "identity_seed": "close-during-recv-test",
+            "control_listen": "127.0.0.1:0",
+        }))
+        .unwrap();
+        let control = Arc::new(ControlProtocolImpl::new(DaemonContext::new(config)));
+        smol::block_on(control.bind_n2r("skt".into(), None, None));
+        let _recv = smolscale::spawn({
+            let control = control.clone();
+            async move { control.recv_message("skt".into()).await }
+        });
+        std::thread::sleep(Duration::from_millis(100));
+
+        let (send_done, recv_done) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = send_done.send(smol::block_on(control.close_socket("skt".into())));
+        });
+        let closed = recv_done
+            .recv_timeout(Duration::from_secs(10))
+            .expect("close_socket blocked on the pending recv_message");
+        assert!(closed.is_ok());
+    }
 }
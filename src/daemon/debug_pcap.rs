@@ -0,0 +1,108 @@
+use std::{
+    fs::File,
+    io::Write,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::Mutex;
+
+use crate::socket::Endpoint;
+
+/// Link type reserved for private use, since the captured bytes are application messages rather than frames.
+const LINKTYPE_USER0: u16 = 147;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const IF_NAME: u16 = 2;
+const EPB_FLAGS: u16 = 2;
+
+#[derive(Clone, Copy)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Writes the messages going through a socket into a Wireshark-readable PCAP-NG file.
+pub struct DebugPcap {
+    file: Mutex<File>,
+}
+
+impl DebugPcap {
+    /// Creates the capture file, writing the section header and a single interface named after the socket.
+    pub fn create(path: &Path, socket_id: &str) -> std::io::Result<Self> {
+        let mut file = File::create(path)?;
+
+        let mut shb = vec![];
+        shb.extend_from_slice(&0x1A2B3C4Du32.to_le_bytes()); // byte-order magic
+        shb.extend_from_slice(&1u16.to_le_bytes()); // major version
+        shb.extend_from_slice(&0u16.to_le_bytes()); // minor version
+        shb.extend_from_slice(&(-1i64).to_le_bytes()); // section length unknown
+        file.write_all(&block(0x0A0D0D0A, shb))?;
+
+        let mut idb = vec![];
+        idb.extend_from_slice(&LINKTYPE_USER0.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes()); // reserved
+        idb.extend_from_slice(&0u32.to_le_bytes()); // no snaplen
+        push_option(&mut idb, IF_NAME, socket_id.as_bytes());
+        push_option(&mut idb, OPT_ENDOFOPT, &[]);
+        file.write_all(&block(1, idb))?;
+
+        file.flush()?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Records one message, along with its direction and the endpoint on the other side.
+    pub fn record(&self, direction: Direction, remote: Endpoint, msg: &[u8]) {
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        let mut epb = vec![];
+        epb.extend_from_slice(&0u32.to_le_bytes()); // interface id
+        epb.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(micros as u32).to_le_bytes());
+        epb.extend_from_slice(&(msg.len() as u32).to_le_bytes()); // captured length
+        epb.extend_from_slice(&(msg.len() as u32).to_le_bytes()); // original length
+        epb.extend_from_slice(msg);
+        pad_to_u32(&mut epb);
+        let flags: u32 = match direction {
+            Direction::Inbound => 0b01,
+            Direction::Outbound => 0b10,
+        };
+        push_option(&mut epb, EPB_FLAGS, &flags.to_le_bytes());
+        push_option(&mut epb, OPT_COMMENT, format!("remote {remote}").as_bytes());
+        push_option(&mut epb, OPT_ENDOFOPT, &[]);
+
+        let mut file = self.file.lock();
+        if let Err(err) = file.write_all(&block(6, epb)).and_then(|_| file.flush()) {
+            log::warn!("could not write to debug pcap: {:?}", err);
+        }
+    }
+}
+
+/// Wraps a block body with its type and the leading and trailing total lengths.
+fn block(block_type: u32, body: Vec<u8>) -> Vec<u8> {
+    let total_len = (body.len() + 12) as u32;
+    let mut out = Vec::with_capacity(total_len as usize);
+    out.extend_from_slice(&block_type.to_le_bytes());
+    out.extend_from_slice(&total_len.to_le_bytes());
+    out.extend_from_slice(&body);
+    out.extend_from_slice(&total_len.to_le_bytes());
+    out
+}
+
+fn push_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend_from_slice(&code.to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(value);
+    pad_to_u32(buf);
+}
+
+fn pad_to_u32(buf: &mut Vec<u8>) {
+    let padding = (4 - buf.len() % 4) % 4;
+    buf.resize(buf.len() + padding, 0);
+}
@@ -1,29 +1,183 @@
-use std::time::Duration;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
 
 use anyhow::Context;
 use earendil_crypt::{Fingerprint, IdentitySecret};
+use earendil_packet::crypt::OnionSecret;
+use earendil_topology::IdentityDescriptor;
 use futures_util::{stream::FuturesUnordered, StreamExt};
 use moka::sync::{Cache, CacheBuilder};
+use parking_lot::Mutex;
+use smol_timeout::TimeoutExt;
 use stdcode::StdcodeSerializeExt;
 
 use crate::{
-    control_protocol::DhtError,
-    global_rpc::{transport::GlobalRpcTransport, GlobalRpcClient},
+    control_protocol::{DaemonEvent, DhtBenchResult, DhtError, DhtStats},
+    global_rpc::{server::LOCAL_DHT_SHARD, transport::GlobalRpcTransport, GlobalRpcClient},
     haven_util::HavenLocator,
 };
 
-use super::context::{CtxField, DaemonContext, RELAY_GRAPH};
+use super::context::{
+    CtxField, DaemonContext, GLOBAL_IDENTITY, GLOBAL_ONION_SK, NEIGH_TABLE, RELAY_GRAPH,
+};
+use super::dht_cache::DhtPersistentCache;
+use super::events::EVENTS;
 
 const DHT_REDUNDANCY: usize = 3;
 
+/// How long a single lookup can take before it's given up on.
+pub const DHT_GET_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The most locators a batch lookup can ask for.
+const MAX_BATCH_GET: usize = 64;
+
+/// How many lookups of a batch run at once.
+const BATCH_GET_CONCURRENCY: usize = 16;
+
 static DHT_CACHE: CtxField<Cache<Fingerprint, HavenLocator>> = |_| {
     CacheBuilder::default()
         .time_to_live(Duration::from_secs(60))
         .build()
 };
 
-/// Insert a locator into the DHT.
+/// How many of the latest network lookups the latency statistics are computed over.
+const LATENCY_WINDOW: usize = 1000;
+
+/// Upper bounds of all but the last bucket of the lookup latency histogram, in milliseconds.
+const LATENCY_BUCKET_BOUNDS_MS: [u128; 4] = [100, 500, 1000, 5000];
+
+/// Counters behind [dht_get_stats].
+#[derive(Default)]
+struct DhtCounters {
+    total_lookups: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    network_successes: AtomicU64,
+    network_failures: AtomicU64,
+    /// network lookups that were given up on, which also count as failures
+    network_timeouts: AtomicU64,
+    /// counts of all network lookups by duration, bucketed by [LATENCY_BUCKET_BOUNDS_MS]
+    latency_histogram: [AtomicU64; 5],
+    /// durations of the latest network lookups, oldest first
+    latencies: Mutex<VecDeque<Duration>>,
+}
+
+impl DhtCounters {
+    fn record_latency(&self, latency: Duration) {
+        let bucket = LATENCY_BUCKET_BOUNDS_MS
+            .iter()
+            .position(|&bound| latency.as_millis() < bound)
+            .unwrap_or(LATENCY_BUCKET_BOUNDS_MS.len());
+        self.latency_histogram[bucket].fetch_add(1, Ordering::Relaxed);
+
+        let mut latencies = self.latencies.lock();
+        if latencies.len() == LATENCY_WINDOW {
+            latencies.pop_front();
+        }
+        latencies.push_back(latency);
+    }
+}
+
+static DHT_COUNTERS: CtxField<DhtCounters> = |_| DhtCounters::default();
+
+/// Returns a snapshot of the statistics of our DHT lookups. Latencies only cover lookups that went to the network; the average and percentile only the latest 1000 of them.
+pub fn dht_get_stats(ctx: &DaemonContext) -> DhtStats {
+    let counters = ctx.get(DHT_COUNTERS);
+    let latencies: Vec<Duration> = counters.latencies.lock().iter().copied().collect();
+    let (avg_lookup_ms, p99_lookup_ms) = latency_summary(&latencies);
+    DhtStats {
+        total_lookups: counters.total_lookups.load(Ordering::Relaxed),
+        cache_hits: counters.cache_hits.load(Ordering::Relaxed),
+        cache_misses: counters.cache_misses.load(Ordering::Relaxed),
+        network_successes: counters.network_successes.load(Ordering::Relaxed),
+        network_failures: counters.network_failures.load(Ordering::Relaxed),
+        network_timeouts: counters.network_timeouts.load(Ordering::Relaxed),
+        latency_histogram: counters
+            .latency_histogram
+            .each_ref()
+            .map(|count| count.load(Ordering::Relaxed)),
+        avg_lookup_ms,
+        p99_lookup_ms,
+    }
+}
+
+/// Returns the average and 99th percentile of the given latencies, in milliseconds. Both are zero if there are none.
+fn latency_summary(latencies: &[Duration]) -> (f64, f64) {
+    if latencies.is_empty() {
+        return (0.0, 0.0);
+    }
+    let mut millis: Vec<f64> = latencies
+        .iter()
+        .map(|latency| latency.as_secs_f64() * 1000.0)
+        .collect();
+    millis.sort_unstable_by(f64::total_cmp);
+    let avg = millis.iter().sum::<f64>() / millis.len() as f64;
+    let p99 = millis[(millis.len() * 99 / 100).min(millis.len() - 1)];
+    (avg, p99)
+}
+
+/// Times `iterations` rounds of inserting a locator for a fresh identity into the DHT and looking it up again. A round fails if the lookup doesn't return the locator.
+pub async fn dht_bench(ctx: &DaemonContext, iterations: u16) -> Result<DhtBenchResult, DhtError> {
+    if dht_key_to_fps(ctx, "").is_empty() {
+        return Err(DhtError::NetworkFailure(
+            "no relays known to host the DHT".into(),
+        ));
+    }
+    let mut insert_latencies = vec![];
+    let mut lookup_latencies = vec![];
+    let mut failures = 0;
+    for _ in 0..iterations {
+        let isk = IdentitySecret::generate();
+        let locator = HavenLocator::new(
+            isk,
+            OnionSecret::generate().public(),
+            ctx.get(GLOBAL_IDENTITY).public().fingerprint(),
+        );
+
+        // the locators are junk, so they go to the replicas without being persisted locally
+        let start = Instant::now();
+        dht_insert_to_network(ctx, locator).await;
+        insert_latencies.push(start.elapsed());
+
+        // the lookup skips the caches, so that it measures the network
+        let start = Instant::now();
+        let found = dht_get_from_network(ctx, isk.public().fingerprint()).await;
+        lookup_latencies.push(start.elapsed());
+        if !matches!(found, Ok(Some(_))) {
+            failures += 1;
+        }
+    }
+    let (avg_insert_ms, p99_insert_ms) = latency_summary(&insert_latencies);
+    let (avg_lookup_ms, p99_lookup_ms) = latency_summary(&lookup_latencies);
+    Ok(DhtBenchResult {
+        avg_insert_ms,
+        avg_lookup_ms,
+        p99_insert_ms,
+        p99_lookup_ms,
+        failure_rate: if iterations == 0 {
+            0.0
+        } else {
+            failures as f64 / iterations as f64
+        },
+    })
+}
+
+/// Insert a locator into the DHT. Inserting a tombstone deletes the haven's locator instead.
 pub async fn dht_insert(ctx: &DaemonContext, locator: HavenLocator) {
+    let key = locator.identity_pk.fingerprint();
+    if locator.is_tombstone {
+        forget_locator(ctx, key);
+    } else {
+        persist_locator(ctx, &locator);
+    }
+    dht_insert_to_network(ctx, locator).await
+}
+
+/// Insert a locator into its replicas in the DHT, without remembering it in any local cache.
+async fn dht_insert_to_network(ctx: &DaemonContext, locator: HavenLocator) {
     let key = locator.identity_pk.fingerprint();
     let replicas = dht_key_to_fps(ctx, &key.to_string());
     let anon_isk = IdentitySecret::generate();
@@ -50,14 +204,157 @@ pub async fn dht_insert(ctx: &DaemonContext, locator: HavenLocator) {
     }
 }
 
-/// Obtain a locator from the DHT.
+/// Deletes the locator of the haven with the given identity from the DHT, by inserting a tombstone.
+pub async fn dht_remove(ctx: &DaemonContext, identity_sk: IdentitySecret) {
+    dht_insert(ctx, HavenLocator::tombstone(identity_sk)).await
+}
+
+/// How long locators stay valid after their havens made them.
+pub fn locator_ttl(ctx: &DaemonContext) -> Duration {
+    Duration::from_secs(ctx.init().locator_ttl_secs)
+}
+
+/// Opens the persistent locator cache at `dht_cache_path`, if one is configured. Locators are kept on disk for twice the locator TTL, so that slightly stale ones can stand in when the network doesn't answer.
+pub static DHT_PERSISTENT_CACHE: CtxField<Option<DhtPersistentCache>> = |ctx| {
+    let path = ctx.init().dht_cache_path.as_ref()?;
+    DhtPersistentCache::open(path, locator_ttl(ctx) * 2)
+        .map_err(|err| log::warn!("cannot open persistent DHT cache at {path:?}: {err}"))
+        .ok()
+};
+
+/// How long a lookup waits for the network before falling back to the persistent cache; shorter than [DHT_GET_TIMEOUT], so that the fallback happens before callers give up.
+const NETWORK_LOOKUP_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Remembers a locator in the persistent cache, if there is one.
+fn persist_locator(ctx: &DaemonContext, locator: &HavenLocator) {
+    if let Some(cache) = ctx.get(DHT_PERSISTENT_CACHE) {
+        cache.insert(locator);
+    }
+}
+
+/// Drops a deleted haven's locator from both caches.
+pub(crate) fn forget_locator(ctx: &DaemonContext, fingerprint: Fingerprint) {
+    ctx.get(DHT_CACHE).invalidate(&fingerprint);
+    if let Some(cache) = ctx.get(DHT_PERSISTENT_CACHE) {
+        cache.remove(fingerprint);
+    }
+}
+
+/// Returns the locator for `fingerprint` from the persistent cache, if there is one that was made less than `max_age` ago.
+fn persisted_locator(
+    ctx: &DaemonContext,
+    fingerprint: Fingerprint,
+    max_age: Duration,
+) -> Option<HavenLocator> {
+    let cache = ctx.get(DHT_PERSISTENT_CACHE).as_ref()?;
+    cache
+        .get(fingerprint)
+        .map_err(|err| log::warn!("cannot read from persistent DHT cache: {err}"))
+        .ok()
+        .flatten()
+        .filter(|locator| !locator.is_expired(max_age))
+}
+
+/// Obtain a locator from the DHT, checking the in-memory cache first. Expired locators count as missing. When the network lookup fails, a locator from the persistent cache up to twice the TTL old stands in.
 pub async fn dht_get(
     ctx: &DaemonContext,
     fingerprint: Fingerprint,
 ) -> Result<Option<HavenLocator>, DhtError> {
+    let result = dht_lookup(ctx, fingerprint).await;
+    ctx.get(EVENTS).emit(DaemonEvent::DhtLookup {
+        fingerprint,
+        found: matches!(result, Ok(Some(_))),
+    });
+    result
+}
+
+async fn dht_lookup(
+    ctx: &DaemonContext,
+    fingerprint: Fingerprint,
+) -> Result<Option<HavenLocator>, DhtError> {
+    let counters = ctx.get(DHT_COUNTERS);
+    counters.total_lookups.fetch_add(1, Ordering::Relaxed);
     if let Some(locator) = ctx.get(DHT_CACHE).get(&fingerprint) {
-        return Ok(Some(locator));
+        if locator.is_expired(locator_ttl(ctx)) {
+            ctx.get(DHT_CACHE).invalidate(&fingerprint);
+        } else {
+            counters.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(locator));
+        }
+    }
+    // the persistent cache is only a fallback, since it may keep a locator around long after its haven moved to another rendezvous point
+    counters.cache_misses.fetch_add(1, Ordering::Relaxed);
+
+    let start = Instant::now();
+    let result = dht_get_from_network(ctx, fingerprint)
+        .timeout(NETWORK_LOOKUP_TIMEOUT)
+        .await
+        .unwrap_or_else(|| {
+            counters.network_timeouts.fetch_add(1, Ordering::Relaxed);
+            Err(DhtError::NetworkFailure("lookup timed out".into()))
+        });
+    if result.is_ok() {
+        counters.network_successes.fetch_add(1, Ordering::Relaxed);
+    } else {
+        counters.network_failures.fetch_add(1, Ordering::Relaxed);
+    }
+    counters.record_latency(start.elapsed());
+
+    match result {
+        Ok(Some(locator)) => {
+            persist_locator(ctx, &locator);
+            Ok(Some(locator))
+        }
+        Err(err) => match persisted_locator(ctx, fingerprint, locator_ttl(ctx) * 2) {
+            Some(locator) => {
+                log::debug!(
+                    "lookup of {fingerprint} failed ({err}), using stale persisted locator"
+                );
+                Ok(Some(locator))
+            }
+            None => Err(err),
+        },
+        Ok(None) => Ok(None),
     }
+}
+
+/// Looks up many locators, a few at a time. Lookups that fail or time out count as missing.
+pub async fn dht_batch_get(
+    ctx: &DaemonContext,
+    fingerprints: Vec<Fingerprint>,
+) -> Result<HashMap<Fingerprint, Option<HavenLocator>>, DhtError> {
+    if fingerprints.len() > MAX_BATCH_GET {
+        return Err(DhtError::BatchTooLarge(MAX_BATCH_GET));
+    }
+    let locators = futures_util::stream::iter(fingerprints)
+        .map(|fingerprint| async move {
+            let locator = match dht_get(ctx, fingerprint).timeout(DHT_GET_TIMEOUT).await {
+                Some(Ok(locator)) => locator,
+                Some(Err(err)) => {
+                    log::debug!("batch lookup of {fingerprint} failed: {err}");
+                    None
+                }
+                None => {
+                    log::debug!("batch lookup of {fingerprint} timed out");
+                    None
+                }
+            };
+            (fingerprint, locator)
+        })
+        .buffer_unordered(BATCH_GET_CONCURRENCY)
+        .collect()
+        .await;
+    Ok(locators)
+}
+
+/// How long a lookup keeps waiting for the other replicas after one answered with a locator, in case they know of a newer one or of a tombstone.
+const REPLICA_GRACE: Duration = Duration::from_secs(2);
+
+/// Looks a locator up from its replicas, caching it if found. The newest valid answer wins; if it's a tombstone, the haven was deleted, and the caches forget it.
+async fn dht_get_from_network(
+    ctx: &DaemonContext,
+    fingerprint: Fingerprint,
+) -> Result<Option<HavenLocator>, DhtError> {
     let replicas = dht_key_to_fps(ctx, &fingerprint.to_string());
     let mut gatherer = FuturesUnordered::new();
     let anon_isk = IdentitySecret::generate();
@@ -67,28 +364,207 @@ pub async fn dht_get(
             anyhow::Ok(gclient.dht_get(fingerprint, false).await?)
         })
     }
-    let mut retval = Ok(None);
-    while let Some(result) = gatherer.next().await {
+    let mut newest: Option<HavenLocator> = None;
+    let mut last_err = None;
+    loop {
+        let result = if newest.is_some() {
+            match gatherer.next().timeout(REPLICA_GRACE).await {
+                Some(result) => result,
+                None => break,
+            }
+        } else {
+            gatherer.next().await
+        };
+        let Some(result) = result else {
+            break;
+        };
         match result {
-            Err(err) => retval = Err(DhtError::NetworkFailure(err.to_string())),
-            Ok(Err(err)) => retval = Err(err),
+            Err(err) => last_err = Some(DhtError::NetworkFailure(err.to_string())),
+            Ok(Err(err)) => last_err = Some(err),
             Ok(Ok(None)) => continue,
             Ok(Ok(Some(locator))) => {
+                if locator.is_expired(locator_ttl(ctx)) {
+                    log::debug!("ignoring expired locator for {fingerprint}");
+                    continue;
+                }
                 let id_pk = locator.identity_pk;
-                let payload = locator.to_sign();
-                if id_pk.fingerprint() == fingerprint {
-                    id_pk
-                        .verify(&payload, &locator.signature)
-                        .map_err(|_| DhtError::VerifyFailed)?;
-                    ctx.get(DHT_CACHE).insert(fingerprint, locator.clone());
-                    return Ok(Some(locator));
-                } else {
-                    retval = Err(DhtError::VerifyFailed);
+                if id_pk.fingerprint() != fingerprint
+                    || id_pk
+                        .verify(&locator.to_sign(), &locator.signature)
+                        .is_err()
+                {
+                    last_err = Some(DhtError::VerifyFailed);
+                    continue;
+                }
+                if newest.as_ref().map_or(true, |newest| {
+                    newest.created_at < locator.created_at
+                        || (newest.created_at == locator.created_at && locator.is_tombstone)
+                }) {
+                    newest = Some(locator);
                 }
             }
         }
     }
-    retval
+    match newest {
+        Some(locator) if locator.is_tombstone => {
+            forget_locator(ctx, fingerprint);
+            Ok(None)
+        }
+        Some(locator) => {
+            ctx.get(DHT_CACHE).insert(fingerprint, locator.clone());
+            Ok(Some(locator))
+        }
+        None => last_err.map_or(Ok(None), Err),
+    }
+}
+
+/// Announce our own relay identity in the DHT under the bootstrap key.
+pub async fn dht_announce_self(ctx: &DaemonContext) {
+    let key = bootstrap_key(ctx);
+    let descriptor =
+        IdentityDescriptor::new(ctx.get(GLOBAL_IDENTITY), ctx.get(GLOBAL_ONION_SK), true);
+    let replicas = dht_key_to_fps(ctx, &key.to_string());
+    let anon_isk = IdentitySecret::generate();
+    let mut gatherer = FuturesUnordered::new();
+
+    for replica in replicas.into_iter().take(DHT_REDUNDANCY) {
+        let descriptor = descriptor.clone();
+        gatherer.push(async move {
+            log::trace!("announcing ourselves to remote replica {replica}");
+            let gclient = GlobalRpcClient(GlobalRpcTransport::new(ctx.clone(), anon_isk, replica));
+            anyhow::Ok(
+                gclient
+                    .dht_announce_relay(key, descriptor)
+                    .await
+                    .context("DHT announce failed")??,
+            )
+        })
+    }
+    while let Some(res) = gatherer.next().await {
+        if let Err(e) = res {
+            log::debug!("DHT announce failed! {e}");
+        }
+    }
+}
+
+/// Obtain the relays announced under a key from the DHT. Without any relays in the relay graph, our neighbors look the key up on our behalf.
+pub async fn dht_get_relays(
+    ctx: &DaemonContext,
+    key: Fingerprint,
+) -> Result<Vec<IdentityDescriptor>, DhtError> {
+    let mut replicas: Vec<(Fingerprint, bool)> = dht_key_to_fps(ctx, &key.to_string())
+        .into_iter()
+        .take(DHT_REDUNDANCY)
+        .map(|fp| (fp, false))
+        .collect();
+    if replicas.is_empty() {
+        replicas = ctx
+            .get(NEIGH_TABLE)
+            .all_neighs()
+            .iter()
+            .map(|neigh| (neigh.remote_idpk().fingerprint(), true))
+            .collect();
+    }
+    let mut gatherer = FuturesUnordered::new();
+    let anon_isk = IdentitySecret::generate();
+    for (replica, recurse) in replicas {
+        gatherer.push(async move {
+            let gclient = GlobalRpcClient(GlobalRpcTransport::new(ctx.clone(), anon_isk, replica));
+            anyhow::Ok(gclient.dht_get_relays(key, recurse).await?)
+        })
+    }
+    let mut relays: HashMap<Fingerprint, IdentityDescriptor> = HashMap::new();
+    let mut last_err = None;
+    let mut any_answered = false;
+    while let Some(result) = gatherer.next().await {
+        let descriptors = match result {
+            Err(err) => {
+                last_err = Some(DhtError::NetworkFailure(err.to_string()));
+                continue;
+            }
+            Ok(Err(err)) => {
+                last_err = Some(err);
+                continue;
+            }
+            Ok(Ok(descriptors)) => descriptors,
+        };
+        any_answered = true;
+        for descriptor in descriptors {
+            let fp = descriptor.identity_pk.fingerprint();
+            if !descriptor.is_relay
+                || descriptor
+                    .identity_pk
+                    .verify(descriptor.to_sign().as_bytes(), &descriptor.sig)
+                    .is_err()
+            {
+                log::debug!("dropping invalid relay announcement for {fp}");
+                continue;
+            }
+            // keep the freshest announcement of every relay
+            if relays
+                .get(&fp)
+                .is_none_or(|known| known.unix_timestamp < descriptor.unix_timestamp)
+            {
+                relays.insert(fp, descriptor);
+            }
+        }
+    }
+    match last_err {
+        Some(err) if !any_answered => Err(err),
+        _ => Ok(relays.into_values().collect()),
+    }
+}
+
+/// Learn the relays announced under the bootstrap key, adding their identities to the relay graph. Returns how many there were.
+pub async fn dht_discover_relays(ctx: &DaemonContext) -> Result<usize, DhtError> {
+    let relays = dht_get_relays(ctx, bootstrap_key(ctx)).await?;
+    let mut graph = ctx.get(RELAY_GRAPH).write();
+    for descriptor in relays.iter() {
+        if let Err(e) = graph.insert_identity(descriptor.clone()) {
+            log::debug!(
+                "could not add announced relay {}: {e}",
+                descriptor.identity_pk.fingerprint()
+            );
+        }
+    }
+    Ok(relays.len())
+}
+
+/// The key that relays announce themselves under: the configured one, or else a well-known default.
+pub fn bootstrap_key(ctx: &DaemonContext) -> Fingerprint {
+    ctx.init().bootstrap_key.unwrap_or_else(|| {
+        let hash = blake3::hash(b"earendil-bootstrap");
+        let mut bytes = [0u8; 20];
+        bytes.copy_from_slice(&hash.as_bytes()[..20]);
+        Fingerprint::from_bytes(&bytes)
+    })
+}
+
+/// Returns up to `k` of the unexpired locators stored on this node whose fingerprints are XOR-closest to `fingerprint`, closest first.
+pub fn dht_get_nearest(
+    ctx: &DaemonContext,
+    fingerprint: Fingerprint,
+    k: usize,
+) -> Vec<HavenLocator> {
+    let mut by_distance = BTreeMap::new();
+    for (key, locator) in ctx
+        .get(LOCAL_DHT_SHARD)
+        .iter()
+        .chain(ctx.get(DHT_CACHE).iter())
+    {
+        if !locator.is_expired(locator_ttl(ctx)) {
+            by_distance.insert(xor_distance(&fingerprint, &key), locator);
+        }
+    }
+    by_distance.into_values().take(k).collect()
+}
+
+fn xor_distance(a: &Fingerprint, b: &Fingerprint) -> [u8; 20] {
+    let mut distance = [0u8; 20];
+    for (i, byte) in distance.iter_mut().enumerate() {
+        *byte = a.as_bytes()[i] ^ b.as_bytes()[i];
+    }
+    distance
 }
 
 fn dht_key_to_fps(ctx: &DaemonContext, key: &str) -> Vec<Fingerprint> {
@@ -106,3 +582,22 @@ fn dht_key_to_fps(ctx: &DaemonContext, key: &str) -> Vec<Fingerprint> {
     all_nodes.sort_unstable_by_key(|fp| *blake3::hash(&(key, fp).stdcode()).as_bytes());
     all_nodes
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_histogram() {
+        let counters = DhtCounters::default();
+        for millis in [5, 99, 100, 700, 999, 4000, 5000, 30000] {
+            counters.record_latency(Duration::from_millis(millis));
+        }
+        let histogram = counters
+            .latency_histogram
+            .each_ref()
+            .map(|count| count.load(Ordering::Relaxed));
+        assert_eq!(histogram, [2, 1, 2, 1, 2]);
+        assert_eq!(counters.latencies.lock().len(), 8);
+    }
+}
@@ -0,0 +1,231 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{mpsc, Arc},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use earendil_crypt::Fingerprint;
+use parking_lot::Mutex;
+use redb::{Database, ReadableTable, TableDefinition};
+use stdcode::StdcodeSerializeExt;
+
+use crate::haven_util::HavenLocator;
+
+/// Stdcode-encoded locators, keyed by the fingerprint of their haven.
+const LOCATORS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("locators");
+
+/// An on-disk cache of the locators we've looked up or inserted, so that lookups can fall back on them when the network fails, even after a restart.
+///
+/// Writes are batched and committed by a thread of the cache's own, so that the fsync of a commit never holds up the executor.
+pub struct DhtPersistentCache {
+    shared: Arc<Shared>,
+    /// wakes the committer up; dropped to stop it
+    send_commit: Option<mpsc::Sender<()>>,
+    committer: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    db: Database,
+    /// writes not yet committed, by haven fingerprint: the locator to store, or `None` to remove it
+    pending: Mutex<HashMap<Fingerprint, Option<HavenLocator>>>,
+    /// held while committing, so that batches reach the disk in the order they were taken
+    commit_lock: Mutex<()>,
+}
+
+impl Shared {
+    fn commit(&self) -> anyhow::Result<()> {
+        let _guard = self.commit_lock.lock();
+        let batch = std::mem::take(&mut *self.pending.lock());
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(LOCATORS)?;
+            for (fingerprint, locator) in batch {
+                match locator {
+                    Some(locator) => {
+                        table.insert(
+                            fingerprint.as_bytes().as_slice(),
+                            locator.stdcode().as_slice(),
+                        )?;
+                    }
+                    None => {
+                        table.remove(fingerprint.as_bytes().as_slice())?;
+                    }
+                }
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+}
+
+impl DhtPersistentCache {
+    /// Opens the cache, creating it if needed, and purges the locators made more than `max_age` ago.
+    pub fn open(path: &Path, max_age: Duration) -> anyhow::Result<Self> {
+        let db = Database::create(path)?;
+        let txn = db.begin_write()?;
+        {
+            let mut table = txn.open_table(LOCATORS)?;
+            let mut stale = vec![];
+            for entry in table.iter()? {
+                let (key, value) = entry?;
+                let expired = stdcode::deserialize::<HavenLocator>(value.value())
+                    .map_or(true, |locator| locator.is_expired(max_age));
+                if expired {
+                    stale.push(key.value().to_vec());
+                }
+            }
+            for key in stale {
+                table.remove(key.as_slice())?;
+            }
+        }
+        txn.commit()?;
+
+        let shared = Arc::new(Shared {
+            db,
+            pending: Default::default(),
+            commit_lock: Default::default(),
+        });
+        let (send_commit, recv_commit) = mpsc::channel::<()>();
+        let committer = std::thread::Builder::new()
+            .name("dht-cache-commit".into())
+            .spawn({
+                let shared = shared.clone();
+                move || {
+                    // commits whatever is pending once per wakeup, and once more when the cache is dropped
+                    while recv_commit.recv().is_ok() {
+                        while recv_commit.try_recv().is_ok() {}
+                        if let Err(err) = shared.commit() {
+                            log::warn!("cannot write to persistent DHT cache: {err}");
+                        }
+                    }
+                    if let Err(err) = shared.commit() {
+                        log::warn!("cannot write to persistent DHT cache: {err}");
+                    }
+                }
+            })?;
+        Ok(Self {
+            shared,
+            send_commit: Some(send_commit),
+            committer: Some(committer),
+        })
+    }
+
+    /// Returns the locator stored for a haven. Locators whose signature doesn't check out, e.g. because the file was tampered with, count as missing.
+    pub fn get(&self, fingerprint: Fingerprint) -> anyhow::Result<Option<HavenLocator>> {
+        let pending = self.shared.pending.lock().get(&fingerprint).cloned();
+        let locator = match pending {
+            Some(pending) => pending,
+            None => {
+                let table = self.shared.db.begin_read()?.open_table(LOCATORS)?;
+                let Some(value) = table.get(fingerprint.as_bytes().as_slice())? else {
+                    return Ok(None);
+                };
+                Some(stdcode::deserialize::<HavenLocator>(value.value())?)
+            }
+        };
+        Ok(locator.filter(|locator| {
+            let valid = locator.identity_pk.fingerprint() == fingerprint
+                && locator
+                    .identity_pk
+                    .verify(&locator.to_sign(), &locator.signature)
+                    .is_ok();
+            if !valid {
+                log::warn!("ignoring persisted locator of {fingerprint} with a bad signature");
+            }
+            valid
+        }))
+    }
+
+    pub fn remove(&self, fingerprint: Fingerprint) {
+        self.write(fingerprint, None);
+    }
+
+    pub fn insert(&self, locator: &HavenLocator) {
+        self.write(locator.identity_pk.fingerprint(), Some(locator.clone()));
+    }
+
+    /// Commits the pending writes right away, blocking until they're on disk.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        self.shared.commit()
+    }
+
+    fn write(&self, fingerprint: Fingerprint, locator: Option<HavenLocator>) {
+        self.shared.pending.lock().insert(fingerprint, locator);
+        if let Some(send_commit) = &self.send_commit {
+            let _ = send_commit.send(());
+        }
+    }
+}
+
+impl Drop for DhtPersistentCache {
+    fn drop(&mut self) {
+        // the committer commits what's left and exits once it can't be woken anymore, releasing the database
+        self.send_commit.take();
+        if let Some(committer) = self.committer.take() {
+            let _ = committer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::IdentitySecret;
+    use earendil_packet::crypt::OnionSecret;
+
+    use super::*;
+
+    #[test]
+    fn test_persistent_cache() {
+        let path = std::env::temp_dir().join(format!(
+            "earendil-dht-cache-test-{}.redb",
+            rand::random::<u64>()
+        ));
+        let isk = IdentitySecret::generate();
+        let locator = HavenLocator::new(
+            isk,
+            OnionSecret::generate().public(),
+            IdentitySecret::generate().public().fingerprint(),
+        );
+        let mut stale = HavenLocator::new(
+            IdentitySecret::generate(),
+            OnionSecret::generate().public(),
+            IdentitySecret::generate().public().fingerprint(),
+        );
+        stale.created_at -= 7200;
+
+        let mut forged = HavenLocator::new(
+            IdentitySecret::generate(),
+            OnionSecret::generate().public(),
+            IdentitySecret::generate().public().fingerprint(),
+        );
+        forged.rendezvous_point = IdentitySecret::generate().public().fingerprint();
+
+        let cache = DhtPersistentCache::open(&path, Duration::from_secs(3600)).unwrap();
+        cache.insert(&locator);
+        cache.insert(&stale);
+        cache.insert(&forged);
+        cache.flush().unwrap();
+        drop(cache);
+
+        // Testing that locators survive reopening, except for the stale ones
+        let cache = DhtPersistentCache::open(&path, Duration::from_secs(3600)).unwrap();
+        let found = cache.get(isk.public().fingerprint()).unwrap().unwrap();
+        assert_eq!(found.rendezvous_point, locator.rendezvous_point);
+        assert!(cache
+            .get(stale.identity_pk.fingerprint())
+            .unwrap()
+            .is_none());
+        // and that locators whose signature doesn't match are never handed out
+        assert!(cache
+            .get(forged.identity_pk.fingerprint())
+            .unwrap()
+            .is_none());
+        drop(cache);
+        std::fs::remove_file(path).unwrap();
+    }
+}
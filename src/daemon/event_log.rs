@@ -0,0 +1,109 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use arc_swap::ArcSwapOption;
+
+use crate::control_protocol::{DaemonEvent, LoggedEvent};
+
+/// How many of the latest events the daemon's event log keeps.
+pub const EVENT_LOG_CAPACITY: usize = 10000;
+
+/// A ring buffer of the latest daemon events, kept so that operators can look back at what happened around an incident.
+///
+/// Writers pick their slot with an atomic counter and swap their event into it, so neither logging nor reading ever takes a lock.
+pub struct EventLog {
+    /// how many events were ever logged, which numbers the next one
+    next_seq: AtomicU64,
+    /// slot `seq % capacity` holds the event numbered `seq`, until a later one overwrites it
+    slots: Box<[ArcSwapOption<LoggedEvent>]>,
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            next_seq: AtomicU64::new(0),
+            slots: (0..capacity.max(1))
+                .map(|_| ArcSwapOption::empty())
+                .collect(),
+        }
+    }
+
+    /// Logs an event, overwriting the oldest one if the log is full.
+    pub fn record(&self, event: &DaemonEvent) {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let logged = Arc::new(LoggedEvent {
+            seq,
+            timestamp_ms: unix_millis(),
+            kind: event.kind(),
+            payload: serde_json::to_value(event).unwrap_or_default(),
+        });
+        self.slots[(seq % self.slots.len() as u64) as usize].rcu(|current| {
+            // a writer that stalled must not overwrite a newer event that wrapped around onto its slot
+            match current {
+                Some(current) if current.seq > seq => Some(current.clone()),
+                _ => Some(logged.clone()),
+            }
+        });
+    }
+
+    /// Returns the first `limit` events numbered `from_seq` or later, oldest first.
+    pub fn read_events(&self, from_seq: u64, limit: usize) -> Vec<LoggedEvent> {
+        let mut events: Vec<Arc<LoggedEvent>> = self
+            .slots
+            .iter()
+            .filter_map(|slot| slot.load_full())
+            .filter(|event| event.seq >= from_seq)
+            .collect();
+        events.sort_unstable_by_key(|event| event.seq);
+        events
+            .into_iter()
+            .take(limit)
+            .map(|event| LoggedEvent::clone(&event))
+            .collect()
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::Fingerprint;
+
+    use crate::control_protocol::EventKind;
+
+    use super::*;
+
+    #[test]
+    fn test_event_log() {
+        let log = EventLog::new(3);
+        let fps: Vec<Fingerprint> = (0..5).map(|i| Fingerprint::from_bytes(&[i; 20])).collect();
+        for fp in &fps {
+            log.record(&DaemonEvent::NeighborConnected(*fp));
+        }
+
+        // only the latest events are kept, oldest first
+        let events = log.read_events(0, 10);
+        assert_eq!(events.len(), 3);
+        assert!(events
+            .iter()
+            .all(|event| event.kind == EventKind::NeighborConnected));
+        assert_eq!(
+            events[0].payload,
+            serde_json::to_value(DaemonEvent::NeighborConnected(fps[2])).unwrap()
+        );
+        assert_eq!(log.read_events(0, 1), events[..1]);
+
+        // paging by sequence number picks up right after the last event read, even within the same millisecond
+        assert_eq!(log.read_events(events[0].seq + 1, 10), events[1..]);
+        assert!(log.read_events(events[2].seq + 1, 10).is_empty());
+    }
+}
@@ -0,0 +1,111 @@
+use std::sync::{atomic::Ordering, Arc};
+
+use async_broadcast::{InactiveReceiver, Receiver, Sender};
+
+use crate::control_protocol::{DaemonEvent, DropReason};
+
+use super::{
+    context::{CtxField, DaemonContext},
+    event_log::{EventLog, EVENT_LOG_CAPACITY},
+    metrics::METRICS,
+};
+
+/// How many events a subscriber can fall behind by before it starts missing the oldest ones.
+const EVENT_QUEUE_CAPACITY: usize = 1000;
+
+pub static EVENTS: CtxField<EventBus> = |_| EventBus::new();
+
+/// Fans out daemon events to every subscriber, and keeps the latest ones in an [EventLog]. Subscribers that fall behind miss events instead of holding up the daemon.
+#[derive(Clone)]
+pub struct EventBus {
+    send: Sender<DaemonEvent>,
+    log: Arc<EventLog>,
+    /// keeps the channel open while nobody is subscribed
+    _keepalive: InactiveReceiver<DaemonEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (mut send, recv) = async_broadcast::broadcast(EVENT_QUEUE_CAPACITY);
+        send.set_overflow(true);
+        Self {
+            send,
+            log: Arc::new(EventLog::new(EVENT_LOG_CAPACITY)),
+            _keepalive: recv.deactivate(),
+        }
+    }
+
+    /// Logs an event and delivers it to the current subscribers, if any.
+    pub fn emit(&self, event: DaemonEvent) {
+        self.log.record(&event);
+        let _ = self.send.try_broadcast(event);
+    }
+
+    /// The log of the latest events emitted.
+    pub fn log(&self) -> &EventLog {
+        &self.log
+    }
+
+    /// Returns a receiver of every event emitted from now on.
+    pub fn subscribe(&self) -> Receiver<DaemonEvent> {
+        self.send.new_receiver()
+    }
+}
+
+/// Notifies subscribers that a packet was dropped.
+pub fn emit_packet_dropped(ctx: &DaemonContext, reason: DropReason) {
+    ctx.get(METRICS)
+        .packets_dropped
+        .fetch_add(1, Ordering::Relaxed);
+    ctx.get(EVENTS).emit(DaemonEvent::PacketDropped { reason });
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::Fingerprint;
+
+    use crate::control_protocol::{EventFilter, EventKind};
+
+    use super::*;
+
+    #[test]
+    fn test_event_bus() {
+        let bus = EventBus::new();
+        let fp = Fingerprint::from_bytes(&[1; 20]);
+
+        // Testing that events emitted while nobody listens are simply lost
+        bus.emit(DaemonEvent::NeighborConnected(fp));
+        let mut recv = bus.subscribe();
+        assert!(recv.try_recv().is_err());
+
+        bus.emit(DaemonEvent::NeighborDisconnected(fp));
+        bus.emit(DaemonEvent::PacketDropped {
+            reason: DropReason::NoSocket,
+        });
+        let filter = EventFilter {
+            kinds: vec![EventKind::PacketDropped],
+        };
+        let received: Vec<_> = std::iter::from_fn(|| recv.try_recv().ok())
+            .filter(|event| filter.matches(event))
+            .collect();
+        assert_eq!(
+            received,
+            [DaemonEvent::PacketDropped {
+                reason: DropReason::NoSocket
+            }]
+        );
+
+        // Testing that a subscriber that falls behind misses the oldest events instead of blocking
+        for _ in 0..EVENT_QUEUE_CAPACITY + 1 {
+            bus.emit(DaemonEvent::DhtInsert(fp));
+        }
+        assert!(recv.try_recv().is_err());
+        assert_eq!(recv.try_recv(), Ok(DaemonEvent::DhtInsert(fp)));
+    }
+}
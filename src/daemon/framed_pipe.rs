@@ -0,0 +1,265 @@
+use std::{future::Future, io::ErrorKind};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use smol::{
+    channel::{Receiver, Sender},
+    future::FutureExt,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    Task,
+};
+use sosistab2::Pipe;
+
+/// How many messages can wait to be written before new ones are dropped, as a congested UDP pipe would.
+const SEND_QUEUE_CAPACITY: usize = 1000;
+
+/// The longest message framed with 4-byte lengths or carried in a WebSocket message, so that a peer can't make us allocate gigabytes by sending a huge length.
+pub const MAX_WIDE_FRAME_LEN: usize = 64 * 1024;
+
+/// A [Pipe] over a connection-oriented transport. Over a byte stream, such as a TCP connection, each message is framed with its big-endian length; transports with their own framing, such as WebSocket, can carry messages as they are.
+pub struct FramedPipe {
+    max_len: usize,
+    send_outgoing: Sender<Bytes>,
+    recv_incoming: Receiver<Bytes>,
+    protocol: &'static str,
+    peer_addr: String,
+    _task: Task<()>,
+}
+
+impl FramedPipe {
+    /// Starts framing messages over the two halves of a stream, with 2-byte lengths. The pipe closes when either direction fails.
+    pub fn new(
+        read: impl AsyncRead + Unpin + Send + 'static,
+        write: impl AsyncWrite + Unpin + Send + 'static,
+        protocol: &'static str,
+        peer_addr: String,
+    ) -> Self {
+        Self::with_prefix(read, write, LengthPrefix::U16, protocol, peer_addr)
+    }
+
+    /// Starts framing messages over the two halves of a stream, with lengths of the given width.
+    pub fn with_prefix(
+        read: impl AsyncRead + Unpin + Send + 'static,
+        write: impl AsyncWrite + Unpin + Send + 'static,
+        prefix: LengthPrefix,
+        protocol: &'static str,
+        peer_addr: String,
+    ) -> Self {
+        let mut pipe = Self::spawn(protocol, peer_addr, |recv_outgoing, send_incoming| {
+            write_loop(write, prefix, recv_outgoing).or(read_frames(read, prefix, send_incoming))
+        });
+        pipe.max_len = prefix.max_len();
+        pipe
+    }
+
+    /// Starts a pipe whose messages are carried by `io`, which takes messages to send from its first argument and delivers received ones to its second. The pipe closes when `io` returns.
+    pub fn spawn<F: Future<Output = anyhow::Result<()>> + Send + 'static>(
+        protocol: &'static str,
+        peer_addr: String,
+        io: impl FnOnce(Receiver<Bytes>, Sender<Bytes>) -> F,
+    ) -> Self {
+        let (send_outgoing, recv_outgoing) = smol::channel::bounded(SEND_QUEUE_CAPACITY);
+        let (send_incoming, recv_incoming) = smol::channel::bounded(SEND_QUEUE_CAPACITY);
+        let io = io(recv_outgoing, send_incoming);
+        let task = smolscale::spawn(async move {
+            if let Err(err) = io.await {
+                log::debug!("{protocol} pipe closed: {:?}", err);
+            }
+        });
+        Self {
+            max_len: u16::MAX as usize,
+            send_outgoing,
+            recv_incoming,
+            protocol,
+            peer_addr,
+            _task: task,
+        }
+    }
+}
+
+#[async_trait]
+impl Pipe for FramedPipe {
+    fn send(&self, to_send: Bytes) {
+        if to_send.len() > self.max_len {
+            log::warn!("dropping {}-byte message too big to frame", to_send.len());
+            return;
+        }
+        let _ = self.send_outgoing.try_send(to_send);
+    }
+
+    async fn recv(&self) -> std::io::Result<Bytes> {
+        self.recv_incoming
+            .recv()
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "framed pipe closed"))
+    }
+
+    fn protocol(&self) -> &str {
+        self.protocol
+    }
+
+    fn peer_metadata(&self) -> &str {
+        ""
+    }
+
+    fn peer_addr(&self) -> String {
+        self.peer_addr.clone()
+    }
+}
+
+/// How many bytes the length before each message takes.
+#[derive(Clone, Copy)]
+pub enum LengthPrefix {
+    U16,
+    U32,
+}
+
+impl LengthPrefix {
+    fn max_len(self) -> usize {
+        match self {
+            LengthPrefix::U16 => u16::MAX as usize,
+            LengthPrefix::U32 => MAX_WIDE_FRAME_LEN,
+        }
+    }
+}
+
+async fn write_loop(
+    mut write: impl AsyncWrite + Unpin,
+    prefix: LengthPrefix,
+    recv_outgoing: Receiver<Bytes>,
+) -> anyhow::Result<()> {
+    loop {
+        let msg = recv_outgoing.recv().await?;
+        write_frame(&mut write, prefix, &msg).await?;
+        // write out everything that queued up meanwhile before flushing
+        while let Ok(msg) = recv_outgoing.try_recv() {
+            write_frame(&mut write, prefix, &msg).await?;
+        }
+        write.flush().await?;
+    }
+}
+
+/// Writes one length-prefixed message, without flushing.
+pub(super) async fn write_frame(
+    write: &mut (impl AsyncWrite + Unpin),
+    prefix: LengthPrefix,
+    msg: &[u8],
+) -> anyhow::Result<()> {
+    match prefix {
+        LengthPrefix::U16 => write.write_all(&(msg.len() as u16).to_be_bytes()).await?,
+        LengthPrefix::U32 => write.write_all(&(msg.len() as u32).to_be_bytes()).await?,
+    }
+    write.write_all(msg).await?;
+    Ok(())
+}
+
+/// Reads length-prefixed messages until the stream fails, delivering each one.
+pub(super) async fn read_frames(
+    mut read: impl AsyncRead + Unpin,
+    prefix: LengthPrefix,
+    send_incoming: Sender<Bytes>,
+) -> anyhow::Result<()> {
+    loop {
+        let len = match prefix {
+            LengthPrefix::U16 => {
+                let mut len = [0u8; 2];
+                read.read_exact(&mut len).await?;
+                u16::from_be_bytes(len) as usize
+            }
+            LengthPrefix::U32 => {
+                let mut len = [0u8; 4];
+                read.read_exact(&mut len).await?;
+                u32::from_be_bytes(len) as usize
+            }
+        };
+        if len > prefix.max_len() {
+            anyhow::bail!("peer sent a {len}-byte frame, longer than the limit")
+        }
+        let mut msg = vec![0u8; len];
+        read.read_exact(&mut msg).await?;
+        send_incoming.send(msg.into()).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_framing() {
+        smol::block_on(async {
+            let listener = smol::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = smol::net::TcpStream::connect(addr).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            let client = FramedPipe::new(client.clone(), client, "tcp", addr.to_string());
+            let server = FramedPipe::new(server.clone(), server, "tcp", addr.to_string());
+
+            client.send(Bytes::from_static(b"hello"));
+            client.send(Bytes::new());
+            client.send(vec![7u8; 1400].into());
+            assert_eq!(server.recv().await.unwrap(), &b"hello"[..]);
+            assert!(server.recv().await.unwrap().is_empty());
+            assert_eq!(server.recv().await.unwrap(), vec![7u8; 1400]);
+
+            server.send(Bytes::from_static(b"world"));
+            assert_eq!(client.recv().await.unwrap(), &b"world"[..]);
+
+            drop(server);
+            assert!(client.recv().await.is_err());
+        })
+    }
+
+    #[test]
+    fn test_wide_framing() {
+        smol::block_on(async {
+            let listener = smol::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = smol::net::TcpStream::connect(addr).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            let client = FramedPipe::with_prefix(
+                client.clone(),
+                client,
+                LengthPrefix::U32,
+                "tcp",
+                addr.to_string(),
+            );
+            let server = FramedPipe::with_prefix(
+                server.clone(),
+                server,
+                LengthPrefix::U32,
+                "tcp",
+                addr.to_string(),
+            );
+
+            // too long for a 2-byte length
+            client.send(vec![7u8; 65536].into());
+            // too long for any frame
+            client.send(vec![7u8; 65537].into());
+            client.send(Bytes::from_static(b"hello"));
+            assert_eq!(server.recv().await.unwrap(), vec![7u8; 65536]);
+            assert_eq!(server.recv().await.unwrap(), &b"hello"[..]);
+        })
+    }
+
+    #[test]
+    fn test_oversized_frame() {
+        smol::block_on(async {
+            let listener = smol::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mut client = smol::net::TcpStream::connect(addr).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            let server = FramedPipe::with_prefix(
+                server.clone(),
+                server,
+                LengthPrefix::U32,
+                "tcp",
+                addr.to_string(),
+            );
+
+            // the pipe closes rather than waiting for a gigabyte to arrive
+            client.write_all(&(1u32 << 30).to_be_bytes()).await.unwrap();
+            assert!(server.recv().await.is_err());
+        })
+    }
+}
@@ -2,14 +2,19 @@ use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::Context;
 use bytes::Bytes;
+use earendil_crypt::Fingerprint;
 use earendil_topology::{AdjacencyDescriptor, IdentityDescriptor};
 use itertools::Itertools;
+use moka::sync::Cache;
 use rand::{seq::SliceRandom, thread_rng, Rng};
 use smol_timeout::TimeoutExt;
 use smolscale::reaper::TaskReaper;
 
+use crate::control_protocol::DaemonEvent;
+
 use super::{
-    context::{GLOBAL_IDENTITY, GLOBAL_ONION_SK, NEIGH_TABLE, RELAY_GRAPH},
+    context::{CtxField, GLOBAL_IDENTITY, GLOBAL_ONION_SK, NEIGH_TABLE, RELAY_GRAPH},
+    events::EVENTS,
     link_connection::LinkConnection,
     DaemonContext,
 };
@@ -59,21 +64,40 @@ pub async fn gossip_loop(ctx: DaemonContext) -> anyhow::Result<()> {
     }
 }
 
-/// One round of gossip with a particular neighbor.
+/// One round of gossip with a particular neighbor. Every step runs even if an earlier one failed, and the round fails with the first error.
 async fn gossip_once(ctx: &DaemonContext, conn: &LinkConnection) -> anyhow::Result<()> {
-    fetch_identity(ctx, conn).await?;
-    sign_adjacency(ctx, conn).await?;
-    gossip_graph(ctx, conn).await?;
-    Ok(())
+    let remote_fingerprint = conn.remote_idpk().fingerprint();
+    let results = [
+        conn.measure_latency()
+            .await
+            .map(|rtt| log::trace!("rtt to {remote_fingerprint} is {rtt:?}"))
+            .context("could not measure latency"),
+        fetch_identity(ctx, conn)
+            .await
+            .context("could not fetch identity"),
+        sign_adjacency(ctx, conn)
+            .await
+            .context("could not sign adjacency"),
+        gossip_graph(ctx, conn)
+            .await
+            .context("could not gossip graph"),
+        check_config_drift(ctx, conn)
+            .await
+            .context("could not check config drift"),
+    ];
+    let mut errors = results.into_iter().filter_map(|res| res.err());
+    let first = errors.next();
+    for err in errors {
+        log::debug!("gossip with {remote_fingerprint} also failed: {err:?}");
+    }
+    first.map_or(Ok(()), Err)
 }
 
 // Step 1: Fetch the identity of the neighbor.
 async fn fetch_identity(ctx: &DaemonContext, conn: &LinkConnection) -> anyhow::Result<()> {
     let remote_fingerprint = conn.remote_idpk().fingerprint();
     log::trace!("getting identity of {remote_fingerprint}");
-    let their_id = conn
-        .link_rpc()
-        .identity(remote_fingerprint)
+    let their_id = fetch_identity_of(conn, remote_fingerprint)
         .await?
         .context("they refused to give us their id descriptor")?;
     ctx.get(RELAY_GRAPH).write().insert_identity(their_id)?;
@@ -81,6 +105,18 @@ async fn fetch_identity(ctx: &DaemonContext, conn: &LinkConnection) -> anyhow::R
     Ok(())
 }
 
+/// Asks the neighbor for the identity of a particular fingerprint, with the method name its protocol version knows.
+async fn fetch_identity_of(
+    conn: &LinkConnection,
+    fp: Fingerprint,
+) -> anyhow::Result<Option<IdentityDescriptor>> {
+    Ok(if conn.version() >= 1 {
+        conn.link_rpc().v1_identity(fp).await?
+    } else {
+        conn.link_rpc().identity(fp).await?
+    })
+}
+
 // Step 2: Sign an adjacency descriptor with the neighbor if the local node is "left" of the neighbor.
 async fn sign_adjacency(ctx: &DaemonContext, conn: &LinkConnection) -> anyhow::Result<()> {
     let remote_idpk = conn.remote_idpk();
@@ -93,15 +129,17 @@ async fn sign_adjacency(ctx: &DaemonContext, conn: &LinkConnection) -> anyhow::R
             left_sig: Bytes::new(),
             right_sig: Bytes::new(),
             unix_timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            bandwidth_kbps: conn.bandwidth_kbps(),
         };
         left_incomplete.left_sig = ctx
             .get(GLOBAL_IDENTITY)
             .sign(left_incomplete.to_sign().as_bytes());
-        let complete = conn
-            .link_rpc()
-            .sign_adjacency(left_incomplete)
-            .await?
-            .context("remote refused to sign off")?;
+        let complete = if conn.version() >= 1 {
+            conn.link_rpc().v1_sign_adjacency(left_incomplete).await?
+        } else {
+            conn.link_rpc().sign_adjacency(left_incomplete).await?
+        }
+        .context("remote refused to sign off")?;
         ctx.get(RELAY_GRAPH).write().insert_adjacency(complete)?;
     }
     Ok(())
@@ -119,21 +157,56 @@ async fn gossip_graph(ctx: &DaemonContext, conn: &LinkConnection) -> anyhow::Res
     //     "asking {remote_fingerprint} for neighbors of {} neighbors!",
     //     random_sample.len()
     // );
-    let adjacencies = conn.link_rpc().adjacencies(random_sample).await?;
+    let adjacencies = if conn.version() >= 1 {
+        conn.link_rpc().v1_adjacencies(random_sample).await?
+    } else {
+        conn.link_rpc().adjacencies(random_sample).await?
+    };
     for adjacency in adjacencies {
         let left_fp = adjacency.left;
         let right_fp = adjacency.right;
-        // fetch and insert the identities. we unconditionally do this since identity descriptors may change over time
-        if let Some(left_id) = conn.link_rpc().identity(left_fp).await? {
-            ctx.get(RELAY_GRAPH).write().insert_identity(left_id)?
+        // fetch and insert the identities. we unconditionally do this since identity descriptors may change over time; one bad adjacency doesn't stop the rest
+        let inserted = async {
+            if let Some(left_id) = fetch_identity_of(conn, left_fp).await? {
+                ctx.get(RELAY_GRAPH).write().insert_identity(left_id)?
+            }
+            if let Some(right_id) = fetch_identity_of(conn, right_fp).await? {
+                ctx.get(RELAY_GRAPH).write().insert_identity(right_id)?
+            }
+            ctx.get(RELAY_GRAPH).write().insert_adjacency(adjacency)?;
+            anyhow::Ok(())
         }
-
-        if let Some(right_id) = conn.link_rpc().identity(right_fp).await? {
-            ctx.get(RELAY_GRAPH).write().insert_identity(right_id)?
+        .await;
+        if let Err(err) = inserted {
+            log::debug!("skipping gossiped adjacency {left_fp} -- {right_fp}: {err:?}");
         }
+    }
+    Ok(())
+}
 
-        // insert the adjacency
-        ctx.get(RELAY_GRAPH).write().insert_adjacency(adjacency)?
+/// The latest in_route config fingerprint each neighbor reported.
+static NEIGH_CONFIG_FINGERPRINTS: CtxField<Cache<Fingerprint, [u8; 32]>> = |_| {
+    Cache::builder()
+        .time_to_idle(Duration::from_secs(3600))
+        .build()
+};
+
+// Step 4: Notice when the neighbor's in_route config changed since we last asked.
+async fn check_config_drift(ctx: &DaemonContext, conn: &LinkConnection) -> anyhow::Result<()> {
+    if conn.version() < 2 {
+        return Ok(());
+    }
+    let remote_fingerprint = conn.remote_idpk().fingerprint();
+    let config_fingerprint = conn.link_rpc().v2_config_fingerprint().await?;
+    let previous = ctx.get(NEIGH_CONFIG_FINGERPRINTS).get(&remote_fingerprint);
+    if previous.is_some_and(|previous| previous != config_fingerprint) {
+        log::warn!(
+            "{remote_fingerprint} changed its in_route config; out_routes to it may need updating"
+        );
+        ctx.get(EVENTS)
+            .emit(DaemonEvent::NeighborConfigChanged(remote_fingerprint));
     }
+    ctx.get(NEIGH_CONFIG_FINGERPRINTS)
+        .insert(remote_fingerprint, config_fingerprint);
     Ok(())
 }
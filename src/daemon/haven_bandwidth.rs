@@ -0,0 +1,139 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use dashmap::DashMap;
+use earendil_crypt::Fingerprint;
+
+use super::context::CtxField;
+
+/// How many seconds the per-haven byte counts cover.
+const WINDOW_SECS: u64 = 60;
+
+pub static HAVEN_BANDWIDTH: CtxField<HavenBandwidthTracker> = |_| Default::default();
+
+/// Bytes that haven sessions exchanged over the last minute, by the fingerprint of the haven: our own for sessions we serve, the remote one for sessions we started.
+#[derive(Default)]
+pub struct HavenBandwidthTracker {
+    buckets: DashMap<Fingerprint, Arc<BandwidthBucket>>,
+}
+
+impl HavenBandwidthTracker {
+    /// Returns the counters of a haven, for sessions to record their traffic in.
+    pub fn bucket(&self, haven_fp: Fingerprint) -> Arc<BandwidthBucket> {
+        self.buckets.entry(haven_fp).or_default().clone()
+    }
+
+    /// Returns how many bytes were sent and received over the last minute for a haven, in that order.
+    pub fn bytes_per_minute(&self, haven_fp: Fingerprint) -> (u64, u64) {
+        self.buckets
+            .get(&haven_fp)
+            .map_or((0, 0), |bucket| bucket.bytes_per_minute())
+    }
+
+    /// Returns the havens we have counters for, some of which may have had no traffic lately.
+    pub fn havens(&self) -> Vec<Fingerprint> {
+        self.buckets.iter().map(|entry| *entry.key()).collect()
+    }
+
+    /// Forgets havens that no session is recording for and that had no traffic over the last minute.
+    pub fn garbage_collect(&self) {
+        self.buckets.retain(|_, bucket| {
+            Arc::strong_count(bucket) > 1 || bucket.bytes_per_minute() != (0, 0)
+        });
+    }
+}
+
+/// Byte counters of one haven, sent and received.
+#[derive(Default)]
+pub struct BandwidthBucket {
+    sent: SecondsRing,
+    received: SecondsRing,
+}
+
+impl BandwidthBucket {
+    pub fn record_sent(&self, bytes: usize) {
+        self.sent.add(unix_secs(), bytes as u64);
+    }
+
+    pub fn record_received(&self, bytes: usize) {
+        self.received.add(unix_secs(), bytes as u64);
+    }
+
+    fn bytes_per_minute(&self) -> (u64, u64) {
+        let now = unix_secs();
+        (self.sent.sum(now), self.received.sum(now))
+    }
+}
+
+/// A byte count for each of the last [WINDOW_SECS] seconds of wall-clock time. Slot `s % WINDOW_SECS` counts second `s`, and is cleared when the clock comes around to it again.
+struct SecondsRing {
+    bytes: [AtomicU64; WINDOW_SECS as usize],
+    /// which second each slot currently counts
+    stamps: [AtomicU64; WINDOW_SECS as usize],
+}
+
+impl Default for SecondsRing {
+    fn default() -> Self {
+        Self {
+            bytes: std::array::from_fn(|_| AtomicU64::new(0)),
+            stamps: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+impl SecondsRing {
+    fn add(&self, now: u64, bytes: u64) {
+        let slot = (now % WINDOW_SECS) as usize;
+        let stamp = self.stamps[slot].load(Ordering::Acquire);
+        // whoever moves the slot on to this second clears it; bytes racing with the clearing may be lost, which is fine for a rate
+        if stamp != now
+            && self.stamps[slot]
+                .compare_exchange(stamp, now, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            self.bytes[slot].store(0, Ordering::Release);
+        }
+        self.bytes[slot].fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn sum(&self, now: u64) -> u64 {
+        (0..WINDOW_SECS as usize)
+            .filter(|&slot| {
+                now.saturating_sub(self.stamps[slot].load(Ordering::Acquire)) < WINDOW_SECS
+            })
+            .map(|slot| self.bytes[slot].load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seconds_ring() {
+        let ring = SecondsRing::default();
+        let start = 1_000_000;
+        ring.add(start, 100);
+        ring.add(start, 50);
+        ring.add(start + 30, 10);
+        assert_eq!(ring.sum(start + 30), 160);
+        // the first second falls out of the window a minute later
+        assert_eq!(ring.sum(start + WINDOW_SECS), 10);
+        // and its slot starts over when it's reused
+        ring.add(start + WINDOW_SECS, 1);
+        assert_eq!(ring.sum(start + WINDOW_SECS), 11);
+        assert_eq!(ring.sum(start + 10 * WINDOW_SECS), 0);
+    }
+}
@@ -1,11 +1,46 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    future::Future,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
+use anyhow::Context;
+use async_tls::TlsAcceptor;
+use dashmap::DashMap;
 use earendil_crypt::Fingerprint;
-use smol::future::FutureExt;
+use quinn::Endpoint;
+#[cfg(unix)]
+use smol::net::unix::{UnixListener, UnixStream};
+use smol::{
+    future::FutureExt,
+    net::{TcpListener, TcpStream},
+    Task,
+};
+use smol_timeout::TimeoutExt;
 use smolscale::reaper::TaskReaper;
+use sosistab2::Pipe;
 use sosistab2_obfsudp::{ObfsUdpListener, ObfsUdpPipe, ObfsUdpPublic, ObfsUdpSecret};
 
-use crate::daemon::{context::NEIGH_TABLE, link_connection::LinkConnection};
+use crate::{
+    config::{InRouteConfig, OutRouteConfig},
+    daemon::{
+        context::{IN_ROUTE_FAILURES, NEIGH_TABLE, TRANSPORTS},
+        framed_pipe::FramedPipe,
+        link_connection::LinkConnection,
+        quic_pipe::{accept_quic_pipe, bind_quic_endpoint, connect_quic_pipe},
+        tls::{load_tls_acceptor, unverified_tls_connector},
+        ws_pipe::{accept_ws_pipe, connect_ws_pipe},
+    },
+    transport::{PipeListener, TransportRegistry},
+};
+
+#[cfg(unix)]
+use crate::daemon::framed_pipe::LengthPrefix;
+
+/// How long a neighbor gets to finish the TLS handshake on a TCP in_route.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
 
 use super::DaemonContext;
 
@@ -13,52 +48,520 @@ use super::DaemonContext;
 pub struct InRouteContext {
     pub daemon_ctx: DaemonContext,
     pub in_route_name: String,
+    /// fingerprints of the neighbors that connected through this in_route
+    pub accepted: Arc<DashMap<Fingerprint, ()>>,
 }
 
-pub async fn in_route_obfsudp(
-    context: InRouteContext,
+/// A running in_route. Dropping it stops the listener.
+pub struct InRouteHandle {
+    pub accepted: Arc<DashMap<Fingerprint, ()>>,
+    pub task: Task<anyhow::Result<()>>,
+}
+
+/// A running out_route. Dropping it stops reconnecting to the neighbor.
+pub struct OutRouteHandle {
+    pub remote_fingerprint: Fingerprint,
+    pub task: Task<anyhow::Result<()>>,
+}
+
+/// Binds the listener of an in_route and starts accepting neighbors on it, failing if the listener can't be bound.
+pub async fn start_in_route(
+    ctx: &DaemonContext,
+    name: &str,
+    config: InRouteConfig,
+) -> anyhow::Result<InRouteHandle> {
+    let context = InRouteContext {
+        daemon_ctx: ctx.clone(),
+        in_route_name: name.to_string(),
+        accepted: Default::default(),
+    };
+    let task = match config {
+        InRouteConfig::Obfsudp { listen, secret } => {
+            let listener = bind_in_route_obfsudp(name, listen, &secret).await?;
+            spawn_in_route(ctx, name, serve_in_route_obfsudp(context.clone(), listener))
+        }
+        InRouteConfig::Tcp { listen, tls_cert } => {
+            let (listener, tls) = bind_in_route_tcp(name, listen, tls_cert.as_deref()).await?;
+            spawn_in_route(
+                ctx,
+                name,
+                serve_in_route_tcp(context.clone(), listener, tls),
+            )
+        }
+        InRouteConfig::Quic { listen, cert, key } => {
+            let endpoint = bind_quic_endpoint(listen, &cert, &key)?;
+            log::debug!("quic in_route {} listen start", name);
+            spawn_in_route(ctx, name, serve_in_route_quic(context.clone(), endpoint))
+        }
+        InRouteConfig::WebSocket {
+            listen,
+            path,
+            tls_cert,
+        } => {
+            let (listener, tls) = bind_in_route_tcp(name, listen, tls_cert.as_deref()).await?;
+            spawn_in_route(
+                ctx,
+                name,
+                serve_in_route_ws(context.clone(), listener, path, tls),
+            )
+        }
+        #[cfg(unix)]
+        InRouteConfig::UnixSocket { path } => {
+            let listener = bind_in_route_unix(name, &path).await?;
+            spawn_in_route(ctx, name, serve_in_route_unix(context.clone(), listener))
+        }
+        #[cfg(not(unix))]
+        InRouteConfig::UnixSocket { .. } => {
+            anyhow::bail!("Unix sockets aren't supported on this platform")
+        }
+        InRouteConfig::Custom { transport, config } => {
+            let listener = transports(ctx)?.get(&transport)?.listen(&config).await?;
+            log::debug!("{} in_route {} listen start", transport, name);
+            spawn_in_route(ctx, name, serve_in_route_custom(context.clone(), listener))
+        }
+    };
+    Ok(InRouteHandle {
+        accepted: context.accepted,
+        task,
+    })
+}
+
+/// Runs an in_route's listener, reporting to [IN_ROUTE_FAILURES] if it stops with an error.
+fn spawn_in_route(
+    ctx: &DaemonContext,
+    name: &str,
+    serve: impl Future<Output = anyhow::Result<()>> + Send + 'static,
+) -> Task<anyhow::Result<()>> {
+    let ctx = ctx.clone();
+    let name = name.to_string();
+    smolscale::spawn(async move {
+        let res = serve.await;
+        if let Err(err) = &res {
+            log::error!("in_route {name} stopped: {:?}", err);
+            let _ = ctx
+                .get(IN_ROUTE_FAILURES)
+                .0
+                .try_send(anyhow::anyhow!("in_route {name} stopped: {err}"));
+        }
+        res
+    })
+}
+
+/// Starts keeping up a connection to the neighbor of an out_route.
+pub fn start_out_route(ctx: &DaemonContext, name: &str, config: OutRouteConfig) -> OutRouteHandle {
+    match config {
+        OutRouteConfig::Obfsudp {
+            fingerprint,
+            connect,
+            cookie,
+            bandwidth_limit_kbps,
+            priority,
+        } => {
+            let context = OutRouteContext {
+                out_route_name: name.to_string(),
+                remote_fingerprint: fingerprint,
+                daemon_ctx: ctx.clone(),
+                bandwidth_limit_kbps,
+                priority,
+            };
+            OutRouteHandle {
+                remote_fingerprint: fingerprint,
+                task: smolscale::spawn(out_route_obfsudp(context, connect, cookie)),
+            }
+        }
+        OutRouteConfig::Tcp {
+            fingerprint,
+            connect,
+            tls,
+            bandwidth_limit_kbps,
+            priority,
+        } => {
+            let context = OutRouteContext {
+                out_route_name: name.to_string(),
+                remote_fingerprint: fingerprint,
+                daemon_ctx: ctx.clone(),
+                bandwidth_limit_kbps,
+                priority,
+            };
+            OutRouteHandle {
+                remote_fingerprint: fingerprint,
+                task: smolscale::spawn(out_route_tcp(context, connect, tls)),
+            }
+        }
+        OutRouteConfig::Quic {
+            fingerprint,
+            connect,
+            bandwidth_limit_kbps,
+            priority,
+        } => {
+            let context = OutRouteContext {
+                out_route_name: name.to_string(),
+                remote_fingerprint: fingerprint,
+                daemon_ctx: ctx.clone(),
+                bandwidth_limit_kbps,
+                priority,
+            };
+            OutRouteHandle {
+                remote_fingerprint: fingerprint,
+                task: smolscale::spawn(keep_out_route(context, "quic", move || {
+                    connect_quic_pipe(connect)
+                })),
+            }
+        }
+        OutRouteConfig::WebSocket {
+            fingerprint,
+            url,
+            bandwidth_limit_kbps,
+            priority,
+        } => {
+            let context = OutRouteContext {
+                out_route_name: name.to_string(),
+                remote_fingerprint: fingerprint,
+                daemon_ctx: ctx.clone(),
+                bandwidth_limit_kbps,
+                priority,
+            };
+            OutRouteHandle {
+                remote_fingerprint: fingerprint,
+                task: smolscale::spawn(keep_out_route(context, "websocket", move || {
+                    let url = url.clone();
+                    async move { connect_ws_pipe(&url).await }
+                })),
+            }
+        }
+        OutRouteConfig::UnixSocket {
+            fingerprint,
+            path,
+            bandwidth_limit_kbps,
+            priority,
+        } => {
+            let context = OutRouteContext {
+                out_route_name: name.to_string(),
+                remote_fingerprint: fingerprint,
+                daemon_ctx: ctx.clone(),
+                bandwidth_limit_kbps,
+                priority,
+            };
+            OutRouteHandle {
+                remote_fingerprint: fingerprint,
+                task: smolscale::spawn(out_route_unix(context, path)),
+            }
+        }
+        OutRouteConfig::Custom {
+            fingerprint,
+            transport,
+            config,
+            bandwidth_limit_kbps,
+            priority,
+        } => {
+            let context = OutRouteContext {
+                out_route_name: name.to_string(),
+                remote_fingerprint: fingerprint,
+                daemon_ctx: ctx.clone(),
+                bandwidth_limit_kbps,
+                priority,
+            };
+            let ctx = ctx.clone();
+            let protocol = transport.clone();
+            OutRouteHandle {
+                remote_fingerprint: fingerprint,
+                task: smolscale::spawn(async move {
+                    keep_out_route(context, &protocol, move || {
+                        let ctx = ctx.clone();
+                        let transport = transport.clone();
+                        let config = config.clone();
+                        async move { transports(&ctx)?.get(&transport)?.dial(&config).await }
+                    })
+                    .await
+                }),
+            }
+        }
+    }
+}
+
+/// Binds the listener of an obfsudp in_route, failing if the address is already in use.
+pub async fn bind_in_route_obfsudp(
+    in_route_name: &str,
     listen: SocketAddr,
-    secret: String,
-) -> anyhow::Result<()> {
+    secret: &str,
+) -> anyhow::Result<ObfsUdpListener> {
     let secret = ObfsUdpSecret::from_bytes(*blake3::hash(secret.as_bytes()).as_bytes());
     log::debug!(
         "obfsudp in_route {} listen start with cookie {}",
-        context.in_route_name,
+        in_route_name,
         hex::encode(secret.to_public().as_bytes())
     );
-    let listener = ObfsUdpListener::bind(listen, secret).await?;
+    Ok(ObfsUdpListener::bind(listen, secret).await?)
+}
+
+/// Accepts connections on an obfsudp in_route's listener, adding them to the neighbor table.
+pub async fn serve_in_route_obfsudp(
+    context: InRouteContext,
+    listener: ObfsUdpListener,
+) -> anyhow::Result<()> {
     let group = TaskReaper::new();
     loop {
         let next = listener.accept().await?;
+        group.attach(smolscale::spawn(accept_neighbor(context.clone(), next)))
+    }
+}
+
+/// Binds the listener of a TCP in_route and loads its TLS certificate, if it has one, failing if either can't be done.
+pub async fn bind_in_route_tcp(
+    in_route_name: &str,
+    listen: SocketAddr,
+    tls_cert: Option<&Path>,
+) -> anyhow::Result<(TcpListener, Option<TlsAcceptor>)> {
+    let tls = tls_cert.map(load_tls_acceptor).transpose()?;
+    log::debug!(
+        "tcp in_route {} listen start{}",
+        in_route_name,
+        if tls.is_some() { " with TLS" } else { "" }
+    );
+    Ok((TcpListener::bind(listen).await?, tls))
+}
+
+/// Accepts connections on a TCP in_route's listener, adding them to the neighbor table.
+pub async fn serve_in_route_tcp(
+    context: InRouteContext,
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+) -> anyhow::Result<()> {
+    let group = TaskReaper::new();
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
         let context = context.clone();
+        let tls = tls.clone();
         group.attach(smolscale::spawn(async move {
-            let connection = LinkConnection::connect(context.daemon_ctx.clone(), next).await?;
-            log::info!(
-                "obfsudp in_route {} accepted {}",
-                context.in_route_name,
-                connection.remote_idpk().fingerprint()
-            );
-            context.daemon_ctx.get(NEIGH_TABLE).insert(
-                connection.remote_idpk().fingerprint(),
-                connection,
-                Duration::from_secs(300),
-            );
-            anyhow::Ok(())
+            stream.set_nodelay(true)?;
+            let pipe = match tls {
+                Some(tls) => {
+                    let stream = tls
+                        .accept(stream)
+                        .timeout(TLS_HANDSHAKE_TIMEOUT)
+                        .await
+                        .context("TLS handshake timed out")??;
+                    let (read, write) = smol::io::split(stream);
+                    FramedPipe::new(read, write, "tcp-tls", peer_addr.to_string())
+                }
+                None => FramedPipe::new(stream.clone(), stream, "tcp", peer_addr.to_string()),
+            };
+            accept_neighbor(context, pipe).await
+        }))
+    }
+}
+
+/// Accepts connections on a QUIC in_route's endpoint, adding them to the neighbor table.
+pub async fn serve_in_route_quic(
+    context: InRouteContext,
+    endpoint: Endpoint,
+) -> anyhow::Result<()> {
+    let group = TaskReaper::new();
+    loop {
+        let connecting = endpoint.accept().await.context("QUIC endpoint closed")?;
+        let context = context.clone();
+        group.attach(smolscale::spawn(async move {
+            let pipe = accept_quic_pipe(connecting).await?;
+            accept_neighbor(context, pipe).await
+        }))
+    }
+}
+
+/// Accepts WebSocket connections on a WebSocket in_route's TCP listener, adding them to the neighbor table.
+pub async fn serve_in_route_ws(
+    context: InRouteContext,
+    listener: TcpListener,
+    path: String,
+    tls: Option<TlsAcceptor>,
+) -> anyhow::Result<()> {
+    let group = TaskReaper::new();
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let context = context.clone();
+        let path = path.clone();
+        let tls = tls.clone();
+        group.attach(smolscale::spawn(async move {
+            stream.set_nodelay(true)?;
+            let pipe = accept_ws_pipe(stream, path, tls).await?;
+            accept_neighbor(context, pipe).await
         }))
     }
 }
 
+/// Binds the socket of a Unix socket in_route. A socket file that nothing listens on anymore is left over from an earlier run, so it's replaced; anything else at the path is left alone.
+#[cfg(unix)]
+pub async fn bind_in_route_unix(in_route_name: &str, path: &Path) -> anyhow::Result<UnixListener> {
+    log::debug!(
+        "unix in_route {} listen start at {}",
+        in_route_name,
+        path.display()
+    );
+    match UnixListener::bind(path) {
+        Err(err)
+            if err.kind() == std::io::ErrorKind::AddrInUse
+                && UnixStream::connect(path).await.is_err() =>
+        {
+            use std::os::unix::fs::FileTypeExt;
+            // never delete something that isn't a socket, such as a file a symlink in its place points to
+            if !std::fs::symlink_metadata(path)?.file_type().is_socket() {
+                anyhow::bail!("cannot bind {}, which isn't a socket", path.display())
+            }
+            std::fs::remove_file(path)?;
+            Ok(UnixListener::bind(path)?)
+        }
+        res => Ok(res.with_context(|| format!("cannot bind {}", path.display()))?),
+    }
+}
+
+/// Accepts connections on a Unix socket in_route's listener, adding them to the neighbor table.
+#[cfg(unix)]
+pub async fn serve_in_route_unix(
+    context: InRouteContext,
+    listener: UnixListener,
+) -> anyhow::Result<()> {
+    // neighbors connect from unnamed sockets, so they go by the path they connected to
+    let path = listener
+        .local_addr()?
+        .as_pathname()
+        .map(|path| path.display().to_string())
+        .unwrap_or_default();
+    let group = TaskReaper::new();
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let pipe = FramedPipe::with_prefix(
+            stream.clone(),
+            stream,
+            LengthPrefix::U32,
+            "unix",
+            path.clone(),
+        );
+        group.attach(smolscale::spawn(accept_neighbor(context.clone(), pipe)))
+    }
+}
+
+/// Accepts the pipes of a custom transport's listener, adding them to the neighbor table.
+pub async fn serve_in_route_custom(
+    context: InRouteContext,
+    listener: Box<dyn PipeListener>,
+) -> anyhow::Result<()> {
+    let group = TaskReaper::new();
+    loop {
+        let next = listener.accept_pipe().await?;
+        group.attach(smolscale::spawn(accept_neighbor(context.clone(), next)))
+    }
+}
+
+fn transports(ctx: &DaemonContext) -> anyhow::Result<&TransportRegistry> {
+    ctx.get(TRANSPORTS)
+        .get()
+        .context("the daemon was started without a transport registry")
+}
+
+/// Sets up a link connection with a neighbor that connected to an in_route, and adds it to the neighbor table.
+async fn accept_neighbor(context: InRouteContext, pipe: impl Pipe) -> anyhow::Result<()> {
+    let protocol = pipe.protocol().to_string();
+    let connection = LinkConnection::connect(
+        context.daemon_ctx.clone(),
+        pipe,
+        &context.daemon_ctx.init().link_connection,
+    )
+    .await?;
+    log::info!(
+        "{} in_route {} accepted {}",
+        protocol,
+        context.in_route_name,
+        connection.remote_idpk().fingerprint()
+    );
+    context
+        .accepted
+        .insert(connection.remote_idpk().fingerprint(), ());
+    context.daemon_ctx.get(NEIGH_TABLE).insert(
+        connection.remote_idpk().fingerprint(),
+        connection,
+        Duration::from_secs(300),
+    );
+    anyhow::Ok(())
+}
+
 #[derive(Clone)]
 pub struct OutRouteContext {
     pub daemon_ctx: DaemonContext,
     pub out_route_name: String,
     pub remote_fingerprint: Fingerprint,
+    /// overrides the bandwidth offered to the neighbor
+    pub bandwidth_limit_kbps: Option<u32>,
+    pub priority: u8,
 }
 
 pub async fn out_route_obfsudp(
     context: OutRouteContext,
     connect: SocketAddr,
     cookie: [u8; 32],
+) -> anyhow::Result<()> {
+    keep_out_route(context, "obfsudp", move || async move {
+        Ok(ObfsUdpPipe::connect(connect, ObfsUdpPublic::from_bytes(cookie), "").await?)
+    })
+    .await
+}
+
+pub async fn out_route_tcp(
+    context: OutRouteContext,
+    connect: String,
+    tls: bool,
+) -> anyhow::Result<()> {
+    keep_out_route(context, "tcp", move || {
+        let connect = connect.clone();
+        async move {
+            let stream = TcpStream::connect(connect.as_str()).await?;
+            stream.set_nodelay(true)?;
+            let peer_addr = stream.peer_addr()?.to_string();
+            if tls {
+                let host = connect
+                    .rsplit_once(':')
+                    .map_or(connect.as_str(), |(host, _)| host)
+                    .trim_start_matches('[')
+                    .trim_end_matches(']');
+                let stream = unverified_tls_connector().connect(host, stream).await?;
+                let (read, write) = smol::io::split(stream);
+                Ok(FramedPipe::new(read, write, "tcp-tls", peer_addr))
+            } else {
+                Ok(FramedPipe::new(stream.clone(), stream, "tcp", peer_addr))
+            }
+        }
+    })
+    .await
+}
+
+pub async fn out_route_unix(context: OutRouteContext, path: PathBuf) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        keep_out_route(context, "unix", move || {
+            let path = path.clone();
+            async move {
+                let stream = UnixStream::connect(&path).await?;
+                Ok(FramedPipe::with_prefix(
+                    stream.clone(),
+                    stream,
+                    LengthPrefix::U32,
+                    "unix",
+                    path.display().to_string(),
+                ))
+            }
+        })
+        .await
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (context, path);
+        anyhow::bail!("Unix sockets aren't supported on this platform")
+    }
+}
+
+/// Keeps up a connection to the neighbor of an out_route, opening a new pipe to it with `connect` whenever the current connection stops working.
+async fn keep_out_route<P: Pipe, F: Future<Output = anyhow::Result<P>>>(
+    context: OutRouteContext,
+    protocol: &str,
+    connect: impl Fn() -> F,
 ) -> anyhow::Result<()> {
     const CONNECTION_LIFETIME: Duration = Duration::from_secs(60);
 
@@ -66,13 +569,45 @@ pub async fn out_route_obfsudp(
     let mut timer2 = smol::Timer::interval(CONNECTION_LIFETIME);
     loop {
         let fallible = async {
-            log::debug!("obfsudp out_route {} trying...", context.out_route_name);
-            let pipe = ObfsUdpPipe::connect(connect, ObfsUdpPublic::from_bytes(cookie), "").await?;
+            let neighs = context.daemon_ctx.get(NEIGH_TABLE);
+            // keep using a connection that still works, rather than opening a second one
+            if let Some(existing) = neighs.lookup(&context.remote_fingerprint) {
+                if let Some(Ok(_)) = existing
+                    .measure_latency()
+                    .timeout(Duration::from_secs(10))
+                    .await
+                {
+                    // the neighbor may have connected to one of our in_routes, whose connections expire; ours shouldn't, since we check on it every round
+                    neighs.pin(&context.remote_fingerprint);
+                    log::debug!(
+                        "{} out_route {} already connected",
+                        protocol,
+                        context.out_route_name
+                    );
+                    return anyhow::Ok(());
+                }
+            }
+            let _connecting = neighs
+                .start_connecting(context.remote_fingerprint)
+                .context("another connection to this neighbor is being opened")?;
+            log::debug!(
+                "{} out_route {} trying...",
+                protocol,
+                context.out_route_name
+            );
+            let pipe = connect().await?;
             log::info!(
-                "obfsudp out_route {} pipe connected",
+                "{} out_route {} pipe connected",
+                protocol,
                 context.out_route_name
             );
-            let connection = LinkConnection::connect(context.daemon_ctx.clone(), pipe).await?;
+            let mut config = context.daemon_ctx.init().link_connection.clone();
+            if let Some(limit) = context.bandwidth_limit_kbps {
+                config.bandwidth_kbps = limit;
+            }
+            let connection = LinkConnection::connect(context.daemon_ctx.clone(), pipe, &config)
+                .await?
+                .with_priority(context.priority);
             if connection.remote_idpk().fingerprint() != context.remote_fingerprint {
                 anyhow::bail!(
                     "remote fingerprint {} different from configured {}",
@@ -80,17 +615,19 @@ pub async fn out_route_obfsudp(
                     context.remote_fingerprint
                 )
             }
-            context
-                .daemon_ctx
-                .get(NEIGH_TABLE)
-                .insert_pinned(context.remote_fingerprint, connection);
-            log::info!("obfsudp out_route {} successful", context.out_route_name);
+            neighs.insert_pinned(context.remote_fingerprint, connection);
+            log::info!(
+                "{} out_route {} successful",
+                protocol,
+                context.out_route_name
+            );
             anyhow::Ok(())
         };
         async {
             if let Err(err) = fallible.await {
                 log::warn!(
-                    "obfs out_route {} failed: {:?}",
+                    "{} out_route {} failed: {:?}",
+                    protocol,
                     context.out_route_name,
                     err
                 );
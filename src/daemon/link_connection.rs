@@ -1,11 +1,17 @@
 use std::{
+    collections::BTreeMap,
     convert::Infallible,
-    sync::Arc,
-    time::{Duration, Instant},
+    mem::size_of,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::Context;
 use async_trait::async_trait;
+use bytes::Bytes;
 
 use clone_macro::clone;
 use concurrent_queue::ConcurrentQueue;
@@ -15,21 +21,32 @@ use earendil_topology::{AdjacencyDescriptor, IdentityDescriptor};
 use futures_util::TryFutureExt;
 use itertools::Itertools;
 use nanorpc::{JrpcRequest, JrpcResponse, RpcService, RpcTransport};
+use parking_lot::Mutex;
 use smol::{
     channel::{Receiver, Sender},
     future::FutureExt,
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
     stream::StreamExt,
 };
+use smol_timeout::TimeoutExt;
 use smolscale::{
     immortal::{Immortal, RespawnStrategy},
     reaper::TaskReaper,
 };
 use sosistab2::{Multiplex, MuxSecret, Pipe};
+use sosistab2_obfsudp::ObfsUdpSecret;
+use stdcode::StdcodeSerializeExt;
+
+use crate::config::{InRouteConfig, LinkConnectionConfig, DEFAULT_PRIORITY};
+
+use super::token_bucket::TokenBucket;
 
 use super::{
-    context::{GLOBAL_IDENTITY, NEIGH_TABLE, RELAY_GRAPH},
-    link_protocol::{AuthResponse, InfoResponse, LinkClient, LinkProtocol, LinkService},
+    context::{CONFIGURED_ROUTES, GLOBAL_IDENTITY, NEIGH_TABLE, RELAY_GRAPH},
+    link_protocol::{
+        negotiate_bandwidth, AuthResponse, InfoResponse, LinkClient, LinkProtocol, LinkService,
+        LINK_PROTOCOL_VERSION,
+    },
     DaemonContext,
 };
 
@@ -37,47 +54,182 @@ use super::{
 #[derive(Clone)]
 pub struct LinkConnection {
     mplex: Arc<Multiplex>,
-    send_outgoing: Sender<RawPacket>,
-    recv_incoming: Receiver<RawPacket>,
+    /// onion packets travel as shared buffers, so each direction copies a packet exactly once: into the buffer when sending, and out of it when the peeler reads it
+    send_outgoing: Sender<Bytes>,
+    recv_incoming: Receiver<Bytes>,
     remote_idpk: IdentityPublic,
+    version: u16,
+    stats: Arc<LinkConnectionStats>,
+    established_at: Instant,
+    /// the bandwidth limit both sides agreed on, in kbps; zero if there is none
+    bandwidth_kbps: u32,
+    limiter: Option<Arc<Mutex<TokenBucket>>>,
+    /// how strongly routing prefers this link over equally short alternatives
+    priority: u8,
     _task: Arc<Immortal>,
 }
 
+/// Traffic statistics of the link to a particular neighbor. These are kept in the [NeighTable](super::neightable::NeighTable), so they accumulate across reconnections.
+#[derive(Default)]
+pub struct LinkConnectionStats {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    packets_sent: AtomicU64,
+    packets_received: AtomicU64,
+    rtt_micros: AtomicU64,
+    /// bytes sent and received in the current bandwidth window, unlike the lifetime totals above
+    window_bytes_sent: AtomicU64,
+    window_bytes_received: AtomicU64,
+    /// when a packet was last sent or received, in unix milliseconds; zero if never
+    last_activity: AtomicU64,
+}
+
+impl LinkConnectionStats {
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    pub fn packets_sent(&self) -> u64 {
+        self.packets_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn packets_received(&self) -> u64 {
+        self.packets_received.load(Ordering::Relaxed)
+    }
+
+    /// The latest round-trip time measured, either during the authentication handshake or by [LinkConnection::measure_latency].
+    pub fn rtt(&self) -> Duration {
+        Duration::from_micros(self.rtt_micros.load(Ordering::Relaxed))
+    }
+
+    fn record_sent(&self, pkt: &[u8]) {
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent
+            .fetch_add(pkt.len() as u64, Ordering::Relaxed);
+        self.window_bytes_sent
+            .fetch_add(pkt.len() as u64, Ordering::Relaxed);
+        self.last_activity.store(unix_millis(), Ordering::Relaxed);
+    }
+
+    fn record_received(&self, pkt: &[u8]) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(pkt.len() as u64, Ordering::Relaxed);
+        self.window_bytes_received
+            .fetch_add(pkt.len() as u64, Ordering::Relaxed);
+        self.last_activity.store(unix_millis(), Ordering::Relaxed);
+    }
+}
+
 impl LinkConnection {
     /// Creates a new Connection, from a single Pipe. Unlike in Geph, n2n Multiplexes in earendil all contain one pipe each.
-    pub async fn connect(ctx: DaemonContext, pipe: impl Pipe) -> anyhow::Result<Self> {
+    pub async fn connect(
+        ctx: DaemonContext,
+        pipe: impl Pipe,
+        config: &LinkConnectionConfig,
+    ) -> anyhow::Result<Self> {
         // First, we construct the Multiplex.
         let my_mux_sk = MuxSecret::generate();
         let mplex = Arc::new(Multiplex::new(my_mux_sk, None));
         mplex.add_pipe(pipe);
-        let (send_outgoing, recv_outgoing) = smol::channel::bounded(100);
-        let (send_incoming, recv_incoming) = smol::channel::bounded(100);
+        let (send_outgoing, recv_outgoing) = smol::channel::bounded(config.channel_capacity);
+        let (send_incoming, recv_incoming) = smol::channel::bounded(config.channel_capacity);
+        let (send_agreed_bandwidth, recv_agreed_bandwidth) = smol::channel::bounded(1);
+        let my_bandwidth_kbps = config.bandwidth_kbps;
         let _task = Arc::new(Immortal::respawn(
             RespawnStrategy::Immediate,
-            clone!([ctx, mplex, send_incoming, recv_outgoing], move || {
-                connection_loop(
-                    ctx.clone(),
-                    mplex.clone(),
-                    send_incoming.clone(),
-                    recv_outgoing.clone(),
-                )
-                .map_err(|e| log::warn!("connection_loop died with {:?}", e))
-            }),
+            clone!(
+                [
+                    ctx,
+                    mplex,
+                    send_incoming,
+                    recv_outgoing,
+                    send_agreed_bandwidth
+                ],
+                move || {
+                    connection_loop(
+                        ctx.clone(),
+                        mplex.clone(),
+                        send_incoming.clone(),
+                        recv_outgoing.clone(),
+                        my_bandwidth_kbps,
+                        send_agreed_bandwidth.clone(),
+                    )
+                    .map_err(|e| log::warn!("connection_loop died with {:?}", e))
+                }
+            ),
         ));
         let rpc = MultiplexRpcTransport::new(mplex.clone());
-        let link = LinkClient::from(rpc);
-        let resp = link
-            .authenticate()
+        // nodes that predate versioning don't know the method at all, which makes them version 0
+        let version = match rpc
+            .call("negotiated_version", &[])
             .await
-            .context("did not respond to authenticate")?;
+            .context("did not respond to negotiated_version")?
+        {
+            Some(Ok(their_version)) => serde_json::from_value::<u16>(their_version)
+                .context("sent a malformed protocol version")?
+                .min(LINK_PROTOCOL_VERSION),
+            _ => 0,
+        };
+        let link = LinkClient::from(rpc);
+        let auth_start = Instant::now();
+        let resp = if version >= 1 {
+            link.v1_authenticate().await
+        } else {
+            link.authenticate().await
+        }
+        .context("did not respond to authenticate")?;
         resp.verify(&mplex.peer_pk().context("could not obtain peer_pk")?)
             .context("did not authenticated correctly")?;
+        if !resp.accepts_version(env!("CARGO_PKG_VERSION")) {
+            mplex.retain(|_| false);
+            anyhow::bail!(
+                "remote runs earendil {} and refuses to link with version {} older than {}",
+                resp.version,
+                env!("CARGO_PKG_VERSION"),
+                resp.min_compatible_version
+            );
+        }
+        let stats = ctx.get(NEIGH_TABLE).stats(&resp.full_pk.fingerprint());
+        stats
+            .rtt_micros
+            .store(auth_start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        // the left end of the link offers its limit and the right end answers with the one both enforce, so the two ends can't settle on different limits
+        let my_fp = ctx.get(GLOBAL_IDENTITY).public().fingerprint();
+        let bandwidth_kbps = if version < 2 {
+            // peers that predate offers don't limit the link on their side
+            config.bandwidth_kbps
+        } else if my_fp < resp.full_pk.fingerprint() {
+            link.v2_bandwidth_offer(config.bandwidth_kbps)
+                .await
+                .context("could not negotiate bandwidth")?
+        } else {
+            recv_agreed_bandwidth
+                .recv()
+                .timeout(BANDWIDTH_OFFER_TIMEOUT)
+                .await
+                .context("neighbor did not offer bandwidth in time")??
+        };
 
         Ok(Self {
             mplex,
             send_outgoing,
             recv_incoming,
             remote_idpk: resp.full_pk,
+            version,
+            stats,
+            established_at: Instant::now(),
+            bandwidth_kbps,
+            limiter: (bandwidth_kbps > 0).then(|| {
+                Arc::new(Mutex::new(TokenBucket::new(
+                    bandwidth_kbps as f64 * 1000.0 / 8.0,
+                )))
+            }),
+            priority: DEFAULT_PRIORITY,
             _task,
         })
     }
@@ -87,28 +239,158 @@ impl LinkConnection {
         self.remote_idpk
     }
 
+    /// Returns the link protocol version both sides agreed to speak.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Sets how strongly routing prefers this link over equally short alternatives.
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Returns how strongly routing prefers this link over equally short alternatives.
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    /// Returns the bandwidth limit both sides agreed on, in kbps, or zero if the link is unlimited.
+    pub fn bandwidth_kbps(&self) -> u32 {
+        self.bandwidth_kbps
+    }
+
+    /// Returns the statistics of the link to this neighbor, including those of earlier connections to it.
+    pub fn stats(&self) -> Arc<LinkConnectionStats> {
+        self.stats.clone()
+    }
+
+    /// Closes the connection once the onion packets already queued have been handed to the multiplex, waiting at most 5 seconds for them.
+    pub async fn disconnect(self) -> anyhow::Result<()> {
+        const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+        // refuse any more packets, while the ones already queued keep draining
+        self.send_outgoing.close();
+        let deadline = Instant::now() + DISCONNECT_TIMEOUT;
+        let drained = loop {
+            if self.send_outgoing.is_empty() {
+                break true;
+            }
+            if Instant::now() >= deadline {
+                break false;
+            }
+            smol::Timer::after(Duration::from_millis(10)).await;
+        };
+        // dropping every pipe closes the multiplex, even if clones of this connection are still around
+        self.mplex.retain(|_| false);
+        anyhow::ensure!(
+            drained,
+            "disconnected with {} onion packets not yet sent",
+            self.send_outgoing.len()
+        );
+        Ok(())
+    }
+
+    /// Returns how many onion packets are queued to be sent and to be received, in that order.
+    pub fn queue_depths(&self) -> (usize, usize) {
+        (self.send_outgoing.len(), self.recv_incoming.len())
+    }
+
+    /// Returns how long this connection has been established.
+    pub fn uptime(&self) -> Duration {
+        self.established_at.elapsed()
+    }
+
+    /// Returns when an onion packet was last sent to or received from this neighbor, if ever.
+    pub fn last_activity(&self) -> Option<Instant> {
+        let last_activity = self.stats.last_activity.load(Ordering::Relaxed);
+        if last_activity == 0 {
+            return None;
+        }
+        let ago = unix_millis().saturating_sub(last_activity);
+        Instant::now().checked_sub(Duration::from_millis(ago))
+    }
+
+    /// Returns how many onion packet bytes were sent to and received from this neighbor since the last reset, in that order.
+    pub fn traffic_stats(&self) -> (u64, u64) {
+        (
+            self.stats.window_bytes_sent.load(Ordering::Relaxed),
+            self.stats.window_bytes_received.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Restarts the counters behind [LinkConnection::traffic_stats] from zero.
+    pub fn reset_traffic_stats(&self) {
+        self.stats.window_bytes_sent.store(0, Ordering::Relaxed);
+        self.stats.window_bytes_received.store(0, Ordering::Relaxed);
+    }
+
+    /// Measures the round-trip time of the link with a ping, remembering it as the latest measurement. Peers that predate pings are sent an info request instead.
+    pub async fn measure_latency(&self) -> anyhow::Result<Duration> {
+        let start = Instant::now();
+        if self.version >= 2 {
+            self.link_rpc().v2_ping().await?;
+        } else if self.version >= 1 {
+            self.link_rpc().v1_info().await?;
+        } else {
+            self.link_rpc().info().await?;
+        }
+        let rtt = start.elapsed();
+        self.stats
+            .rtt_micros
+            .store(rtt.as_micros() as u64, Ordering::Relaxed);
+        Ok(rtt)
+    }
+
+    /// Returns the latest round-trip time measured on the link to this neighbor, if any.
+    pub fn last_rtt(&self) -> Option<Duration> {
+        match self.stats.rtt_micros.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
     /// Returns a handle to the N2N RPC.
     pub fn link_rpc(&self) -> LinkClient {
         LinkClient::from(MultiplexRpcTransport::new(self.mplex.clone()))
     }
 
-    /// Sends an onion-routing packet down this connection.
+    /// Sends an onion-routing packet down this connection. Packets beyond the negotiated bandwidth are dropped, like those that find the queue full.
     pub async fn send_raw_packet(&self, pkt: RawPacket) {
-        let _ = self.send_outgoing.try_send(pkt);
+        if let Some(limiter) = &self.limiter {
+            if !limiter.lock().try_take(size_of::<RawPacket>()) {
+                return;
+            }
+        }
+        // copies the packet into a buffer that is then shared with the transport, rather than copied again for it
+        let pkt = Bytes::copy_from_slice(bytemuck::bytes_of(&pkt));
+        if self.send_outgoing.try_send(pkt.clone()).is_ok() {
+            self.stats.record_sent(&pkt);
+        }
     }
 
     /// Sends an onion-routing packet down this connection.
     pub async fn recv_raw_packet(&self) -> anyhow::Result<RawPacket> {
-        Ok(self.recv_incoming.recv().await?)
+        let pkt = self.recv_incoming.recv().await?;
+        self.stats.record_received(&pkt);
+        // the one copy on the way in; the length was checked when the packet came off the wire, and unaligned reads are fine for any buffer
+        Ok(bytemuck::pod_read_unaligned(&pkt))
     }
 }
 
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+}
+
 /// Main loop for the connection.
 async fn connection_loop(
     ctx: DaemonContext,
     mplex: Arc<Multiplex>,
-    send_incoming: Sender<RawPacket>,
-    recv_outgoing: Receiver<RawPacket>,
+    send_incoming: Sender<Bytes>,
+    recv_outgoing: Receiver<Bytes>,
+    bandwidth_kbps: u32,
+    send_agreed_bandwidth: Sender<u32>,
 ) -> anyhow::Result<Infallible> {
     let _onion_keepalive = Immortal::respawn(
         RespawnStrategy::Immediate,
@@ -120,6 +402,8 @@ async fn connection_loop(
     let service = Arc::new(LinkService(LinkProtocolImpl {
         ctx: ctx.clone(),
         mplex: mplex.clone(),
+        bandwidth_kbps,
+        send_agreed_bandwidth,
     }));
 
     let group: TaskReaper<anyhow::Result<()>> = TaskReaper::new();
@@ -154,8 +438,8 @@ async fn connection_loop(
 
 async fn onion_keepalive(
     mplex: Arc<Multiplex>,
-    send_incoming: Sender<RawPacket>,
-    recv_outgoing: Receiver<RawPacket>,
+    send_incoming: Sender<Bytes>,
+    recv_outgoing: Receiver<Bytes>,
 ) -> anyhow::Result<()> {
     loop {
         let stream = mplex.open_conn("onion_packets").await?;
@@ -165,22 +449,23 @@ async fn onion_keepalive(
 
 async fn handle_onion_packets(
     conn: sosistab2::Stream,
-    send_incoming: Sender<RawPacket>,
-    recv_outgoing: Receiver<RawPacket>,
+    send_incoming: Sender<Bytes>,
+    recv_outgoing: Receiver<Bytes>,
 ) -> anyhow::Result<()> {
     let up = async {
         loop {
             let pkt = recv_outgoing.recv().await?;
-            conn.send_urel(bytemuck::bytes_of(&pkt).to_vec().into())
-                .await?;
+            conn.send_urel(pkt).await?;
         }
     };
     let dn = async {
         loop {
             let pkt = conn.recv_urel().await?;
-            let pkt: RawPacket = *bytemuck::try_from_bytes(&pkt)
-                .ok()
-                .context("incoming urel packet of the wrong size to be an onion packet")?;
+            // the buffer is handed on as-is, and only copied out when the packet is read
+            anyhow::ensure!(
+                pkt.len() == size_of::<RawPacket>(),
+                "incoming urel packet of the wrong size to be an onion packet"
+            );
             send_incoming.try_send(pkt)?;
         }
     };
@@ -189,6 +474,9 @@ async fn handle_onion_packets(
 
 const POOL_TIMEOUT: Duration = Duration::from_secs(60);
 
+/// How long the right end of a link waits for the left end to offer its bandwidth limit.
+const BANDWIDTH_OFFER_TIMEOUT: Duration = Duration::from_secs(30);
+
 type PooledConn = (BufReader<sosistab2::Stream>, sosistab2::Stream);
 
 struct MultiplexRpcTransport {
@@ -239,33 +527,41 @@ impl RpcTransport for MultiplexRpcTransport {
 struct LinkProtocolImpl {
     ctx: DaemonContext,
     mplex: Arc<Multiplex>,
+    /// our own bandwidth limit for this link, in kbps, which may be an out_route's override of the global one
+    bandwidth_kbps: u32,
+    /// where the limit agreed on when the neighbor makes its offer goes, for [LinkConnection::connect] to pick up
+    send_agreed_bandwidth: Sender<u32>,
 }
 
 #[async_trait]
 impl LinkProtocol for LinkProtocolImpl {
-    async fn authenticate(&self) -> AuthResponse {
+    async fn negotiated_version(&self) -> u16 {
+        LINK_PROTOCOL_VERSION
+    }
+
+    async fn v1_authenticate(&self) -> AuthResponse {
         let local_pk = self.mplex.local_pk();
         AuthResponse::new(self.ctx.get(GLOBAL_IDENTITY), &local_pk)
     }
 
-    async fn info(&self) -> InfoResponse {
+    async fn v1_info(&self) -> InfoResponse {
         InfoResponse {
             version: env!("CARGO_PKG_VERSION").to_string(),
         }
     }
 
-    async fn sign_adjacency(
+    async fn v1_sign_adjacency(
         &self,
         mut left_incomplete: AdjacencyDescriptor,
     ) -> Option<AdjacencyDescriptor> {
-        // This must be a neighbor that is "left" of us
+        // This must be a neighbor that is "left" of us, claiming the bandwidth we agreed on
         let valid = left_incomplete.left < left_incomplete.right
             && left_incomplete.right == self.ctx.get(GLOBAL_IDENTITY).public().fingerprint()
             && self
                 .ctx
                 .get(NEIGH_TABLE)
                 .lookup(&left_incomplete.left)
-                .is_some();
+                .is_some_and(|conn| conn.bandwidth_kbps() == left_incomplete.bandwidth_kbps);
         if !valid {
             log::debug!("neighbor not right of us! Refusing to sign adjacency x_x");
             return None;
@@ -289,11 +585,11 @@ impl LinkProtocol for LinkProtocolImpl {
         Some(left_incomplete)
     }
 
-    async fn identity(&self, fp: Fingerprint) -> Option<IdentityDescriptor> {
+    async fn v1_identity(&self, fp: Fingerprint) -> Option<IdentityDescriptor> {
         self.ctx.get(RELAY_GRAPH).read().identity(&fp)
     }
 
-    async fn adjacencies(&self, fps: Vec<Fingerprint>) -> Vec<AdjacencyDescriptor> {
+    async fn v1_adjacencies(&self, fps: Vec<Fingerprint>) -> Vec<AdjacencyDescriptor> {
         let rg = self.ctx.get(RELAY_GRAPH).read();
         fps.into_iter()
             .flat_map(|fp| {
@@ -305,4 +601,96 @@ impl LinkProtocol for LinkProtocolImpl {
             .dedup()
             .collect()
     }
+
+    async fn v2_ping(&self) {}
+
+    async fn v2_config_fingerprint(&self) -> [u8; 32] {
+        // only the public side of each in_route goes in, so the hash reveals nothing about the secrets
+        let configured = self.ctx.get(CONFIGURED_ROUTES).lock().await;
+        let in_routes: BTreeMap<&String, (u16, [u8; 32])> = configured
+            .config
+            .in_routes
+            .iter()
+            .map(|(name, config)| match config {
+                InRouteConfig::Obfsudp { listen, secret } => {
+                    let secret =
+                        ObfsUdpSecret::from_bytes(*blake3::hash(secret.as_bytes()).as_bytes());
+                    (name, (listen.port(), *secret.to_public().as_bytes()))
+                }
+                // TCP in_routes have no public key, so only whether they use TLS goes in
+                InRouteConfig::Tcp { listen, tls_cert } => (
+                    name,
+                    (
+                        listen.port(),
+                        *blake3::hash(&[tls_cert.is_some() as u8]).as_bytes(),
+                    ),
+                ),
+                // QUIC in_routes present a certificate, but neighbors don't check it
+                InRouteConfig::Quic { listen, .. } => {
+                    (name, (listen.port(), *blake3::hash(b"quic").as_bytes()))
+                }
+                InRouteConfig::WebSocket {
+                    listen,
+                    path,
+                    tls_cert,
+                } => (
+                    name,
+                    (
+                        listen.port(),
+                        *blake3::hash(&(path, tls_cert.is_some()).stdcode()).as_bytes(),
+                    ),
+                ),
+                InRouteConfig::UnixSocket { path } => (
+                    name,
+                    (
+                        0,
+                        *blake3::hash(path.to_string_lossy().as_bytes()).as_bytes(),
+                    ),
+                ),
+                // custom transports may keep secrets anywhere in their config, so only the transport goes in
+                InRouteConfig::Custom { transport, .. } => {
+                    (name, (0, *blake3::hash(transport.as_bytes()).as_bytes()))
+                }
+            })
+            .collect();
+        *blake3::hash(&in_routes.stdcode()).as_bytes()
+    }
+
+    async fn v2_request_adjacency_signed_by(&self, fp: Fingerprint) -> Option<AdjacencyDescriptor> {
+        let my_fp = self.ctx.get(GLOBAL_IDENTITY).public().fingerprint();
+        let rg = self.ctx.get(RELAY_GRAPH).read();
+        let found = rg
+            .adjacencies(&my_fp)?
+            .find(|adj| adj.left == fp && adj.right == my_fp);
+        found
+    }
+
+    async fn v2_bandwidth_offer(&self, offered_kbps: u32) -> u32 {
+        let agreed = negotiate_bandwidth(offered_kbps, self.bandwidth_kbps);
+        let _ = self.send_agreed_bandwidth.try_send(agreed);
+        agreed
+    }
+
+    async fn authenticate(&self) -> AuthResponse {
+        self.v1_authenticate().await
+    }
+
+    async fn info(&self) -> InfoResponse {
+        self.v1_info().await
+    }
+
+    async fn sign_adjacency(
+        &self,
+        left_incomplete: AdjacencyDescriptor,
+    ) -> Option<AdjacencyDescriptor> {
+        self.v1_sign_adjacency(left_incomplete).await
+    }
+
+    async fn identity(&self, fp: Fingerprint) -> Option<IdentityDescriptor> {
+        self.v1_identity(fp).await
+    }
+
+    async fn adjacencies(&self, fps: Vec<Fingerprint>) -> Vec<AdjacencyDescriptor> {
+        self.v1_adjacencies(fps).await
+    }
 }
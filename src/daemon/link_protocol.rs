@@ -8,28 +8,76 @@ use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 use sosistab2::MuxPublic;
 
+/// The newest version of the link protocol that this node speaks.
+pub const LINK_PROTOCOL_VERSION: u16 = 2;
+
+/// The oldest earendil release that this node agrees to link with. Raise it when rolling out a breaking change.
+pub const MIN_COMPATIBLE_VERSION: &str = "0.0.1";
+
+/// The node-to-node protocol. Every method is prefixed with the protocol version that introduced it, so that methods from different versions can coexist and old nodes keep working when a signature changes. Nodes that predate versioning speak version 0: they don't know `negotiated_version`, and call the unprefixed aliases of the `v1_` methods.
 #[nanorpc_derive]
 #[async_trait]
 pub trait LinkProtocol {
+    /// Returns the newest protocol version the other end supports. Both ends then speak the lower of the two versions.
+    async fn negotiated_version(&self) -> u16;
+
     /// Challenge the other end to prove their identity.
-    async fn authenticate(&self) -> AuthResponse;
+    async fn v1_authenticate(&self) -> AuthResponse;
 
     /// A method that returns some random info. Used for keepalive and statistics.
-    async fn info(&self) -> InfoResponse;
+    async fn v1_info(&self) -> InfoResponse;
 
     /// Asks the other end to complete an adjacency descriptor. Returns None to indicate refusal. This is called by the "left-hand" neighbor to ask the "right-hand" neighbor to sign.
-    async fn sign_adjacency(
+    async fn v1_sign_adjacency(
         &self,
         left_incomplete: AdjacencyDescriptor,
     ) -> Option<AdjacencyDescriptor>;
 
     /// Gets the identity of a particular fingerprint. Returns None if that identity is not known to this node.
-    async fn identity(&self, fp: Fingerprint) -> Option<IdentityDescriptor>;
+    async fn v1_identity(&self, fp: Fingerprint) -> Option<IdentityDescriptor>;
 
     /// Gets all the adjacency-descriptors adjacent to the given fingerprints. This is called repeatedly to eventually discover the entire graph.
+    async fn v1_adjacencies(&self, fps: Vec<Fingerprint>) -> Vec<AdjacencyDescriptor>;
+
+    /// Does nothing and responds immediately. Used to measure the round-trip time of the link.
+    async fn v2_ping(&self);
+
+    /// Returns a hash of what peers need to know to connect to the responder's in_routes: their ports and cookies. Peers compare it across calls to notice when the configuration changed under them.
+    async fn v2_config_fingerprint(&self) -> [u8; 32];
+
+    /// Offers the most bandwidth, in kbps, that the caller will forward for the responder, and returns the limit both sides then hold the link to: [negotiate_bandwidth] of the offer and the responder's own limit. Zero means no limit. Only the end of the link with the lower fingerprint makes an offer.
+    async fn v2_bandwidth_offer(&self, offered_kbps: u32) -> u32;
+
+    /// Gets the adjacency descriptor with the given fingerprint on the left and the responder on the right, if the responder has one. Lets a newly connected node learn a particular link of the responder without fetching all of its adjacencies.
+    async fn v2_request_adjacency_signed_by(&self, fp: Fingerprint) -> Option<AdjacencyDescriptor>;
+
+    /// Alias of [LinkProtocol::v1_authenticate] for version 0 nodes.
+    async fn authenticate(&self) -> AuthResponse;
+
+    /// Alias of [LinkProtocol::v1_info] for version 0 nodes.
+    async fn info(&self) -> InfoResponse;
+
+    /// Alias of [LinkProtocol::v1_sign_adjacency] for version 0 nodes.
+    async fn sign_adjacency(
+        &self,
+        left_incomplete: AdjacencyDescriptor,
+    ) -> Option<AdjacencyDescriptor>;
+
+    /// Alias of [LinkProtocol::v1_identity] for version 0 nodes.
+    async fn identity(&self, fp: Fingerprint) -> Option<IdentityDescriptor>;
+
+    /// Alias of [LinkProtocol::v1_adjacencies] for version 0 nodes.
     async fn adjacencies(&self, fps: Vec<Fingerprint>) -> Vec<AdjacencyDescriptor>;
 }
 
+/// Combines the bandwidth offers of both ends of a link into the limit they both enforce, in kbps. An offer of zero sets no limit, so the link is unlimited only if neither end limits it.
+pub fn negotiate_bandwidth(my_kbps: u32, their_kbps: u32) -> u32 {
+    match (my_kbps, their_kbps) {
+        (0, kbps) | (kbps, 0) => kbps,
+        (mine, theirs) => mine.min(theirs),
+    }
+}
+
 /// Response to an authentication challenge.
 #[serde_as]
 #[derive(Serialize, Deserialize)]
@@ -38,6 +86,12 @@ pub struct AuthResponse {
     pub full_pk: IdentityPublic,
     #[serde_as(as = "serde_with::hex::Hex")]
     pub binding_sig: Bytes,
+    /// The earendil release of the responder. Empty for nodes that predate this field.
+    #[serde(default)]
+    pub version: String,
+    /// The oldest earendil release that the responder agrees to link with. Empty for nodes that predate this field, which link with anyone.
+    #[serde(default)]
+    pub min_compatible_version: String,
 }
 
 /// Response to an info request.
@@ -57,6 +111,23 @@ impl AuthResponse {
         AuthResponse {
             full_pk: my_identity.public(),
             binding_sig: Bytes::from(binding_sig.as_ref().to_vec()),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            min_compatible_version: MIN_COMPATIBLE_VERSION.to_string(),
+        }
+    }
+
+    /// Checks whether the responder agrees to link with a node running the given earendil release.
+    pub fn accepts_version(&self, version: &str) -> bool {
+        if self.min_compatible_version.is_empty() {
+            return true;
+        }
+        match (
+            parse_version(version),
+            parse_version(&self.min_compatible_version),
+        ) {
+            (Some(ours), Some(min)) => ours >= min,
+            // we can't tell, so let the connection happen rather than cut nodes off over a typo
+            _ => true,
         }
     }
 
@@ -66,3 +137,50 @@ impl AuthResponse {
         self.full_pk.verify(to_sign.as_bytes(), &self.binding_sig)
     }
 }
+
+/// Parses the `major.minor.patch` part of a semver version, ignoring any pre-release or build suffix.
+fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let core = version.split(['-', '+']).next()?;
+    let mut parts = core.split('.').map(|part| part.parse::<u64>().ok());
+    let version = (parts.next()??, parts.next()??, parts.next()??);
+    parts.next().is_none().then_some(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sosistab2::MuxSecret;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("0.0.1"), Some((0, 0, 1)));
+        assert_eq!(parse_version("1.12.3-beta.1+abc"), Some((1, 12, 3)));
+        assert_eq!(parse_version("1.2"), None);
+        assert_eq!(parse_version("1.2.3.4"), None);
+        assert_eq!(parse_version("one.two.three"), None);
+    }
+
+    #[test]
+    fn test_accepts_version() {
+        let mut resp = AuthResponse::new(
+            &IdentitySecret::generate(),
+            &MuxSecret::generate().to_public(),
+        );
+        resp.min_compatible_version = "0.2.0".into();
+        assert!(resp.accepts_version("0.2.0"));
+        assert!(resp.accepts_version("0.10.0"));
+        assert!(!resp.accepts_version("0.1.9"));
+
+        // Testing that peers predating the field accept everyone
+        resp.min_compatible_version = String::new();
+        assert!(resp.accepts_version("0.0.0"));
+    }
+
+    #[test]
+    fn test_negotiate_bandwidth() {
+        assert_eq!(negotiate_bandwidth(0, 0), 0);
+        assert_eq!(negotiate_bandwidth(10_000, 0), 10_000);
+        assert_eq!(negotiate_bandwidth(0, 10_000), 10_000);
+        assert_eq!(negotiate_bandwidth(10_000, 2_500), 2_500);
+    }
+}
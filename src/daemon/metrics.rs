@@ -0,0 +1,267 @@
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use smol::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use smol_timeout::TimeoutExt;
+use smolscale::reaper::TaskReaper;
+
+use super::{
+    context::{CtxField, DaemonContext, NEIGH_TABLE, RELAY_GRAPH},
+    dht::dht_get_stats,
+};
+
+/// The longest request head we read before giving up on a scrape.
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// Counters that are only kept for the metrics endpoint.
+#[derive(Default)]
+pub struct MetricsCounters {
+    /// onion packets we peeled and passed on to the next hop
+    pub packets_forwarded: AtomicU64,
+    /// packets we dropped, for any [crate::control_protocol::DropReason]
+    pub packets_dropped: AtomicU64,
+    /// haven encryption sessions currently running, on either side
+    pub active_haven_sessions: AtomicU64,
+}
+
+pub static METRICS: CtxField<MetricsCounters> = |_| MetricsCounters::default();
+
+/// Serves the daemon's metrics at `/metrics`, in the Prometheus text exposition format.
+pub async fn metrics_loop(ctx: DaemonContext, listen: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(listen).await?;
+    log::info!("serving metrics at http://{listen}/metrics");
+    let reaper = TaskReaper::new();
+    loop {
+        let (conn, _) = listener.accept().await?;
+        let ctx = ctx.clone();
+        reaper.attach(smolscale::spawn(async move {
+            if let Err(err) = serve_scrape(&ctx, conn)
+                .timeout(Duration::from_secs(10))
+                .await
+                .unwrap_or_else(|| Err(anyhow::anyhow!("timed out")))
+            {
+                log::debug!("metrics scrape failed: {:?}", err)
+            }
+        }));
+    }
+}
+
+/// Answers one HTTP request and closes the connection.
+async fn serve_scrape(ctx: &DaemonContext, mut conn: TcpStream) -> anyhow::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_LEN {
+            anyhow::bail!("request head too long")
+        }
+        let n = conn.read(&mut buf).await?;
+        if n == 0 {
+            anyhow::bail!("connection closed before the request was complete")
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let request_line = String::from_utf8_lossy(&head);
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => http_response(
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            &render_metrics(ctx),
+        ),
+        _ => http_response("404 Not Found", "text/plain; charset=utf-8", "not found\n"),
+    };
+    conn.write_all(response.as_bytes()).await?;
+    conn.flush().await?;
+    Ok(())
+}
+
+fn http_response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// Renders a snapshot of the daemon's metrics.
+pub fn render_metrics(ctx: &DaemonContext) -> String {
+    let mut out = String::new();
+    let (nodes, edges) = {
+        let graph = ctx.get(RELAY_GRAPH).read();
+        (graph.all_nodes().count(), graph.all_adjacencies().count())
+    };
+    write_metric(
+        &mut out,
+        "earendil_relay_graph_nodes_total",
+        "Relays in our view of the relay graph.",
+        "gauge",
+        &[("", nodes as u64)],
+    );
+    write_metric(
+        &mut out,
+        "earendil_relay_graph_edges_total",
+        "Adjacencies in our view of the relay graph.",
+        "gauge",
+        &[("", edges as u64)],
+    );
+
+    let neighs = ctx.get(NEIGH_TABLE).all_neighs();
+    write_metric(
+        &mut out,
+        "earendil_neighbors_total",
+        "Nodes we have a link connection to.",
+        "gauge",
+        &[("", neighs.len() as u64)],
+    );
+    // the lifetime totals only ever grow, unlike the window counters behind bandwidth_stats, which restart every bandwidth window
+    let mut bytes_sent = vec![];
+    let mut bytes_received = vec![];
+    let mut packets_sent = vec![];
+    let mut packets_received = vec![];
+    let mut rtts = vec![];
+    let mut queued = vec![];
+    for neigh in neighs.iter() {
+        let label = format!("neighbor=\"{}\"", neigh.remote_idpk().fingerprint());
+        let stats = neigh.stats();
+        let (queued_out, queued_in) = neigh.queue_depths();
+        queued.push((format!("{label},direction=\"out\""), queued_out as u64));
+        queued.push((format!("{label},direction=\"in\""), queued_in as u64));
+        bytes_sent.push((label.clone(), stats.bytes_sent()));
+        bytes_received.push((label.clone(), stats.bytes_received()));
+        packets_sent.push((label.clone(), stats.packets_sent()));
+        packets_received.push((label.clone(), stats.packets_received()));
+        // a zero round-trip time means none was measured yet
+        if !stats.rtt().is_zero() {
+            rtts.push((label, stats.rtt().as_micros() as u64));
+        }
+    }
+    write_metric(
+        &mut out,
+        "earendil_neighbor_bytes_sent_total",
+        "Onion packet bytes sent to each neighbor, over all links to it.",
+        "counter",
+        &bytes_sent,
+    );
+    write_metric(
+        &mut out,
+        "earendil_neighbor_bytes_received_total",
+        "Onion packet bytes received from each neighbor, over all links to it.",
+        "counter",
+        &bytes_received,
+    );
+    write_metric(
+        &mut out,
+        "earendil_neighbor_packets_sent_total",
+        "Onion packets sent to each neighbor, over all links to it.",
+        "counter",
+        &packets_sent,
+    );
+    write_metric(
+        &mut out,
+        "earendil_neighbor_packets_received_total",
+        "Onion packets received from each neighbor, over all links to it.",
+        "counter",
+        &packets_received,
+    );
+    write_metric(
+        &mut out,
+        "earendil_neighbor_rtt_microseconds",
+        "The latest round-trip time measured to each neighbor.",
+        "gauge",
+        &rtts,
+    );
+    write_metric(
+        &mut out,
+        "earendil_neighbor_queued_packets",
+        "Onion packets waiting in the link connection to each neighbor, by whether they are to be sent or were received.",
+        "gauge",
+        &queued,
+    );
+
+    let counters = ctx.get(METRICS);
+    write_metric(
+        &mut out,
+        "earendil_packets_forwarded_total",
+        "Onion packets peeled and passed on to the next hop.",
+        "counter",
+        &[("", counters.packets_forwarded.load(Ordering::Relaxed))],
+    );
+    write_metric(
+        &mut out,
+        "earendil_packets_dropped_total",
+        "Packets dropped for any reason.",
+        "counter",
+        &[("", counters.packets_dropped.load(Ordering::Relaxed))],
+    );
+
+    let dht = dht_get_stats(ctx);
+    write_metric(
+        &mut out,
+        "earendil_dht_lookups_total",
+        "DHT lookups, by whether they were answered from the cache, went to the network, or timed out there.",
+        "counter",
+        &[
+            ("result=\"hit\"", dht.cache_hits),
+            ("result=\"miss\"", dht.cache_misses),
+            ("result=\"timeout\"", dht.network_timeouts),
+        ],
+    );
+
+    write_metric(
+        &mut out,
+        "earendil_active_haven_sessions",
+        "Haven encryption sessions currently running.",
+        "gauge",
+        &[("", counters.active_haven_sessions.load(Ordering::Relaxed))],
+    );
+    out
+}
+
+/// Appends one metric family, whose samples are given as their labels (without the braces) and values.
+fn write_metric(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    kind: &str,
+    samples: &[(impl AsRef<str>, u64)],
+) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    for (labels, value) in samples {
+        let labels = labels.as_ref();
+        if labels.is_empty() {
+            let _ = writeln!(out, "{name} {value}");
+        } else {
+            let _ = writeln!(out, "{name}{{{labels}}} {value}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_metric() {
+        let mut out = String::new();
+        write_metric(&mut out, "foo_total", "Foos.", "counter", &[("", 3)]);
+        write_metric(
+            &mut out,
+            "bar",
+            "Bars.",
+            "gauge",
+            &[("kind=\"a\"", 1), ("kind=\"b\"", 2)],
+        );
+        assert_eq!(
+            out,
+            "# HELP foo_total Foos.\n# TYPE foo_total counter\nfoo_total 3\n\
+             # HELP bar Bars.\n# TYPE bar gauge\nbar{kind=\"a\"} 1\nbar{kind=\"b\"} 2\n"
+        );
+    }
+}
@@ -1,19 +1,36 @@
-use std::time::{Duration, Instant};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use earendil_crypt::Fingerprint;
 use earendil_packet::RawPacket;
+use futures_util::TryFutureExt;
 use smol::channel::{Receiver, Sender};
 use smolscale::immortal::Immortal;
 
-use super::link_connection::LinkConnection;
+use crate::{
+    control_protocol::{DaemonEvent, DropReason},
+    log_error,
+};
+
+use super::{
+    events::EventBus,
+    link_connection::{LinkConnection, LinkConnectionStats},
+};
 
 /// A table of the neighbors of the current node
 #[allow(clippy::type_complexity)]
 pub struct NeighTable {
     table: DashMap<Fingerprint, (LinkConnection, Option<Instant>, Immortal)>,
+    /// not garbage-collected with the table, so that statistics outlive individual connections
+    stats: DashMap<Fingerprint, Arc<LinkConnectionStats>>,
+    /// neighbors that a connection is currently being opened to
+    connecting: Arc<DashSet<Fingerprint>>,
     send_incoming: Sender<RawPacket>,
     recv_incoming: Receiver<RawPacket>,
+    events: EventBus,
 }
 
 impl Default for NeighTable {
@@ -25,11 +42,19 @@ impl Default for NeighTable {
 impl NeighTable {
     /// Create a new NeighTable.
     pub fn new() -> Self {
+        Self::with_events(EventBus::new())
+    }
+
+    /// Create a new NeighTable that reports neighbors coming and going, and packets it drops, to the given bus.
+    pub fn with_events(events: EventBus) -> Self {
         let (send_incoming, recv_incoming) = smol::channel::bounded(100);
         Self {
             table: Default::default(),
+            stats: Default::default(),
+            connecting: Default::default(),
             send_incoming,
             recv_incoming,
+            events,
         }
     }
 
@@ -40,7 +65,11 @@ impl NeighTable {
 
     /// Inject a packet *as if* it came from another node.
     pub async fn inject_asif_incoming(&self, pkt: RawPacket) {
-        let _ = self.send_incoming.try_send(pkt);
+        if self.send_incoming.try_send(pkt).is_err() {
+            self.events.emit(DaemonEvent::PacketDropped {
+                reason: DropReason::QueueFull,
+            });
+        }
     }
 
     /// Insert a fingerprint-connection mapping with a TTL.
@@ -61,7 +90,8 @@ impl NeighTable {
     ) {
         let expiry = ttl.map(|ttl| Instant::now() + ttl);
         let send_incoming = self.send_incoming.clone();
-        self.table.insert(
+        let events = self.events.clone();
+        let previous = self.table.insert(
             fingerprint,
             (
                 connection.clone(),
@@ -69,12 +99,36 @@ impl NeighTable {
                 Immortal::spawn(async move {
                     loop {
                         if let Ok(pkt) = connection.recv_raw_packet().await {
-                            let _ = send_incoming.try_send(pkt);
+                            if send_incoming.try_send(pkt).is_err() {
+                                events.emit(DaemonEvent::PacketDropped {
+                                    reason: DropReason::QueueFull,
+                                });
+                            }
                         }
                     }
                 }),
             ),
         );
+        if previous.is_none() {
+            self.events
+                .emit(DaemonEvent::NeighborConnected(fingerprint));
+        }
+    }
+
+    /// Keeps the connection to a neighbor from expiring before `until`, if there is one.
+    pub fn hold_until(&self, fingerprint: &Fingerprint, until: Instant) {
+        if let Some(mut entry) = self.table.get_mut(fingerprint) {
+            if let Some(expiry) = entry.1.as_mut() {
+                *expiry = (*expiry).max(until);
+            }
+        }
+    }
+
+    /// Keeps the connection to a neighbor from expiring, if there is one.
+    pub fn pin(&self, fingerprint: &Fingerprint) {
+        if let Some(mut entry) = self.table.get_mut(fingerprint) {
+            entry.1 = None;
+        }
     }
 
     /// Lookup a connection by its fingerprint.
@@ -84,6 +138,30 @@ impl NeighTable {
             .map(|entry| entry.value().0.clone())
     }
 
+    /// Remove a neighbor, closing the connection to it once the packets already queued for it are sent.
+    pub fn remove(&self, fingerprint: &Fingerprint) {
+        if let Some((_, (connection, _, _))) = self.table.remove(fingerprint) {
+            smolscale::spawn(connection.disconnect().map_err(log_error("disconnect"))).detach();
+            self.events
+                .emit(DaemonEvent::NeighborDisconnected(*fingerprint));
+        }
+    }
+
+    /// Returns the link statistics of a neighbor, creating empty ones if it was never connected to.
+    pub fn stats(&self, fingerprint: &Fingerprint) -> Arc<LinkConnectionStats> {
+        self.stats.entry(*fingerprint).or_default().clone()
+    }
+
+    /// Marks a connection to the given neighbor as being opened, returning `None` if another one already is. The mark is cleared when the returned guard is dropped.
+    pub fn start_connecting(&self, fingerprint: Fingerprint) -> Option<ConnectingGuard> {
+        self.connecting
+            .insert(fingerprint)
+            .then(|| ConnectingGuard {
+                connecting: self.connecting.clone(),
+                fingerprint,
+            })
+    }
+
     /// Returns all the connections.
     pub fn all_neighs(&self) -> Vec<LinkConnection> {
         self.table.iter().map(|s| s.0.clone()).collect()
@@ -93,9 +171,56 @@ impl NeighTable {
     pub fn garbage_collect(&self) {
         let now = Instant::now();
         self.table
-            .retain(|_fingerprint, (_connection, expiry, _)| match expiry {
-                Some(instant) => *instant > now,
-                None => true,
+            .retain(|fingerprint, (_connection, expiry, _)| match expiry {
+                Some(instant) if *instant <= now => {
+                    self.events
+                        .emit(DaemonEvent::NeighborDisconnected(*fingerprint));
+                    false
+                }
+                _ => true,
             });
     }
 }
+
+/// Held while a connection to a neighbor is being opened. See [NeighTable::start_connecting].
+pub struct ConnectingGuard {
+    connecting: Arc<DashSet<Fingerprint>>,
+    fingerprint: Fingerprint,
+}
+
+impl Drop for ConnectingGuard {
+    fn drop(&mut self) {
+        self.connecting.remove(&self.fingerprint);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrent_connects_to_same_neighbor() {
+        let table = NeighTable::new();
+        let fingerprint = Fingerprint::from_bytes(&[10; 20]);
+        let attempt = || async {
+            let guard = table.start_connecting(fingerprint);
+            // hold on to the mark for as long as a connection attempt would take
+            smol::Timer::after(Duration::from_millis(50)).await;
+            guard.is_some()
+        };
+
+        // Testing that only one of two concurrent attempts gets to connect
+        let (first, second) = smol::block_on(smol::future::zip(attempt(), attempt()));
+        assert!(first ^ second);
+
+        // Testing that the neighbor can be connected to again once the attempt is over
+        assert!(table.start_connecting(fingerprint).is_some());
+
+        // Testing that attempts to different neighbors don't interfere
+        let _guard = table.start_connecting(fingerprint).unwrap();
+        assert!(table
+            .start_connecting(Fingerprint::from_bytes(&[11; 20]))
+            .is_some());
+        assert!(table.start_connecting(fingerprint).is_none());
+    }
+}
@@ -1,25 +1,54 @@
-use std::time::Instant;
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
 
-use anyhow::Context;
-use earendil_crypt::Fingerprint;
+use earendil_crypt::{Fingerprint, IdentitySecret};
 use earendil_packet::{InnerPacket, PeeledPacket};
 
 use crate::{
+    control_protocol::{DaemonEvent, DropReason},
     daemon::{
         context::{ANON_DESTS, DEGARBLERS, GLOBAL_IDENTITY, GLOBAL_ONION_SK, NEIGH_TABLE},
+        events::{emit_packet_dropped, EVENTS},
+        metrics::METRICS,
+        peer_probe::{echo_probe, PEER_PROBE_DOCK},
         rrb_balance::{decrement_rrb_balance, replenish_rrb},
     },
-    socket::Endpoint,
+    socket::{n2r_socket::PacketId, Endpoint},
 };
 
-use super::context::{DaemonContext, SOCKET_RECV_QUEUES};
+use super::context::{CtxField, DaemonContext, SOCKET_RECV_QUEUES};
+
+/// How often the packets forwarded to each neighbor are summed up in a [DaemonEvent::PacketForwarded], rather than emitting one per packet.
+pub const FORWARD_EVENT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Packets forwarded to each neighbor since the last [emit_forward_events].
+static FORWARD_COUNTS: CtxField<DashMap<Fingerprint, u64>> = |_| Default::default();
+
+/// Emits a [DaemonEvent::PacketForwarded] for each neighbor that packets were forwarded to since the last call.
+pub fn emit_forward_events(ctx: &DaemonContext) {
+    let counts = ctx.get(FORWARD_COUNTS);
+    let next_hops: Vec<Fingerprint> = counts.iter().map(|entry| *entry.key()).collect();
+    for next_hop in next_hops {
+        if let Some((_, count)) = counts.remove(&next_hop) {
+            ctx.get(EVENTS)
+                .emit(DaemonEvent::PacketForwarded { next_hop, count });
+        }
+    }
+}
 
 /// Loop that takes incoming packets, peels them, and processes them
 pub async fn peel_forward_loop(ctx: DaemonContext) -> anyhow::Result<()> {
     loop {
         let pkt = ctx.get(NEIGH_TABLE).recv_raw_packet().await;
         let now = Instant::now();
-        let peeled = pkt.peel(ctx.get(GLOBAL_ONION_SK))?;
+        let packet_id: PacketId = *blake3::hash(&pkt.header.outer).as_bytes();
+        let peeled = pkt
+            .peel(ctx.get(GLOBAL_ONION_SK))
+            .inspect_err(|_| emit_packet_dropped(&ctx, DropReason::BadPacket))?;
 
         scopeguard::defer!(log::trace!("message peel forward took {:?}", now.elapsed()));
         match peeled {
@@ -27,27 +56,26 @@ pub async fn peel_forward_loop(ctx: DaemonContext) -> anyhow::Result<()> {
                 to: next_hop,
                 pkt: inner,
             } => {
-                let conn = ctx
-                    .get(NEIGH_TABLE)
-                    .lookup(&next_hop)
-                    .context("could not find this next hop")?;
+                let Some(conn) = ctx.get(NEIGH_TABLE).lookup(&next_hop) else {
+                    emit_packet_dropped(&ctx, DropReason::NoNextHop);
+                    anyhow::bail!("could not find this next hop")
+                };
                 conn.send_raw_packet(inner).await;
+                ctx.get(METRICS)
+                    .packets_forwarded
+                    .fetch_add(1, Ordering::Relaxed);
+                *ctx.get(FORWARD_COUNTS).entry(next_hop).or_default() += 1;
             }
             PeeledPacket::Received {
                 from: src_fp,
                 pkt: inner,
-            } => process_inner_pkt(
-                &ctx,
-                inner,
-                src_fp,
-                ctx.get(GLOBAL_IDENTITY).public().fingerprint(),
-            )?,
+            } => process_inner_pkt(&ctx, inner, src_fp, *ctx.get(GLOBAL_IDENTITY), packet_id)?,
             PeeledPacket::GarbledReply { id, mut pkt } => {
                 log::trace!("received garbled packet");
-                let reply_degarbler = ctx
-                    .get(DEGARBLERS)
-                    .remove(&id)
-                    .context(format!("no degarbler for this garbled pkt with id {id}, despite {} items in the degarbler", ctx.get(DEGARBLERS).entry_count()))?;
+                let Some(reply_degarbler) = ctx.get(DEGARBLERS).remove(&id) else {
+                    emit_packet_dropped(&ctx, DropReason::NoDegarbler);
+                    anyhow::bail!("no degarbler for this garbled pkt with id {id}, despite {} items in the degarbler", ctx.get(DEGARBLERS).entry_count())
+                };
                 let (inner, src_fp) = reply_degarbler.degarble(&mut pkt)?;
                 log::trace!("packet has been degarbled!");
                 decrement_rrb_balance(&ctx, reply_degarbler.my_anon_isk(), src_fp);
@@ -57,7 +85,8 @@ pub async fn peel_forward_loop(ctx: DaemonContext) -> anyhow::Result<()> {
                     &ctx,
                     inner,
                     src_fp,
-                    reply_degarbler.my_anon_isk().public().fingerprint(),
+                    reply_degarbler.my_anon_isk(),
+                    packet_id,
                 )?;
             }
         }
@@ -68,23 +97,36 @@ fn process_inner_pkt(
     ctx: &DaemonContext,
     inner: InnerPacket,
     src_fp: Fingerprint,
-    dest_fp: Fingerprint,
+    dest_isk: IdentitySecret,
+    packet_id: PacketId,
 ) -> anyhow::Result<()> {
     match inner {
+        InnerPacket::Message(msg)
+            if msg.dest_dock == PEER_PROBE_DOCK && dest_isk != *ctx.get(GLOBAL_IDENTITY) =>
+        {
+            echo_probe(ctx, dest_isk, src_fp, msg.body);
+        }
         InnerPacket::Message(msg) => {
             // log::debug!("received InnerPacket::Message: {:?}", msg);
-            let dest = Endpoint::new(dest_fp, msg.dest_dock);
-            if let Some(send_incoming) = ctx.get(SOCKET_RECV_QUEUES).get(&dest) {
-                send_incoming.try_send((msg, src_fp))?;
+            let dest = Endpoint::new(dest_isk.public().fingerprint(), msg.dest_dock);
+            if let Some(recv_queue) = ctx.get(SOCKET_RECV_QUEUES).get(&dest) {
+                // stop handing the socket more messages before its window fills up completely
+                if recv_queue.is_backpressured() {
+                    recv_queue.record_backpressure_drop();
+                    emit_packet_dropped(ctx, DropReason::QueueFull);
+                    anyhow::bail!("socket {dest} is backpressured, dropping message from {src_fp}")
+                }
+                recv_queue
+                    .deliver(msg, src_fp, packet_id)
+                    .inspect_err(|_| emit_packet_dropped(ctx, DropReason::QueueFull))?;
             } else {
+                emit_packet_dropped(ctx, DropReason::NoSocket);
                 anyhow::bail!("No socket listening on destination {dest}")
             }
         }
         InnerPacket::ReplyBlocks(reply_blocks) => {
             log::trace!("received a batch of ReplyBlocks");
-            for reply_block in reply_blocks {
-                ctx.get(ANON_DESTS).lock().insert(src_fp, reply_block);
-            }
+            ctx.get(ANON_DESTS).insert_batch(src_fp, reply_blocks);
         }
     }
     Ok(())
@@ -0,0 +1,165 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bytes::Bytes;
+use dashmap::DashMap;
+use earendil_crypt::{Fingerprint, IdentitySecret};
+use earendil_packet::Dock;
+use futures_util::{StreamExt, TryFutureExt};
+use smol::channel::Sender;
+use smol_timeout::TimeoutExt;
+
+use crate::{
+    log_error,
+    socket::{n2r_socket::N2rSocket, Endpoint},
+};
+
+use super::context::{send_n2r, CtxField, DaemonContext, ANON_DESTS, GLOBAL_IDENTITY};
+
+/// Dock that probes are sent from and echoed back to.
+pub const PEER_PROBE_DOCK: Dock = 100003;
+
+const PROBE_RETRIES: usize = 3;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many peers are probed at once. A dead peer takes up a probe for `PROBE_RETRIES` * `PROBE_TIMEOUT`.
+const PROBE_CONCURRENCY: usize = 64;
+
+/// The most probe echoes we send at once; probes arriving beyond that are dropped, so that peers can't make us spawn tasks without bound.
+const MAX_ECHOES_IN_FLIGHT: usize = 64;
+
+/// Probe echoes currently being sent.
+static ECHOES_IN_FLIGHT: CtxField<AtomicUsize> = |_| AtomicUsize::new(0);
+
+/// Loop that probes the anonymous peers whose reply blocks have gone unused for `idle_probe_secs`, forgetting the reply blocks of those that never answer.
+pub async fn peer_probe_loop(ctx: DaemonContext) -> anyhow::Result<()> {
+    let idle = Duration::from_secs(ctx.init().idle_probe_secs);
+    let socket = N2rSocket::bind(
+        ctx.clone(),
+        *ctx.get(GLOBAL_IDENTITY),
+        Some(PEER_PROBE_DOCK),
+    );
+    // echoes all arrive at the one socket, so they are routed to the waiting probes by nonce
+    let pending_probes: Arc<DashMap<Bytes, (Fingerprint, Sender<()>)>> = Default::default();
+    let _demux = smolscale::spawn(demux_echoes(socket.clone(), pending_probes.clone()));
+    loop {
+        smol::Timer::after(idle.min(Duration::from_secs(60))).await;
+        let mut to_probe = vec![];
+        for fingerprint in ctx.get(ANON_DESTS).idle_fingerprints(idle) {
+            // a probe needs a reply block, and a peer whose blocks have all expired can't be reached anyway
+            if ctx.get(ANON_DESTS).peek_count(&fingerprint) == 0 {
+                log::debug!(
+                    "anonymous peer {fingerprint} has no usable reply blocks left, forgetting it"
+                );
+                ctx.get(ANON_DESTS).drain(&fingerprint);
+            } else {
+                to_probe.push(fingerprint);
+            }
+        }
+        let (ctx, socket, pending_probes) = (&ctx, &socket, &pending_probes);
+        futures_util::stream::iter(to_probe)
+            .map(|fingerprint| async move {
+                (
+                    fingerprint,
+                    probe_peer(socket, pending_probes, fingerprint).await,
+                )
+            })
+            .buffer_unordered(PROBE_CONCURRENCY)
+            .for_each(|(fingerprint, answered)| async move {
+                if !answered {
+                    let forgotten = ctx.get(ANON_DESTS).drain(&fingerprint).len();
+                    log::debug!("anonymous peer {fingerprint} did not answer probes, forgetting its {forgotten} reply blocks");
+                }
+            })
+            .await;
+    }
+}
+
+/// Sends a probe to the given peer through one of its reply blocks, and waits for it to be echoed back.
+async fn probe_peer(
+    socket: &N2rSocket,
+    pending_probes: &DashMap<Bytes, (Fingerprint, Sender<()>)>,
+    fingerprint: Fingerprint,
+) -> bool {
+    for _ in 0..PROBE_RETRIES {
+        let nonce = Bytes::copy_from_slice(&rand::random::<u64>().to_be_bytes());
+        let (send_echo, recv_echo) = smol::channel::bounded(1);
+        pending_probes.insert(nonce.clone(), (fingerprint, send_echo));
+        // echoes to earlier, timed out probes find no entry and are ignored
+        scopeguard::defer!({
+            pending_probes.remove(&nonce);
+        });
+        if socket
+            .send_to(nonce.clone(), Endpoint::new(fingerprint, PEER_PROBE_DOCK))
+            .await
+            .is_err()
+        {
+            continue;
+        }
+        if let Some(Ok(())) = recv_echo.recv().timeout(PROBE_TIMEOUT).await {
+            return true;
+        }
+    }
+    false
+}
+
+/// Routes the echoes arriving at the probe socket to the probes waiting for them.
+async fn demux_echoes(
+    socket: N2rSocket,
+    pending_probes: Arc<DashMap<Bytes, (Fingerprint, Sender<()>)>>,
+) {
+    loop {
+        let (body, endpoint) = match socket.recv_from().await {
+            Ok(received) => received,
+            Err(err) => {
+                log::debug!("peer probe socket stopped receiving: {err}");
+                return;
+            }
+        };
+        if let Some(probe) = pending_probes.get(&body) {
+            let (fingerprint, send_echo) = probe.value();
+            if *fingerprint == endpoint.fingerprint {
+                let _ = send_echo.try_send(());
+            }
+        }
+    }
+}
+
+/// Echoes a probe from a relay holding our reply blocks back to it, so it knows we're still around. Probes beyond [MAX_ECHOES_IN_FLIGHT] are dropped.
+pub fn echo_probe(
+    ctx: &DaemonContext,
+    dest_isk: IdentitySecret,
+    src_fp: Fingerprint,
+    body: Vec<Bytes>,
+) {
+    let in_flight = ctx.get(ECHOES_IN_FLIGHT);
+    if in_flight.fetch_add(1, Ordering::Relaxed) >= MAX_ECHOES_IN_FLIGHT {
+        in_flight.fetch_sub(1, Ordering::Relaxed);
+        log::debug!("too many probe echoes in flight, dropping the probe from {src_fp}");
+        return;
+    }
+    let ctx = ctx.clone();
+    smolscale::spawn(
+        async move {
+            scopeguard::defer!({
+                ctx.get(ECHOES_IN_FLIGHT).fetch_sub(1, Ordering::Relaxed);
+            });
+            send_n2r(
+                &ctx,
+                dest_isk,
+                PEER_PROBE_DOCK,
+                src_fp,
+                PEER_PROBE_DOCK,
+                body,
+            )
+            .await
+        }
+        .map_err(log_error("probe echo")),
+    )
+    .detach();
+}
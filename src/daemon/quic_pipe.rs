@@ -0,0 +1,142 @@
+use std::{future::Future, net::SocketAddr, path::Path, pin::Pin, sync::Arc, time::Instant};
+
+use bytes::Bytes;
+use quinn::{
+    AsyncStdRuntime, AsyncTimer, AsyncUdpSocket, Connecting, Connection, Endpoint, EndpointConfig,
+    Runtime, SendDatagramError,
+};
+use rustls::ServerConfig;
+use smol::{
+    channel::{Receiver, Sender},
+    future::FutureExt,
+};
+
+use super::{
+    framed_pipe::{read_frames, write_frame, FramedPipe, LengthPrefix},
+    tls::{load_cert_and_key, unverified_client_config},
+};
+
+/// The ALPN protocol that both sides of a QUIC link ask for.
+const ALPN: &[u8] = b"earendil";
+
+/// Runs quinn's background tasks on smolscale, like the rest of the daemon, instead of starting async-std's executor. Timers and sockets come from async-io, the reactor smol uses too.
+#[derive(Debug)]
+struct SmolscaleRuntime;
+
+impl Runtime for SmolscaleRuntime {
+    fn new_timer(&self, i: Instant) -> Pin<Box<dyn AsyncTimer>> {
+        AsyncStdRuntime.new_timer(i)
+    }
+
+    fn spawn(&self, future: Pin<Box<dyn Future<Output = ()> + Send>>) {
+        smolscale::spawn(future).detach();
+    }
+
+    fn wrap_udp_socket(
+        &self,
+        socket: std::net::UdpSocket,
+    ) -> std::io::Result<Box<dyn AsyncUdpSocket>> {
+        AsyncStdRuntime.wrap_udp_socket(socket)
+    }
+}
+
+fn bind_endpoint(
+    bind: SocketAddr,
+    server_config: Option<quinn::ServerConfig>,
+) -> anyhow::Result<Endpoint> {
+    Ok(Endpoint::new(
+        EndpointConfig::default(),
+        server_config,
+        std::net::UdpSocket::bind(bind)?,
+        Arc::new(SmolscaleRuntime),
+    )?)
+}
+
+/// Binds the endpoint of a QUIC in_route, failing if its certificate can't be loaded or its address is in use.
+pub fn bind_quic_endpoint(
+    listen: SocketAddr,
+    cert_path: &Path,
+    key_path: &Path,
+) -> anyhow::Result<Endpoint> {
+    let (certs, key) = load_cert_and_key(cert_path, key_path)?;
+    let mut crypto = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    bind_endpoint(
+        listen,
+        Some(quinn::ServerConfig::with_crypto(Arc::new(crypto))),
+    )
+}
+
+/// Finishes the handshake of a QUIC connection to an in_route, as a pipe.
+pub async fn accept_quic_pipe(connecting: Connecting) -> anyhow::Result<FramedPipe> {
+    Ok(quic_pipe(connecting.await?, None))
+}
+
+/// Connects to a QUIC in_route. Its certificate isn't checked, since the link protocol authenticates the neighbor anyway.
+pub async fn connect_quic_pipe(connect: SocketAddr) -> anyhow::Result<FramedPipe> {
+    let bind: SocketAddr = if connect.is_ipv6() {
+        "[::]:0".parse()?
+    } else {
+        "0.0.0.0:0".parse()?
+    };
+    let mut endpoint = bind_endpoint(bind, None)?;
+    let mut crypto = unverified_client_config();
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+    // the name is only used for the certificate, which isn't checked
+    let conn = endpoint.connect(connect, "earendil")?.await?;
+    Ok(quic_pipe(conn, Some(endpoint)))
+}
+
+/// Carries pipe messages in QUIC datagrams, so that a lost one doesn't hold up the others, as over UDP. Messages too big for a datagram go length-prefixed over a unidirectional stream instead.
+fn quic_pipe(conn: Connection, endpoint: Option<Endpoint>) -> FramedPipe {
+    let peer_addr = conn.remote_address().to_string();
+    FramedPipe::spawn("quic", peer_addr, |recv_outgoing, send_incoming| {
+        async move {
+            // a client endpoint lives as long as its only connection
+            let _endpoint = endpoint;
+            write_loop(&conn, recv_outgoing)
+                .or(read_loop(&conn, send_incoming))
+                .await
+        }
+    })
+}
+
+async fn write_loop(conn: &Connection, recv_outgoing: Receiver<Bytes>) -> anyhow::Result<()> {
+    let mut stream = None;
+    loop {
+        let msg = recv_outgoing.recv().await?;
+        if conn
+            .max_datagram_size()
+            .map_or(false, |max| msg.len() <= max)
+        {
+            match conn.send_datagram(msg) {
+                Err(SendDatagramError::ConnectionLost(err)) => return Err(err.into()),
+                // anything else only loses the message, as a congested UDP link would
+                _ => continue,
+            }
+        }
+        if stream.is_none() {
+            stream = Some(conn.open_uni().await?);
+        }
+        if let Some(stream) = &mut stream {
+            write_frame(stream, LengthPrefix::U16, &msg).await?;
+        }
+    }
+}
+
+async fn read_loop(conn: &Connection, send_incoming: Sender<Bytes>) -> anyhow::Result<()> {
+    read_datagrams(conn, send_incoming.clone())
+        .or(async { read_frames(conn.accept_uni().await?, LengthPrefix::U16, send_incoming).await })
+        .await
+}
+
+async fn read_datagrams(conn: &Connection, send_incoming: Sender<Bytes>) -> anyhow::Result<()> {
+    loop {
+        let msg = conn.read_datagram().await?;
+        send_incoming.send(msg).await?;
+    }
+}
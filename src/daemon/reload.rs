@@ -0,0 +1,333 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    config::{ConfigFile, InRouteConfig, OutRouteConfig},
+    control_protocol::ReloadError,
+};
+
+use super::{
+    context::{CONFIGURED_ROUTES, IN_ROUTES, NEIGH_TABLE},
+    inout_route::{start_in_route, start_out_route, InRouteHandle, OutRouteHandle},
+    DaemonContext,
+};
+
+/// The in_routes and out_routes of the config file, as currently running.
+pub struct ConfiguredRoutes {
+    /// the config last loaded, with its routes matching the running ones
+    pub config: ConfigFile,
+    in_routes: BTreeMap<String, InRouteHandle>,
+    out_routes: BTreeMap<String, OutRouteHandle>,
+}
+
+impl ConfiguredRoutes {
+    /// Creates the routes of a daemon that has not started any yet.
+    pub fn new(ctx: &DaemonContext) -> Self {
+        let mut config = ctx.init().clone();
+        config.in_routes.clear();
+        config.out_routes.clear();
+        Self {
+            config,
+            in_routes: BTreeMap::new(),
+            out_routes: BTreeMap::new(),
+        }
+    }
+}
+
+/// Starts the in_routes and out_routes of the config the daemon was started with.
+pub async fn start_configured_routes(ctx: &DaemonContext) -> Result<(), ReloadError> {
+    let mut routes = ctx.get(CONFIGURED_ROUTES).lock().await;
+    apply_routes(
+        ctx,
+        &mut routes,
+        &ctx.init().in_routes,
+        &ctx.init().out_routes,
+    )
+    .await
+}
+
+/// Switches the running in_routes and out_routes over to those of a new config, leaving untouched the routes that didn't change. Every other field must be the same as in the running config, since it can only take effect on restart.
+pub async fn reload_config(ctx: &DaemonContext, new_config: ConfigFile) -> Result<(), ReloadError> {
+    let mut routes = ctx.get(CONFIGURED_ROUTES).lock().await;
+    check_immutable_fields(&routes.config, &new_config)?;
+    // whether the node is a relay is decided at startup, by whether it has any in_routes
+    if routes.config.in_routes.is_empty() != new_config.in_routes.is_empty() {
+        return Err(ReloadError::ImmutableField("in_routes".into()));
+    }
+    apply_routes(
+        ctx,
+        &mut routes,
+        &new_config.in_routes,
+        &new_config.out_routes,
+    )
+    .await
+}
+
+fn check_immutable_fields(old: &ConfigFile, new: &ConfigFile) -> Result<(), ReloadError> {
+    let to_fields = |config: &ConfigFile| match serde_json::to_value(config) {
+        Ok(serde_json::Value::Object(fields)) => Ok(fields),
+        _ => Err(ReloadError::BadConfigFile(
+            "config does not serialize to a map".into(),
+        )),
+    };
+    let (old, new) = (to_fields(old)?, to_fields(new)?);
+    for key in old.keys().chain(new.keys()) {
+        if key != "in_routes" && key != "out_routes" && old.get(key) != new.get(key) {
+            return Err(ReloadError::ImmutableField(key.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Stops the routes that are gone or changed, then starts the ones that are new or changed. Either all of it happens or none of it: if an in_route fails to start, the in_routes are put back the way they were and the out_routes are left alone.
+async fn apply_routes(
+    ctx: &DaemonContext,
+    routes: &mut ConfiguredRoutes,
+    in_routes: &BTreeMap<String, InRouteConfig>,
+    out_routes: &BTreeMap<String, OutRouteConfig>,
+) -> Result<(), ReloadError> {
+    if let Some(name) = in_routes
+        .keys()
+        .find(|name| ctx.get(IN_ROUTES).contains_key(*name))
+    {
+        return Err(ReloadError::InRouteError(format!(
+            "{name}: an in_route with this name was added at runtime"
+        )));
+    }
+
+    let stale_in_routes: Vec<String> = routes
+        .config
+        .in_routes
+        .iter()
+        .filter(|(name, config)| in_routes.get(*name) != Some(config))
+        .map(|(name, _)| name.clone())
+        .collect();
+    let mut stopped = vec![];
+    for name in stale_in_routes {
+        if let Some(config) = routes.config.in_routes.remove(&name) {
+            stopped.push((name.clone(), config));
+        }
+        if let Some(handle) = routes.in_routes.remove(&name) {
+            log::info!("stopping in_route {name}");
+            stop_in_route(ctx, handle).await;
+        }
+    }
+    let mut started = vec![];
+    for (name, config) in in_routes {
+        if routes.config.in_routes.contains_key(name) {
+            continue;
+        }
+        match start_in_route(ctx, name, config.clone()).await {
+            Ok(handle) => {
+                routes.in_routes.insert(name.clone(), handle);
+                routes.config.in_routes.insert(name.clone(), config.clone());
+                started.push(name.clone());
+            }
+            Err(e) => {
+                restore_in_routes(ctx, routes, started, stopped).await;
+                return Err(ReloadError::InRouteError(format!("{name}: {e}")));
+            }
+        }
+    }
+
+    let stale_out_routes: Vec<String> = routes
+        .config
+        .out_routes
+        .iter()
+        .filter(|(name, config)| out_routes.get(*name) != Some(config))
+        .map(|(name, _)| name.clone())
+        .collect();
+    for name in stale_out_routes {
+        routes.config.out_routes.remove(&name);
+        if let Some(handle) = routes.out_routes.remove(&name) {
+            log::info!("stopping out_route {name}");
+            handle.task.cancel().await;
+            ctx.get(NEIGH_TABLE).remove(&handle.remote_fingerprint);
+        }
+    }
+    for (name, config) in out_routes {
+        if routes.config.out_routes.contains_key(name) {
+            continue;
+        }
+        routes
+            .out_routes
+            .insert(name.clone(), start_out_route(ctx, name, config.clone()));
+        routes
+            .config
+            .out_routes
+            .insert(name.clone(), config.clone());
+    }
+    Ok(())
+}
+
+/// Undoes a partly applied reload of the in_routes, stopping the ones that were started and restarting the ones that were stopped.
+async fn restore_in_routes(
+    ctx: &DaemonContext,
+    routes: &mut ConfiguredRoutes,
+    started: Vec<String>,
+    stopped: Vec<(String, InRouteConfig)>,
+) {
+    for name in started {
+        routes.config.in_routes.remove(&name);
+        if let Some(handle) = routes.in_routes.remove(&name) {
+            stop_in_route(ctx, handle).await;
+        }
+    }
+    for (name, config) in stopped {
+        match start_in_route(ctx, &name, config.clone()).await {
+            Ok(handle) => {
+                routes.in_routes.insert(name.clone(), handle);
+                routes.config.in_routes.insert(name, config);
+            }
+            Err(e) => log::error!("could not restart in_route {name}: {:?}", e),
+        }
+    }
+}
+
+/// Stops an in_route's listener and disconnects the neighbors that came in through it.
+pub async fn stop_in_route(ctx: &DaemonContext, handle: InRouteHandle) {
+    handle.task.cancel().await;
+    for neigh in handle.accepted.iter() {
+        ctx.get(NEIGH_TABLE).remove(neigh.key());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use earendil_crypt::IdentitySecret;
+    use serde_json::json;
+
+    use super::*;
+
+    fn free_port() -> u16 {
+        UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    fn config(in_routes: &[(&str, u16)], out_routes: &[(&str, &str)]) -> ConfigFile {
+        let in_routes: serde_json::Map<String, serde_json::Value> = in_routes
+            .iter()
+            .map(|(name, port)| {
+                (
+                    name.to_string(),
+                    json!({"protocol": "obfsudp", "listen": format!("127.0.0.1:{port}"), "secret": name}),
+                )
+            })
+            .collect();
+        let out_routes: serde_json::Map<String, serde_json::Value> = out_routes
+            .iter()
+            .map(|(name, fingerprint)| {
+                (
+                    name.to_string(),
+                    json!({
+                        "protocol": "obfsudp",
+                        "fingerprint": fingerprint,
+                        "connect": format!("127.0.0.1:{}", free_port()),
+                        "cookie": hex::encode([0u8; 32]),
+                    }),
+                )
+            })
+            .collect();
+        serde_json::from_value(json!({
+            "identity_seed": "reload-test",
+            "control_listen": "127.0.0.1:0",
+            "in_routes": in_routes,
+            "out_routes": out_routes,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_reload_config() {
+        let (kept_port, removed_port, added_port) = (free_port(), free_port(), free_port());
+        let removed_peer = IdentitySecret::generate()
+            .public()
+            .fingerprint()
+            .to_string();
+        let added_peer = IdentitySecret::generate()
+            .public()
+            .fingerprint()
+            .to_string();
+        let initial = config(
+            &[("kept", kept_port), ("removed", removed_port)],
+            &[("removed_peer", &removed_peer)],
+        );
+        let ctx = DaemonContext::new(initial);
+        smol::block_on(async {
+            start_configured_routes(&ctx).await.unwrap();
+            let routes = ctx.get(CONFIGURED_ROUTES).lock().await;
+            assert_eq!(
+                routes.in_routes.keys().collect::<Vec<_>>(),
+                ["kept", "removed"]
+            );
+            assert_eq!(
+                routes.out_routes.keys().collect::<Vec<_>>(),
+                ["removed_peer"]
+            );
+            drop(routes);
+
+            // Testing that one added and one removed peer take effect, leaving the rest alone
+            let reloaded = config(
+                &[("kept", kept_port), ("added", added_port)],
+                &[("added_peer", &added_peer)],
+            );
+            reload_config(&ctx, reloaded.clone()).await.unwrap();
+            let routes = ctx.get(CONFIGURED_ROUTES).lock().await;
+            assert_eq!(
+                routes.in_routes.keys().collect::<Vec<_>>(),
+                ["added", "kept"]
+            );
+            assert_eq!(routes.out_routes.keys().collect::<Vec<_>>(), ["added_peer"]);
+            assert_eq!(
+                serde_json::to_value(&routes.config).unwrap(),
+                serde_json::to_value(&reloaded).unwrap()
+            );
+            drop(routes);
+
+            // Testing that a reload where an in_route can't listen changes nothing at all
+            let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let failing = config(
+                &[
+                    ("kept", kept_port),
+                    ("taken", taken.local_addr().unwrap().port()),
+                ],
+                &[("removed_peer", &removed_peer)],
+            );
+            assert!(matches!(
+                reload_config(&ctx, failing).await,
+                Err(ReloadError::InRouteError(_))
+            ));
+            let routes = ctx.get(CONFIGURED_ROUTES).lock().await;
+            assert_eq!(
+                routes.in_routes.keys().collect::<Vec<_>>(),
+                ["added", "kept"]
+            );
+            assert_eq!(routes.out_routes.keys().collect::<Vec<_>>(), ["added_peer"]);
+            assert_eq!(
+                serde_json::to_value(&routes.config).unwrap(),
+                serde_json::to_value(&reloaded).unwrap()
+            );
+            drop(routes);
+
+            // Testing that fields that only take effect on restart are refused, without touching the routes
+            let mut immutable = config(&[("kept", kept_port)], &[]);
+            immutable.idle_probe_secs += 1;
+            assert!(matches!(
+                reload_config(&ctx, immutable).await,
+                Err(ReloadError::ImmutableField(field)) if field == "idle_probe_secs"
+            ));
+            assert_eq!(ctx.get(CONFIGURED_ROUTES).lock().await.in_routes.len(), 2);
+
+            // Testing that the node can't stop being a relay
+            let client = config(&[], &[("added_peer", &added_peer)]);
+            assert!(matches!(
+                reload_config(&ctx, client).await,
+                Err(ReloadError::ImmutableField(field)) if field == "in_routes"
+            ));
+        });
+    }
+}
@@ -1,12 +1,31 @@
-use std::{collections::VecDeque, num::NonZeroUsize};
+use std::{
+    collections::VecDeque,
+    num::NonZeroUsize,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use earendil_crypt::Fingerprint;
 use earendil_packet::ReplyBlock;
 use lru::LruCache;
+use parking_lot::RwLock;
+
+/// By default, reply blocks older than this are considered stale and never used.
+const DEFAULT_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// How many reply blocks are kept for each fingerprint, beyond which new ones displace the oldest.
+const REPLY_BLOCKS_PER_FINGERPRINT: usize = 1000;
+
+struct TimestampedReplyBlock {
+    block: ReplyBlock,
+    inserted_at: Instant,
+}
 
 struct ReplyBlockDeque {
-    pub deque: VecDeque<ReplyBlock>,
+    pub deque: VecDeque<TimestampedReplyBlock>,
     pub capacity: usize,
+    /// when a reply block was last consumed, or when the deque was created if none has been
+    pub last_used: Instant,
 }
 
 impl ReplyBlockDeque {
@@ -14,10 +33,18 @@ impl ReplyBlockDeque {
         ReplyBlockDeque {
             deque: VecDeque::with_capacity(capacity),
             capacity,
+            last_used: Instant::now(),
         }
     }
 
     fn insert(&mut self, item: ReplyBlock) {
+        self.insert_timestamped(TimestampedReplyBlock {
+            block: item,
+            inserted_at: Instant::now(),
+        });
+    }
+
+    fn insert_timestamped(&mut self, item: TimestampedReplyBlock) {
         if self.deque.len() == self.capacity {
             // remove the oldest element
             self.deque.pop_front();
@@ -27,12 +54,33 @@ impl ReplyBlockDeque {
     }
 
     fn pop(&mut self) -> Option<ReplyBlock> {
-        self.deque.pop_back()
+        self.last_used = Instant::now();
+        self.deque.pop_back().map(|item| item.block)
+    }
+
+    /// Counts the reply blocks that have been in the deque for less than `ttl`.
+    fn count_unexpired(&self, ttl: Duration) -> usize {
+        self.deque
+            .iter()
+            .filter(|item| item.inserted_at.elapsed() < ttl)
+            .count()
+    }
+
+    /// Discards every reply block that has been in the deque for at least `ttl`.
+    fn discard_expired(&mut self, ttl: Duration) {
+        // the deque is in insertion order, so the expired blocks are all at the front
+        while let Some(oldest) = self.deque.front() {
+            if oldest.inserted_at.elapsed() < ttl {
+                break;
+            }
+            self.deque.pop_front();
+        }
     }
 }
 
 pub struct ReplyBlockStore {
     items: LruCache<Fingerprint, ReplyBlockDeque>,
+    ttl: Duration,
 }
 
 impl Default for ReplyBlockStore {
@@ -45,22 +93,129 @@ impl ReplyBlockStore {
     pub fn new() -> Self {
         let items =
             LruCache::new(NonZeroUsize::new(5000).expect("reply block store can't be of size 0"));
-        Self { items }
+        Self {
+            items,
+            ttl: DEFAULT_TTL,
+        }
+    }
+
+    /// Sets how long a reply block stays usable after being inserted. Defaults to one hour.
+    pub fn set_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
     }
 
     pub fn insert(&mut self, fingerprint: Fingerprint, rb: ReplyBlock) {
-        let deque = self
-            .items
-            .get_or_insert_mut(fingerprint, || ReplyBlockDeque::new(1000));
+        let deque = self.items.get_or_insert_mut(fingerprint, || {
+            ReplyBlockDeque::new(REPLY_BLOCKS_PER_FINGERPRINT)
+        });
         deque.insert(rb);
     }
 
+    pub fn insert_batch(
+        &mut self,
+        fingerprint: Fingerprint,
+        rbs: impl IntoIterator<Item = ReplyBlock>,
+    ) {
+        for rb in rbs {
+            self.insert(fingerprint, rb);
+        }
+    }
+
     pub fn pop(&mut self, fingerprint: &Fingerprint) -> Option<ReplyBlock> {
         match self.items.get_mut(fingerprint) {
-            Some(deque) => deque.pop(),
+            Some(deque) => {
+                deque.discard_expired(self.ttl);
+                deque.pop()
+            }
             None => None,
         }
     }
+
+    /// Returns how many usable reply blocks are stored for the given fingerprint, without consuming any or touching the LRU order.
+    pub fn peek_count(&self, fingerprint: &Fingerprint) -> usize {
+        self.items
+            .peek(fingerprint)
+            .map_or(0, |deque| deque.count_unexpired(self.ttl))
+    }
+
+    /// Moves every reply block in `other` into this store, appending each fingerprint's blocks after ours and keeping the time they were inserted at. Blocks beyond a fingerprint's capacity displace the oldest ones.
+    // meant for reloading a snapshot of the store, which the daemon doesn't take yet
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn merge(&mut self, other: ReplyBlockStore) {
+        for (fingerprint, other_deque) in other.items {
+            let deque = self.items.get_or_insert_mut(fingerprint, || {
+                ReplyBlockDeque::new(REPLY_BLOCKS_PER_FINGERPRINT)
+            });
+            for item in other_deque.deque {
+                deque.insert_timestamped(item);
+            }
+        }
+    }
+
+    /// Returns the fingerprints none of whose reply blocks have been consumed for at least `idle`.
+    pub fn idle_fingerprints(&self, idle: Duration) -> Vec<Fingerprint> {
+        self.items
+            .iter()
+            .filter(|(_, deque)| deque.last_used.elapsed() >= idle)
+            .map(|(fingerprint, _)| *fingerprint)
+            .collect()
+    }
+
+    /// Removes and returns all the usable reply blocks stored for the given fingerprint, oldest first, forgetting the fingerprint.
+    pub fn drain(&mut self, fingerprint: &Fingerprint) -> Vec<ReplyBlock> {
+        match self.items.pop(fingerprint) {
+            Some(mut deque) => {
+                deque.discard_expired(self.ttl);
+                deque.deque.into_iter().map(|item| item.block).collect()
+            }
+            None => vec![],
+        }
+    }
+}
+
+/// A [ReplyBlockStore] that can be cheaply cloned and shared between tasks. Only the operations that modify the store take the write lock.
+#[derive(Clone, Default)]
+pub struct SharedReplyBlockStore {
+    inner: Arc<RwLock<ReplyBlockStore>>,
+}
+
+impl SharedReplyBlockStore {
+    pub fn new(store: ReplyBlockStore) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(store)),
+        }
+    }
+
+    // the daemon only inserts batches, but the wrapper mirrors the whole store
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn insert(&self, fingerprint: Fingerprint, rb: ReplyBlock) {
+        self.inner.write().insert(fingerprint, rb)
+    }
+
+    pub fn insert_batch(
+        &self,
+        fingerprint: Fingerprint,
+        rbs: impl IntoIterator<Item = ReplyBlock>,
+    ) {
+        self.inner.write().insert_batch(fingerprint, rbs)
+    }
+
+    pub fn pop(&self, fingerprint: &Fingerprint) -> Option<ReplyBlock> {
+        self.inner.write().pop(fingerprint)
+    }
+
+    pub fn drain(&self, fingerprint: &Fingerprint) -> Vec<ReplyBlock> {
+        self.inner.write().drain(fingerprint)
+    }
+
+    pub fn peek_count(&self, fingerprint: &Fingerprint) -> usize {
+        self.inner.read().peek_count(fingerprint)
+    }
+
+    pub fn idle_fingerprints(&self, idle: Duration) -> Vec<Fingerprint> {
+        self.inner.read().idle_fingerprints(idle)
+    }
 }
 
 #[cfg(test)]
@@ -168,4 +323,154 @@ mod tests {
         // Testing get when item does not exist
         assert_eq!(rb_store.pop(&fingerprint), None);
     }
+
+    #[test]
+    fn test_reply_block_store_peek_count() {
+        let mut rb_store = ReplyBlockStore::new();
+        let fingerprint = Fingerprint::from_bytes(&[10; 20]);
+
+        // Testing peek_count when no entry exists
+        assert_eq!(rb_store.peek_count(&fingerprint), 0);
+
+        // Testing that peek_count does not consume anything
+        rb_store.insert(fingerprint, create_reply_block());
+        rb_store.insert(fingerprint, create_reply_block());
+        assert_eq!(rb_store.peek_count(&fingerprint), 2);
+        assert_eq!(rb_store.peek_count(&fingerprint), 2);
+
+        rb_store.pop(&fingerprint);
+        assert_eq!(rb_store.peek_count(&fingerprint), 1);
+    }
+
+    #[test]
+    fn test_reply_block_store_drain() {
+        let mut rb_store = ReplyBlockStore::new();
+        let fingerprint = Fingerprint::from_bytes(&[10; 20]);
+        let rb1 = create_reply_block();
+        let rb2 = create_reply_block();
+
+        // Testing drain when no entry exists
+        assert!(rb_store.drain(&fingerprint).is_empty());
+
+        // Testing that drain returns everything, oldest first, and empties the entry
+        rb_store.insert_batch(fingerprint, [rb1.clone(), rb2.clone()]);
+        assert_eq!(rb_store.drain(&fingerprint), vec![rb1, rb2]);
+        assert_eq!(rb_store.peek_count(&fingerprint), 0);
+        assert_eq!(rb_store.pop(&fingerprint), None);
+    }
+
+    #[test]
+    fn test_reply_block_store_merge() {
+        let fingerprint = Fingerprint::from_bytes(&[10; 20]);
+        let other_fingerprint = Fingerprint::from_bytes(&[20; 20]);
+
+        let mut other = ReplyBlockStore::new();
+        other.insert_batch(fingerprint, (0..600).map(|_| create_reply_block()));
+        let newest = create_reply_block();
+        other.insert(fingerprint, newest.clone());
+        other.insert(other_fingerprint, create_reply_block());
+        let mut rb_store = ReplyBlockStore::new();
+        rb_store.insert_batch(fingerprint, (0..600).map(|_| create_reply_block()));
+
+        // Testing that the combined depth is capped at the per-fingerprint capacity
+        rb_store.merge(other);
+        assert_eq!(
+            rb_store.peek_count(&fingerprint),
+            REPLY_BLOCKS_PER_FINGERPRINT
+        );
+        assert_eq!(rb_store.peek_count(&other_fingerprint), 1);
+
+        // Testing that the merged blocks were appended after the existing ones
+        assert_eq!(rb_store.pop(&fingerprint), Some(newest));
+    }
+
+    #[test]
+    fn test_shared_reply_block_store() {
+        let rb_store = SharedReplyBlockStore::default();
+        let fingerprint = Fingerprint::from_bytes(&[10; 20]);
+        let rb = create_reply_block();
+
+        // Testing that clones share the same underlying store
+        let other = rb_store.clone();
+        other.insert_batch(fingerprint, [rb.clone()]);
+        assert_eq!(rb_store.peek_count(&fingerprint), 1);
+        assert_eq!(rb_store.pop(&fingerprint), Some(rb));
+        assert_eq!(other.peek_count(&fingerprint), 0);
+    }
+
+    #[test]
+    fn test_shared_reply_block_store_insert() {
+        let rb_store = SharedReplyBlockStore::default();
+        let fingerprint = Fingerprint::from_bytes(&[10; 20]);
+        let rb1 = create_reply_block();
+        let rb2 = create_reply_block();
+
+        // Testing insert through a clone, one block at a time
+        let other = rb_store.clone();
+        other.insert(fingerprint, rb1.clone());
+        other.insert(fingerprint, rb2.clone());
+        assert_eq!(rb_store.peek_count(&fingerprint), 2);
+
+        // Testing that the newest block is popped first
+        assert_eq!(rb_store.pop(&fingerprint), Some(rb2));
+        assert_eq!(rb_store.drain(&fingerprint), vec![rb1]);
+    }
+
+    #[test]
+    fn test_reply_block_store_expired_pop() {
+        let mut rb_store = ReplyBlockStore::new().set_ttl(Duration::from_millis(50));
+        let fingerprint = Fingerprint::from_bytes(&[10; 20]);
+
+        // Testing pop when the only item has expired
+        rb_store.insert(fingerprint, create_reply_block());
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(rb_store.pop(&fingerprint), None);
+
+        // Testing that expired items are skipped while fresh ones are still returned
+        rb_store.insert(fingerprint, create_reply_block());
+        rb_store.insert(fingerprint, create_reply_block());
+        std::thread::sleep(Duration::from_millis(100));
+        let fresh = create_reply_block();
+        rb_store.insert(fingerprint, fresh.clone());
+        assert_eq!(rb_store.pop(&fingerprint), Some(fresh));
+        assert_eq!(rb_store.pop(&fingerprint), None);
+    }
+
+    #[test]
+    fn test_reply_block_store_unexpired_pop() {
+        let mut rb_store = ReplyBlockStore::new().set_ttl(Duration::from_secs(60));
+        let fingerprint = Fingerprint::from_bytes(&[10; 20]);
+        let rb = create_reply_block();
+
+        // Testing pop when the item is still within its TTL
+        rb_store.insert(fingerprint, rb.clone());
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(rb_store.pop(&fingerprint), Some(rb));
+    }
+
+    #[test]
+    fn test_reply_block_store_idle_and_drain() {
+        let mut rb_store = ReplyBlockStore::new();
+        let idle_fp = Fingerprint::from_bytes(&[10; 20]);
+        let busy_fp = Fingerprint::from_bytes(&[11; 20]);
+        rb_store.insert(idle_fp, create_reply_block());
+        rb_store.insert(busy_fp, create_reply_block());
+        rb_store.insert(busy_fp, create_reply_block());
+
+        // Testing that only the fingerprint whose blocks were not consumed is idle
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(rb_store.pop(&busy_fp).is_some());
+        assert_eq!(
+            rb_store.idle_fingerprints(Duration::from_millis(50)),
+            vec![idle_fp]
+        );
+
+        // Testing that draining a fingerprint forgets its blocks
+        assert_eq!(rb_store.drain(&idle_fp).len(), 1);
+        assert_eq!(rb_store.peek_count(&idle_fp), 0);
+        assert!(rb_store
+            .idle_fingerprints(Duration::from_millis(50))
+            .is_empty());
+        assert_eq!(rb_store.peek_count(&busy_fp), 1);
+    }
 }
@@ -1,18 +1,26 @@
-use std::{net::Ipv4Addr, str::FromStr};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
 
 use anyhow::Context;
+use bytes::Bytes;
 use earendil_crypt::{Fingerprint, IdentitySecret};
+use earendil_packet::Dock;
 use futures_util::{io, TryFutureExt};
+use parking_lot::Mutex;
 use smol::{
     future::FutureExt,
-    io::AsyncWriteExt,
-    net::{TcpListener, TcpStream},
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
 };
 use smolscale::reaper::TaskReaper;
 use socksv5::v5::*;
 
 use crate::{
-    config::{Fallback, Socks5},
+    config::{Fallback, RouteProfile, Socks5},
     socket::{Endpoint, Socket},
     stream::Stream,
 };
@@ -21,101 +29,355 @@ use super::DaemonContext;
 
 pub async fn socks5_loop(ctx: DaemonContext, socks5_cfg: Socks5) -> anyhow::Result<()> {
     log::debug!("socks5 loop started");
-    let tcp_listener = TcpListener::bind(socks5_cfg.listen).await?;
-    let fallback = socks5_cfg.fallback;
-    let reaper = TaskReaper::new();
-
-    loop {
-        let (client_stream, _) = tcp_listener.accept().await?;
+    let proxy = Socks5Proxy {
+        ctx,
+        routes: BTreeMap::new(),
+        fallback: socks5_cfg.fallback,
+        identity: None,
+        route_profile: None,
+    };
+    proxy.serve(socks5_cfg.listen).await
+}
 
-        reaper.attach(smolscale::spawn(
-            socks5_once(ctx.clone(), client_stream, fallback)
-                .map_err(|e| log::warn!("socks5 worker failed: {:?}", e)),
-        ));
-    }
+/// A SOCKS5 server that carries its clients' TCP connections over earendil streams, and their UDP datagrams over haven sockets.
+pub(crate) struct Socks5Proxy {
+    pub ctx: DaemonContext,
+    /// the haven each destination IP address stands for; `<fingerprint>.haven` names reach havens without an entry here
+    pub routes: BTreeMap<IpAddr, Fingerprint>,
+    /// what to do with TCP connections to destinations that aren't havens
+    pub fallback: Fallback,
+    /// the identity to reach havens under, or `None` for a fresh one for every client connection, so that havens can't link a client's connections together
+    pub identity: Option<IdentitySecret>,
+    pub route_profile: Option<RouteProfile>,
 }
 
-async fn socks5_once(
-    ctx: DaemonContext,
-    client_stream: TcpStream,
-    fallback: Fallback,
-) -> anyhow::Result<()> {
-    client_stream.set_nodelay(true)?;
-    let _handshake = read_handshake(client_stream.clone()).await?;
-    write_auth_method(client_stream.clone(), SocksV5AuthMethod::Noauth).await?;
-    let request = read_request(client_stream.clone()).await?;
-    let port = request.port;
-    let domain: String = match &request.host {
-        SocksV5Host::Domain(dom) => String::from_utf8_lossy(dom).parse()?,
-        SocksV5Host::Ipv4(v4) => {
-            let v4addr = Ipv4Addr::new(v4[0], v4[1], v4[2], v4[3]);
-            v4addr.to_string()
+impl Socks5Proxy {
+    /// Accepts SOCKS5 clients on `listen`, serving each in its own task.
+    pub async fn serve(self, listen: SocketAddr) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(listen).await?;
+        let proxy = Arc::new(self);
+        let reaper = TaskReaper::new();
+        loop {
+            let (client, _) = listener.accept().await?;
+            reaper.attach(smolscale::spawn(
+                proxy
+                    .clone()
+                    .serve_client(client)
+                    .map_err(|e| log::warn!("socks5 worker failed: {:?}", e)),
+            ));
         }
-        _ => anyhow::bail!("IPv6 not supported"),
-    };
-    let addr = format!("{domain}:{port}");
-
-    write_request_status(
-        client_stream.clone(),
-        SocksV5RequestStatus::Success,
-        request.host,
-        port,
-    )
-    .await?;
-
-    log::info!("socks5 received request for {addr}");
-
-    let mut split_domain = domain.split('.');
-    let top_level = split_domain.clone().last();
-
-    if let Some(top) = top_level {
-        if top == "haven" {
-            let endpoint = Endpoint::new(
-                Fingerprint::from_str(split_domain.next().context("invalid Earendil address")?)?,
-                port.into(),
-            );
-            let earendil_skt =
-                Socket::bind_haven_internal(ctx.clone(), IdentitySecret::generate(), None, None);
-            let earendil_stream = Stream::connect(earendil_skt, endpoint).await?;
-
-            io::copy(client_stream.clone(), &mut earendil_stream.clone())
-                .race(io::copy(
-                    earendil_stream.clone(),
-                    &mut client_stream.clone(),
-                ))
+    }
+
+    async fn serve_client(self: Arc<Self>, client: TcpStream) -> anyhow::Result<()> {
+        client.set_nodelay(true)?;
+        read_handshake(client.clone()).await?;
+        write_auth_method(client.clone(), SocksV5AuthMethod::Noauth).await?;
+        let request = read_request(client.clone()).await?;
+        match request.command {
+            SocksV5Command::Connect => self.connect(client, request.host, request.port).await,
+            SocksV5Command::UdpAssociate => self.udp_associate(client).await,
+            _ => {
+                write_request_status(
+                    client,
+                    SocksV5RequestStatus::CommandNotSupported,
+                    request.host,
+                    request.port,
+                )
                 .await?;
-        } else {
-            match fallback {
-                Fallback::Block => return Ok(()),
-                Fallback::PassThrough => {
-                    let passthrough_stream = TcpStream::connect(addr).await?;
-                    io::copy(client_stream.clone(), &mut passthrough_stream.clone())
-                        .race(io::copy(
-                            passthrough_stream.clone(),
-                            &mut client_stream.clone(),
-                        ))
+                anyhow::bail!("unsupported SOCKS5 command")
+            }
+        }
+    }
+
+    async fn connect(&self, client: TcpStream, host: SocksV5Host, port: u16) -> anyhow::Result<()> {
+        let endpoint = match resolve(&self.routes, &host, port) {
+            Ok(endpoint) => endpoint,
+            Err(err) => return self.connect_fallback(client, host, port, err).await,
+        };
+        log::debug!("socks5 connecting to {endpoint}");
+        let stream = match Stream::connect(self.bind_socket(), endpoint).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                write_request_status(client, SocksV5RequestStatus::ConnectionRefused, host, port)
+                    .await?;
+                return Err(err);
+            }
+        };
+        write_request_status(client.clone(), SocksV5RequestStatus::Success, host, port).await?;
+        io::copy(client.clone(), &mut stream.clone())
+            .race(io::copy(stream.clone(), &mut client.clone()))
+            .await?;
+        Ok(())
+    }
+
+    /// Handles a connection to a destination that isn't a haven, as [Fallback] says.
+    async fn connect_fallback(
+        &self,
+        client: TcpStream,
+        host: SocksV5Host,
+        port: u16,
+        err: anyhow::Error,
+    ) -> anyhow::Result<()> {
+        let addr = match &host {
+            SocksV5Host::Domain(name) => format!("{}:{port}", String::from_utf8_lossy(name)),
+            SocksV5Host::Ipv4(ip) => SocketAddr::from((*ip, port)).to_string(),
+            SocksV5Host::Ipv6(ip) => SocketAddr::from((*ip, port)).to_string(),
+        };
+        log::debug!("socks5 falling back for {addr}");
+        // a `.haven` name that doesn't parse must never leak out to the clearnet
+        let is_haven_name = matches!(&host, SocksV5Host::Domain(name) if name.ends_with(b".haven"));
+        match self.fallback {
+            _ if is_haven_name => {
+                write_request_status(client, SocksV5RequestStatus::HostUnreachable, host, port)
+                    .await?;
+                Err(err)
+            }
+            Fallback::Block => {
+                write_request_status(client, SocksV5RequestStatus::HostUnreachable, host, port)
+                    .await?;
+                Err(err)
+            }
+            Fallback::PassThrough => {
+                let passthrough_stream = match TcpStream::connect(&addr).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        write_request_status(
+                            client,
+                            SocksV5RequestStatus::ConnectionRefused,
+                            host,
+                            port,
+                        )
                         .await?;
-                }
-                Fallback::SimpleProxy { remote } => {
-                    let remote_skt = Socket::bind_haven_internal(
-                        ctx.clone(),
-                        IdentitySecret::generate(),
-                        None,
-                        None,
-                    );
-                    let mut remote_stream = Stream::connect(remote_skt, remote).await?;
-                    let prepend = (addr.len() as u16).to_be_bytes();
-                    remote_stream.write(&prepend).await?;
-
-                    remote_stream.write(addr.as_bytes()).await?;
-
-                    io::copy(client_stream.clone(), &mut remote_stream.clone())
-                        .race(io::copy(remote_stream.clone(), &mut client_stream.clone()))
+                        return Err(err.into());
+                    }
+                };
+                write_request_status(client.clone(), SocksV5RequestStatus::Success, host, port)
+                    .await?;
+                io::copy(client.clone(), &mut passthrough_stream.clone())
+                    .race(io::copy(passthrough_stream.clone(), &mut client.clone()))
+                    .await?;
+                Ok(())
+            }
+            Fallback::SimpleProxy { remote } => {
+                let mut remote_stream = match Stream::connect(self.bind_socket(), remote).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        write_request_status(
+                            client,
+                            SocksV5RequestStatus::ConnectionRefused,
+                            host,
+                            port,
+                        )
                         .await?;
+                        return Err(err);
+                    }
+                };
+                write_request_status(client.clone(), SocksV5RequestStatus::Success, host, port)
+                    .await?;
+                remote_stream
+                    .write_all(&(addr.len() as u16).to_be_bytes())
+                    .await?;
+                remote_stream.write_all(addr.as_bytes()).await?;
+                io::copy(client.clone(), &mut remote_stream.clone())
+                    .race(io::copy(remote_stream.clone(), &mut client.clone()))
+                    .await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Relays datagrams between a UDP socket the client is told about and a haven socket, for as long as the client keeps its TCP connection open.
+    async fn udp_associate(&self, client: TcpStream) -> anyhow::Result<()> {
+        let client_ip = client.peer_addr()?.ip();
+        let relay = UdpSocket::bind((client.local_addr()?.ip(), 0)).await?;
+        let relay_addr = relay.local_addr()?;
+        write_request_status(
+            client.clone(),
+            SocksV5RequestStatus::Success,
+            socks_host(relay_addr.ip()),
+            relay_addr.port(),
+        )
+        .await?;
+        let earendil_skt = self.bind_socket();
+        // where the client sends from, which only its first datagram tells
+        let client_addr: Mutex<Option<SocketAddr>> = Mutex::new(None);
+        let closed = async {
+            let mut buf = [0u8; 1];
+            while client.clone().read(&mut buf).await? > 0 {}
+            anyhow::Ok(())
+        };
+        self.relay_up(&relay, client_ip, &client_addr, &earendil_skt)
+            .race(self.relay_down(&relay, &client_addr, &earendil_skt))
+            .race(closed)
+            .await
+    }
+
+    async fn relay_up(
+        &self,
+        relay: &UdpSocket,
+        client_ip: IpAddr,
+        client_addr: &Mutex<Option<SocketAddr>>,
+        earendil_skt: &Socket,
+    ) -> anyhow::Result<()> {
+        let mut buf = [0u8; 65536];
+        loop {
+            let (n, from) = relay.recv_from(&mut buf).await?;
+            // nobody but the client that asked for the association gets to use it
+            if from.ip() != client_ip {
+                continue;
+            }
+            *client_addr.lock() = Some(from);
+            let Some((host, port, payload)) = parse_udp_datagram(&buf[..n]) else {
+                continue;
+            };
+            match resolve(&self.routes, &host, port) {
+                Ok(endpoint) => {
+                    earendil_skt
+                        .send_to(Bytes::copy_from_slice(payload), endpoint)
+                        .await?
                 }
+                Err(err) => log::debug!("socks5 dropping datagram: {:?}", err),
             }
         }
     }
 
-    Ok(())
+    async fn relay_down(
+        &self,
+        relay: &UdpSocket,
+        client_addr: &Mutex<Option<SocketAddr>>,
+        earendil_skt: &Socket,
+    ) -> anyhow::Result<()> {
+        loop {
+            let (msg, from) = earendil_skt.recv_from().await?;
+            let Some(client_addr) = *client_addr.lock() else {
+                continue;
+            };
+            let Ok(port) = u16::try_from(from.dock) else {
+                continue;
+            };
+            let datagram = udp_datagram(&self.source_host(from.fingerprint), port, &msg);
+            relay.send_to(&datagram, client_addr).await?;
+        }
+    }
+
+    fn bind_socket(&self) -> Socket {
+        let identity = self.identity.unwrap_or_else(IdentitySecret::generate);
+        let skt = Socket::bind_haven_internal(self.ctx.clone(), identity, None, None);
+        if self.route_profile.is_some() {
+            skt.set_route_profile(self.route_profile.clone());
+        }
+        skt
+    }
+
+    /// The address a haven's datagrams appear to come from: an IP address routed to it, or else its `.haven` name.
+    fn source_host(&self, fingerprint: Fingerprint) -> SocksV5Host {
+        self.routes
+            .iter()
+            .find(|(_, fp)| **fp == fingerprint)
+            .map(|(ip, _)| socks_host(*ip))
+            .unwrap_or_else(|| SocksV5Host::Domain(format!("{fingerprint}.haven").into_bytes()))
+    }
+}
+
+/// Finds the haven endpoint a SOCKS5 destination stands for.
+fn resolve(
+    routes: &BTreeMap<IpAddr, Fingerprint>,
+    host: &SocksV5Host,
+    port: u16,
+) -> anyhow::Result<Endpoint> {
+    let fingerprint = match host {
+        SocksV5Host::Ipv4(ip) => routes.get(&IpAddr::from(*ip)).copied(),
+        SocksV5Host::Ipv6(ip) => routes.get(&IpAddr::from(*ip)).copied(),
+        SocksV5Host::Domain(name) => {
+            let name = String::from_utf8_lossy(name);
+            name.strip_suffix(".haven")
+                .map(Fingerprint::from_str)
+                .transpose()?
+        }
+    }
+    .context("destination is not routed to any haven")?;
+    Ok(Endpoint::new(fingerprint, port as Dock))
+}
+
+fn socks_host(ip: IpAddr) -> SocksV5Host {
+    match ip {
+        IpAddr::V4(ip) => SocksV5Host::Ipv4(ip.octets()),
+        IpAddr::V6(ip) => SocksV5Host::Ipv6(ip.octets()),
+    }
+}
+
+/// Splits a datagram from a SOCKS5 client into its destination and payload. Fragmented datagrams aren't supported, and come back as `None` like malformed ones.
+fn parse_udp_datagram(datagram: &[u8]) -> Option<(SocksV5Host, u16, &[u8])> {
+    let (header, rest) = datagram.split_first_chunk::<4>()?;
+    if header[..3] != [0, 0, 0] {
+        return None;
+    }
+    let (host, rest) = match header[3] {
+        1 => {
+            let (ip, rest) = rest.split_first_chunk::<4>()?;
+            (SocksV5Host::Ipv4(*ip), rest)
+        }
+        3 => {
+            let (len, rest) = rest.split_first()?;
+            let name = rest.get(..*len as usize)?;
+            (SocksV5Host::Domain(name.to_vec()), &rest[*len as usize..])
+        }
+        4 => {
+            let (ip, rest) = rest.split_first_chunk::<16>()?;
+            (SocksV5Host::Ipv6(*ip), rest)
+        }
+        _ => return None,
+    };
+    let (port, payload) = rest.split_first_chunk::<2>()?;
+    Some((host, u16::from_be_bytes(*port), payload))
+}
+
+/// Wraps a payload for a SOCKS5 client, saying where it came from.
+fn udp_datagram(host: &SocksV5Host, port: u16, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = vec![0, 0, 0];
+    match host {
+        SocksV5Host::Ipv4(ip) => {
+            datagram.push(1);
+            datagram.extend_from_slice(ip);
+        }
+        SocksV5Host::Domain(name) => {
+            datagram.push(3);
+            datagram.push(name.len() as u8);
+            datagram.extend_from_slice(name);
+        }
+        SocksV5Host::Ipv6(ip) => {
+            datagram.push(4);
+            datagram.extend_from_slice(ip);
+        }
+    }
+    datagram.extend_from_slice(&port.to_be_bytes());
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_udp_datagram() {
+        let host = SocksV5Host::Domain(b"example.haven".to_vec());
+        let datagram = udp_datagram(&host, 53, b"hello");
+        let (parsed_host, port, payload) = parse_udp_datagram(&datagram).unwrap();
+        assert!(matches!(parsed_host, SocksV5Host::Domain(name) if name == b"example.haven"));
+        assert_eq!(port, 53);
+        assert_eq!(payload, b"hello");
+
+        let datagram = udp_datagram(&SocksV5Host::Ipv4([10, 0, 0, 1]), 80, b"");
+        let (parsed_host, port, payload) = parse_udp_datagram(&datagram).unwrap();
+        assert!(matches!(parsed_host, SocksV5Host::Ipv4([10, 0, 0, 1])));
+        assert_eq!(port, 80);
+        assert!(payload.is_empty());
+
+        // fragments and truncated headers are dropped
+        let mut fragment = datagram.clone();
+        fragment[2] = 1;
+        assert!(parse_udp_datagram(&fragment).is_none());
+        assert!(parse_udp_datagram(&datagram[..6]).is_none());
+    }
 }
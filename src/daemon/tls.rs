@@ -0,0 +1,81 @@
+use std::{io::BufReader, path::Path, sync::Arc, time::SystemTime};
+
+use anyhow::Context;
+use async_tls::{TlsAcceptor, TlsConnector};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, PrivateKey, ServerConfig, ServerName,
+};
+
+/// Loads the TLS identity of an in_route from a PEM file holding its certificate chain and private key.
+pub fn load_tls_acceptor(pem_path: &Path) -> anyhow::Result<TlsAcceptor> {
+    let (certs, key) = load_cert_and_key(pem_path, pem_path)?;
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Loads a certificate chain and its private key from PEM files, which may be the same file.
+pub fn load_cert_and_key(
+    cert_path: &Path,
+    key_path: &Path,
+) -> anyhow::Result<(Vec<Certificate>, PrivateKey)> {
+    let certs: Vec<Certificate> = read_pem(cert_path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(cert) => Some(Certificate(cert)),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        anyhow::bail!("no certificate in {}", cert_path.display())
+    }
+    let key = read_pem(key_path)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .with_context(|| format!("no private key in {}", key_path.display()))?;
+    Ok((certs, key))
+}
+
+fn read_pem(path: &Path) -> anyhow::Result<Vec<rustls_pemfile::Item>> {
+    let pem = std::fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+    Ok(rustls_pemfile::read_all(&mut BufReader::new(
+        pem.as_slice(),
+    ))?)
+}
+
+/// Returns a TLS connector that accepts any server certificate. TLS only hides the link from onlookers; the link protocol authenticates the neighbor by its fingerprint afterwards.
+pub fn unverified_tls_connector() -> TlsConnector {
+    TlsConnector::from(Arc::new(unverified_client_config()))
+}
+
+/// A TLS client config that accepts any server certificate, for transports that the link protocol authenticates.
+pub fn unverified_client_config() -> ClientConfig {
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(AnyServerCert))
+        .with_no_client_auth()
+}
+
+struct AnyServerCert;
+
+impl ServerCertVerifier for AnyServerCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
@@ -0,0 +1,39 @@
+use std::time::Instant;
+
+/// A token-bucket rate limiter, which lets through bursts of up to `burst` tokens, by default a second's worth.
+pub struct TokenBucket {
+    tokens_per_sec: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(tokens_per_sec: f64) -> Self {
+        Self::with_burst(tokens_per_sec, tokens_per_sec)
+    }
+
+    /// Creates a token bucket that starts full with `burst` tokens, for rates too slow to allow a second's worth as a burst.
+    pub fn with_burst(tokens_per_sec: f64, burst: f64) -> Self {
+        Self {
+            tokens_per_sec,
+            burst,
+            tokens: burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes `amount` tokens, returning false if there aren't enough. Amounts bigger than the burst get through once the bucket is full.
+    pub fn try_take(&mut self, amount: usize) -> bool {
+        let now = Instant::now();
+        self.tokens = (self.tokens
+            + now.duration_since(self.last_refill).as_secs_f64() * self.tokens_per_sec)
+            .min(self.burst.max(amount as f64));
+        self.last_refill = now;
+        if self.tokens < amount as f64 {
+            return false;
+        }
+        self.tokens -= amount as f64;
+        true
+    }
+}
@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use async_tls::TlsAcceptor;
+use async_tungstenite::{
+    tungstenite::{
+        handshake::server::{ErrorResponse, Request, Response},
+        http::{StatusCode, Uri},
+        protocol::WebSocketConfig,
+        Message,
+    },
+    WebSocketStream,
+};
+use bytes::Bytes;
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use smol::{
+    channel::{Receiver, Sender},
+    future::FutureExt,
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use smol_timeout::TimeoutExt;
+
+use super::{
+    framed_pipe::{FramedPipe, MAX_WIDE_FRAME_LEN},
+    tls::unverified_tls_connector,
+};
+
+/// How long a neighbor gets to finish the TLS and WebSocket handshakes.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Caps WebSocket messages and frames at the longest pipe message, well under tungstenite's defaults of 64 MiB and 16 MiB, since the neighbor isn't authenticated until after the handshake.
+fn ws_config() -> WebSocketConfig {
+    let mut config = WebSocketConfig::default();
+    config.max_message_size = Some(MAX_WIDE_FRAME_LEN);
+    config.max_frame_size = Some(MAX_WIDE_FRAME_LEN);
+    config
+}
+
+/// Accepts a WebSocket connection at `path` from a neighbor that connected to a WebSocket in_route, wrapping it in TLS first if the in_route has a certificate.
+pub async fn accept_ws_pipe(
+    stream: TcpStream,
+    path: String,
+    tls: Option<TlsAcceptor>,
+) -> anyhow::Result<FramedPipe> {
+    let peer_addr = stream.peer_addr()?.to_string();
+    async {
+        match tls {
+            Some(tls) => {
+                let stream = tls.accept(stream).await?;
+                Ok(ws_pipe(accept_at_path(stream, &path).await?, peer_addr))
+            }
+            None => Ok(ws_pipe(accept_at_path(stream, &path).await?, peer_addr)),
+        }
+    }
+    .timeout(HANDSHAKE_TIMEOUT)
+    .await
+    .context("WebSocket handshake timed out")?
+}
+
+/// Dials a `ws://` or `wss://` URL. Certificates of `wss://` servers aren't checked, since the link protocol authenticates the neighbor anyway.
+pub async fn connect_ws_pipe(url: &str) -> anyhow::Result<FramedPipe> {
+    let uri: Uri = url.parse()?;
+    let tls = match uri.scheme_str() {
+        Some("ws") => false,
+        Some("wss") => true,
+        _ => anyhow::bail!("{url} is not a ws:// or wss:// URL"),
+    };
+    let host = uri.host().context("WebSocket URL has no host")?;
+    let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+    let stream = TcpStream::connect((host.trim_matches(&['[', ']'][..]), port)).await?;
+    stream.set_nodelay(true)?;
+    let peer_addr = stream.peer_addr()?.to_string();
+    if tls {
+        let stream = unverified_tls_connector().connect(host, stream).await?;
+        let (ws, _) =
+            async_tungstenite::client_async_with_config(url, stream, Some(ws_config())).await?;
+        Ok(ws_pipe(ws, peer_addr))
+    } else {
+        let (ws, _) =
+            async_tungstenite::client_async_with_config(url, stream, Some(ws_config())).await?;
+        Ok(ws_pipe(ws, peer_addr))
+    }
+}
+
+async fn accept_at_path<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    path: &str,
+) -> anyhow::Result<WebSocketStream<S>> {
+    let check_path = |request: &Request, response: Response| {
+        if request.uri().path() == path {
+            Ok(response)
+        } else {
+            let mut not_found = ErrorResponse::new(None);
+            *not_found.status_mut() = StatusCode::NOT_FOUND;
+            Err(not_found)
+        }
+    };
+    Ok(
+        async_tungstenite::accept_hdr_async_with_config(stream, check_path, Some(ws_config()))
+            .await?,
+    )
+}
+
+/// Carries each pipe message in one binary WebSocket message.
+fn ws_pipe<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    ws: WebSocketStream<S>,
+    peer_addr: String,
+) -> FramedPipe {
+    FramedPipe::spawn("websocket", peer_addr, |recv_outgoing, send_incoming| {
+        let (write, read) = ws.split();
+        write_loop(write, recv_outgoing).or(read_loop(read, send_incoming))
+    })
+}
+
+async fn write_loop<S: AsyncRead + AsyncWrite + Unpin>(
+    mut write: SplitSink<WebSocketStream<S>, Message>,
+    recv_outgoing: Receiver<Bytes>,
+) -> anyhow::Result<()> {
+    loop {
+        let msg = recv_outgoing.recv().await?;
+        write.send(Message::Binary(msg.to_vec())).await?;
+    }
+}
+
+async fn read_loop<S: AsyncRead + AsyncWrite + Unpin>(
+    mut read: SplitStream<WebSocketStream<S>>,
+    send_incoming: Sender<Bytes>,
+) -> anyhow::Result<()> {
+    loop {
+        match read.next().await.context("WebSocket closed")?? {
+            Message::Binary(msg) => send_incoming.send(msg.into()).await?,
+            Message::Close(_) => anyhow::bail!("WebSocket closed"),
+            // pings are answered by the WebSocket itself, and nothing else carries pipe messages
+            _ => {}
+        }
+    }
+}
@@ -3,11 +3,16 @@ pub mod transport;
 
 use async_trait::async_trait;
 
-use earendil_crypt::Fingerprint;
+use bytes::Bytes;
 use earendil_crypt::VerifyError;
+use earendil_crypt::{Fingerprint, IdentityPublic, IdentitySecret};
 use earendil_packet::Dock;
+use earendil_topology::IdentityDescriptor;
 
 use nanorpc::nanorpc_derive;
+use serde::{Deserialize, Serialize};
+use stdcode::StdcodeSerializeExt;
+use thiserror::Error;
 
 use crate::control_protocol::DhtError;
 use crate::haven_util::HavenLocator;
@@ -15,11 +20,20 @@ use crate::haven_util::RegisterHavenReq;
 
 pub const GLOBAL_RPC_DOCK: Dock = 100001;
 
+/// The most haven locators one call to [GlobalRpcProtocol::dht_list] returns.
+pub const DHT_LIST_PAGE_SIZE: usize = 100;
+
+/// Identifies a subscription to a pubsub channel, for unsubscribing.
+pub type SubscriptionId = u64;
+
 #[nanorpc_derive]
 #[async_trait]
 pub trait GlobalRpcProtocol {
     async fn ping(&self, i: u64) -> u64;
 
+    /// Answers a trace-route probe with this node's fingerprint.
+    async fn hop_report(&self) -> Fingerprint;
+
     async fn dht_insert(&self, locator: HavenLocator, recurse: bool) -> Result<(), DhtError>;
 
     async fn dht_get(
@@ -28,5 +42,90 @@ pub trait GlobalRpcProtocol {
         recurse: bool,
     ) -> Result<Option<HavenLocator>, DhtError>;
 
+    /// Returns up to `limit` of the haven locators stored in this node's shard of the DHT, in order of fingerprint, starting after `after`. At most [DHT_LIST_PAGE_SIZE] are returned, however high `limit` is.
+    async fn dht_list(&self, after: Option<Fingerprint>, limit: usize) -> Vec<HavenLocator>;
+
+    /// Stores a relay's own signed identity under a well-known key, such as the bootstrap key.
+    async fn dht_announce_relay(
+        &self,
+        key: Fingerprint,
+        descriptor: IdentityDescriptor,
+    ) -> Result<(), DhtError>;
+
+    /// Returns the relay identities announced under a key.
+    async fn dht_get_relays(
+        &self,
+        key: Fingerprint,
+        recurse: bool,
+    ) -> Result<Vec<IdentityDescriptor>, DhtError>;
+
     async fn alloc_forward(&self, forward_req: RegisterHavenReq) -> Result<(), VerifyError>;
+
+    /// Undoes `alloc_forward`. Only recently signed requests are accepted, so that old registrations can't be replayed to deregister a haven.
+    async fn dealloc_forward(&self, forward_req: RegisterHavenReq) -> Result<(), VerifyError>;
+
+    /// Asks a relay on the given route to keep its links to the hops next to it on the route up until the reservation expires, or for at most 10 minutes.
+    async fn reserve_route(&self, reservation: RouteReservation) -> Result<(), VerifyError>;
+
+    /// Subscribes the calling endpoint to a channel on this relay, so that messages published to it are sent there as [ChannelMessage]s. Subscriptions lapse after a while unless renewed by subscribing again, which keeps the same ID.
+    async fn subscribe(&self, channel: String) -> Result<SubscriptionId, PubsubError>;
+
+    /// Cancels a subscription of the calling endpoint, returning whether there was one.
+    async fn unsubscribe(&self, channel: String, id: SubscriptionId) -> bool;
+
+    /// Sends a message to every subscriber of a channel on this relay, returning how many subscribers there were.
+    async fn publish(&self, channel: String, message: Bytes) -> Result<usize, PubsubError>;
+}
+
+/// A message published to a pubsub channel, as delivered to the channel's subscribers, encoded with stdcode.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChannelMessage {
+    pub channel: String,
+    pub message: Bytes,
+}
+
+#[derive(Error, Serialize, Deserialize, Debug)]
+pub enum PubsubError {
+    #[error("channel has too many subscribers")]
+    ChannelFull,
+    #[error("too many messages published to this channel or relay")]
+    RateLimited,
+    #[error("channel name is too long")]
+    ChannelNameTooLong,
+    #[error("relay has too many channels or subscriptions")]
+    RelayFull,
+}
+
+/// A signed announcement that the requester is about to send traffic along `route`, valid until `expires_at`. It's signed with a throwaway key, so that relays can't tell who uses the route; the signature only keeps it from being altered on the way.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RouteReservation {
+    pub requester_pk: IdentityPublic,
+    pub route: Vec<Fingerprint>,
+    pub expires_at: u64,
+    pub signature: Bytes,
+}
+
+impl RouteReservation {
+    pub fn new(
+        requester_sk: &IdentitySecret,
+        route: Vec<Fingerprint>,
+        expires_at: u64,
+    ) -> RouteReservation {
+        let mut reservation = RouteReservation {
+            requester_pk: requester_sk.public(),
+            route,
+            expires_at,
+            signature: Bytes::new(),
+        };
+        reservation.signature = requester_sk.sign(&reservation.to_sign());
+        reservation
+    }
+
+    pub fn to_sign(&self) -> [u8; 32] {
+        let reservation = RouteReservation {
+            signature: Bytes::new(),
+            ..self.clone()
+        };
+        *blake3::keyed_hash(b"route_reservation_______________", &reservation.stdcode()).as_bytes()
+    }
 }
@@ -1,48 +1,218 @@
-use std::time::Duration;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use async_trait::async_trait;
+use bytes::Bytes;
+use dashmap::DashMap;
 use moka::sync::Cache;
+use parking_lot::Mutex;
+use stdcode::StdcodeSerializeExt;
 
 use crate::{
-    control_protocol::DhtError,
+    control_protocol::{DaemonEvent, DhtError},
     daemon::{
-        context::{CtxField, DaemonContext},
-        dht::{dht_get, dht_insert},
+        context::{CtxField, DaemonContext, GLOBAL_IDENTITY, NEIGH_TABLE},
+        dht::{dht_get, dht_get_relays, dht_insert, forget_locator, locator_ttl},
+        events::EVENTS,
+        token_bucket::TokenBucket,
     },
     haven_util::{HavenLocator, RegisterHavenReq},
+    socket::{n2r_socket::N2rSocket, Endpoint},
 };
 use earendil_crypt::{Fingerprint, VerifyError};
+use earendil_topology::IdentityDescriptor;
 
-use super::GlobalRpcProtocol;
+use super::{
+    ChannelMessage, GlobalRpcProtocol, PubsubError, RouteReservation, SubscriptionId,
+    DHT_LIST_PAGE_SIZE,
+};
 
+/// Serves the global RPC requests of one caller.
 pub struct GlobalRpcImpl {
     ctx: DaemonContext,
+    caller: Endpoint,
 }
 
 impl GlobalRpcImpl {
-    pub fn new(ctx: DaemonContext) -> GlobalRpcImpl {
-        GlobalRpcImpl { ctx }
+    pub fn new(ctx: DaemonContext, caller: Endpoint) -> GlobalRpcImpl {
+        GlobalRpcImpl { ctx, caller }
+    }
+
+    /// Counts an insert of the given haven's locator against its rate limit, returning false if it's over the limit.
+    fn take_insert_token(&self, key: Fingerprint) -> bool {
+        let per_minute = self.ctx.init().dht_inserts_per_minute as f64;
+        self.ctx
+            .get(INSERT_LIMITERS)
+            .get_with(key, || {
+                Arc::new(Mutex::new(TokenBucket::with_burst(
+                    per_minute / 60.0,
+                    per_minute,
+                )))
+            })
+            .lock()
+            .try_take(1)
+    }
+
+    /// Counts an insert for a haven whose locator this node doesn't store yet against the node-wide budget for new keys, returning false if it's used up.
+    fn take_new_key_token(&self) -> bool {
+        self.ctx.get(NEW_KEY_LIMITER).lock().try_take(1)
+    }
+
+    /// Counts a publish to the given channel against its rate limit, returning false if it's over the limit.
+    fn take_publish_token(&self, channel: &str) -> bool {
+        self.ctx
+            .get(PUBLISH_LIMITERS)
+            .get_with(channel.to_string(), || {
+                Arc::new(Mutex::new(TokenBucket::with_burst(
+                    PUBLISHES_PER_SEC,
+                    PUBLISHES_PER_SEC * 2.0,
+                )))
+            })
+            .lock()
+            .try_take(1)
     }
 }
 
-static LOCAL_DHT_SHARD: CtxField<Cache<Fingerprint, HavenLocator>> = |_| {
+pub static LOCAL_DHT_SHARD: CtxField<Cache<Fingerprint, HavenLocator>> = |_| {
     Cache::builder()
         .time_to_live(Duration::from_secs(600))
         .build()
 };
 
+/// Rate limiters of the inserts into [LOCAL_DHT_SHARD], keyed by the fingerprint of the haven whose locator is inserted. Idle limiters are full anyway, so they can be forgotten.
+static INSERT_LIMITERS: CtxField<Cache<Fingerprint, Arc<Mutex<TokenBucket>>>> = |_| {
+    Cache::builder()
+        .max_capacity(100_000)
+        .time_to_idle(Duration::from_secs(60))
+        .build()
+};
+
+/// Rate limiter of the inserts for havens that [LOCAL_DHT_SHARD] has no locator of yet, shared by all of them, since making up new haven identities costs nothing.
+static NEW_KEY_LIMITER: CtxField<Mutex<TokenBucket>> = |ctx| {
+    let per_minute = ctx.init().dht_new_keys_per_minute as f64;
+    Mutex::new(TokenBucket::with_burst(per_minute / 60.0, per_minute))
+};
+
+/// Relay identities announced to this node, keyed by the announcement key and the relay's fingerprint. Bounded, since anyone can make up new relay identities.
+pub static RELAY_ANNOUNCEMENTS: CtxField<Cache<(Fingerprint, Fingerprint), IdentityDescriptor>> =
+    |_| {
+        Cache::builder()
+            .max_capacity(10_000)
+            .time_to_live(Duration::from_secs(600))
+            .build()
+    };
+
+/// How old a deregistration request can be before it's rejected as a possible replay.
+const MAX_DEALLOC_AGE: Duration = Duration::from_secs(60);
+
 pub static REGISTERED_HAVENS: CtxField<Cache<Fingerprint, ()>> = |_| {
     Cache::builder()
         .time_to_live(Duration::from_secs(3600))
         .build()
 };
 
+/// The longest a route reservation keeps links up, however far off its expiry is.
+const MAX_RESERVATION_TTL: Duration = Duration::from_secs(600);
+
+/// Reservations of longer routes are ignored, since no route that long is ever used.
+const MAX_RESERVED_ROUTE_LEN: usize = 16;
+
+/// How long a pubsub subscription lasts unless it's renewed.
+const SUBSCRIPTION_TTL: Duration = Duration::from_secs(600);
+
+/// Each published message is sent once per subscriber, so channels are kept small enough that publishing can't be used to flood the network.
+const MAX_SUBSCRIBERS_PER_CHANNEL: usize = 256;
+
+/// How many channels this node keeps subscribers of at once.
+const MAX_CHANNELS: usize = 10_000;
+
+/// How many subscriptions this node keeps at once, over all channels.
+const MAX_SUBSCRIPTIONS: usize = 100_000;
+
+/// The longest channel name, in bytes.
+const MAX_CHANNEL_NAME_LEN: usize = 64;
+
+const PUBLISHES_PER_SEC: f64 = 5.0;
+
+/// How many published messages this node sends to subscribers per second, over all channels, since the per-channel limit alone doesn't stop anyone from publishing to many channels at once.
+const DELIVERIES_PER_SEC: f64 = 1000.0;
+
+struct Subscriber {
+    id: SubscriptionId,
+    endpoint: Endpoint,
+    renewed_at: Instant,
+}
+
+impl Subscriber {
+    fn is_expired(&self) -> bool {
+        self.renewed_at.elapsed() > SUBSCRIPTION_TTL
+    }
+}
+
+/// Subscribers of the pubsub channels on this node, by channel.
+static CHANNELS: CtxField<DashMap<String, Vec<Subscriber>>> = |_| Default::default();
+
+/// How many subscriptions [CHANNELS] holds, over all channels.
+static SUBSCRIPTION_COUNT: CtxField<AtomicUsize> = |_| Default::default();
+
+/// Rate limiter of the messages sent to subscribers, shared by all channels.
+static DELIVERY_LIMITER: CtxField<Mutex<TokenBucket>> = |_| {
+    Mutex::new(TokenBucket::with_burst(
+        DELIVERIES_PER_SEC,
+        DELIVERIES_PER_SEC,
+    ))
+};
+
+/// Rate limiters of the publishes to each channel.
+static PUBLISH_LIMITERS: CtxField<Cache<String, Arc<Mutex<TokenBucket>>>> = |_| {
+    Cache::builder()
+        .max_capacity(100_000)
+        .time_to_idle(Duration::from_secs(60))
+        .build()
+};
+
+/// The socket that published messages are sent to subscribers from.
+static PUBSUB_SOCKET: CtxField<N2rSocket> =
+    |ctx| N2rSocket::bind(ctx.clone(), *ctx.get(GLOBAL_IDENTITY), None);
+
+/// Forgets expired pubsub subscriptions, and channels left without subscribers.
+pub fn garbage_collect_channels(ctx: &DaemonContext) {
+    ctx.get(CHANNELS).retain(|_, subscribers| {
+        forget_subscribers(ctx, subscribers, |subscriber| subscriber.is_expired());
+        !subscribers.is_empty()
+    });
+}
+
+/// Removes the subscribers of a channel that match `remove`, keeping [SUBSCRIPTION_COUNT] up to date. Returns how many were removed.
+fn forget_subscribers(
+    ctx: &DaemonContext,
+    subscribers: &mut Vec<Subscriber>,
+    mut remove: impl FnMut(&Subscriber) -> bool,
+) -> usize {
+    let before = subscribers.len();
+    subscribers.retain(|subscriber| !remove(subscriber));
+    let removed = before - subscribers.len();
+    ctx.get(SUBSCRIPTION_COUNT)
+        .fetch_sub(removed, Ordering::Relaxed);
+    removed
+}
+
 #[async_trait]
 impl GlobalRpcProtocol for GlobalRpcImpl {
     async fn ping(&self, i: u64) -> u64 {
         i
     }
 
+    async fn hop_report(&self) -> Fingerprint {
+        log::trace!("answering trace-route probe");
+        self.ctx.get(GLOBAL_IDENTITY).public().fingerprint()
+    }
+
     async fn dht_insert(&self, locator: HavenLocator, recurse: bool) -> Result<(), DhtError> {
         let key = locator.identity_pk.fingerprint();
 
@@ -53,7 +223,34 @@ impl GlobalRpcProtocol for GlobalRpcImpl {
                 .identity_pk
                 .verify(&locator.to_sign(), &locator.signature)
                 .map_err(|_| DhtError::VerifyFailed)?;
-            self.ctx.get(LOCAL_DHT_SHARD).insert(key, locator.clone());
+            let shard = self.ctx.get(LOCAL_DHT_SHARD);
+            let stored = shard.get(&key);
+            // a replayed old locator or tombstone mustn't overwrite or delete a locator made after it, and changes nothing, so it doesn't count against the limit either. Tombstones win ties, since a haven can delete its locator within the second it made it.
+            let is_newer = stored.map_or(true, |stored| {
+                stored.created_at < locator.created_at
+                    || (stored.created_at == locator.created_at
+                        && locator.is_tombstone
+                        && !stored.is_tombstone)
+            });
+            if !is_newer {
+                return Ok(());
+            }
+            if !self.take_insert_token(key) {
+                log::debug!("refusing DHT insert for {key}: rate limited");
+                return Err(DhtError::RateLimited);
+            }
+            if !shard.contains_key(&key) && !self.take_new_key_token() {
+                log::debug!("refusing DHT insert for {key}: too many new keys");
+                return Err(DhtError::RateLimited);
+            }
+            // tombstones are stored like locators until they expire, so that lookups learn the haven was deleted rather than finding nothing and trying other caches
+            let is_tombstone = locator.is_tombstone;
+            shard.insert(key, locator);
+            if is_tombstone {
+                forget_locator(&self.ctx, key);
+            } else {
+                self.ctx.get(EVENTS).emit(DaemonEvent::DhtInsert(key));
+            }
         }
         Ok(())
     }
@@ -64,14 +261,69 @@ impl GlobalRpcProtocol for GlobalRpcImpl {
         recurse: bool,
     ) -> Result<Option<HavenLocator>, DhtError> {
         if let Some(val) = self.ctx.get(LOCAL_DHT_SHARD).get(&key) {
-            return Ok(Some(val));
-        } else if recurse {
+            if !val.is_expired(locator_ttl(&self.ctx)) {
+                return Ok(Some(val));
+            }
+            self.ctx.get(LOCAL_DHT_SHARD).invalidate(&key);
+        }
+        if recurse {
             log::debug!("searching DHT for {key}");
             return dht_get(&self.ctx, key).await;
         }
         Ok(None)
     }
 
+    async fn dht_list(&self, after: Option<Fingerprint>, limit: usize) -> Vec<HavenLocator> {
+        let mut locators: Vec<HavenLocator> = self
+            .ctx
+            .get(LOCAL_DHT_SHARD)
+            .iter()
+            .filter(|(key, _)| after.map_or(true, |after| **key > after))
+            .map(|(_, locator)| locator)
+            .filter(|locator| !locator.is_tombstone && !locator.is_expired(locator_ttl(&self.ctx)))
+            .collect();
+        locators.sort_unstable_by_key(|locator| locator.identity_pk.fingerprint());
+        locators.truncate(limit.min(DHT_LIST_PAGE_SIZE));
+        locators
+    }
+
+    async fn dht_announce_relay(
+        &self,
+        key: Fingerprint,
+        descriptor: IdentityDescriptor,
+    ) -> Result<(), DhtError> {
+        descriptor
+            .identity_pk
+            .verify(descriptor.to_sign().as_bytes(), &descriptor.sig)
+            .map_err(|_| DhtError::VerifyFailed)?;
+        if !descriptor.is_relay {
+            return Err(DhtError::VerifyFailed);
+        }
+        self.ctx
+            .get(RELAY_ANNOUNCEMENTS)
+            .insert((key, descriptor.identity_pk.fingerprint()), descriptor);
+        Ok(())
+    }
+
+    async fn dht_get_relays(
+        &self,
+        key: Fingerprint,
+        recurse: bool,
+    ) -> Result<Vec<IdentityDescriptor>, DhtError> {
+        let relays: Vec<IdentityDescriptor> = self
+            .ctx
+            .get(RELAY_ANNOUNCEMENTS)
+            .iter()
+            .filter(|(announced, _)| announced.0 == key)
+            .map(|(_, descriptor)| descriptor)
+            .collect();
+        if relays.is_empty() && recurse {
+            log::debug!("searching DHT for relays announced under {key}");
+            return dht_get_relays(&self.ctx, key).await;
+        }
+        Ok(relays)
+    }
+
     async fn alloc_forward(&self, registration: RegisterHavenReq) -> Result<(), VerifyError> {
         registration
             .identity_pk
@@ -79,6 +331,155 @@ impl GlobalRpcProtocol for GlobalRpcImpl {
         self.ctx
             .get(REGISTERED_HAVENS)
             .insert(registration.identity_pk.fingerprint(), ());
+        self.ctx.get(EVENTS).emit(DaemonEvent::HavenRegistered(
+            registration.identity_pk.fingerprint(),
+        ));
         Ok(())
     }
+
+    async fn dealloc_forward(&self, registration: RegisterHavenReq) -> Result<(), VerifyError> {
+        registration
+            .identity_pk
+            .verify(registration.to_sign().as_bytes(), &registration.sig)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if now.abs_diff(registration.unix_timestamp) > MAX_DEALLOC_AGE.as_secs() {
+            return Err(VerifyError::SignatureMismatch);
+        }
+        self.ctx
+            .get(REGISTERED_HAVENS)
+            .remove(&registration.identity_pk.fingerprint());
+        Ok(())
+    }
+
+    async fn reserve_route(&self, reservation: RouteReservation) -> Result<(), VerifyError> {
+        reservation
+            .requester_pk
+            .verify(&reservation.to_sign(), &reservation.signature)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if reservation.expires_at <= now || reservation.route.len() > MAX_RESERVED_ROUTE_LEN {
+            return Ok(());
+        }
+        let my_fp = self.ctx.get(GLOBAL_IDENTITY).public().fingerprint();
+        let route = &reservation.route;
+        let Some(pos) = route.iter().position(|fp| *fp == my_fp) else {
+            return Ok(());
+        };
+        // reservations are unauthenticated, so all they do is keep links that already exist from expiring, for a bounded time
+        let ttl = Duration::from_secs(reservation.expires_at - now).min(MAX_RESERVATION_TTL);
+        log::debug!(
+            "holding the links of a reserved route of {} hops for {ttl:?}",
+            route.len()
+        );
+        let until = Instant::now() + ttl;
+        let neighs = self.ctx.get(NEIGH_TABLE);
+        let prev_hop = pos.checked_sub(1).map(|i| route[i]);
+        let next_hop = route.get(pos + 1).copied();
+        for hop in prev_hop.into_iter().chain(next_hop) {
+            neighs.hold_until(&hop, until);
+        }
+        Ok(())
+    }
+    async fn subscribe(&self, channel: String) -> Result<SubscriptionId, PubsubError> {
+        if channel.len() > MAX_CHANNEL_NAME_LEN {
+            return Err(PubsubError::ChannelNameTooLong);
+        }
+        let channels = self.ctx.get(CHANNELS);
+        if channels.len() >= MAX_CHANNELS && !channels.contains_key(&channel) {
+            log::debug!("refusing subscription to {channel}: too many channels");
+            return Err(PubsubError::RelayFull);
+        }
+        let mut subscribers = channels.entry(channel).or_default();
+        forget_subscribers(&self.ctx, &mut subscribers, |subscriber| {
+            subscriber.is_expired()
+        });
+        if let Some(subscriber) = subscribers
+            .iter_mut()
+            .find(|subscriber| subscriber.endpoint == self.caller)
+        {
+            subscriber.renewed_at = Instant::now();
+            return Ok(subscriber.id);
+        }
+        if subscribers.len() >= MAX_SUBSCRIBERS_PER_CHANNEL {
+            return Err(PubsubError::ChannelFull);
+        }
+        let count = self.ctx.get(SUBSCRIPTION_COUNT);
+        if count
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                (count < MAX_SUBSCRIPTIONS).then_some(count + 1)
+            })
+            .is_err()
+        {
+            log::debug!("refusing subscription: too many subscriptions");
+            return Err(PubsubError::RelayFull);
+        }
+        let id = rand::random();
+        subscribers.push(Subscriber {
+            id,
+            endpoint: self.caller,
+            renewed_at: Instant::now(),
+        });
+        Ok(id)
+    }
+
+    async fn unsubscribe(&self, channel: String, id: SubscriptionId) -> bool {
+        let Some(mut subscribers) = self.ctx.get(CHANNELS).get_mut(&channel) else {
+            return false;
+        };
+        forget_subscribers(&self.ctx, &mut subscribers, |subscriber| {
+            subscriber.id == id && subscriber.endpoint == self.caller
+        }) > 0
+    }
+
+    async fn publish(&self, channel: String, message: Bytes) -> Result<usize, PubsubError> {
+        if channel.len() > MAX_CHANNEL_NAME_LEN {
+            return Err(PubsubError::ChannelNameTooLong);
+        }
+        if !self.take_publish_token(&channel) {
+            log::debug!("refusing publish to {channel}: rate limited");
+            return Err(PubsubError::RateLimited);
+        }
+        let endpoints: Vec<Endpoint> = match self.ctx.get(CHANNELS).get_mut(&channel) {
+            Some(mut subscribers) => {
+                forget_subscribers(&self.ctx, &mut subscribers, |subscriber| {
+                    subscriber.is_expired()
+                });
+                subscribers
+                    .iter()
+                    .map(|subscriber| subscriber.endpoint)
+                    .collect()
+            }
+            None => vec![],
+        };
+        if !endpoints.is_empty()
+            && !self
+                .ctx
+                .get(DELIVERY_LIMITER)
+                .lock()
+                .try_take(endpoints.len())
+        {
+            log::debug!("refusing publish to {channel}: delivery budget used up");
+            return Err(PubsubError::RateLimited);
+        }
+        let body = Bytes::from(ChannelMessage { channel, message }.stdcode());
+        for &endpoint in endpoints.iter() {
+            let ctx = self.ctx.clone();
+            let body = body.clone();
+            smolscale::spawn(async move {
+                if let Err(err) = ctx.get(PUBSUB_SOCKET).send_to(body, endpoint).await {
+                    log::debug!(
+                        "could not deliver published message to {endpoint}: {:?}",
+                        err
+                    );
+                }
+            })
+            .detach();
+        }
+        Ok(endpoints.len())
+    }
 }
@@ -1,22 +1,55 @@
-use std::time::{Duration, Instant};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
+use dashmap::DashMap;
 use earendil_crypt::{Fingerprint, IdentitySecret};
 use futures_util::{future, FutureExt};
-use nanorpc::{JrpcRequest, JrpcResponse, RpcTransport};
-use smol::Timer;
+use nanorpc::{JrpcId, JrpcRequest, JrpcResponse, RpcTransport};
+use parking_lot::Mutex;
+use smol::{channel::Sender, Task, Timer};
+use smol_timeout::TimeoutExt;
 
 use crate::{
-    daemon::context::DaemonContext,
+    control_protocol::GlobalRpcError,
+    daemon::context::{CtxField, DaemonContext},
     socket::{n2r_socket::N2rSocket, Endpoint},
 };
 
 use super::GLOBAL_RPC_DOCK;
 
+const DEFAULT_MAX_RETRIES: u32 = 5;
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How many calls in a row to a destination must fail for its circuit to open. Circuits are shared by every transport to the destination, so this isn't configurable per transport.
+const FAILURE_THRESHOLD: u32 = 3;
+/// How long an open circuit fails calls before letting a trial call through.
+const OPEN_DURATION: Duration = Duration::from_secs(30);
+
+/// Circuit-breaker state of every destination that recently failed. Destinations without an entry are closed.
+static CIRCUIT_STATES: CtxField<DashMap<Fingerprint, CircuitState>> = |_| Default::default();
+
+#[derive(Clone, Copy)]
+enum CircuitState {
+    /// Calls go through; this many calls in a row have failed.
+    Closed { failures: u32 },
+    /// Calls fail immediately until the given time.
+    Open { until: Instant },
+    /// A single trial call is in flight. If it never finishes, another trial is allowed after the given time.
+    HalfOpen { retry_after: Instant },
+}
+
 pub struct GlobalRpcTransport {
     ctx: DaemonContext,
     anon_isk: IdentitySecret,
     dest_fp: Fingerprint,
+    max_retries: u32,
+    max_backoff: Duration,
+    /// bound on first use and reused by every call, so that all calls come from the same endpoint
+    socket: Mutex<Option<(N2rSocket, Task<()>)>>,
+    /// calls waiting for a response, keyed by their serialized request id
+    pending_calls: Arc<DashMap<String, Sender<JrpcResponse>>>,
 }
 
 impl GlobalRpcTransport {
@@ -25,10 +58,153 @@ impl GlobalRpcTransport {
         anon_isk: IdentitySecret,
         dest_fp: Fingerprint,
     ) -> GlobalRpcTransport {
-        GlobalRpcTransport {
+        GlobalRpcTransport::builder(ctx, anon_isk, dest_fp).build()
+    }
+
+    pub fn builder(
+        ctx: DaemonContext,
+        anon_isk: IdentitySecret,
+        dest_fp: Fingerprint,
+    ) -> GlobalRpcTransportBuilder {
+        GlobalRpcTransportBuilder {
             ctx,
             anon_isk,
             dest_fp,
+            max_retries: DEFAULT_MAX_RETRIES,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+        }
+    }
+
+    /// Sends a JSON-RPC notification, which the destination executes without responding. Returns once the notification has left the socket, so the transport can be dropped right after.
+    pub async fn send_notification(
+        &self,
+        method: &str,
+        args: &[serde_json::Value],
+    ) -> anyhow::Result<()> {
+        log::debug!("=====> {}/{} (notification)", self.dest_fp, method);
+        let notification = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": args,
+        });
+        let endpoint = Endpoint::new(self.dest_fp, GLOBAL_RPC_DOCK);
+        let socket = self.socket();
+        if let Err(err) = socket
+            .send_to(serde_json::to_string(&notification)?.into(), endpoint)
+            .await
+        {
+            self.reset_socket();
+            return Err(err.into());
+        }
+        // dropping the socket stops its send batcher, which would lose the notification
+        while socket.pending_outgoing() > 0 {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+        Ok(())
+    }
+
+    /// Closes the circuit to the destination, so that calls to it are attempted again right away.
+    pub fn reset_circuit(&self) {
+        self.ctx.get(CIRCUIT_STATES).remove(&self.dest_fp);
+    }
+
+    /// Checks whether a call to the destination may go through, moving an expired open circuit to half-open.
+    fn enter_circuit(&self) -> Result<(), GlobalRpcError> {
+        let states = self.ctx.get(CIRCUIT_STATES);
+        let mut state = match states.get_mut(&self.dest_fp) {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+        match *state {
+            CircuitState::Closed { .. } => Ok(()),
+            CircuitState::Open { until } | CircuitState::HalfOpen { retry_after: until }
+                if Instant::now() < until =>
+            {
+                Err(GlobalRpcError::CircuitOpen)
+            }
+            _ => {
+                log::debug!("circuit to {} is half-open; trying once more", self.dest_fp);
+                *state = CircuitState::HalfOpen {
+                    retry_after: Instant::now() + OPEN_DURATION,
+                };
+                Ok(())
+            }
+        }
+    }
+
+    fn record_failure(&self) {
+        let states = self.ctx.get(CIRCUIT_STATES);
+        let mut state = states
+            .entry(self.dest_fp)
+            .or_insert(CircuitState::Closed { failures: 0 });
+        let failures = match *state {
+            CircuitState::Closed { failures } => failures + 1,
+            _ => FAILURE_THRESHOLD,
+        };
+        *state = if failures >= FAILURE_THRESHOLD {
+            log::debug!(
+                "opening circuit to {} after {failures} failures",
+                self.dest_fp
+            );
+            CircuitState::Open {
+                until: Instant::now() + OPEN_DURATION,
+            }
+        } else {
+            CircuitState::Closed { failures }
+        };
+    }
+
+    /// Returns the shared socket, binding a new one if there is none or if the previous one stopped receiving.
+    fn socket(&self) -> N2rSocket {
+        let mut slot = self.socket.lock();
+        if let Some((socket, demux)) = slot.as_ref() {
+            if !demux.is_finished() {
+                return socket.clone();
+            }
+        }
+        let socket = N2rSocket::bind(self.ctx.clone(), self.anon_isk, None);
+        let demux = smolscale::spawn(demux_responses(socket.clone(), self.pending_calls.clone()));
+        *slot = Some((socket.clone(), demux));
+        socket
+    }
+
+    /// Forgets the current socket, so that the next call binds a fresh one.
+    fn reset_socket(&self) {
+        *self.socket.lock() = None;
+    }
+}
+
+/// Builds a [GlobalRpcTransport] with non-default retry behavior.
+pub struct GlobalRpcTransportBuilder {
+    ctx: DaemonContext,
+    anon_isk: IdentitySecret,
+    dest_fp: Fingerprint,
+    max_retries: u32,
+    max_backoff: Duration,
+}
+
+impl GlobalRpcTransportBuilder {
+    /// Sets how many times a request is sent before giving up, at least once. Defaults to 5.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries.max(1);
+        self
+    }
+
+    /// Sets the longest time to wait for a response before resending. Defaults to 60 seconds.
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    pub fn build(self) -> GlobalRpcTransport {
+        GlobalRpcTransport {
+            ctx: self.ctx,
+            anon_isk: self.anon_isk,
+            dest_fp: self.dest_fp,
+            max_retries: self.max_retries,
+            max_backoff: self.max_backoff,
+            socket: Mutex::new(None),
+            pending_calls: Default::default(),
         }
     }
 }
@@ -38,27 +214,77 @@ impl RpcTransport for GlobalRpcTransport {
     type Error = anyhow::Error;
 
     async fn call_raw(&self, req: JrpcRequest) -> Result<JrpcResponse, Self::Error> {
+        self.enter_circuit()?;
+        match self.call_with_retries(req).await {
+            Ok(jrpc_res) => {
+                self.ctx.get(CIRCUIT_STATES).remove(&self.dest_fp);
+                Ok(jrpc_res)
+            }
+            Err(err) => {
+                self.record_failure();
+                Err(err)
+            }
+        }
+    }
+}
+
+impl GlobalRpcTransport {
+    /// How long to wait for a response to the attempt with the given number of earlier retries.
+    fn attempt_timeout(&self, retries: u32) -> Duration {
+        Duration::from_secs(2u64.saturating_pow(retries + 1)).min(self.max_backoff)
+    }
+
+    /// Sends the request until a response arrives, giving up after `max_retries` attempts or once they've had all the time they're owed, whichever comes first. Cancel-safe: dropping the future only forgets the pending call.
+    async fn call_with_retries(&self, req: JrpcRequest) -> anyhow::Result<JrpcResponse> {
         log::debug!("=====> {}/{} ({:?})", self.dest_fp, req.method, req.id);
         let endpoint = Endpoint::new(self.dest_fp, GLOBAL_RPC_DOCK);
-        let socket = N2rSocket::bind(self.ctx.clone(), self.anon_isk, None);
+        let key = call_key(&req.id);
+        let (send_resp, recv_resp) = smol::channel::bounded(1);
+        self.pending_calls.insert(key.clone(), send_resp);
+        let _guard = scopeguard::guard((), |_| {
+            self.pending_calls.remove(&key);
+        });
+        let start = Instant::now();
+        // bounded by wall time as well as by attempts, so that stalled sends can't stretch the call out indefinitely
+        let deadline = start
+            + (0..self.max_retries)
+                .map(|retries| self.attempt_timeout(retries))
+                .sum::<Duration>();
         let mut retries = 0;
-        let mut timeout: Duration;
 
         loop {
-            socket
+            if retries >= self.max_retries || Instant::now() >= deadline {
+                anyhow::bail!(
+                    "no GlobalRPC response from {} after {retries} attempts ({:?})",
+                    self.dest_fp,
+                    start.elapsed()
+                );
+            }
+            match self
+                .socket()
                 .send_to(serde_json::to_string(&req)?.into(), endpoint)
-                .await?;
+                .timeout(deadline.saturating_duration_since(Instant::now()))
+                .await
+            {
+                Some(Ok(())) => {}
+                Some(Err(err)) => {
+                    self.reset_socket();
+                    return Err(err.into());
+                }
+                None => anyhow::bail!(
+                    "timed out sending GlobalRPC request to {} ({:?})",
+                    self.dest_fp,
+                    start.elapsed()
+                ),
+            }
 
-            timeout = Duration::from_secs(2u64.pow(retries + 1));
-            let when = Instant::now() + timeout;
+            let when = (Instant::now() + self.attempt_timeout(retries)).min(deadline);
             let timer = Timer::at(when);
-            let recv_future = Box::pin(socket.recv_from());
+            let recv_future = Box::pin(recv_resp.recv());
 
             match future::select(recv_future, timer.fuse()).await {
                 future::Either::Left((res, _)) => match res {
-                    Ok((res, _endpoint)) => {
-                        let jrpc_res: JrpcResponse =
-                            serde_json::from_str(&String::from_utf8(res.to_vec())?)?;
+                    Ok(jrpc_res) => {
                         log::debug!("<===== {}/{} ({:?})", self.dest_fp, req.method, req.id);
                         return Ok(jrpc_res);
                     }
@@ -74,3 +300,39 @@ impl RpcTransport for GlobalRpcTransport {
         }
     }
 }
+
+/// Routes every response arriving at the socket to the call waiting for it.
+async fn demux_responses(
+    socket: N2rSocket,
+    pending_calls: Arc<DashMap<String, Sender<JrpcResponse>>>,
+) {
+    loop {
+        let msg = match socket.recv_from().await {
+            Ok((msg, _endpoint)) => msg,
+            Err(err) => {
+                log::debug!("GlobalRPC socket stopped receiving: {err}");
+                return;
+            }
+        };
+        let jrpc_res: JrpcResponse = match serde_json::from_slice(&msg) {
+            Ok(jrpc_res) => jrpc_res,
+            Err(err) => {
+                log::debug!("dropping malformed GlobalRPC response: {err}");
+                continue;
+            }
+        };
+        // the entry is removed by the first response, so responses to retransmissions are dropped
+        if let Some((_, send_resp)) = pending_calls.remove(&call_key(&jrpc_res.id)) {
+            let _ = send_resp.try_send(jrpc_res);
+        } else {
+            log::debug!(
+                "dropping GlobalRPC response to unknown call {:?}",
+                jrpc_res.id
+            );
+        }
+    }
+}
+
+fn call_key(id: &JrpcId) -> String {
+    serde_json::to_string(id).unwrap_or_default()
+}
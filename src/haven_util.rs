@@ -1,13 +1,20 @@
+mod dns;
+mod http_connect_proxy;
+
 use std::{
     net::SocketAddr,
     sync::Arc,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use bytes::Bytes;
 use clone_macro::clone;
+use dashmap::DashMap;
 use earendil_crypt::{Fingerprint, IdentityPublic, IdentitySecret};
-use earendil_packet::{crypt::OnionPublic, Dock};
+use earendil_packet::{
+    crypt::{OnionPublic, OnionSecret},
+    Dock,
+};
 use futures_util::io;
 use moka::sync::{Cache, CacheBuilder};
 use serde::{Deserialize, Serialize};
@@ -20,19 +27,42 @@ use smolscale::{immortal::Immortal, reaper::TaskReaper};
 use stdcode::StdcodeSerializeExt;
 
 use crate::{
-    config::{ForwardHandler, HavenForwardConfig},
-    daemon::context::DaemonContext,
-    socket::{Endpoint, Socket},
+    config::{Fallback, ForwardHandler, HavenForwardConfig},
+    daemon::{
+        context::{CtxField, DaemonContext},
+        socks5::Socks5Proxy,
+    },
+    socket::{Endpoint, HavenSocketConfig, Socket},
     stream::StreamListener,
 };
 
+use self::{
+    dns::{dns_proxy, dns_resolver},
+    http_connect_proxy::http_connect_proxy,
+};
+
 pub const HAVEN_FORWARD_DOCK: Dock = 100002;
 
+/// The least time between two runs of a haven's `on_fail_command`, which doubles with every run up to [MAX_ON_FAIL_BACKOFF].
+const MIN_ON_FAIL_BACKOFF: Duration = Duration::from_secs(10);
+
+const MAX_ON_FAIL_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// For each haven whose `on_fail_command` ran, when it may run next and the backoff that set that time. Havens restart right after failing, so without this a haven that fails at once would run its command in a tight loop.
+static ON_FAIL_BACKOFF: CtxField<DashMap<Fingerprint, (Instant, Duration)>> =
+    |_| Default::default();
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct HavenLocator {
     pub identity_pk: IdentityPublic,
     pub onion_pk: OnionPublic,
     pub rendezvous_point: Fingerprint,
+    /// other rendezvous points the haven is registered with, which also forward to it; clients fall over to these, in order, when the primary stops answering
+    pub rendezvous_backups: Vec<Fingerprint>,
+    /// when the locator was made, as a UNIX timestamp; covered by the signature, so that stale locators can't be passed off as fresh
+    pub created_at: u64,
+    /// whether this marks the haven as gone, rather than locating it; replicas store it in place of the locator until it expires, so that lookups learn of the deletion
+    pub is_tombstone: bool,
     pub signature: Bytes,
 }
 
@@ -41,22 +71,52 @@ impl HavenLocator {
         identity_sk: IdentitySecret,
         onion_pk: OnionPublic,
         rendezvous_fingerprint: Fingerprint,
+    ) -> HavenLocator {
+        Self::with_backups(identity_sk, onion_pk, rendezvous_fingerprint, vec![])
+    }
+
+    pub fn with_backups(
+        identity_sk: IdentitySecret,
+        onion_pk: OnionPublic,
+        rendezvous_fingerprint: Fingerprint,
+        rendezvous_backups: Vec<Fingerprint>,
     ) -> HavenLocator {
         let identity_pk = identity_sk.public();
-        let locator = HavenLocator {
+        let mut locator = HavenLocator {
             identity_pk,
             onion_pk,
             rendezvous_point: rendezvous_fingerprint,
+            rendezvous_backups,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            is_tombstone: false,
             signature: Bytes::new(),
         };
-        let signature = identity_sk.sign(&locator.to_sign());
+        locator.signature = identity_sk.sign(&locator.to_sign());
+        locator
+    }
 
-        HavenLocator {
-            identity_pk,
-            onion_pk,
-            rendezvous_point: rendezvous_fingerprint,
-            signature,
-        }
+    /// Creates a tombstone, which deletes the haven's locator from the DHT. Its onion key and rendezvous point are meaningless.
+    pub fn tombstone(identity_sk: IdentitySecret) -> HavenLocator {
+        let mut locator = Self::new(
+            identity_sk,
+            OnionSecret::generate().public(),
+            identity_sk.public().fingerprint(),
+        );
+        locator.is_tombstone = true;
+        locator.signature = identity_sk.sign(&locator.to_sign());
+        locator
+    }
+
+    /// Whether the locator was made more than `ttl` ago, so that the haven may well have gone away since.
+    pub fn is_expired(&self, ttl: Duration) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        now.saturating_sub(self.created_at) > ttl.as_secs()
     }
 
     pub fn to_sign(&self) -> [u8; 32] {
@@ -64,6 +124,9 @@ impl HavenLocator {
             identity_pk: self.identity_pk,
             onion_pk: self.onion_pk,
             rendezvous_point: self.rendezvous_point,
+            rendezvous_backups: self.rendezvous_backups.clone(),
+            created_at: self.created_at,
+            is_tombstone: self.is_tombstone,
             signature: Bytes::new(),
         };
         let hash = blake3::keyed_hash(b"haven_locator___________________", &locator.stdcode());
@@ -107,7 +170,10 @@ impl RegisterHavenReq {
 /// Starts a "down" loop that listens for incoming UDP traffic in the reverse direction and
 /// forwards it back to the earnedil network.
 pub async fn haven_loop(ctx: DaemonContext, haven_cfg: HavenForwardConfig) -> anyhow::Result<()> {
-    match haven_cfg.handler {
+    let on_fail_command = haven_cfg.on_fail_command.clone();
+    let on_fail_ctx = ctx.clone();
+    let haven_fp = haven_cfg.identity.actualize()?.public().fingerprint();
+    let result = match haven_cfg.handler {
         ForwardHandler::UdpService {
             listen_dock,
             upstream,
@@ -119,6 +185,98 @@ pub async fn haven_loop(ctx: DaemonContext, haven_cfg: HavenForwardConfig) -> an
         ForwardHandler::SimpleProxy { listen_dock } => {
             simple_proxy(ctx, haven_cfg, listen_dock).await
         }
+        ForwardHandler::HttpConnectProxy {
+            listen_dock,
+            ref allowed_domains,
+        } => {
+            let allowed_domains = allowed_domains.clone();
+            http_connect_proxy(ctx, haven_cfg, listen_dock, allowed_domains).await
+        }
+        ForwardHandler::DnsResolver {
+            listen_dock,
+            upstream,
+        } => dns_resolver(ctx, haven_cfg, listen_dock, upstream).await,
+        ForwardHandler::DnsProxy {
+            listen,
+            resolver_haven,
+            resolver_dock,
+        } => {
+            let resolver = Endpoint::new(resolver_haven, resolver_dock);
+            dns_proxy(ctx, haven_cfg, listen, resolver).await
+        }
+        ForwardHandler::Socks5Proxy {
+            listen,
+            ref routes,
+            use_haven_identity,
+        } => {
+            let identity = if use_haven_identity {
+                let haven_id = haven_cfg.identity.actualize()?;
+                log::debug!(
+                    "SOCKS5 proxy haven fingerprint: {}",
+                    haven_id.public().fingerprint()
+                );
+                Some(haven_id)
+            } else {
+                None
+            };
+            let proxy = Socks5Proxy {
+                route_profile: haven_cfg
+                    .route_profile
+                    .as_ref()
+                    .and_then(|name| ctx.init().profiles.get(name).cloned()),
+                ctx,
+                routes: routes.clone(),
+                fallback: Fallback::Block,
+                identity,
+            };
+            proxy.serve(listen).await
+        }
+    };
+    if let (Err(err), Some(command)) = (&result, on_fail_command) {
+        if take_on_fail_turn(&on_fail_ctx, haven_fp) {
+            // detached, so that a slow command doesn't hold up restarting the haven
+            smolscale::spawn(run_on_fail_command(command, haven_fp, format!("{err:?}"))).detach();
+        } else {
+            log::debug!("haven {haven_fp} failed again too soon to run its on_fail_command");
+        }
+    }
+    result
+}
+
+/// Decides whether a haven that just failed gets to run its `on_fail_command`, backing off exponentially while it keeps failing. A haven that stayed quiet for as long as its backoff starts over from [MIN_ON_FAIL_BACKOFF].
+fn take_on_fail_turn(ctx: &DaemonContext, haven_fp: Fingerprint) -> bool {
+    let now = Instant::now();
+    let mut entry = ctx
+        .get(ON_FAIL_BACKOFF)
+        .entry(haven_fp)
+        .or_insert((now, Duration::ZERO));
+    let (next_run, backoff) = *entry;
+    if now < next_run {
+        return false;
+    }
+    let backoff = if now > next_run + backoff {
+        MIN_ON_FAIL_BACKOFF
+    } else {
+        (backoff * 2).clamp(MIN_ON_FAIL_BACKOFF, MAX_ON_FAIL_BACKOFF)
+    };
+    *entry = (now + backoff, backoff);
+    true
+}
+
+/// Runs a haven's `on_fail_command` through the shell, telling it which haven failed and why.
+async fn run_on_fail_command(command: String, haven_fp: Fingerprint, reason: String) {
+    log::warn!("haven {haven_fp} failed, running `{command}`");
+    let status = smol::process::Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .env("EARENDIL_HAVEN_FP", haven_fp.to_string())
+        .env("EARENDIL_FAIL_REASON", reason)
+        .status()
+        .await;
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => log::warn!("on_fail_command of haven {haven_fp} exited with {status}"),
+        Err(err) => log::warn!("could not run on_fail_command of haven {haven_fp}: {err}"),
     }
 }
 
@@ -148,12 +306,14 @@ async fn udp_forward(
         haven_id.public().fingerprint()
     );
 
-    let earendil_skt = Arc::new(Socket::bind_haven_internal(
+    let earendil_skt = Arc::new(Socket::bind_haven_internal_with_config(
         ctx.clone(),
         haven_id,
         Some(listen_dock),
-        Some(haven_cfg.rendezvous),
+        vec![haven_cfg.rendezvous],
+        haven_socket_config(&haven_cfg),
     ));
+    set_haven_route_profile(&ctx, &haven_cfg, &earendil_skt);
     let dmux_table: Cache<Endpoint, (Arc<UdpSocket>, Arc<Immortal>)> = CacheBuilder::default()
         .time_to_idle(Duration::from_secs(60 * 60))
         .build();
@@ -180,6 +340,22 @@ async fn udp_forward(
     }
 }
 
+/// The socket tunables of a haven, which only accepts authenticated clients if it has an allowlist of them.
+fn haven_socket_config(haven_cfg: &HavenForwardConfig) -> HavenSocketConfig {
+    HavenSocketConfig {
+        require_client_auth: !haven_cfg.allowed_clients.is_empty(),
+        allowed_clients: haven_cfg.allowed_clients.clone(),
+        ..Default::default()
+    }
+}
+
+/// Applies the route profile a haven's config names, which validating the config ensures exists.
+fn set_haven_route_profile(ctx: &DaemonContext, haven_cfg: &HavenForwardConfig, skt: &Socket) {
+    if let Some(name) = &haven_cfg.route_profile {
+        skt.set_route_profile(ctx.init().profiles.get(name).cloned());
+    }
+}
+
 async fn tcp_forward(
     ctx: DaemonContext,
     haven_cfg: HavenForwardConfig,
@@ -192,12 +368,14 @@ async fn tcp_forward(
         haven_id.public().fingerprint()
     );
 
-    let earendil_skt = Socket::bind_haven_internal(
+    let earendil_skt = Socket::bind_haven_internal_with_config(
         ctx.clone(),
         haven_id,
         Some(listen_dock),
-        Some(haven_cfg.rendezvous),
+        vec![haven_cfg.rendezvous],
+        haven_socket_config(&haven_cfg),
     );
+    set_haven_route_profile(&ctx, &haven_cfg, &earendil_skt);
 
     let mut listener = StreamListener::listen(earendil_skt);
 
@@ -227,12 +405,14 @@ async fn simple_proxy(
         haven_id.public().fingerprint()
     );
 
-    let earendil_skt = Socket::bind_haven_internal(
+    let earendil_skt = Socket::bind_haven_internal_with_config(
         ctx.clone(),
         haven_id,
         Some(listen_dock),
-        Some(haven_cfg.rendezvous),
+        vec![haven_cfg.rendezvous],
+        haven_socket_config(&haven_cfg),
     );
+    set_haven_route_profile(&ctx, &haven_cfg, &earendil_skt);
 
     let mut listener = StreamListener::listen(earendil_skt);
 
@@ -260,3 +440,50 @@ async fn simple_proxy(
         }));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locator_expiry() {
+        let isk = IdentitySecret::generate();
+        let mut locator = HavenLocator::new(
+            isk,
+            OnionSecret::generate().public(),
+            IdentitySecret::generate().public().fingerprint(),
+        );
+        assert!(!locator.is_expired(Duration::from_secs(3600)));
+
+        assert!(isk
+            .public()
+            .verify(&locator.to_sign(), &locator.signature)
+            .is_ok());
+
+        // Testing that the timestamp is covered by the signature
+        locator.created_at -= 7200;
+        assert!(locator.is_expired(Duration::from_secs(3600)));
+        assert!(isk
+            .public()
+            .verify(&locator.to_sign(), &locator.signature)
+            .is_err());
+    }
+
+    #[test]
+    fn test_tombstone() {
+        let isk = IdentitySecret::generate();
+        let mut tombstone = HavenLocator::tombstone(isk);
+        assert!(tombstone.is_tombstone);
+        assert!(isk
+            .public()
+            .verify(&tombstone.to_sign(), &tombstone.signature)
+            .is_ok());
+
+        // Testing that a tombstone can't be turned into a live locator
+        tombstone.is_tombstone = false;
+        assert!(isk
+            .public()
+            .verify(&tombstone.to_sign(), &tombstone.signature)
+            .is_err());
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use clone_macro::clone;
+use earendil_crypt::IdentitySecret;
+use earendil_packet::Dock;
+use moka::sync::{Cache, CacheBuilder};
+use smol::{future::FutureExt, lock::Semaphore, net::UdpSocket};
+use smol_timeout::TimeoutExt;
+use smolscale::reaper::TaskReaper;
+
+use crate::{
+    config::HavenForwardConfig,
+    daemon::context::DaemonContext,
+    socket::{Endpoint, Socket},
+};
+
+use super::{haven_socket_config, set_haven_route_profile};
+
+/// How long the upstream resolver gets to answer a query.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many queries a resolver haven asks upstream at once; queries beyond that are dropped, as a busy resolver would.
+const MAX_CONCURRENT_QUERIES: usize = 256;
+
+/// Answers DNS queries arriving as haven datagrams by asking `upstream`, each over a UDP socket of its own so that whatever comes back on it is the answer to that query.
+pub async fn dns_resolver(
+    ctx: DaemonContext,
+    haven_cfg: HavenForwardConfig,
+    listen_dock: Dock,
+    upstream: SocketAddr,
+) -> anyhow::Result<()> {
+    let haven_id = haven_cfg.identity.actualize()?;
+    log::debug!(
+        "DNS resolver haven fingerprint: {}",
+        haven_id.public().fingerprint()
+    );
+
+    let earendil_skt = Arc::new(Socket::bind_haven_internal_with_config(
+        ctx.clone(),
+        haven_id,
+        Some(listen_dock),
+        vec![haven_cfg.rendezvous],
+        haven_socket_config(&haven_cfg),
+    ));
+    set_haven_route_profile(&ctx, &haven_cfg, &earendil_skt);
+
+    let reaper = TaskReaper::new();
+    let in_flight = Arc::new(Semaphore::new(MAX_CONCURRENT_QUERIES));
+    loop {
+        let (query, src_endpoint) = earendil_skt.recv_from().await?;
+        let Some(permit) = in_flight.try_acquire_arc() else {
+            log::debug!("dropping DNS query from {src_endpoint}: too many in flight");
+            continue;
+        };
+        reaper.attach(smolscale::spawn(clone!([earendil_skt], async move {
+            let _permit = permit;
+            let upstream_skt = UdpSocket::bind(if upstream.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            })
+            .await?;
+            upstream_skt.connect(upstream).await?;
+            upstream_skt.send(&query).await?;
+            let mut buf = [0u8; 65536];
+            let n = upstream_skt
+                .recv(&mut buf)
+                .timeout(UPSTREAM_TIMEOUT)
+                .await
+                .context("upstream resolver timed out")??;
+            earendil_skt
+                .send_to(Bytes::copy_from_slice(&buf[..n]), src_endpoint)
+                .await?;
+            anyhow::Ok(())
+        })));
+    }
+}
+
+/// Serves DNS over UDP on `listen`, passing each query on to the DNS resolver haven at `resolver`. Queries go out under a fresh identity every time the proxy starts, rather than the haven's, so that the resolver can't tie them to it.
+pub async fn dns_proxy(
+    ctx: DaemonContext,
+    haven_cfg: HavenForwardConfig,
+    listen: SocketAddr,
+    resolver: Endpoint,
+) -> anyhow::Result<()> {
+    let udp_skt = UdpSocket::bind(listen).await?;
+    let earendil_skt =
+        Socket::bind_haven_internal(ctx.clone(), IdentitySecret::generate(), None, None);
+    set_haven_route_profile(&ctx, &haven_cfg, &earendil_skt);
+    // queries from different clients may share an ID, so each is sent on with one of our own
+    let pending: Cache<u16, (u16, SocketAddr)> = CacheBuilder::default()
+        .time_to_live(UPSTREAM_TIMEOUT * 2)
+        .build();
+    let next_id = AtomicU16::new(rand::random());
+
+    proxy_queries(&udp_skt, &earendil_skt, resolver, &pending, &next_id)
+        .race(proxy_answers(&udp_skt, &earendil_skt, resolver, &pending))
+        .await
+}
+
+async fn proxy_queries(
+    udp_skt: &UdpSocket,
+    earendil_skt: &Socket,
+    resolver: Endpoint,
+    pending: &Cache<u16, (u16, SocketAddr)>,
+    next_id: &AtomicU16,
+) -> anyhow::Result<()> {
+    let mut buf = [0u8; 65536];
+    loop {
+        let (n, client) = udp_skt.recv_from(&mut buf).await?;
+        let mut query = buf[..n].to_vec();
+        let Some(id) = message_id(&query) else {
+            continue;
+        };
+        let proxied_id = next_id.fetch_add(1, Ordering::Relaxed);
+        pending.insert(proxied_id, (id, client));
+        query[..2].copy_from_slice(&proxied_id.to_be_bytes());
+        earendil_skt.send_to(query.into(), resolver).await?;
+    }
+}
+
+async fn proxy_answers(
+    udp_skt: &UdpSocket,
+    earendil_skt: &Socket,
+    resolver: Endpoint,
+    pending: &Cache<u16, (u16, SocketAddr)>,
+) -> anyhow::Result<()> {
+    loop {
+        let (answer, src_endpoint) = earendil_skt.recv_from().await?;
+        if src_endpoint != resolver {
+            continue;
+        }
+        let mut answer = answer.to_vec();
+        let Some((id, client)) = message_id(&answer).and_then(|id| pending.remove(&id)) else {
+            continue;
+        };
+        answer[..2].copy_from_slice(&id.to_be_bytes());
+        udp_skt.send_to(&answer, client).await?;
+    }
+}
+
+/// The ID in the header of a DNS message, if it's long enough to have a header.
+fn message_id(message: &[u8]) -> Option<u16> {
+    if message.len() < 12 {
+        return None;
+    }
+    Some(u16::from_be_bytes([message[0], message[1]]))
+}
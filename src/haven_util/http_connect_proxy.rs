@@ -0,0 +1,163 @@
+use std::sync::Arc;
+
+use earendil_packet::Dock;
+use futures_util::io;
+use smol::{
+    future::FutureExt,
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use smolscale::reaper::TaskReaper;
+
+use crate::{
+    config::HavenForwardConfig, daemon::context::DaemonContext, socket::Socket,
+    stream::StreamListener,
+};
+
+use super::{haven_socket_config, set_haven_route_profile};
+
+/// The longest request head a client may send before its CONNECT is refused.
+const MAX_HEAD_LEN: usize = 8192;
+
+/// Serves HTTP/1.1 CONNECT requests arriving over earendil streams, relaying each stream to a TCP connection to a host in `allowed_domains` or one of their subdomains.
+pub async fn http_connect_proxy(
+    ctx: DaemonContext,
+    haven_cfg: HavenForwardConfig,
+    listen_dock: Dock,
+    allowed_domains: Vec<String>,
+) -> anyhow::Result<()> {
+    let haven_id = haven_cfg.identity.actualize()?;
+    log::debug!(
+        "HTTP CONNECT proxy haven fingerprint: {}",
+        haven_id.public().fingerprint()
+    );
+
+    let earendil_skt = Socket::bind_haven_internal_with_config(
+        ctx.clone(),
+        haven_id,
+        Some(listen_dock),
+        vec![haven_cfg.rendezvous],
+        haven_socket_config(&haven_cfg),
+    );
+    set_haven_route_profile(&ctx, &haven_cfg, &earendil_skt);
+
+    let mut listener = StreamListener::listen(earendil_skt);
+    let allowed_domains = Arc::new(allowed_domains);
+
+    let reaper = TaskReaper::new();
+    loop {
+        let earendil_stream = listener.accept().await?;
+        log::trace!("HTTP CONNECT proxy earendil stream accepted");
+        let allowed_domains = allowed_domains.clone();
+        reaper.attach(smolscale::spawn(async move {
+            // anything the client sent after the request head stays buffered in `reader`, and is relayed with the rest
+            let mut reader = BufReader::new(earendil_stream.clone());
+            let mut writer = earendil_stream;
+            let head = read_head(&mut reader).await?;
+            let target = match parse_connect(&head) {
+                Ok(target) => target,
+                Err(status) => {
+                    writer.write_all(response(status).as_bytes()).await?;
+                    anyhow::bail!("refused HTTP request: {status}")
+                }
+            };
+            if !domain_allowed(&target, &allowed_domains) {
+                writer
+                    .write_all(response("403 Forbidden").as_bytes())
+                    .await?;
+                anyhow::bail!("refused CONNECT to {target}, which isn't allowed");
+            }
+            let tcp_stream = match TcpStream::connect(target.as_str()).await {
+                Ok(tcp_stream) => tcp_stream,
+                Err(err) => {
+                    writer
+                        .write_all(response("502 Bad Gateway").as_bytes())
+                        .await?;
+                    return Err(err.into());
+                }
+            };
+            writer
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await?;
+            io::copy(reader, &mut tcp_stream.clone())
+                .race(io::copy(tcp_stream, &mut writer))
+                .await?;
+            anyhow::Ok(())
+        }));
+    }
+}
+
+/// Reads up to and including the blank line ending a request head.
+async fn read_head(reader: &mut (impl AsyncBufRead + Unpin)) -> anyhow::Result<String> {
+    let mut head = String::new();
+    let mut limited = reader.take(MAX_HEAD_LEN as u64);
+    loop {
+        if limited.read_line(&mut head).await? == 0 {
+            anyhow::bail!("request head cut short or longer than {MAX_HEAD_LEN} bytes");
+        }
+        if head.ends_with("\r\n\r\n") || head == "\r\n" {
+            return Ok(head);
+        }
+    }
+}
+
+/// Picks the `host:port` out of a CONNECT request head, or the status to refuse any other request with.
+fn parse_connect(head: &str) -> Result<String, &'static str> {
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    match (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) {
+        (Some("CONNECT"), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+            Ok(target.to_string())
+        }
+        (Some(_), Some(_), Some(_)) => Err("405 Method Not Allowed"),
+        _ => Err("400 Bad Request"),
+    }
+}
+
+/// Whether the host of a `host:port` target is an allowed domain or a subdomain of one.
+fn domain_allowed(target: &str, allowed_domains: &[String]) -> bool {
+    let Some((host, _)) = target.rsplit_once(':') else {
+        return false;
+    };
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    allowed_domains.iter().any(|domain| {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        host == domain || host.ends_with(&format!(".{domain}"))
+    })
+}
+
+fn response(status: &str) -> String {
+    format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_connect() {
+        assert_eq!(
+            parse_connect("CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n"),
+            Ok("example.com:443".to_string())
+        );
+        assert_eq!(
+            parse_connect("GET / HTTP/1.1\r\n\r\n"),
+            Err("405 Method Not Allowed")
+        );
+        assert_eq!(parse_connect("\r\n"), Err("400 Bad Request"));
+    }
+
+    #[test]
+    fn test_domain_allowed() {
+        let allowed = vec!["example.com".to_string()];
+        assert!(domain_allowed("example.com:443", &allowed));
+        assert!(domain_allowed("WWW.Example.com:443", &allowed));
+        assert!(!domain_allowed("badexample.com:443", &allowed));
+        assert!(!domain_allowed("example.com.evil.net:443", &allowed));
+        assert!(!domain_allowed("example.com", &allowed));
+        assert!(!domain_allowed("example.com:443", &[]));
+    }
+}
@@ -6,6 +6,7 @@ mod global_rpc;
 mod haven_util;
 pub mod socket;
 pub mod stream;
+pub mod transport;
 
 fn log_error<E>(label: &str) -> impl FnOnce(E) + '_
 where
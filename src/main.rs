@@ -1,4 +1,3 @@
-use anyhow::Context;
 use bip39::Mnemonic;
 use clap::{Parser, Subcommand};
 use earendil::commands::ControlCommands;
@@ -31,6 +30,9 @@ enum Commands {
         control_command: ControlCommands,
     },
     GenerateSeed,
+
+    /// Prints a JSON Schema describing the config file.
+    ConfigSchema,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -39,16 +41,14 @@ fn main() -> anyhow::Result<()> {
 
     match Args::parse().command {
         Commands::Daemon { config } => {
-            let json: serde_json::Value =
-                serde_yaml::from_slice(&std::fs::read(config).context("cannot read config file")?)
-                    .context("syntax error in config file")?;
-            let config_parsed: ConfigFile = serde_json::from_value(json)?;
+            let config_parsed = ConfigFile::load(&config)?;
             log::debug!(
                 "parsed config file: {}",
                 serde_json::to_string_pretty(&config_parsed)?
             );
             log::info!("about to init daemon!");
-            let _daemon = Daemon::init(config_parsed)?;
+            let daemon = Daemon::init(config_parsed)?;
+            daemon.set_config_path(config);
             loop {
                 std::thread::park()
             }
@@ -62,6 +62,13 @@ fn main() -> anyhow::Result<()> {
             println!("{}", seed_phrase);
             Ok(())
         }
+        Commands::ConfigSchema => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&ConfigFile::json_schema())?
+            );
+            Ok(())
+        }
     }
 }
 
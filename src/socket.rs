@@ -1,12 +1,18 @@
-use std::{fmt::Display, str::FromStr};
+use std::{
+    fmt::Display,
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
 use bytes::Bytes;
 use earendil_crypt::{Fingerprint, IdentitySecret};
-use earendil_packet::Dock;
+use earendil_packet::{crypt::OnionPublic, Dock};
 use serde::{Deserialize, Serialize};
+use smol::Timer;
 use thiserror::Error;
 
 use crate::{
+    config::RouteProfile,
     control_protocol::SendMessageError,
     daemon::{context::DaemonContext, Daemon},
 };
@@ -14,9 +20,19 @@ use crate::{
 use self::{haven_socket::HavenSocket, n2r_socket::N2rSocket};
 
 pub(crate) mod crypt_session;
+pub(crate) mod group_key;
 pub(crate) mod haven_socket;
 pub(crate) mod n2r_socket;
 
+pub use crypt_session::{AckToken, SessionStats};
+pub use group_key::{GroupPublicKey, GroupSecretKey};
+pub use haven_socket::HavenSocketConfig;
+pub use n2r_socket::N2rSocketConfig;
+
+const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A socket bound to a dock. Clones share the same underlying socket, so a socket can be handed to several tasks without wrapping it in an `Arc`.
+#[derive(Clone)]
 pub struct Socket {
     inner: InnerSocket,
 }
@@ -28,12 +44,34 @@ impl Socket {
         dock: Option<Dock>,
         rendezvous_point: Option<Fingerprint>,
     ) -> Socket {
-        let inner = HavenSocket::bind(daemon.ctx.clone(), isk, dock, rendezvous_point);
+        let inner = HavenSocket::bind(
+            daemon.ctx.clone(),
+            isk,
+            dock,
+            rendezvous_point.into_iter().collect(),
+        );
         Self {
-            inner: InnerSocket::Haven(inner),
+            inner: InnerSocket::Haven(Box::new(inner)),
         }
     }
 
+    /// Binds a haven socket with custom tunables. A haven server registers with every given rendezvous point, falling back to the others when one is unreachable.
+    pub fn bind_haven_with_config(
+        daemon: &Daemon,
+        isk: IdentitySecret,
+        dock: Option<Dock>,
+        rendezvous_points: Vec<Fingerprint>,
+        config: HavenSocketConfig,
+    ) -> Socket {
+        Self::bind_haven_internal_with_config(
+            daemon.ctx.clone(),
+            isk,
+            dock,
+            rendezvous_points,
+            config,
+        )
+    }
+
     pub fn bind_n2r(daemon: &Daemon, isk: IdentitySecret, dock: Option<Dock>) -> Socket {
         let inner = N2rSocket::bind(daemon.ctx.clone(), isk, dock);
         Self {
@@ -41,17 +79,52 @@ impl Socket {
         }
     }
 
+    /// Binds an N2R socket with the given tunables.
+    pub fn bind_n2r_with_config(
+        daemon: &Daemon,
+        isk: IdentitySecret,
+        dock: Option<Dock>,
+        config: N2rSocketConfig,
+    ) -> Socket {
+        let inner = N2rSocket::bind_with_config(daemon.ctx.clone(), isk, dock, config);
+        Self {
+            inner: InnerSocket::N2r(inner),
+        }
+    }
+
     pub(crate) fn bind_haven_internal(
         ctx: DaemonContext,
         isk: IdentitySecret,
         dock: Option<Dock>,
         rendezvous_point: Option<Fingerprint>,
     ) -> Socket {
-        let inner = InnerSocket::Haven(HavenSocket::bind(ctx.clone(), isk, dock, rendezvous_point));
+        let inner = InnerSocket::Haven(Box::new(HavenSocket::bind(
+            ctx.clone(),
+            isk,
+            dock,
+            rendezvous_point.into_iter().collect(),
+        )));
 
         Self { inner }
     }
 
+    pub(crate) fn bind_haven_internal_with_config(
+        ctx: DaemonContext,
+        isk: IdentitySecret,
+        dock: Option<Dock>,
+        rendezvous_points: Vec<Fingerprint>,
+        config: HavenSocketConfig,
+    ) -> Socket {
+        let inner = InnerSocket::Haven(Box::new(HavenSocket::bind_with_config(
+            ctx,
+            isk,
+            dock,
+            rendezvous_points,
+            config,
+        )));
+        Self { inner }
+    }
+
     pub(crate) fn bind_n2r_internal(
         ctx: DaemonContext,
         isk: IdentitySecret,
@@ -68,6 +141,67 @@ impl Socket {
         }
     }
 
+    /// Sends the same message to several endpoints, returning the outcome of each send in order. Haven sockets send concurrently.
+    pub async fn send_to_many(
+        &self,
+        body: Bytes,
+        endpoints: Vec<Endpoint>,
+    ) -> Vec<Result<(), SocketSendError>> {
+        match &self.inner {
+            InnerSocket::N2r(s) => {
+                let mut results = Vec::with_capacity(endpoints.len());
+                for endpoint in endpoints {
+                    results.push(s.send_to(body.clone(), endpoint).await);
+                }
+                results
+            }
+            InnerSocket::Haven(s) => s.send_to_many(body, endpoints).await,
+        }
+    }
+
+    /// Sends several messages at once, returning the outcome of each send in order. N2R sockets pack the messages to the same destination into shared packets, so that they use up fewer reply blocks.
+    pub async fn send_batch(
+        &self,
+        messages: Vec<(Bytes, Endpoint)>,
+    ) -> Vec<Result<(), SocketSendError>> {
+        match &self.inner {
+            InnerSocket::N2r(s) => s.send_batch(messages).await,
+            InnerSocket::Haven(s) => {
+                let mut results = Vec::with_capacity(messages.len());
+                for (body, endpoint) in messages {
+                    results.push(s.send_to(body, endpoint).await);
+                }
+                results
+            }
+        }
+    }
+
+    /// Sends a message and returns a token that can be waited on for the receiver's acknowledgement. Only supported by haven sockets.
+    pub async fn send_with_ack(
+        &self,
+        body: Bytes,
+        endpoint: Endpoint,
+        ack_timeout: Duration,
+    ) -> Result<AckToken, SocketSendError> {
+        match &self.inner {
+            InnerSocket::N2r(_) => Err(SocketSendError::AckUnsupported),
+            InnerSocket::Haven(s) => s.send_with_ack(body, endpoint, ack_timeout).await,
+        }
+    }
+
+    /// Sends a message and waits for the receiver to acknowledge it, for up to `timeout`. Only supported by haven sockets.
+    pub async fn send_to_confirmed(
+        &self,
+        body: Bytes,
+        endpoint: Endpoint,
+        timeout: Duration,
+    ) -> Result<(), SocketSendError> {
+        match &self.inner {
+            InnerSocket::N2r(_) => Err(SocketSendError::AckUnsupported),
+            InnerSocket::Haven(s) => s.send_to_confirmed(body, endpoint, timeout).await,
+        }
+    }
+
     pub async fn recv_from(&self) -> Result<(Bytes, Endpoint), SocketRecvError> {
         match &self.inner {
             InnerSocket::N2r(s) => s.recv_from().await,
@@ -75,16 +209,189 @@ impl Socket {
         }
     }
 
+    /// Returns the next incoming message if one is already queued, without waiting.
+    pub fn try_recv_from(&self) -> Option<(Bytes, Endpoint)> {
+        match &self.inner {
+            InnerSocket::N2r(s) => s.try_recv_from(),
+            InnerSocket::Haven(s) => s.try_recv_from(),
+        }
+    }
+
+    /// Waits at most `timeout` for the next incoming message, returning `None` if none arrived.
+    pub async fn recv_from_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<Option<(Bytes, Endpoint)>, SocketRecvError> {
+        match &self.inner {
+            InnerSocket::N2r(s) => s.recv_from_timeout(timeout).await,
+            InnerSocket::Haven(s) => s.recv_from_timeout(timeout).await,
+        }
+    }
+
+    /// Sends a message to every member of a group, each copy encrypted to that member's key. Only supported by haven sockets.
+    pub async fn send_to_group(
+        &self,
+        body: Bytes,
+        group_key: &GroupPublicKey,
+    ) -> Result<(), SocketSendError> {
+        match &self.inner {
+            InnerSocket::N2r(_) => Err(SocketSendError::GroupUnsupported),
+            InnerSocket::Haven(s) => s.send_to_group(body, group_key).await,
+        }
+    }
+
+    /// Receives the next group message addressed to the given key. Only supported by haven sockets.
+    pub async fn recv_from_group(
+        &self,
+        group_sk: &GroupSecretKey,
+    ) -> Result<(Bytes, Endpoint), SocketRecvError> {
+        match &self.inner {
+            InnerSocket::N2r(_) => Err(SocketRecvError::GroupUnsupported),
+            InnerSocket::Haven(s) => s.recv_from_group(group_sk).await,
+        }
+    }
+
+    /// Closes the socket after waiting for the messages already sent to leave, for up to 10 seconds.
+    pub async fn close(self) -> Result<(), SocketSendError> {
+        self.close_with_timeout(DEFAULT_CLOSE_TIMEOUT).await
+    }
+
+    /// Closes the socket after waiting for the messages already sent to leave, for up to `timeout`.
+    pub async fn close_with_timeout(self, timeout: Duration) -> Result<(), SocketSendError> {
+        match self.inner {
+            InnerSocket::Haven(s) => s.close(timeout).await,
+            InnerSocket::N2r(s) => {
+                let deadline = Instant::now() + timeout;
+                while s.pending_outgoing() > 0 {
+                    if Instant::now() >= deadline {
+                        return Err(SocketSendError::CloseTimedOut(s.pending_outgoing()));
+                    }
+                    Timer::after(Duration::from_millis(10)).await;
+                }
+                Ok(())
+            }
+        }
+    }
+
     pub fn local_endpoint(&self) -> Endpoint {
         match &self.inner {
             InnerSocket::Haven(haven_skt) => haven_skt.local_endpoint(),
             InnerSocket::N2r(n2r_skt) => n2r_skt.local_endpoint(),
         }
     }
+
+    /// Returns the fingerprint of the identity this socket was bound with. It's the node's own fingerprint only for sockets bound with the node's identity; anonymous sockets have fingerprints of their own.
+    pub fn local_fingerprint(&self) -> Fingerprint {
+        self.local_endpoint().fingerprint
+    }
+
+    /// Returns the onion public key a haven server advertises, for publishing it out of band. `None` for clients and n2r sockets.
+    pub fn onion_public_key(&self) -> Option<OnionPublic> {
+        match &self.inner {
+            InnerSocket::Haven(haven_skt) => haven_skt.onion_public_key(),
+            InnerSocket::N2r(_) => None,
+        }
+    }
+
+    /// Returns the ID of the current encryption session with an endpoint, which both sides log. Only haven sockets have sessions.
+    pub fn session_id(&self, endpoint: Endpoint) -> Option<[u8; 8]> {
+        match &self.inner {
+            InnerSocket::Haven(haven_skt) => haven_skt.session_id(endpoint),
+            InnerSocket::N2r(_) => None,
+        }
+    }
+
+    /// Returns the traffic exchanged with an endpoint over its current encryption session. Only haven sockets have sessions.
+    pub fn session_stats(&self, endpoint: Endpoint) -> Option<SessionStats> {
+        match &self.inner {
+            InnerSocket::Haven(haven_skt) => haven_skt.session_stats(endpoint),
+            InnerSocket::N2r(_) => None,
+        }
+    }
+
+    /// Moves a haven socket to another rendezvous point, or takes it off every rendezvous point with `None`. Only supported by haven sockets.
+    pub async fn set_rendezvous_point(
+        &self,
+        rendezvous_point: Option<Fingerprint>,
+    ) -> Result<(), SocketSendError> {
+        match &self.inner {
+            InnerSocket::Haven(haven_skt) => {
+                haven_skt.set_rendezvous_point(rendezvous_point).await;
+                Ok(())
+            }
+            InnerSocket::N2r(_) => Err(SocketSendError::RendezvousUnsupported),
+        }
+    }
+
+    /// Returns statistics of the messages this socket sent and received.
+    pub fn socket_stats(&self) -> SocketStats {
+        match &self.inner {
+            InnerSocket::Haven(haven_skt) => haven_skt.socket_stats(),
+            InnerSocket::N2r(n2r_skt) => n2r_skt.stats(),
+        }
+    }
+
+    /// Whether messages that arrive for this socket are being dropped, because it isn't receiving them fast enough.
+    pub fn is_backpressured(&self) -> bool {
+        match &self.inner {
+            InnerSocket::Haven(haven_skt) => haven_skt.is_backpressured(),
+            InnerSocket::N2r(n2r_skt) => n2r_skt.is_backpressured(),
+        }
+    }
+
+    /// Constrains the routes of the messages this socket sends, or lifts the constraints with `None`.
+    pub fn set_route_profile(&self, profile: Option<RouteProfile>) {
+        match &self.inner {
+            InnerSocket::Haven(haven_skt) => haven_skt.set_route_profile(profile),
+            InnerSocket::N2r(n2r_skt) => n2r_skt.set_route_profile(profile),
+        }
+    }
+
+    /// Returns how many rendezvous points currently acknowledge this haven's registration. Always zero for clients and n2r sockets.
+    pub fn rendezvous_count(&self) -> usize {
+        match &self.inner {
+            InnerSocket::Haven(haven_skt) => haven_skt.rendezvous_count(),
+            InnerSocket::N2r(_) => 0,
+        }
+    }
+
+    pub fn kind(&self) -> SocketKind {
+        match &self.inner {
+            InnerSocket::Haven(_) => SocketKind::Haven,
+            InnerSocket::N2r(_) => SocketKind::N2r,
+        }
+    }
+}
+
+/// Whether a socket talks N2R directly, or encrypts its messages as a haven client or server.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketKind {
+    N2r,
+    Haven,
+}
+
+/// Statistics of the messages a socket sent and received. Haven sockets count their encrypted messages, including the ones that set up sessions.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct SocketStats {
+    /// messages handed to the network
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    /// messages received from the network, not counting dropped ones
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    /// messages that could not be sent, because the outgoing queue was full or no packet could be made for them
+    pub send_errors: u64,
+    /// times receiving failed because the socket stopped receiving
+    pub recv_errors: u64,
+    /// messages dropped because the packet carrying them was received shortly before, e.g. when a relay delivered it twice
+    pub duplicates_dropped: u64,
+    /// messages dropped because the socket was backpressured, with most of its receive window taken by messages not yet received
+    pub packets_dropped_backpressure: u64,
 }
 
+#[derive(Clone)]
 enum InnerSocket {
-    Haven(HavenSocket),
+    Haven(Box<HavenSocket>),
     N2r(N2rSocket),
 }
 
@@ -94,12 +401,28 @@ pub enum SocketSendError {
     N2rSendError(#[from] SendMessageError),
     #[error("haven encryption problem: {0}")]
     HavenEncryptionError(String),
+    #[error("acknowledgements are only supported on haven sockets")]
+    AckUnsupported,
+    #[error("timed out with {0} outgoing messages not yet sent")]
+    CloseTimedOut(usize),
+    #[error("group messages are only supported on haven sockets")]
+    GroupUnsupported,
+    #[error("the session ran out of nonces and could not be rekeyed")]
+    NonceExhausted,
+    #[error("the receiver did not acknowledge the message in time")]
+    NotAcknowledged,
+    #[error("rendezvous points are only supported on haven sockets")]
+    RendezvousUnsupported,
+    #[error("the outgoing queue is full")]
+    QueueFull,
 }
 
 #[derive(Error, Serialize, Deserialize, Debug)]
 pub enum SocketRecvError {
     #[error("error receiving in n2r_socket")]
     N2rRecvError,
+    #[error("group messages are only supported on haven sockets")]
+    GroupUnsupported,
 }
 
 #[derive(Copy, Clone, Deserialize, Serialize, Hash, Debug, PartialEq, PartialOrd, Ord, Eq)]
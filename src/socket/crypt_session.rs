@@ -1,38 +1,263 @@
 use std::convert::Infallible;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use bytes::Bytes;
+use dashmap::DashMap;
 use earendil_crypt::{Fingerprint, IdentityPublic, IdentitySecret};
-use earendil_packet::crypt::{AeadKey, OnionPublic, OnionSecret};
+use earendil_packet::crypt::{OnionPublic, OnionSecret};
 use futures_util::{future::Shared, FutureExt};
-use replay_filter::ReplayFilter;
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use smol::future::FutureExt as Fe;
 use smol::{
     channel::{Receiver, Sender},
-    Task,
+    Task, Timer,
 };
 use smol_timeout::TimeoutExt;
 use stdcode::StdcodeSerializeExt;
+use zeroize::Zeroize;
 
 use crate::{control_protocol::DhtError, daemon::dht::dht_get};
-use crate::{daemon::context::DaemonContext, haven_util::HAVEN_FORWARD_DOCK};
+use crate::{
+    daemon::{
+        context::DaemonContext,
+        haven_bandwidth::{BandwidthBucket, HAVEN_BANDWIDTH},
+        metrics::METRICS,
+    },
+    haven_util::HAVEN_FORWARD_DOCK,
+};
 
 use super::{n2r_socket::N2rSocket, Endpoint};
 
+mod ratchet;
+mod replay_window;
+mod resumption;
+use ratchet::KeyRatchet;
+pub use ratchet::RatchetPolicy;
+use replay_window::ReplayWindow;
+pub use resumption::{Resumption, ResumptionToken};
+
 #[derive(Clone)]
 pub struct CryptSession {
-    send_outgoing: Sender<Bytes>,
+    session_id: [u8; 8],
+    send_outgoing: Sender<(Bytes, OutgoingKind)>,
     send_incoming: Sender<HavenMsg>,
+    /// senders waiting for an acknowledgement, keyed by message id
+    pending_acks: Arc<DashMap<u64, Sender<()>>>,
+    counters: Arc<SessionCounters>,
+    /// keeps the other side from evicting the session while it's idle; cancelled when the session is dropped
+    keepalive: Arc<Mutex<Option<Task<()>>>>,
     _task: Shared<Task<String>>, // returns an error string
 }
 
+/// A snapshot of the traffic that went through an encryption session.
+#[derive(Clone, Copy, Debug)]
+pub struct SessionStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub messages_dropped_oversized: u64,
+    pub messages_dropped_replayed: u64,
+    pub established_at: Instant,
+}
+
+/// Plaintext traffic counters of a session, updated as messages go through it.
+struct SessionCounters {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    messages_dropped_oversized: AtomicU64,
+    messages_dropped_replayed: AtomicU64,
+    established_at: Instant,
+    /// the per-minute counters of the haven this session talks to or serves, shared with its other sessions
+    haven_bandwidth: Arc<BandwidthBucket>,
+}
+
+impl SessionCounters {
+    fn new(haven_bandwidth: Arc<BandwidthBucket>) -> Self {
+        Self {
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            messages_received: AtomicU64::new(0),
+            messages_dropped_oversized: AtomicU64::new(0),
+            messages_dropped_replayed: AtomicU64::new(0),
+            established_at: Instant::now(),
+            haven_bandwidth,
+        }
+    }
+
+    fn record_sent(&self, len: usize) {
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.haven_bandwidth.record_sent(len);
+    }
+
+    fn record_received(&self, len: usize) {
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+        self.haven_bandwidth.record_received(len);
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub enum HavenMsg {
-    ClientHs(Handshake),
+    /// The client's handshake, with its signature over the transcript, which proves its identity to servers that require it.
+    ClientHs {
+        hs: Handshake,
+        transcript_sig: Bytes,
+    },
     ServerHs(Handshake),
-    Regular { nonce: u64, inner: Bytes },
+    Regular {
+        nonce: u64,
+        inner: Bytes,
+    },
+    /// A regular message that the receiver should acknowledge with an `Ack` carrying the same `msg_id`.
+    AckRequest {
+        msg_id: u64,
+        nonce: u64,
+        inner: Bytes,
+    },
+    Ack {
+        msg_id: u64,
+    },
+    /// A regular message whose plaintext is a group-encrypted message, delivered separately from ordinary messages.
+    Group {
+        nonce: u64,
+        inner: Bytes,
+    },
+    /// Asks the server to step the session's keys to `epoch`, mixing in a new exchange with the client's `eph_pk`.
+    Rekey {
+        epoch: u64,
+        eph_pk: OnionPublic,
+        mac: [u8; 32],
+    },
+    /// The server's half of the exchange of a `Rekey`.
+    RekeyReply {
+        epoch: u64,
+        eph_pk: OnionPublic,
+        mac: [u8; 32],
+    },
+    /// Starts a session with a token from an earlier one instead of a handshake. Both sides mix `client_nonce` into the earlier session's secret, so no two sessions share keys.
+    Resume {
+        token: ResumptionToken,
+        client_nonce: [u8; 32],
+    },
+    /// A token that the server issues at the start of a session, which the client can later resume it with.
+    Ticket(ResumptionToken),
+    /// The server couldn't redeem the client's token, so the client must start over with a handshake.
+    ResumeRejected,
+    /// Only resets the receiver's idle timer for the session, carrying nothing to deliver.
+    Keepalive,
+}
+
+/// How a session gets its shared secret.
+pub enum SessionStart {
+    /// we're the client, and start with a handshake
+    Connect {
+        my_osk: OnionSecret,
+        hs: Handshake,
+        transcript_sig: Bytes,
+    },
+    /// we're the client, and redeem a token that the server issued in an earlier session; the session lacks forward secrecy until its first rekey, as [ResumptionToken] explains
+    Resume {
+        token: ResumptionToken,
+        resume_sec: [u8; 32],
+        client_nonce: [u8; 32],
+    },
+    /// we're the server, and answer the client's handshake, which the given fingerprint sent
+    Accept(Handshake, Fingerprint),
+    /// we're the server, and the client redeemed a token for the given secret
+    AcceptResume {
+        resume_sec: [u8; 32],
+        client_nonce: [u8; 32],
+    },
+}
+
+impl SessionStart {
+    /// Starts a session as the client, with a handshake with the server of the given fingerprint.
+    pub fn connect(my_isk: &IdentitySecret, server_fp: Fingerprint) -> Self {
+        let my_osk = OnionSecret::generate();
+        let hs = Handshake::new(my_isk, &my_osk);
+        let transcript_sig = my_isk.sign(hs.transcript(server_fp).as_bytes());
+        SessionStart::Connect {
+            my_osk,
+            hs,
+            transcript_sig,
+        }
+    }
+
+    /// Starts a session as the client, redeeming a token from an earlier session.
+    pub fn resume(token: ResumptionToken, resume_sec: [u8; 32]) -> Self {
+        SessionStart::Resume {
+            token,
+            resume_sec,
+            client_nonce: rand::random(),
+        }
+    }
+
+    /// The ID of the session, which both sides derive from the client's opening message.
+    fn session_id(&self) -> [u8; 8] {
+        let hash = match self {
+            SessionStart::Connect { hs, .. } | SessionStart::Accept(hs, _) => hs.to_sign(),
+            SessionStart::Resume { client_nonce, .. }
+            | SessionStart::AcceptResume { client_nonce, .. } => blake3::hash(client_nonce),
+        };
+        let mut session_id = [0; 8];
+        session_id.copy_from_slice(&hash.as_bytes()[..8]);
+        session_id
+    }
+}
+
+/// How many messages sent under an optimistically resumed session are kept for sending again, should the server reject the resumption.
+const MAX_UNCONFIRMED_MESSAGES: usize = 256;
+
+/// How long a client waits for the server's handshake before sending its own again, through the haven's next rendezvous point.
+const HANDSHAKE_RESEND: Duration = Duration::from_secs(10);
+
+/// How an outgoing message is framed.
+#[derive(Clone, Copy)]
+enum OutgoingKind {
+    Regular,
+    AckRequest(u64),
+    Group,
+    Keepalive,
+}
+
+/// Where a session delivers the messages it decrypts.
+#[derive(Clone)]
+pub struct IncomingSinks {
+    pub regular: Sender<(Bytes, Endpoint)>,
+    pub group: Sender<(Bytes, Endpoint)>,
+    /// decrypted messages larger than this are dropped instead of delivered
+    pub max_message_size: usize,
+}
+
+/// A handle to the acknowledgement of a message sent with `send_with_ack`.
+pub struct AckToken {
+    msg_id: u64,
+    recv_ack: Receiver<()>,
+    deadline: Instant,
+    pending_acks: Arc<DashMap<u64, Sender<()>>>,
+}
+
+impl AckToken {
+    /// Waits until the acknowledgement arrives or the timeout expires. Returns whether the acknowledgement was received.
+    pub async fn wait(self) -> bool {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        matches!(self.recv_ack.recv().timeout(remaining).await, Some(Ok(())))
+    }
+}
+
+impl Drop for AckToken {
+    fn drop(&mut self) {
+        self.pending_acks.remove(&self.msg_id);
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -43,50 +268,87 @@ pub struct Handshake {
 }
 
 impl CryptSession {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         my_isk: IdentitySecret,
         remote: Endpoint,
         rendezvous_fp: Option<Fingerprint>,
         n2r_skt: N2rSocket,
-        send_incoming_decrypted: Sender<(Bytes, Endpoint)>,
+        incoming_sinks: IncomingSinks,
         ctx: DaemonContext,
-        client_info: Option<(Handshake, Fingerprint)>,
+        start: SessionStart,
+        ratchet_policy: RatchetPolicy,
+        resumption: Arc<Resumption>,
     ) -> anyhow::Result<Self> {
-        if let Some((hs, fp)) = client_info.clone() {
+        if let SessionStart::Accept(hs, fp) = &start {
             hs.id_pk.verify(hs.to_sign().as_bytes(), &hs.sig)?; // verify sig & src_fp
-            if hs.id_pk.fingerprint() != fp {
+            if hs.id_pk.fingerprint() != *fp {
                 anyhow::bail!("spoofed src fingerprint for ClientHandshake!")
             }
         }
+        let session_id = start.session_id();
         let (send_out, recv_out) = smol::channel::unbounded();
         let (send_in, recv_in) = smol::channel::unbounded();
+        let pending_acks: Arc<DashMap<u64, Sender<()>>> = Default::default();
+        // servers count traffic under their own fingerprint, clients under the haven's
+        let haven_fp = match rendezvous_fp {
+            Some(_) => my_isk.public().fingerprint(),
+            None => remote.fingerprint,
+        };
+        let counters = Arc::new(SessionCounters::new(
+            ctx.get(HAVEN_BANDWIDTH).bucket(haven_fp),
+        ));
         let task = smolscale::spawn(
             enc_task(
+                session_id,
                 my_isk,
                 n2r_skt,
                 remote,
                 rendezvous_fp,
                 recv_in,
                 recv_out,
-                send_incoming_decrypted,
-                client_info.map(|(hs, _)| hs),
+                incoming_sinks,
+                start,
+                pending_acks.clone(),
+                counters.clone(),
+                ratchet_policy,
+                resumption,
                 ctx,
             )
             .map(move |e| format!("{:?}", e.unwrap_err())),
         );
         Ok(Self {
+            session_id,
             send_outgoing: send_out,
             send_incoming: send_in,
+            pending_acks,
+            counters,
+            keepalive: Default::default(),
             _task: task.shared(),
         })
     }
 
+    /// Returns an ID of the session that both sides agree on, for matching up their logs. It's derived from the client's opening message, so it's no secret.
+    pub fn session_id(&self) -> [u8; 8] {
+        self.session_id
+    }
+
     async fn wait_error(&self) -> anyhow::Result<()> {
         Err(anyhow::anyhow!(self._task.clone().await))
     }
 
     pub async fn send_outgoing(&self, msg: Bytes) -> anyhow::Result<()> {
-        if self.send_outgoing.send(msg).await.is_err() {
+        self.send_outgoing_kind(msg, OutgoingKind::Regular).await
+    }
+
+    /// Sends an already group-encrypted message, which the other side delivers to its group receivers.
+    pub async fn send_outgoing_group(&self, msg: Bytes) -> anyhow::Result<()> {
+        self.send_outgoing_kind(msg, OutgoingKind::Group).await
+    }
+
+    async fn send_outgoing_kind(&self, msg: Bytes, kind: OutgoingKind) -> anyhow::Result<()> {
+        self.counters.record_sent(msg.len());
+        if self.send_outgoing.send((msg, kind)).await.is_err() {
             // channel is unbounded
             self.wait_error().await
         } else {
@@ -94,6 +356,86 @@ impl CryptSession {
         }
     }
 
+    /// Sends a message that the other side will acknowledge, returning a token that resolves when the acknowledgement arrives or `ack_timeout` expires.
+    pub async fn send_outgoing_with_ack(
+        &self,
+        msg: Bytes,
+        ack_timeout: Duration,
+    ) -> anyhow::Result<AckToken> {
+        self.counters.record_sent(msg.len());
+        let msg_id: u64 = rand::random();
+        let (send_ack, recv_ack) = smol::channel::bounded(1);
+        self.pending_acks.insert(msg_id, send_ack);
+        // if sending fails, dropping the token deregisters the message id
+        let token = AckToken {
+            msg_id,
+            recv_ack,
+            deadline: Instant::now() + ack_timeout,
+            pending_acks: self.pending_acks.clone(),
+        };
+        if self
+            .send_outgoing
+            .send((msg, OutgoingKind::AckRequest(msg_id)))
+            .await
+            .is_err()
+        {
+            // channel is unbounded
+            self.wait_error().await?;
+        }
+        Ok(token)
+    }
+
+    /// Sends a keepalive whenever `interval` passes in which the other side sent us messages but we sent it none, so that the other side's idle timer for this session is reset while the session is in use. Sessions that carry no traffic at all are left to expire.
+    pub fn start_keepalive(&self, interval: Duration) {
+        let send_outgoing = self.send_outgoing.clone();
+        let counters = self.counters.clone();
+        let task = smolscale::spawn(async move {
+            let mut last_sent = counters.messages_sent.load(Ordering::Relaxed);
+            let mut last_received = counters.messages_received.load(Ordering::Relaxed);
+            loop {
+                Timer::after(interval).await;
+                let sent = counters.messages_sent.load(Ordering::Relaxed);
+                let received = counters.messages_received.load(Ordering::Relaxed);
+                if sent == last_sent
+                    && received != last_received
+                    && send_outgoing
+                        .send((Bytes::new(), OutgoingKind::Keepalive))
+                        .await
+                        .is_err()
+                {
+                    return;
+                }
+                last_sent = sent;
+                last_received = received;
+            }
+        });
+        *self.keepalive.lock() = Some(task);
+    }
+
+    /// Returns the traffic that went through this session so far.
+    pub fn stats(&self) -> SessionStats {
+        SessionStats {
+            bytes_sent: self.counters.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.counters.bytes_received.load(Ordering::Relaxed),
+            messages_sent: self.counters.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.counters.messages_received.load(Ordering::Relaxed),
+            messages_dropped_oversized: self
+                .counters
+                .messages_dropped_oversized
+                .load(Ordering::Relaxed),
+            messages_dropped_replayed: self
+                .counters
+                .messages_dropped_replayed
+                .load(Ordering::Relaxed),
+            established_at: self.counters.established_at,
+        }
+    }
+
+    /// Returns how many outgoing messages are still waiting to be encrypted and sent.
+    pub fn outgoing_len(&self) -> usize {
+        self.send_outgoing.len()
+    }
+
     pub async fn send_incoming(&self, msg: HavenMsg) -> anyhow::Result<()> {
         if self.send_incoming.send(msg).await.is_err() {
             // channel is unbounded
@@ -104,23 +446,44 @@ impl CryptSession {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn enc_task(
+    session_id: [u8; 8],
     my_isk: IdentitySecret,
     n2r_skt: N2rSocket,
     remote: Endpoint,
     rendezvous_fp: Option<Fingerprint>,
     recv_incoming: Receiver<HavenMsg>,
-    recv_outgoing: Receiver<Bytes>,
-    send_incoming_decrypted: Sender<(Bytes, Endpoint)>,
-    client_hs: Option<Handshake>,
+    recv_outgoing: Receiver<(Bytes, OutgoingKind)>,
+    incoming_sinks: IncomingSinks,
+    start: SessionStart,
+    pending_acks: Arc<DashMap<u64, Sender<()>>>,
+    counters: Arc<SessionCounters>,
+    ratchet_policy: RatchetPolicy,
+    resumption: Arc<Resumption>,
     ctx: DaemonContext,
 ) -> anyhow::Result<Infallible> {
+    // prefixes every log line, so that the logs of both sides can be matched up
+    let sid = hex::encode(session_id);
+    let metrics = ctx.get(METRICS);
+    metrics
+        .active_haven_sessions
+        .fetch_add(1, Ordering::Relaxed);
+    scopeguard::defer!({
+        metrics
+            .active_haven_sessions
+            .fetch_sub(1, Ordering::Relaxed);
+    });
+    // which of the haven's rendezvous points the client sends through, counting its backups after the primary
+    let rendezvous_idx = AtomicUsize::new(0);
     let send_to_rendezvous = |msg: Bytes| async {
-        let fwd_body = (msg, remote).stdcode();
-        let rendezvous_ep = match rendezvous_fp {
+        let fwd_body: Bytes = (msg, remote).stdcode().into();
+        match rendezvous_fp {
             Some(rob) => {
                 // We're the server
-                Endpoint::new(rob, HAVEN_FORWARD_DOCK)
+                n2r_skt
+                    .send_to(fwd_body, Endpoint::new(rob, HAVEN_FORWARD_DOCK))
+                    .await?;
             }
             None => {
                 // We're the client: look up Rob's addr in rendezvous dht
@@ -135,79 +498,286 @@ async fn enc_task(
                     )
                     .context(format!("DHT failed for {}", remote.fingerprint))?
                     .context(format!("DHT returned None for {}", remote.fingerprint))?;
-                Endpoint::new(bob_locator.rendezvous_point, HAVEN_FORWARD_DOCK)
+                let candidates: Vec<Fingerprint> = std::iter::once(bob_locator.rendezvous_point)
+                    .chain(bob_locator.rendezvous_backups)
+                    .collect();
+                // start from the rendezvous point that last worked, falling over to the next one whenever sending fails
+                let first = rendezvous_idx.load(Ordering::Relaxed);
+                let mut last_err = None;
+                for offset in 0..candidates.len() {
+                    let idx = (first + offset) % candidates.len();
+                    let rob = candidates[idx];
+                    match n2r_skt
+                        .send_to(fwd_body.clone(), Endpoint::new(rob, HAVEN_FORWARD_DOCK))
+                        .await
+                    {
+                        Ok(()) => {
+                            rendezvous_idx.store(idx, Ordering::Relaxed);
+                            return anyhow::Ok(());
+                        }
+                        Err(e) => {
+                            log::debug!(
+                                "session {sid}: could not reach rendezvous point {rob}: {e}"
+                            );
+                            last_err = Some(e);
+                        }
+                    }
+                }
+                if let Some(e) = last_err {
+                    return Err(e.into());
+                }
             }
-        };
-        n2r_skt.send_to(fwd_body.into(), rendezvous_ep).await?;
+        }
         anyhow::Ok(())
     };
 
-    // complete handshake to get the shared secret
-    let my_osk = OnionSecret::generate();
-    let my_hs = Handshake::new(&my_isk, &my_osk);
-    let shared_sec = match client_hs {
-        Some(hs) => {
-            // we already verified the signature in the Encrypter constructor
-            let msg = HavenMsg::ServerHs(my_hs).stdcode().into();
-            send_to_rendezvous(msg).await?; // respond with server handshake
-            my_osk.shared_secret(&hs.eph_pk)
+    let is_server = matches!(
+        start,
+        SessionStart::Accept(..) | SessionStart::AcceptResume { .. }
+    );
+    // messages sent since we optimistically resumed a session, until the server accepts the resumption; if it rejects it instead, they're sent again after a handshake
+    let unconfirmed: Mutex<Option<Vec<(Bytes, OutgoingKind)>>> = Mutex::new(None);
+    // messages that the next attempt sends before any new ones
+    let mut replay = vec![];
+    let mut start = start;
+    loop {
+        // complete handshake, or resume an earlier session, to get the shared secret
+        let mut shared_sec = match start {
+            SessionStart::Accept(hs, _) => {
+                // we already verified the signature in the Encrypter constructor
+                let my_osk = OnionSecret::generate();
+                let msg = HavenMsg::ServerHs(Handshake::new(&my_isk, &my_osk))
+                    .stdcode()
+                    .into();
+                send_to_rendezvous(msg).await?; // respond with server handshake
+                my_osk.shared_secret(&hs.eph_pk)
+            }
+            SessionStart::Connect {
+                my_osk,
+                hs,
+                transcript_sig,
+            } => {
+                let msg: Bytes = HavenMsg::ClientHs { hs, transcript_sig }.stdcode().into();
+                send_to_rendezvous(msg.clone()).await?; // send client handshake
+                loop {
+                    match recv_incoming.recv().timeout(HANDSHAKE_RESEND).await {
+                        Some(in_msg) => {
+                            if let HavenMsg::ServerHs(hs) = in_msg? {
+                                break my_osk.shared_secret(&hs.eph_pk);
+                            }
+                        }
+                        None => {
+                            // the rendezvous point may have gone down without us noticing, so try the haven's next one
+                            log::debug!("session {sid}: no server handshake yet, resending it through the next rendezvous point");
+                            rendezvous_idx.fetch_add(1, Ordering::Relaxed);
+                            send_to_rendezvous(msg.clone()).await?;
+                        }
+                    }
+                }
+            }
+            SessionStart::Resume {
+                token,
+                mut resume_sec,
+                client_nonce,
+            } => {
+                let msg = HavenMsg::Resume {
+                    token,
+                    client_nonce,
+                };
+                send_to_rendezvous(msg.stdcode().into()).await?;
+                *unconfirmed.lock() = Some(vec![]);
+                let shared_sec = resumed_secret(&resume_sec, &client_nonce);
+                resume_sec.zeroize();
+                shared_sec
+            }
+            SessionStart::AcceptResume {
+                mut resume_sec,
+                client_nonce,
+            } => {
+                let shared_sec = resumed_secret(&resume_sec, &client_nonce);
+                resume_sec.zeroize();
+                shared_sec
+            }
+        };
+        // the secret that a later session resumes this one with
+        let mut resume_sec =
+            *blake3::keyed_hash(blake3::hash(b"haven-resume").as_bytes(), &shared_sec).as_bytes();
+        log::debug!("session {sid}: established with {remote}");
+        if is_server {
+            let token = resumption.issue(resume_sec, remote.fingerprint);
+            resume_sec.zeroize();
+            send_to_rendezvous(HavenMsg::Ticket(token).stdcode().into()).await?;
         }
-        None => {
-            let msg = HavenMsg::ClientHs(my_hs).stdcode().into();
-            send_to_rendezvous(msg).await?; // send client handshake
+        // only the client has no rendezvous point of its own
+        let ratchet = Mutex::new(KeyRatchet::new(
+            &shared_sec,
+            rendezvous_fp.is_none(),
+            ratchet_policy,
+        ));
+        shared_sec.zeroize();
+        // sends a rekey if the client is due to step the keys
+        let poll_step = || async {
+            let rekey = ratchet.lock().poll_step();
+            if let Some(rekey) = rekey {
+                send_to_rendezvous(rekey.stdcode().into()).await?;
+            }
+            anyhow::Ok(())
+        };
+
+        // start up & down loops
+        let up_loop = async {
+            let mut nonce = 0;
+            let mut replay = std::mem::take(&mut replay).into_iter();
             loop {
-                let in_msg = recv_incoming.recv().await?;
-                if let HavenMsg::ServerHs(hs) = in_msg {
-                    break my_osk.shared_secret(&hs.eph_pk);
+                let (msg, kind) = match replay.next() {
+                    Some(replayed) => replayed,
+                    None => recv_outgoing.recv().await?,
+                };
+                if let OutgoingKind::Keepalive = kind {
+                    send_to_rendezvous(HavenMsg::Keepalive.stdcode().into()).await?;
+                    continue;
                 }
+                if let Some(unconfirmed) = unconfirmed.lock().as_mut() {
+                    if unconfirmed.len() < MAX_UNCONFIRMED_MESSAGES {
+                        unconfirmed.push((msg.clone(), kind));
+                    }
+                }
+                let ctext = ratchet.lock().seal(nonce, &msg);
+                let msg = match kind {
+                    OutgoingKind::Regular => HavenMsg::Regular {
+                        nonce,
+                        inner: ctext.into(),
+                    },
+                    OutgoingKind::AckRequest(msg_id) => HavenMsg::AckRequest {
+                        msg_id,
+                        nonce,
+                        inner: ctext.into(),
+                    },
+                    OutgoingKind::Group => HavenMsg::Group {
+                        nonce,
+                        inner: ctext.into(),
+                    },
+                    OutgoingKind::Keepalive => unreachable!("keepalives aren't encrypted"),
+                }
+                .stdcode();
+                send_to_rendezvous(msg.into()).await?;
+                log::trace!("session {sid}: sent message with nonce {nonce}");
+                nonce += 1;
+                poll_step().await?;
             }
-        }
-    };
-    let up_key = AeadKey::from_bytes(
-        blake3::keyed_hash(blake3::hash(b"haven-up").as_bytes(), &shared_sec).as_bytes(),
-    );
-    let down_key = AeadKey::from_bytes(
-        blake3::keyed_hash(blake3::hash(b"haven-dn").as_bytes(), &shared_sec).as_bytes(),
-    );
-    let (enc_key, dec_key) = if rendezvous_fp.is_none() {
-        (up_key, down_key) // we're the client
-    } else {
-        (down_key, up_key) // we're the server
-    };
+        };
 
-    // start up & down loops
-    let up_loop = async {
-        let mut nonce = 0;
-        loop {
-            let msg = recv_outgoing.recv().await?;
-            let ctext = enc_key.seal(&pad_nonce(nonce), &msg);
-            let msg = HavenMsg::Regular {
-                nonce,
-                inner: ctext.into(),
+        // steps the keys of idle sessions, and asks again for lost rekey replies
+        let ratchet_loop = async {
+            loop {
+                let deadline = ratchet.lock().next_deadline();
+                let Some(deadline) = deadline else {
+                    // only the client steps the keys
+                    return smol::future::pending().await;
+                };
+                Timer::at(deadline).await;
+                poll_step().await?;
             }
-            .stdcode();
-            send_to_rendezvous(msg.into()).await?;
-            nonce += 1;
-        }
-    };
+        };
 
-    let down_loop = async {
-        let mut rf = ReplayFilter::default();
-        loop {
-            let msg = recv_incoming.recv().await?;
-            if let HavenMsg::Regular { nonce, inner } = msg {
-                if rf.add(nonce) {
-                    let plain = dec_key.open(&pad_nonce(nonce), &inner)?;
-                    let _ = send_incoming_decrypted.try_send((plain.into(), remote));
+        let down_loop = async {
+            let mut replay_window = ReplayWindow::default();
+            // once the server issued us a ticket, it has accepted the session, so any rejection is a replay of an old one
+            let mut ticket_received = false;
+            loop {
+                let msg = recv_incoming.recv().await?;
+                let (nonce, inner, msg_id, sink) = match msg {
+                    HavenMsg::Regular { nonce, inner } => {
+                        (nonce, inner, None, &incoming_sinks.regular)
+                    }
+                    HavenMsg::AckRequest {
+                        msg_id,
+                        nonce,
+                        inner,
+                    } => (nonce, inner, Some(msg_id), &incoming_sinks.regular),
+                    HavenMsg::Group { nonce, inner } => (nonce, inner, None, &incoming_sinks.group),
+                    HavenMsg::Ack { msg_id } => {
+                        if let Some((_, send_ack)) = pending_acks.remove(&msg_id) {
+                            let _ = send_ack.try_send(());
+                        }
+                        continue;
+                    }
+                    HavenMsg::Rekey { epoch, eph_pk, mac } => {
+                        let reply = ratchet.lock().on_rekey(epoch, eph_pk, mac);
+                        match reply {
+                            Some(reply) => send_to_rendezvous(reply.stdcode().into()).await?,
+                            None => log::debug!("session {sid}: dropping stale or forged rekey"),
+                        }
+                        continue;
+                    }
+                    HavenMsg::RekeyReply { epoch, eph_pk, mac } => {
+                        if !ratchet.lock().on_rekey_reply(epoch, eph_pk, mac) {
+                            log::debug!("session {sid}: dropping stale or forged rekey reply");
+                        }
+                        continue;
+                    }
+                    HavenMsg::Ticket(token) if !is_server => {
+                        ticket_received = true;
+                        *unconfirmed.lock() = None;
+                        resumption.store(remote, token, resume_sec);
+                        continue;
+                    }
+                    HavenMsg::ResumeRejected if !is_server && !ticket_received => {
+                        // the token expired, or the haven no longer accepts us without a handshake
+                        log::debug!(
+                            "session {sid}: {remote} rejected our resumption token, falling back to a handshake"
+                        );
+                        return anyhow::Ok(());
+                    }
+                    _ => {
+                        log::debug!("session {sid}: stray handshake message!");
+                        continue;
+                    }
+                };
+                if replay_window.check(nonce) {
+                    let plain = ratchet.lock().open(nonce, &inner);
+                    let Some(plain) = plain else {
+                        // most likely sent under keys we already forgot
+                        log::debug!(
+                            "session {sid}: dropping undecryptable message with nonce {nonce}"
+                        );
+                        continue;
+                    };
+                    replay_window.accept(nonce);
+                    if plain.len() > incoming_sinks.max_message_size {
+                        log::warn!(
+                            "session {sid}: dropping {}-byte message from {remote} exceeding the {}-byte limit",
+                            plain.len(),
+                            incoming_sinks.max_message_size
+                        );
+                        counters
+                            .messages_dropped_oversized
+                            .fetch_add(1, Ordering::Relaxed);
+                    } else {
+                        log::trace!("session {sid}: received message with nonce {nonce}");
+                        counters.record_received(plain.len());
+                        let _ = sink.try_send((plain.into(), remote));
+                    }
+                    poll_step().await?;
                 } else {
-                    log::debug!("received pkt with duplicate nonce! dropping...")
+                    log::debug!(
+                        "session {sid}: dropping message with replayed or too old nonce {nonce}"
+                    );
+                    counters
+                        .messages_dropped_replayed
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+                // acknowledge even duplicates, since the duplicate may be a retransmission caused by a lost ack
+                if let Some(msg_id) = msg_id {
+                    send_to_rendezvous(HavenMsg::Ack { msg_id }.stdcode().into()).await?;
                 }
-            } else {
-                log::debug!("stray handshake message!");
             }
-        }
-    };
-    up_loop.race(down_loop).await
+        };
+        up_loop.race(down_loop).race(ratchet_loop).await?;
+        // only a rejected resumption ends the loops without an error
+        replay = unconfirmed.lock().take().unwrap_or_default();
+        start = SessionStart::connect(&my_isk, remote.fingerprint);
+    }
 }
 
 impl Handshake {
@@ -230,6 +800,26 @@ impl Handshake {
         this.sig = Bytes::new();
         blake3::keyed_hash(b"haven_handshake_________________", &this.stdcode())
     }
+
+    /// The value that a client signs to prove that it's starting this handshake with the given server, so that the signature can't be replayed to another one.
+    fn transcript(&self, server_fp: Fingerprint) -> blake3::Hash {
+        let mut hasher = blake3::Hasher::new_keyed(b"haven_client_auth_______________");
+        hasher.update(self.to_sign().as_bytes());
+        hasher.update(server_fp.as_bytes());
+        hasher.finalize()
+    }
+
+    /// Verifies a client's signature over the transcript of its handshake with the given server.
+    pub fn verify_transcript(&self, server_fp: Fingerprint, sig: &[u8]) -> anyhow::Result<()> {
+        self.id_pk
+            .verify(self.transcript(server_fp).as_bytes(), sig)?;
+        Ok(())
+    }
+}
+
+/// Derives the shared secret of a resumed session.
+fn resumed_secret(resume_sec: &[u8; 32], client_nonce: &[u8; 32]) -> [u8; 32] {
+    *blake3::keyed_hash(resume_sec, client_nonce).as_bytes()
 }
 
 fn pad_nonce(input: u64) -> [u8; 12] {
@@ -238,3 +828,24 @@ fn pad_nonce(input: u64) -> [u8; 12] {
     buffer[..8].copy_from_slice(&bytes);
     buffer
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_transcript() {
+        let client_isk = IdentitySecret::generate();
+        let server_fp = IdentitySecret::generate().public().fingerprint();
+        let SessionStart::Connect {
+            hs, transcript_sig, ..
+        } = SessionStart::connect(&client_isk, server_fp)
+        else {
+            unreachable!()
+        };
+        assert!(hs.verify_transcript(server_fp, &transcript_sig).is_ok());
+        // the signature is bound to the server it was made for
+        let other_fp = IdentitySecret::generate().public().fingerprint();
+        assert!(hs.verify_transcript(other_fp, &transcript_sig).is_err());
+    }
+}
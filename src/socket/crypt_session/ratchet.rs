@@ -0,0 +1,344 @@
+use std::time::{Duration, Instant};
+
+use earendil_packet::crypt::{AeadKey, OnionPublic, OnionSecret};
+use zeroize::Zeroize;
+
+use super::{pad_nonce, HavenMsg};
+
+/// How long the client waits for the reply to a rekey before asking again.
+const REKEY_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often a session replaces its keys.
+#[derive(Clone, Copy, Debug)]
+pub struct RatchetPolicy {
+    /// messages encrypted or decrypted under one set of keys
+    pub messages: u64,
+    /// time spent under one set of keys
+    pub interval: Duration,
+}
+
+/// The symmetric keys of a session. Every so often the client starts a fresh Diffie-Hellman exchange, and both sides mix its result into the keys and forget the old ones, so that stealing the current keys doesn't expose earlier traffic.
+///
+/// Rekey messages are authenticated with the current chain key, so nobody outside the session can make the two sides step apart.
+pub(super) struct KeyRatchet {
+    is_client: bool,
+    policy: RatchetPolicy,
+    epoch: u64,
+    /// secret that the keys of the next epoch are derived from, together with the next exchange
+    chain_key: [u8; 32],
+    enc_key: AeadKey,
+    /// the server's encryption key of the current epoch, held back until the client sends under the new keys, since the client can't decrypt with it before handling our reply
+    next_enc_key: Option<AeadKey>,
+    dec_key: AeadKey,
+    /// the decryption key of the previous epoch, kept only until the peer sends something under the current one
+    prev_dec_key: Option<AeadKey>,
+    /// the client's half of the exchange it started, and when it last asked for the reply
+    pending: Option<(OnionSecret, Instant)>,
+    /// the server's reply to the latest rekey, resent if the client asks again
+    last_reply: Option<(OnionPublic, HavenMsg)>,
+    messages_in_epoch: u64,
+    epoch_started: Instant,
+}
+
+impl KeyRatchet {
+    pub fn new(shared_sec: &[u8; 32], is_client: bool, policy: RatchetPolicy) -> Self {
+        let (chain_key, enc_key, dec_key) = derive_keys(shared_sec, is_client);
+        Self {
+            is_client,
+            policy,
+            epoch: 0,
+            chain_key,
+            enc_key,
+            next_enc_key: None,
+            dec_key,
+            prev_dec_key: None,
+            pending: None,
+            last_reply: None,
+            messages_in_epoch: 0,
+            epoch_started: Instant::now(),
+        }
+    }
+
+    /// How many ratchet steps were taken so far.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn seal(&mut self, nonce: u64, plain: &[u8]) -> Vec<u8> {
+        self.messages_in_epoch += 1;
+        self.enc_key.seal(&pad_nonce(nonce), plain)
+    }
+
+    /// Decrypts a message sent under the current keys, or under the previous ones if the peer hasn't switched yet.
+    pub fn open(&mut self, nonce: u64, ctext: &[u8]) -> Option<Vec<u8>> {
+        let nonce = pad_nonce(nonce);
+        if let Ok(plain) = self.dec_key.open(&nonce, ctext) {
+            self.messages_in_epoch += 1;
+            // the peer switched, so nothing it sends can need the previous key anymore, and it can read what we send under the new one
+            self.prev_dec_key = None;
+            if let Some(enc_key) = self.next_enc_key.take() {
+                self.enc_key = enc_key;
+            }
+            return Some(plain);
+        }
+        self.prev_dec_key.as_ref()?.open(&nonce, ctext).ok()
+    }
+
+    /// When [KeyRatchet::poll_step] should next be called, or `None` if we're the server, which never starts a step.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if !self.is_client {
+            return None;
+        }
+        Some(match &self.pending {
+            Some((_, asked_at)) => *asked_at + REKEY_RETRY_INTERVAL,
+            None => self.epoch_started + self.policy.interval,
+        })
+    }
+
+    /// Returns the rekey message to send, if we're the client and a step is due or its reply is overdue.
+    pub fn poll_step(&mut self) -> Option<HavenMsg> {
+        if !self.is_client {
+            return None;
+        }
+        let now = Instant::now();
+        let due = self.messages_in_epoch >= self.policy.messages
+            || now >= self.epoch_started + self.policy.interval;
+        let eph_pk = match &mut self.pending {
+            Some((osk, asked_at)) if now >= *asked_at + REKEY_RETRY_INTERVAL => {
+                *asked_at = now;
+                osk.public()
+            }
+            None if due => {
+                let osk = OnionSecret::generate();
+                let eph_pk = osk.public();
+                self.pending = Some((osk, now));
+                eph_pk
+            }
+            _ => return None,
+        };
+        let epoch = self.epoch + 1;
+        Some(HavenMsg::Rekey {
+            epoch,
+            eph_pk,
+            mac: rekey_mac(&self.chain_key, b"rekey", epoch, &eph_pk),
+        })
+    }
+
+    /// Handles the client's rekey as the server, stepping to the next epoch. Returns the reply to send, or `None` if the rekey is stale or forged.
+    pub fn on_rekey(&mut self, epoch: u64, eph_pk: OnionPublic, mac: [u8; 32]) -> Option<HavenMsg> {
+        if self.is_client {
+            return None;
+        }
+        if epoch == self.epoch {
+            // our reply got lost, so the client asked again
+            return match &self.last_reply {
+                Some((asked_pk, reply)) if *asked_pk == eph_pk => Some(reply.clone()),
+                _ => None,
+            };
+        }
+        if epoch != self.epoch + 1 || !verify_mac(&self.chain_key, b"rekey", epoch, &eph_pk, mac) {
+            return None;
+        }
+        let osk = OnionSecret::generate();
+        let my_pk = osk.public();
+        let reply = HavenMsg::RekeyReply {
+            epoch,
+            eph_pk: my_pk,
+            mac: rekey_mac(&self.chain_key, b"rekey-reply", epoch, &my_pk),
+        };
+        self.step(&osk, &eph_pk);
+        self.last_reply = Some((eph_pk, reply.clone()));
+        Some(reply)
+    }
+
+    /// Handles the server's reply as the client, stepping to the next epoch. Returns whether the reply was accepted.
+    pub fn on_rekey_reply(&mut self, epoch: u64, eph_pk: OnionPublic, mac: [u8; 32]) -> bool {
+        if epoch != self.epoch + 1
+            || !verify_mac(&self.chain_key, b"rekey-reply", epoch, &eph_pk, mac)
+        {
+            return false;
+        }
+        match self.pending.take() {
+            Some((osk, _)) => {
+                self.step(&osk, &eph_pk);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn step(&mut self, my_osk: &OnionSecret, their_pk: &OnionPublic) {
+        let mut shared_sec = my_osk.shared_secret(their_pk);
+        let mut root = *blake3::keyed_hash(&self.chain_key, &shared_sec).as_bytes();
+        let (chain_key, enc_key, dec_key) = derive_keys(&root, self.is_client);
+        shared_sec.zeroize();
+        root.zeroize();
+        self.chain_key.zeroize();
+        self.chain_key = chain_key;
+        // dropping an AeadKey zeroes it
+        if self.is_client {
+            self.enc_key = enc_key;
+        } else if let Some(held) = self.next_enc_key.replace(enc_key) {
+            // the client sent this rekey after stepping to the keys we held back, so it can read them now
+            self.enc_key = held;
+        }
+        self.prev_dec_key = Some(std::mem::replace(&mut self.dec_key, dec_key));
+        self.epoch += 1;
+        self.messages_in_epoch = 0;
+        self.epoch_started = Instant::now();
+    }
+}
+
+impl Drop for KeyRatchet {
+    fn drop(&mut self) {
+        self.chain_key.zeroize();
+    }
+}
+
+/// Derives the chain key and the encryption and decryption keys of an epoch from its root secret.
+fn derive_keys(root: &[u8; 32], is_client: bool) -> ([u8; 32], AeadKey, AeadKey) {
+    let chain_key = *blake3::keyed_hash(blake3::hash(b"haven-chain").as_bytes(), root).as_bytes();
+    let up_key = AeadKey::from_bytes(
+        blake3::keyed_hash(blake3::hash(b"haven-up").as_bytes(), root).as_bytes(),
+    );
+    let down_key = AeadKey::from_bytes(
+        blake3::keyed_hash(blake3::hash(b"haven-dn").as_bytes(), root).as_bytes(),
+    );
+    if is_client {
+        (chain_key, up_key, down_key)
+    } else {
+        (chain_key, down_key, up_key)
+    }
+}
+
+fn rekey_mac(chain_key: &[u8; 32], label: &[u8], epoch: u64, eph_pk: &OnionPublic) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new_keyed(chain_key);
+    hasher.update(label);
+    hasher.update(&epoch.to_le_bytes());
+    hasher.update(eph_pk.as_bytes());
+    *hasher.finalize().as_bytes()
+}
+
+fn verify_mac(
+    chain_key: &[u8; 32],
+    label: &[u8],
+    epoch: u64,
+    eph_pk: &OnionPublic,
+    mac: [u8; 32],
+) -> bool {
+    // comparing blake3 hashes takes constant time
+    blake3::Hash::from(rekey_mac(chain_key, label, epoch, eph_pk)) == blake3::Hash::from(mac)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ratchet_pair(shared_sec: &[u8; 32]) -> (KeyRatchet, KeyRatchet) {
+        let policy = RatchetPolicy {
+            messages: 2,
+            interval: Duration::from_secs(60),
+        };
+        (
+            KeyRatchet::new(shared_sec, true, policy),
+            KeyRatchet::new(shared_sec, false, policy),
+        )
+    }
+
+    fn rekey(client: &mut KeyRatchet, server: &mut KeyRatchet) {
+        let Some(HavenMsg::Rekey { epoch, eph_pk, mac }) = client.poll_step() else {
+            panic!("client didn't start a step");
+        };
+        let Some(HavenMsg::RekeyReply { epoch, eph_pk, mac }) = server.on_rekey(epoch, eph_pk, mac)
+        else {
+            panic!("server rejected the rekey");
+        };
+        assert!(client.on_rekey_reply(epoch, eph_pk, mac));
+    }
+
+    #[test]
+    fn test_ratchet_step() {
+        let shared_sec = [7u8; 32];
+        let (mut client, mut server) = ratchet_pair(&shared_sec);
+        // a pair that never steps still holds the initial keys
+        let (mut stale_client, mut stale_server) = ratchet_pair(&shared_sec);
+
+        let old_ctext = client.seal(0, b"hello");
+        assert_eq!(server.open(0, &old_ctext).unwrap(), b"hello");
+        assert!(client.poll_step().is_none());
+        let in_flight = client.seal(1, b"in flight");
+
+        rekey(&mut client, &mut server);
+        assert_eq!((client.epoch(), server.epoch()), (1, 1));
+
+        let new_ctext = client.seal(2, b"hello");
+        assert_ne!(new_ctext, stale_client.seal(2, b"hello"));
+        assert!(stale_server.open(2, &new_ctext).is_none());
+        // messages sent before the step are readable until the client sends under the new keys
+        assert_eq!(server.open(1, &in_flight).unwrap(), b"in flight");
+        assert_eq!(server.open(2, &new_ctext).unwrap(), b"hello");
+        assert!(server.open(1, &in_flight).is_none());
+        assert!(server.open(0, &old_ctext).is_none());
+
+        let reply = server.seal(3, b"world");
+        assert_eq!(client.open(3, &reply).unwrap(), b"world");
+    }
+
+    #[test]
+    fn test_server_sends_during_rekey() {
+        let (mut client, mut server) = ratchet_pair(&[7u8; 32]);
+        client.seal(0, b"");
+        client.seal(1, b"");
+        let Some(HavenMsg::Rekey { epoch, eph_pk, mac }) = client.poll_step() else {
+            panic!("client didn't start a step");
+        };
+        let Some(HavenMsg::RekeyReply { epoch, eph_pk, mac }) = server.on_rekey(epoch, eph_pk, mac)
+        else {
+            panic!("server rejected the rekey");
+        };
+        // sent after the server stepped, but before the client handles the reply
+        let before_reply = server.seal(2, b"before reply");
+        assert_eq!(client.open(2, &before_reply).unwrap(), b"before reply");
+        assert!(client.on_rekey_reply(epoch, eph_pk, mac));
+        // the server keeps the old keys until it hears from the client under the new ones
+        let after_reply = server.seal(3, b"after reply");
+        assert_eq!(client.open(3, &after_reply).unwrap(), b"after reply");
+
+        // a second step before anything the client sent under the first one arrived
+        client.seal(4, b"lost");
+        client.seal(5, b"lost");
+        rekey(&mut client, &mut server);
+        assert_eq!((client.epoch(), server.epoch()), (2, 2));
+        let second_step = server.seal(6, b"second step");
+        assert_eq!(client.open(6, &second_step).unwrap(), b"second step");
+
+        let up = client.seal(7, b"up");
+        assert_eq!(server.open(7, &up).unwrap(), b"up");
+        let down = server.seal(8, b"down");
+        assert_eq!(client.open(8, &down).unwrap(), b"down");
+        assert!(client.open(6, &second_step).is_none());
+    }
+
+    #[test]
+    fn test_forged_rekey() {
+        let (mut client, mut server) = ratchet_pair(&[7u8; 32]);
+        let eph_pk = OnionSecret::generate().public();
+        assert!(server.on_rekey(1, eph_pk, [0u8; 32]).is_none());
+        assert_eq!(server.epoch(), 0);
+
+        // a replayed rekey is answered with the same reply, without stepping again
+        client.seal(0, b"");
+        client.seal(1, b"");
+        let Some(HavenMsg::Rekey { epoch, eph_pk, mac }) = client.poll_step() else {
+            panic!("client didn't start a step");
+        };
+        let reply = server.on_rekey(epoch, eph_pk, mac).unwrap();
+        assert!(server.on_rekey(epoch, eph_pk, mac).is_some());
+        assert_eq!(server.epoch(), 1);
+        let HavenMsg::RekeyReply { epoch, eph_pk, mac } = reply else {
+            panic!("server didn't reply");
+        };
+        assert!(client.on_rekey_reply(epoch, eph_pk, mac));
+        assert!(!client.on_rekey_reply(epoch, eph_pk, mac));
+    }
+}
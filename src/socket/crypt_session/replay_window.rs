@@ -0,0 +1,76 @@
+/// How many nonces, counting the highest one accepted, the window remembers.
+const WINDOW_SIZE: u64 = 64;
+
+/// A sliding-window anti-replay check, the same as IPsec's and WireGuard's. Nonces more than [WINDOW_SIZE] below the highest one accepted are rejected, as are nonces already accepted within the window.
+///
+/// Checking and accepting are separate, so that only nonces of messages that decrypted move the window, and forged messages can't push it past legitimate ones.
+#[derive(Default)]
+pub(super) struct ReplayWindow {
+    max_nonce_seen: u64,
+    /// bit `i` is set if nonce `max_nonce_seen - i` was accepted
+    bitmap: u64,
+}
+
+impl ReplayWindow {
+    /// Whether a message with this nonce may be accepted.
+    pub fn check(&self, nonce: u64) -> bool {
+        if nonce > self.max_nonce_seen {
+            return true;
+        }
+        let offset = self.max_nonce_seen - nonce;
+        offset < WINDOW_SIZE && self.bitmap & (1 << offset) == 0
+    }
+
+    /// Marks a nonce as accepted, sliding the window forward if it's the highest one yet. Callers must first [ReplayWindow::check] it.
+    pub fn accept(&mut self, nonce: u64) {
+        if nonce > self.max_nonce_seen {
+            let shift = nonce - self.max_nonce_seen;
+            self.bitmap = if shift < WINDOW_SIZE {
+                self.bitmap << shift
+            } else {
+                0
+            };
+            self.max_nonce_seen = nonce;
+        }
+        self.bitmap |= 1 << (self.max_nonce_seen - nonce);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the nonces that the window lets through, in order.
+    fn deliver(window: &mut ReplayWindow, nonces: &[u64]) -> Vec<u64> {
+        nonces
+            .iter()
+            .copied()
+            .filter(|&nonce| {
+                let fresh = window.check(nonce);
+                if fresh {
+                    window.accept(nonce);
+                }
+                fresh
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_replays() {
+        let mut window = ReplayWindow::default();
+        assert_eq!(
+            deliver(&mut window, &[0, 0, 1, 3, 2, 3, 1]),
+            vec![0, 1, 3, 2]
+        );
+        // reordering within the window is fine, but anything older is dropped
+        assert_eq!(
+            deliver(&mut window, &[100, 40, 37, 36, 37]),
+            vec![100, 40, 37]
+        );
+        // a jump past the whole window forgets everything below it
+        assert_eq!(
+            deliver(&mut window, &[1000, 100, 999, 999]),
+            vec![1000, 999]
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use earendil_crypt::{Fingerprint, IdentitySecret};
+use earendil_packet::crypt::AeadKey;
+use moka::sync::Cache;
+use serde::{Deserialize, Serialize};
+use stdcode::StdcodeSerializeExt;
+use zeroize::Zeroize;
+
+use super::Endpoint;
+
+/// How long a resumption token can be redeemed after it was issued.
+pub const RESUMPTION_TOKEN_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Lets a client that talked to a haven recently start a new session without a handshake. It's sealed under a key that only the issuing haven knows, and can be redeemed once.
+///
+/// A resumed session has no forward secrecy of its own: its keys follow from the token and the earlier session's secret, so whoever records the token and later learns the haven's identity secret can read the session until its first rekey mixes in a fresh exchange.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ResumptionToken {
+    /// random, and picks the key that the blob is sealed with
+    nonce: [u8; 32],
+    /// a sealed [TokenContents]
+    blob: Bytes,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TokenContents {
+    resume_sec: [u8; 32],
+    /// the client the token was issued to, so that nobody else can redeem it
+    client_fp: Fingerprint,
+    /// seconds since the Unix epoch
+    expires_at: u64,
+}
+
+/// The resumption state of a haven socket, shared by its sessions.
+///
+/// Which tokens were redeemed is only kept in memory, while tokens stay valid across restarts. So a token that was redeemed before the haven restarted can be replayed once more after it, until it expires. Replaying it gets nobody a session they couldn't already have: a token only works for the client it was issued to, and the resumed keys also depend on the secret of the earlier session.
+pub struct Resumption {
+    /// the key that tokens we issue are sealed with, derived from our identity so that tokens outlive restarts
+    ticket_key: [u8; 32],
+    /// nonces of the tokens redeemed with us, so that each token resumes one session only; forgotten on restart
+    redeemed: Cache<[u8; 32], ()>,
+    /// the tokens that havens issued to us, with the secret that each resumes
+    tokens: Cache<Endpoint, (ResumptionToken, [u8; 32])>,
+}
+
+impl Resumption {
+    /// Creates the resumption state of a haven socket bound with the given identity.
+    pub fn new(isk: &IdentitySecret) -> Self {
+        Self {
+            ticket_key: blake3::derive_key("earendil haven resumption ticket key", isk.as_bytes()),
+            redeemed: Cache::builder().time_to_live(RESUMPTION_TOKEN_TTL).build(),
+            tokens: Cache::builder().time_to_live(RESUMPTION_TOKEN_TTL).build(),
+        }
+    }
+
+    /// Issues a token that lets the given client resume a session with `resume_sec`.
+    pub fn issue(&self, resume_sec: [u8; 32], client_fp: Fingerprint) -> ResumptionToken {
+        let nonce: [u8; 32] = rand::random();
+        let contents = TokenContents {
+            resume_sec,
+            client_fp,
+            expires_at: unix_now() + RESUMPTION_TOKEN_TTL.as_secs(),
+        };
+        let mut plain = contents.stdcode();
+        let blob = self.token_key(&nonce).seal(&[0; 12], &plain);
+        plain.zeroize();
+        ResumptionToken {
+            nonce,
+            blob: blob.into(),
+        }
+    }
+
+    /// Returns the secret of a token issued to the given client, unless the token is forged, expired, or already redeemed.
+    pub fn redeem(
+        &self,
+        token: &ResumptionToken,
+        client_fp: Fingerprint,
+    ) -> anyhow::Result<[u8; 32]> {
+        let mut plain = self
+            .token_key(&token.nonce)
+            .open(&[0; 12], &token.blob)
+            .map_err(|_| anyhow::anyhow!("resumption token wasn't issued by us"))?;
+        let contents = stdcode::deserialize::<TokenContents>(&plain);
+        plain.zeroize();
+        let contents = contents?;
+        if contents.client_fp != client_fp {
+            anyhow::bail!("resumption token was issued to another client")
+        }
+        if contents.expires_at < unix_now() {
+            anyhow::bail!("resumption token expired")
+        }
+        // checking and marking the token in one step, so that concurrent handshakes can't both redeem it
+        if !self.redeemed.entry(token.nonce).or_insert(()).is_fresh() {
+            anyhow::bail!("resumption token was already redeemed")
+        }
+        Ok(contents.resume_sec)
+    }
+
+    /// Remembers a token that a haven issued to us.
+    pub fn store(&self, remote: Endpoint, token: ResumptionToken, resume_sec: [u8; 32]) {
+        self.tokens.insert(remote, (token, resume_sec));
+    }
+
+    /// Takes the token that a haven issued to us, if there is one that hasn't expired, since tokens can only be redeemed once.
+    pub fn take(&self, remote: Endpoint) -> Option<(ResumptionToken, [u8; 32])> {
+        self.tokens.remove(&remote)
+    }
+
+    fn token_key(&self, nonce: &[u8; 32]) -> AeadKey {
+        AeadKey::from_bytes(blake3::keyed_hash(&self.ticket_key, nonce).as_bytes())
+    }
+}
+
+impl Drop for Resumption {
+    fn drop(&mut self) {
+        self.ticket_key.zeroize();
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before the Unix epoch")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redeem() {
+        let server_isk = IdentitySecret::generate();
+        let server = Resumption::new(&server_isk);
+        let alice = Fingerprint::from_bytes(&[1; 20]);
+        let mallory = Fingerprint::from_bytes(&[2; 20]);
+
+        let token = server.issue([7; 32], alice);
+        assert!(server.redeem(&token, mallory).is_err());
+        assert_eq!(server.redeem(&token, alice).unwrap(), [7; 32]);
+        // tokens resume one session only
+        assert!(server.redeem(&token, alice).is_err());
+
+        // another haven can't read our tokens, but we still can after a restart
+        let token = server.issue([7; 32], alice);
+        assert!(Resumption::new(&IdentitySecret::generate())
+            .redeem(&token, alice)
+            .is_err());
+        assert_eq!(
+            Resumption::new(&server_isk).redeem(&token, alice).unwrap(),
+            [7; 32]
+        );
+        let mut forged = token.clone();
+        forged.nonce[0] ^= 1;
+        assert!(server.redeem(&forged, alice).is_err());
+        assert!(server.redeem(&token, alice).is_ok());
+    }
+}
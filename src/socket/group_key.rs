@@ -0,0 +1,53 @@
+use earendil_packet::crypt::{box_decrypt, box_encrypt, AeadError, OnionPublic, OnionSecret};
+use serde::{Deserialize, Serialize};
+
+use super::Endpoint;
+
+/// The recipients of a group message: every member's haven endpoint, along with the public key its copy is encrypted to.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct GroupPublicKey {
+    members: Vec<(Endpoint, OnionPublic)>,
+}
+
+impl GroupPublicKey {
+    pub fn new(members: Vec<(Endpoint, OnionPublic)>) -> Self {
+        Self { members }
+    }
+
+    pub fn add_member(&mut self, endpoint: Endpoint, member_pk: OnionPublic) {
+        self.members.push((endpoint, member_pk));
+    }
+
+    pub fn members(&self) -> &[(Endpoint, OnionPublic)] {
+        &self.members
+    }
+
+    /// Encrypts a message separately to every member, using a fresh X25519 key exchange per copy.
+    pub(crate) fn encrypt(&self, body: &[u8]) -> Vec<(Endpoint, Vec<u8>)> {
+        self.members
+            .iter()
+            .map(|(endpoint, member_pk)| (*endpoint, box_encrypt(body, member_pk).0))
+            .collect()
+    }
+}
+
+/// A group member's secret key, used to decrypt the copies of group messages addressed to it.
+///
+/// Like [OnionSecret], this is intentionally not serializable.
+#[derive(Clone)]
+pub struct GroupSecretKey(OnionSecret);
+
+impl GroupSecretKey {
+    pub fn generate() -> Self {
+        Self(OnionSecret::generate())
+    }
+
+    /// Returns the public key that other members put into their [GroupPublicKey].
+    pub fn public(&self) -> OnionPublic {
+        self.0.public()
+    }
+
+    pub(crate) fn decrypt(&self, ctext: &[u8]) -> Result<Vec<u8>, AeadError> {
+        box_decrypt(ctext, &self.0).map(|(plain, _)| plain)
+    }
+}
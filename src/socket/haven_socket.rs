@@ -1,41 +1,195 @@
 use bytes::Bytes;
 use clone_macro::clone;
+use dashmap::DashMap;
 use earendil_crypt::{Fingerprint, IdentitySecret};
-use earendil_packet::{crypt::OnionSecret, Dock};
-use moka::sync::Cache;
-use smol::{
-    channel::{Receiver, Sender},
-    Task, Timer,
+use earendil_packet::{
+    crypt::{OnionPublic, OnionSecret},
+    Dock,
 };
+use futures_util::future;
+use moka::sync::Cache;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use smol::{channel::Receiver, Task, Timer};
 use smol_timeout::TimeoutExt;
 use smolscale::immortal::{Immortal, RespawnStrategy};
-use std::time::Duration;
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use stdcode::StdcodeSerializeExt;
 
 use crate::{
-    daemon::{context::DaemonContext, dht::dht_insert},
+    config::RouteProfile,
+    daemon::{
+        context::DaemonContext,
+        dht::{dht_insert, dht_remove},
+    },
     global_rpc::{transport::GlobalRpcTransport, GlobalRpcClient},
-    haven_util::{HavenLocator, RegisterHavenReq},
+    haven_util::{HavenLocator, RegisterHavenReq, HAVEN_FORWARD_DOCK},
 };
 
 use super::{
-    crypt_session::{CryptSession, HavenMsg},
+    crypt_session::{
+        AckToken, CryptSession, Handshake, HavenMsg, IncomingSinks, RatchetPolicy, Resumption,
+        SessionStart, SessionStats,
+    },
+    group_key::{GroupPublicKey, GroupSecretKey},
     n2r_socket::N2rSocket,
-    Endpoint, SocketRecvError, SocketSendError,
+    Endpoint, SocketRecvError, SocketSendError, SocketStats,
 };
 
+/// How long changing the rendezvous point waits for the old ones to deregister us.
+const DEREGISTER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a haven re-inserts its locator into the DHT when its rendezvous points stay the same.
+const LOCATOR_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The least time between two inserts of a haven's locator, which keeps havens well below the 10 inserts a minute that relays accept by default.
+const MIN_LOCATOR_INSERT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many messages a session sends before it's replaced by a new one, so that its nonces never wrap around. Ratcheting changes the keys far more often, but not the nonce counter.
+const MAX_SESSION_MESSAGES: u64 = 1 << 63;
+
+/// How long messages that arrive before their session are kept, waiting for it to start.
+const EARLY_MSG_TTL: Duration = Duration::from_secs(10);
+
+/// How many not-yet started sessions we keep early messages for at once.
+const EARLY_MSG_SESSIONS: u64 = 1000;
+
+/// How many early messages we keep for each not-yet started session.
+const EARLY_MSGS_PER_SESSION: usize = 64;
+
+/// Tunables of a [HavenSocket]. Missing fields take their default values when deserializing.
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HavenSocketConfig {
+    /// how long an encryption session with a remote endpoint is kept after it was last used
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    pub crypt_session_ttl: Duration,
+    /// how many encryption sessions are kept at once
+    pub crypt_session_max_capacity: u64,
+    /// how many decrypted messages can wait for `recv_from` before new ones are dropped
+    pub recv_channel_capacity: usize,
+    /// largest decrypted message that is delivered; larger ones are dropped, so that queued messages can't pin unbounded memory
+    pub max_message_size: usize,
+    /// how many messages a session encrypts or decrypts before the client steps its keys to fresh ones
+    pub ratchet_messages: u64,
+    /// how long a session uses the same keys at most before the client steps them to fresh ones
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    pub ratchet_interval: Duration,
+    /// whether clients must sign the transcript of their handshake, proving that they hold the identity they open the session with
+    pub require_client_auth: bool,
+    /// the only clients that can open sessions when `require_client_auth` is set; empty allows every client
+    #[serde_as(as = "Vec<serde_with::DisplayFromStr>")]
+    pub allowed_clients: Vec<Fingerprint>,
+}
+
+impl Default for HavenSocketConfig {
+    fn default() -> Self {
+        Self {
+            crypt_session_ttl: Duration::from_secs(60 * 30),
+            crypt_session_max_capacity: 100_000,
+            recv_channel_capacity: 1000,
+            max_message_size: 65536,
+            ratchet_messages: 100,
+            ratchet_interval: Duration::from_secs(60),
+            require_client_auth: false,
+            allowed_clients: vec![],
+        }
+    }
+}
+
+impl HavenSocketConfig {
+    /// How long a session that only receives goes without sending before it sends a keepalive: 5 minutes before it would expire, or halfway there for short TTLs.
+    fn keepalive_interval(&self) -> Duration {
+        const KEEPALIVE_MARGIN: Duration = Duration::from_secs(5 * 60);
+        if self.crypt_session_ttl > KEEPALIVE_MARGIN * 2 {
+            self.crypt_session_ttl - KEEPALIVE_MARGIN
+        } else {
+            self.crypt_session_ttl / 2
+        }
+    }
+
+    fn ratchet_policy(&self) -> RatchetPolicy {
+        RatchetPolicy {
+            messages: self.ratchet_messages,
+            interval: self.ratchet_interval,
+        }
+    }
+
+    fn client_auth_policy(&self) -> ClientAuthPolicy {
+        ClientAuthPolicy {
+            required: self.require_client_auth,
+            allowed_clients: Arc::new(self.allowed_clients.iter().copied().collect()),
+        }
+    }
+}
+
+/// Which clients a haven accepts sessions from.
+#[derive(Clone)]
+struct ClientAuthPolicy {
+    required: bool,
+    allowed_clients: Arc<HashSet<Fingerprint>>,
+}
+
+impl ClientAuthPolicy {
+    /// Checks a client's handshake with us, requiring a valid signature over its transcript if client authentication is on.
+    fn check_handshake(
+        &self,
+        hs: &Handshake,
+        transcript_sig: &[u8],
+        client_fp: Fingerprint,
+        server_fp: Fingerprint,
+    ) -> anyhow::Result<()> {
+        if self.required {
+            hs.verify_transcript(server_fp, transcript_sig)?;
+        }
+        self.check_allowed(client_fp)
+    }
+
+    /// Checks that a client is on the allowlist, if client authentication is on and there is one.
+    fn check_allowed(&self, client_fp: Fingerprint) -> anyhow::Result<()> {
+        if self.required
+            && !self.allowed_clients.is_empty()
+            && !self.allowed_clients.contains(&client_fp)
+        {
+            anyhow::bail!("client {client_fp} is not allowed")
+        }
+        Ok(())
+    }
+}
+
+/// A haven socket. Clones share the same underlying socket, like the two halves of a channel.
+#[derive(Clone)]
 pub struct HavenSocket {
     ctx: DaemonContext,
     n2r_socket: N2rSocket,
     identity_sk: IdentitySecret,
-    rendezvous_point: Option<Fingerprint>,
-    _register_haven_task: Option<Task<()>>,
+    /// every rendezvous point we try to register with; empty if we're a client
+    rendezvous_points: Arc<Mutex<Vec<Fingerprint>>>,
+    /// the rendezvous point that sessions we initiate go through
+    primary_rendezvous: Arc<Mutex<Option<Fingerprint>>>,
+    /// the rendezvous points that acknowledged our latest registration with them
+    registered_rendezvous: Arc<DashMap<Fingerprint, ()>>,
+    /// the onion key advertised in our haven locators; only havens with rendezvous points have one
+    onion_pk: Arc<Mutex<Option<OnionPublic>>>,
+    register_haven_task: Arc<Mutex<Option<Task<()>>>>,
+    keepalive_interval: Duration,
+    ratchet_policy: RatchetPolicy,
+    resumption: Arc<Resumption>,
     /// mapping between destination endpoints and encryption sessions
     crypt_sessions: Cache<Endpoint, CryptSession>,
     /// buffer for decrypted incoming messages
     recv_incoming_decrypted: Receiver<(Bytes, Endpoint)>,
-    send_incoming_decrypted: Sender<(Bytes, Endpoint)>,
+    /// buffer for incoming group messages, which are still encrypted to the group key
+    recv_incoming_group: Receiver<(Bytes, Endpoint)>,
+    incoming_sinks: IncomingSinks,
     /// task that dispatches not-yet decrypted incoming packets to their right encrypters
-    _recv_task: Immortal,
+    _recv_task: Arc<Immortal>,
 }
 
 impl HavenSocket {
@@ -43,114 +197,117 @@ impl HavenSocket {
         ctx: DaemonContext,
         isk: IdentitySecret,
         dock: Option<Dock>,
-        rendezvous_point: Option<Fingerprint>,
+        rendezvous_points: Vec<Fingerprint>,
+    ) -> HavenSocket {
+        Self::bind_with_config(
+            ctx,
+            isk,
+            dock,
+            rendezvous_points,
+            HavenSocketConfig::default(),
+        )
+    }
+
+    pub fn bind_with_config(
+        ctx: DaemonContext,
+        isk: IdentitySecret,
+        dock: Option<Dock>,
+        rendezvous_points: Vec<Fingerprint>,
+        config: HavenSocketConfig,
     ) -> HavenSocket {
         let n2r_skt = N2rSocket::bind(ctx.clone(), isk, dock);
         let encrypters: Cache<Endpoint, CryptSession> = Cache::builder()
-            .max_capacity(100_000)
-            .time_to_live(Duration::from_secs(60 * 30))
+            .max_capacity(config.crypt_session_max_capacity)
+            .time_to_idle(config.crypt_session_ttl)
             .build();
-        let (send_incoming_decrypted, recv_incoming_decrypted) = smol::channel::bounded(1000);
+        let (send_incoming_decrypted, recv_incoming_decrypted) =
+            smol::channel::bounded(config.recv_channel_capacity);
+        let (send_incoming_group, recv_incoming_group) =
+            smol::channel::bounded(config.recv_channel_capacity);
+        let incoming_sinks = IncomingSinks {
+            regular: send_incoming_decrypted,
+            group: send_incoming_group,
+            max_message_size: config.max_message_size,
+        };
+        let keepalive_interval = config.keepalive_interval();
+        let ratchet_policy = config.ratchet_policy();
+        let resumption = Arc::new(Resumption::new(&isk));
+        let client_auth = config.client_auth_policy();
         let recv_task = Immortal::respawn(
             RespawnStrategy::Immediate,
             clone!(
-                [n2r_skt, encrypters, send_incoming_decrypted, ctx],
+                [
+                    n2r_skt,
+                    encrypters,
+                    incoming_sinks,
+                    resumption,
+                    client_auth,
+                    ctx
+                ],
                 move || {
                     recv_task(
                         n2r_skt.clone(),
                         encrypters.clone(),
                         isk,
-                        rendezvous_point,
-                        send_incoming_decrypted.clone(),
+                        incoming_sinks.clone(),
+                        keepalive_interval,
+                        ratchet_policy,
+                        resumption.clone(),
+                        client_auth.clone(),
                         ctx.clone(),
                     )
                 }
             ),
         );
 
-        if let Some(rob) = rendezvous_point {
+        let registered_rendezvous: Arc<DashMap<Fingerprint, ()>> = Default::default();
+        // until some rendezvous point acknowledges us, optimistically treat the first one as primary
+        let primary_rendezvous = Arc::new(Mutex::new(rendezvous_points.first().copied()));
+        let (register_haven_task, onion_pk) = if rendezvous_points.is_empty() {
+            // We're Alice
+            (None, None)
+        } else {
             // We're Bob:
-            // spawn a task that keeps telling our rendezvous relay node to remember us once in a while
-            log::debug!("binding haven with rendezvous_point {}", rob);
-            let context = ctx.clone();
-            let registration_isk = isk;
-            let task = smolscale::spawn(async move {
-                // generate a new onion keypair
-                let onion_sk = OnionSecret::generate();
-                let onion_pk = onion_sk.public();
-                // register forwarding with the rendezvous relay node
-                let gclient = GlobalRpcClient(GlobalRpcTransport::new(context.clone(), isk, rob));
-                let forward_req = RegisterHavenReq::new(registration_isk);
-                loop {
-                    match gclient
-                        .alloc_forward(forward_req.clone())
-                        .timeout(Duration::from_secs(30))
-                        .await
-                    {
-                        Some(Err(e)) => {
-                            log::debug!("registering haven rendezvous {rob} failed: {:?}", e);
-                            Timer::after(Duration::from_secs(3)).await;
-                            continue;
-                        }
-                        None => {
-                            log::debug!("registering haven rendezvous relay timed out");
-                            Timer::after(Duration::from_secs(3)).await;
-                        }
-                        _ => {
-                            dht_insert(
-                                &context,
-                                HavenLocator::new(registration_isk, onion_pk, rob),
-                            )
-                            .timeout(Duration::from_secs(30))
-                            .await;
-                            Timer::after(Duration::from_secs(5)).await;
-                        }
-                    }
-                }
-            });
+            // spawn a task that keeps telling our rendezvous relay nodes to remember us once in a while
+            log::debug!(
+                "binding haven with rendezvous_points {:?}",
+                rendezvous_points
+            );
+            // generate a new onion keypair
+            let onion_pk = OnionSecret::generate().public();
+            let task = smolscale::spawn(register_haven_loop(
+                ctx.clone(),
+                isk,
+                onion_pk,
+                rendezvous_points.clone(),
+                primary_rendezvous.clone(),
+                registered_rendezvous.clone(),
+            ));
+            (Some(task), Some(onion_pk))
+        };
 
-            HavenSocket {
-                ctx,
-                n2r_socket: n2r_skt,
-                identity_sk: isk,
-                rendezvous_point,
-                _register_haven_task: Some(task),
-                crypt_sessions: encrypters,
-                recv_incoming_decrypted,
-                send_incoming_decrypted,
-                _recv_task: recv_task,
-            }
-        } else {
-            // We're Alice
-            HavenSocket {
-                ctx,
-                n2r_socket: n2r_skt,
-                identity_sk: isk,
-                rendezvous_point,
-                _register_haven_task: None,
-                crypt_sessions: encrypters,
-                recv_incoming_decrypted,
-                send_incoming_decrypted,
-                _recv_task: recv_task,
-            }
+        HavenSocket {
+            ctx,
+            n2r_socket: n2r_skt,
+            identity_sk: isk,
+            rendezvous_points: Arc::new(Mutex::new(rendezvous_points)),
+            primary_rendezvous,
+            registered_rendezvous,
+            onion_pk: Arc::new(Mutex::new(onion_pk)),
+            register_haven_task: Arc::new(Mutex::new(register_haven_task)),
+            keepalive_interval,
+            ratchet_policy,
+            resumption,
+            crypt_sessions: encrypters,
+            recv_incoming_decrypted,
+            recv_incoming_group,
+            incoming_sinks,
+            _recv_task: Arc::new(recv_task),
         }
     }
 
     pub async fn send_to(&self, body: Bytes, endpoint: Endpoint) -> Result<(), SocketSendError> {
-        let enc = self
-            .crypt_sessions
-            .try_get_with(endpoint, || {
-                CryptSession::new(
-                    self.identity_sk,
-                    endpoint,
-                    self.rendezvous_point,
-                    self.n2r_socket.clone(),
-                    self.send_incoming_decrypted.clone(),
-                    self.ctx.clone(),
-                    None,
-                )
-            })
-            .map_err(|e| SocketSendError::HavenEncryptionError(e.to_string()))?;
+        let enc = self.get_crypt_session(endpoint)?;
         if let Err(e) = enc.send_outgoing(body).await {
             self.crypt_sessions.remove(&endpoint);
             Err(SocketSendError::HavenEncryptionError(e.to_string()))
@@ -159,6 +316,92 @@ impl HavenSocket {
         }
     }
 
+    /// Sends the same message to several endpoints concurrently, so that any handshakes happen in parallel. Returns the outcome of each send, in the order of `endpoints`.
+    pub async fn send_to_many(
+        &self,
+        body: Bytes,
+        endpoints: Vec<Endpoint>,
+    ) -> Vec<Result<(), SocketSendError>> {
+        let sends = endpoints
+            .into_iter()
+            .map(|endpoint| self.send_to(body.clone(), endpoint));
+        future::join_all(sends).await
+    }
+
+    /// Sends a message that the receiving haven socket automatically acknowledges.
+    pub async fn send_with_ack(
+        &self,
+        body: Bytes,
+        endpoint: Endpoint,
+        ack_timeout: Duration,
+    ) -> Result<AckToken, SocketSendError> {
+        let enc = self.get_crypt_session(endpoint)?;
+        enc.send_outgoing_with_ack(body, ack_timeout)
+            .await
+            .map_err(|e| {
+                self.crypt_sessions.remove(&endpoint);
+                SocketSendError::HavenEncryptionError(e.to_string())
+            })
+    }
+
+    /// Sends a message and waits until the receiving haven socket acknowledges it, failing if no acknowledgement arrives within `timeout`.
+    pub async fn send_to_confirmed(
+        &self,
+        body: Bytes,
+        endpoint: Endpoint,
+        timeout: Duration,
+    ) -> Result<(), SocketSendError> {
+        let token = self.send_with_ack(body, endpoint, timeout).await?;
+        if token.wait().await {
+            Ok(())
+        } else {
+            Err(SocketSendError::NotAcknowledged)
+        }
+    }
+
+    fn get_crypt_session(&self, endpoint: Endpoint) -> Result<CryptSession, SocketSendError> {
+        let session = self.get_or_create_crypt_session(endpoint)?;
+        if session.stats().messages_sent < MAX_SESSION_MESSAGES {
+            return Ok(session);
+        }
+        // the session is about to run out of nonces, so it must never be used again
+        self.crypt_sessions.remove(&endpoint);
+        if self.onion_pk.lock().is_some() {
+            // only the client side can start a new handshake; the client rekeys when it next sends
+            return Err(SocketSendError::NonceExhausted);
+        }
+        log::debug!("rekeying the session with {endpoint} before its nonces run out");
+        self.get_or_create_crypt_session(endpoint)
+    }
+
+    fn get_or_create_crypt_session(
+        &self,
+        endpoint: Endpoint,
+    ) -> Result<CryptSession, SocketSendError> {
+        self.crypt_sessions
+            .try_get_with(endpoint, || {
+                // resume our last session with the endpoint if we can, saving the handshake's round trip
+                let start = match self.resumption.take(endpoint) {
+                    Some((token, resume_sec)) => SessionStart::resume(token, resume_sec),
+                    None => SessionStart::connect(&self.identity_sk, endpoint.fingerprint),
+                };
+                let session = CryptSession::new(
+                    self.identity_sk,
+                    endpoint,
+                    *self.primary_rendezvous.lock(),
+                    self.n2r_socket.clone(),
+                    self.incoming_sinks.clone(),
+                    self.ctx.clone(),
+                    start,
+                    self.ratchet_policy,
+                    self.resumption.clone(),
+                )?;
+                session.start_keepalive(self.keepalive_interval);
+                anyhow::Ok(session)
+            })
+            .map_err(|e| SocketSendError::HavenEncryptionError(e.to_string()))
+    }
+
     pub async fn recv_from(&self) -> Result<(Bytes, Endpoint), SocketRecvError> {
         Ok(self
             .recv_incoming_decrypted
@@ -167,21 +410,216 @@ impl HavenSocket {
             .expect("this must be infallible here, because the sending side is never dropped"))
     }
 
+    /// Returns the next decrypted message if one is already queued, without waiting.
+    pub fn try_recv_from(&self) -> Option<(Bytes, Endpoint)> {
+        self.recv_incoming_decrypted.try_recv().ok()
+    }
+
+    /// Waits at most `timeout` for the next decrypted message, returning `None` if none arrived.
+    pub async fn recv_from_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<Option<(Bytes, Endpoint)>, SocketRecvError> {
+        self.recv_from().timeout(timeout).await.transpose()
+    }
+
+    /// Sends a message to every member of a group, encrypting a separate copy to each member's key. Every copy is sent, even if sending an earlier one failed.
+    pub async fn send_to_group(
+        &self,
+        body: Bytes,
+        group_key: &GroupPublicKey,
+    ) -> Result<(), SocketSendError> {
+        let sends = group_key
+            .encrypt(&body)
+            .into_iter()
+            .map(|(endpoint, ctext)| async move {
+                let enc = self.get_crypt_session(endpoint)?;
+                enc.send_outgoing_group(ctext.into()).await.map_err(|e| {
+                    self.crypt_sessions.remove(&endpoint);
+                    SocketSendError::HavenEncryptionError(e.to_string())
+                })
+            });
+        future::join_all(sends).await.into_iter().collect()
+    }
+
+    /// Receives the next group message that can be decrypted with the given key. Group messages encrypted to other keys are dropped.
+    pub async fn recv_from_group(
+        &self,
+        group_sk: &GroupSecretKey,
+    ) -> Result<(Bytes, Endpoint), SocketRecvError> {
+        loop {
+            let (ctext, endpoint) =
+                self.recv_incoming_group.recv().await.expect(
+                    "this must be infallible here, because the sending side is never dropped",
+                );
+            match group_sk.decrypt(&ctext) {
+                Ok(plain) => return Ok((plain.into(), endpoint)),
+                Err(_) => log::debug!("dropping group message from {endpoint} for another key"),
+            }
+        }
+    }
+
     pub fn local_endpoint(&self) -> Endpoint {
         self.n2r_socket.local_endpoint()
     }
+
+    /// Returns the onion public key this haven advertises in the DHT, so that it can also be published out of band. Clients have none.
+    pub fn onion_public_key(&self) -> Option<OnionPublic> {
+        *self.onion_pk.lock()
+    }
+
+    /// Returns the ID of the encryption session with the given endpoint, if there is one. The other side reports the same ID, and logs it too.
+    pub fn session_id(&self, endpoint: Endpoint) -> Option<[u8; 8]> {
+        self.crypt_sessions
+            .get(&endpoint)
+            .map(|session| session.session_id())
+    }
+
+    /// Returns the traffic of the encryption session with the given endpoint, if there is one.
+    pub fn session_stats(&self, endpoint: Endpoint) -> Option<SessionStats> {
+        self.crypt_sessions
+            .get(&endpoint)
+            .map(|session| session.stats())
+    }
+
+    /// Returns statistics of the messages received by the underlying n2r socket.
+    pub fn socket_stats(&self) -> SocketStats {
+        self.n2r_socket.stats()
+    }
+
+    pub fn is_backpressured(&self) -> bool {
+        self.n2r_socket.is_backpressured()
+    }
+
+    pub fn set_route_profile(&self, profile: Option<RouteProfile>) {
+        self.n2r_socket.set_route_profile(profile)
+    }
+
+    /// Returns how many rendezvous points acknowledged our latest registration with them.
+    pub fn rendezvous_count(&self) -> usize {
+        self.registered_rendezvous.len()
+    }
+
+    /// Moves a haven server to another rendezvous point, deregistering it from its current ones. `None` takes the haven off every rendezvous point, while existing sessions keep working. Setting a rendezvous point on a client turns it into a haven server.
+    pub async fn set_rendezvous_point(&self, rendezvous_point: Option<Fingerprint>) {
+        // stop refreshing the old registrations, so they can't outlive the deregistration below
+        let old_task = self.register_haven_task.lock().take();
+        if let Some(task) = old_task {
+            task.cancel().await;
+        }
+        let old_points = std::mem::replace(
+            &mut *self.rendezvous_points.lock(),
+            rendezvous_point.into_iter().collect(),
+        );
+        self.registered_rendezvous.clear();
+        *self.primary_rendezvous.lock() = rendezvous_point;
+        let stale_points = old_points
+            .into_iter()
+            .filter(|rob| Some(*rob) != rendezvous_point)
+            .collect::<Vec<_>>();
+        self.deregister(&stale_points, Instant::now() + DEREGISTER_TIMEOUT)
+            .await;
+
+        if let Some(rob) = rendezvous_point {
+            log::debug!("moving haven to rendezvous {rob}");
+            let onion_pk = *self
+                .onion_pk
+                .lock()
+                .get_or_insert_with(|| OnionSecret::generate().public());
+            let task = smolscale::spawn(register_haven_loop(
+                self.ctx.clone(),
+                self.identity_sk,
+                onion_pk,
+                vec![rob],
+                self.primary_rendezvous.clone(),
+                self.registered_rendezvous.clone(),
+            ));
+            *self.register_haven_task.lock() = Some(task);
+        }
+    }
+
+    /// Closes the socket once everything sent so far has left, waiting at most `timeout`. A haven server also deregisters from its rendezvous relay and deletes its locator from the DHT, which affects every clone of the socket.
+    pub async fn close(self, timeout: Duration) -> Result<(), SocketSendError> {
+        let deadline = Instant::now() + timeout;
+        // stop refreshing the registration, so it can't outlive the deregistration below
+        let task = self.register_haven_task.lock().take();
+        if let Some(task) = task {
+            task.cancel().await;
+        }
+
+        loop {
+            let pending = self
+                .crypt_sessions
+                .iter()
+                .map(|(_, session)| session.outgoing_len())
+                .sum::<usize>()
+                + self.n2r_socket.pending_outgoing();
+            if pending == 0 {
+                break;
+            }
+            if Instant::now() >= deadline {
+                return Err(SocketSendError::CloseTimedOut(pending));
+            }
+            Timer::after(Duration::from_millis(10)).await;
+        }
+
+        let rendezvous_points = self.rendezvous_points.lock().clone();
+        self.deregister(&rendezvous_points, deadline).await;
+        if !rendezvous_points.is_empty()
+            && dht_remove(&self.ctx, self.identity_sk)
+                .timeout(deadline.saturating_duration_since(Instant::now()))
+                .await
+                .is_none()
+        {
+            log::debug!("removing haven locator from the DHT timed out");
+        }
+        Ok(())
+    }
+
+    /// Tells the given rendezvous points to stop forwarding to us, giving up at `deadline`. Deregistration is best effort, so no response is awaited.
+    async fn deregister(&self, rendezvous_points: &[Fingerprint], deadline: Instant) {
+        let forward_req = match serde_json::to_value(RegisterHavenReq::new(self.identity_sk)) {
+            Ok(forward_req) => forward_req,
+            Err(e) => {
+                log::warn!("could not serialize haven deregistration: {e}");
+                return;
+            }
+        };
+        for &rob in rendezvous_points {
+            let transport = GlobalRpcTransport::new(self.ctx.clone(), self.identity_sk, rob);
+            match transport
+                .send_notification("dealloc_forward", std::slice::from_ref(&forward_req))
+                .timeout(deadline.saturating_duration_since(Instant::now()))
+                .await
+            {
+                Some(Ok(())) => log::debug!("sent haven deregistration to rendezvous {rob}"),
+                Some(Err(e)) => log::debug!("deregistering haven from {rob} failed: {:?}", e),
+                None => log::debug!("deregistering haven from {rob} timed out"),
+            }
+        }
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn recv_task(
     n2r_skt: N2rSocket,
     encrypters: Cache<Endpoint, CryptSession>,
     isk: IdentitySecret,
-    rob: Option<Fingerprint>,
-    send_incoming_decrypted: Sender<(Bytes, Endpoint)>,
+    incoming_sinks: IncomingSinks,
+    keepalive_interval: Duration,
+    ratchet_policy: RatchetPolicy,
+    resumption: Arc<Resumption>,
+    client_auth: ClientAuthPolicy,
     ctx: DaemonContext,
 ) -> anyhow::Result<()> {
+    let my_fp = isk.public().fingerprint();
+    // messages that arrived before the session they belong to, which happens when they overtake the client's `Resume`
+    let early_msgs: Cache<Endpoint, Arc<Mutex<Vec<HavenMsg>>>> = Cache::builder()
+        .max_capacity(EARLY_MSG_SESSIONS)
+        .time_to_live(EARLY_MSG_TTL)
+        .build();
     loop {
-        let (n2r_msg, _rendezvous_ep) = n2r_skt.recv_from().await?;
+        let (n2r_msg, rendezvous_ep) = n2r_skt.recv_from().await?;
         let (body, remote): (Bytes, Endpoint) = stdcode::deserialize(&n2r_msg)?;
         let haven_msg: HavenMsg = stdcode::deserialize(&body)?;
 
@@ -189,24 +627,184 @@ async fn recv_task(
         match haven_msg.clone() {
             HavenMsg::ServerHs(_) => match encrypter {
                 Some(enc) => enc.send_incoming(haven_msg).await?,
-                None => anyhow::bail!("stray msg; dropping"),
+                None => log::debug!("dropping stray handshake from {remote}"),
             },
-            HavenMsg::ClientHs(hs) => encrypters.insert(
-                remote,
-                CryptSession::new(
+            HavenMsg::ClientHs { hs, transcript_sig } => {
+                if let Err(e) =
+                    client_auth.check_handshake(&hs, &transcript_sig, remote.fingerprint, my_fp)
+                {
+                    log::debug!("rejecting handshake from {remote}: {e}");
+                    continue;
+                }
+                let session = CryptSession::new(
                     isk,
                     remote,
-                    rob,
+                    // reply through the rendezvous point the client reached us through
+                    Some(rendezvous_ep.fingerprint),
                     n2r_skt.clone(),
-                    send_incoming_decrypted.clone(),
+                    incoming_sinks.clone(),
                     ctx.clone(),
-                    Some((hs, remote.fingerprint)),
-                )?,
-            ),
-            HavenMsg::Regular { nonce: _, inner: _ } => match encrypter {
+                    SessionStart::Accept(hs, remote.fingerprint),
+                    ratchet_policy,
+                    resumption.clone(),
+                )?;
+                session.start_keepalive(keepalive_interval);
+                encrypters.insert(remote, session);
+                // anything that arrived earlier was sealed under keys that this session doesn't have
+                early_msgs.invalidate(&remote);
+            }
+            HavenMsg::Resume {
+                token,
+                client_nonce,
+            } => match client_auth
+                .check_allowed(remote.fingerprint)
+                .and_then(|_| resumption.redeem(&token, remote.fingerprint))
+            {
+                Ok(resume_sec) => {
+                    let session = CryptSession::new(
+                        isk,
+                        remote,
+                        Some(rendezvous_ep.fingerprint),
+                        n2r_skt.clone(),
+                        incoming_sinks.clone(),
+                        ctx.clone(),
+                        SessionStart::AcceptResume {
+                            resume_sec,
+                            client_nonce,
+                        },
+                        ratchet_policy,
+                        resumption.clone(),
+                    )?;
+                    session.start_keepalive(keepalive_interval);
+                    if let Some(early) = early_msgs.remove(&remote) {
+                        let early = std::mem::take(&mut *early.lock());
+                        for msg in early {
+                            session.send_incoming(msg).await?;
+                        }
+                    }
+                    encrypters.insert(remote, session);
+                }
+                Err(e) => {
+                    log::debug!("rejecting session resumption from {remote}: {e}");
+                    // the client sends these again once it completes a handshake
+                    early_msgs.invalidate(&remote);
+                    let reply = (Bytes::from(HavenMsg::ResumeRejected.stdcode()), remote).stdcode();
+                    n2r_skt
+                        .send_to(
+                            reply.into(),
+                            Endpoint::new(rendezvous_ep.fingerprint, HAVEN_FORWARD_DOCK),
+                        )
+                        .await?;
+                }
+            },
+            HavenMsg::Regular { .. } | HavenMsg::AckRequest { .. } | HavenMsg::Group { .. } => {
+                match encrypter {
+                    Some(enc) => enc.send_incoming(haven_msg).await?,
+                    None => {
+                        let early = early_msgs.get_with(remote, Default::default);
+                        let mut early = early.lock();
+                        if early.len() < EARLY_MSGS_PER_SESSION {
+                            early.push(haven_msg);
+                        } else {
+                            log::debug!("dropping message from {remote}, which has no session");
+                        }
+                    }
+                }
+            }
+            HavenMsg::Keepalive => {
+                // looking the session up already reset its idle timer
+            }
+            HavenMsg::Ack { .. }
+            | HavenMsg::Rekey { .. }
+            | HavenMsg::RekeyReply { .. }
+            | HavenMsg::Ticket(_)
+            | HavenMsg::ResumeRejected => match encrypter {
                 Some(enc) => enc.send_incoming(haven_msg).await?,
-                None => anyhow::bail!("stray msg; dropping"),
+                None => log::debug!("dropping stray message from {remote}"),
             },
         }
     }
 }
+
+/// Keeps the haven registered with its rendezvous points, trying them in round-robin order. The first point to acknowledge while the primary is unregistered becomes the new primary, and every registered point is advertised in the DHT, sparingly enough that relays never rate-limit the inserts.
+async fn register_haven_loop(
+    ctx: DaemonContext,
+    isk: IdentitySecret,
+    onion_pk: OnionPublic,
+    rendezvous_points: Vec<Fingerprint>,
+    primary_rendezvous: Arc<Mutex<Option<Fingerprint>>>,
+    registered_rendezvous: Arc<DashMap<Fingerprint, ()>>,
+) {
+    let forward_req = RegisterHavenReq::new(isk);
+    // the rendezvous points we last advertised in the DHT, and when
+    let mut last_insert: Option<((Fingerprint, Vec<Fingerprint>), Instant)> = None;
+    for rob in rendezvous_points.iter().copied().cycle() {
+        // register forwarding with the rendezvous relay node
+        // every attempt fits in the 30 second timeout below, waiting 2, 4, 8 and 8 seconds, so a lost request is resent a few times
+        let gclient = GlobalRpcClient(
+            GlobalRpcTransport::builder(ctx.clone(), isk, rob)
+                .max_retries(4)
+                .max_backoff(Duration::from_secs(8))
+                .build(),
+        );
+        match gclient
+            .alloc_forward(forward_req.clone())
+            .timeout(Duration::from_secs(30))
+            .await
+        {
+            Some(Ok(Ok(()))) => {
+                registered_rendezvous.insert(rob, ());
+                let primary = {
+                    let mut primary = primary_rendezvous.lock();
+                    match *primary {
+                        Some(fp) if registered_rendezvous.contains_key(&fp) => fp,
+                        _ => {
+                            log::debug!("promoting rendezvous {rob} to primary");
+                            *primary = Some(rob);
+                            rob
+                        }
+                    }
+                };
+                let mut backups: Vec<Fingerprint> = registered_rendezvous
+                    .iter()
+                    .map(|entry| *entry.key())
+                    .filter(|fp| *fp != primary)
+                    .collect();
+                backups.sort_unstable();
+                let is_due = match &last_insert {
+                    None => true,
+                    Some((advertised, inserted_at)) => {
+                        inserted_at.elapsed() >= MIN_LOCATOR_INSERT_INTERVAL
+                            && (advertised != &(primary, backups.clone())
+                                || inserted_at.elapsed() >= LOCATOR_REFRESH_INTERVAL)
+                    }
+                };
+                if is_due {
+                    last_insert = Some(((primary, backups.clone()), Instant::now()));
+                    dht_insert(
+                        &ctx,
+                        HavenLocator::with_backups(isk, onion_pk, primary, backups),
+                    )
+                    .timeout(Duration::from_secs(30))
+                    .await;
+                }
+                Timer::after(Duration::from_secs(5)).await;
+            }
+            Some(Ok(Err(e))) => {
+                log::debug!("haven rendezvous {rob} refused registration: {e}");
+                registered_rendezvous.remove(&rob);
+                Timer::after(Duration::from_secs(3)).await;
+            }
+            Some(Err(e)) => {
+                log::debug!("registering haven rendezvous {rob} failed: {:?}", e);
+                registered_rendezvous.remove(&rob);
+                Timer::after(Duration::from_secs(3)).await;
+            }
+            None => {
+                log::debug!("registering haven rendezvous relay {rob} timed out");
+                registered_rendezvous.remove(&rob);
+                Timer::after(Duration::from_secs(3)).await;
+            }
+        }
+    }
+}
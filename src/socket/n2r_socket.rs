@@ -1,6 +1,9 @@
 use std::{
     collections::{HashMap, VecDeque},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
@@ -10,29 +13,117 @@ use concurrent_queue::ConcurrentQueue;
 use earendil_crypt::{Fingerprint, IdentitySecret};
 use earendil_packet::{Dock, Message};
 use futures_util::TryFutureExt;
+use moka::sync::Cache;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 
 use smol::channel::{Receiver, Sender};
+use smol_timeout::TimeoutExt;
 use smolscale::immortal::{Immortal, RespawnStrategy};
 
 use crate::{
-    daemon::context::{send_n2r, DaemonContext, SOCKET_RECV_QUEUES},
+    config::RouteProfile,
+    daemon::context::{send_n2r, DaemonContext, ROUTE_PROFILES, SOCKET_RECV_QUEUES},
     log_error,
     socket::SocketRecvError,
 };
 
-use super::{Endpoint, SocketSendError};
+use super::{Endpoint, SocketSendError, SocketStats};
+
+const OUTGOING_QUEUE_CAPACITY: usize = 10000;
+
+/// How long a received packet is remembered, so that copies of it are dropped.
+const DEDUP_WINDOW: Duration = Duration::from_secs(30);
+
+/// Identifies an onion packet as it arrived at this node. Every packet's header is encrypted to a fresh key, so two packets only share an ID if one is a copy of the other.
+pub(crate) type PacketId = [u8; 32];
+
+/// Tunables of an [N2rSocket]. Missing fields take their default values when deserializing.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct N2rSocketConfig {
+    /// how many received messages can wait for `recv_from`; once 80% of them are taken, the socket is backpressured and the daemon drops new messages for it
+    pub recv_window: usize,
+}
+
+impl Default for N2rSocketConfig {
+    fn default() -> Self {
+        Self { recv_window: 256 }
+    }
+}
+
+/// Traffic counters behind [N2rSocket::stats].
+#[derive(Default)]
+struct TrafficCounters {
+    packets_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    packets_received: AtomicU64,
+    bytes_received: AtomicU64,
+    send_errors: AtomicU64,
+    recv_errors: AtomicU64,
+}
 
 #[derive(Clone)]
 pub struct N2rSocket {
     bound_dock: Arc<BoundDock>,
-    recv_incoming: Receiver<(Message, Fingerprint)>,
+    recv_incoming: Receiver<(Message, Fingerprint, PacketId)>,
+    packets_dropped_backpressure: Arc<AtomicU64>,
     incoming_queue: Arc<ConcurrentQueue<(Bytes, Endpoint)>>,
+    /// IDs of the packets received recently, to drop copies that relays deliver more than once
+    recently_received: Cache<PacketId, ()>,
+    duplicates_dropped: Arc<AtomicU64>,
+    counters: Arc<TrafficCounters>,
 
     send_outgoing: Sender<(Bytes, Endpoint)>,
+    /// number of messages given to `send_to` that have not been handed to the network yet
+    in_flight: Arc<AtomicUsize>,
+    /// number of outgoing queue slots held by live [SendPermit]s
+    reserved: Arc<AtomicUsize>,
     _send_batcher: Arc<Immortal>,
 }
 
+/// Where the daemon delivers the messages that arrive for an [N2rSocket].
+pub(crate) struct RecvQueue {
+    send_incoming: Sender<(Message, Fingerprint, PacketId)>,
+    packets_dropped_backpressure: Arc<AtomicU64>,
+}
+
+impl RecvQueue {
+    /// Whether the socket is falling behind: 80% of its receive window is taken by messages it hasn't received yet. The packet forwarder checks this before delivering more messages.
+    pub fn is_backpressured(&self) -> bool {
+        is_backpressured(self.send_incoming.len(), self.send_incoming.capacity())
+    }
+
+    /// Counts a message that was dropped because the socket was backpressured.
+    pub fn record_backpressure_drop(&self) {
+        self.packets_dropped_backpressure
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Hands a message to the socket, or drops it if the socket's receive window is full.
+    pub fn deliver(
+        &self,
+        message: Message,
+        src_fp: Fingerprint,
+        packet_id: PacketId,
+    ) -> anyhow::Result<()> {
+        if self
+            .send_incoming
+            .try_send((message, src_fp, packet_id))
+            .is_err()
+        {
+            self.record_backpressure_drop();
+            anyhow::bail!("socket's receive window is full, dropping message from {src_fp}");
+        }
+        Ok(())
+    }
+}
+
+/// Whether a receive window of the given capacity is 80% full.
+fn is_backpressured(queued: usize, window: Option<usize>) -> bool {
+    window.is_some_and(|window| queued >= window - window / 5)
+}
+
 struct BoundDock {
     fp: Fingerprint,
     dock: Dock,
@@ -42,6 +133,16 @@ struct BoundDock {
 impl N2rSocket {
     /// Binds an N2R socket.
     pub fn bind(ctx: DaemonContext, idsk: IdentitySecret, dock: Option<Dock>) -> N2rSocket {
+        Self::bind_with_config(ctx, idsk, dock, N2rSocketConfig::default())
+    }
+
+    /// Binds an N2R socket with the given tunables.
+    pub fn bind_with_config(
+        ctx: DaemonContext,
+        idsk: IdentitySecret,
+        dock: Option<Dock>,
+        config: N2rSocketConfig,
+    ) -> N2rSocket {
         let our_fingerprint = idsk.public().fingerprint();
         let dock = if let Some(dock) = dock {
             dock
@@ -63,62 +164,223 @@ impl N2rSocket {
             dock,
             ctx: ctx.clone(),
         });
-        let (send_incoming, recv_incoming) = smol::channel::bounded(1000);
+        let (send_incoming, recv_incoming) = smol::channel::bounded(config.recv_window.max(1));
+        let packets_dropped_backpressure: Arc<AtomicU64> = Default::default();
         ctx.get(SOCKET_RECV_QUEUES).insert(
             Endpoint {
                 fingerprint: our_fingerprint,
                 dock,
             },
-            send_incoming,
+            RecvQueue {
+                send_incoming,
+                packets_dropped_backpressure: packets_dropped_backpressure.clone(),
+            },
         );
 
-        let (send_outgoing, recv_outgoing) = smol::channel::bounded(10000);
+        let (send_outgoing, recv_outgoing) = smol::channel::bounded(OUTGOING_QUEUE_CAPACITY);
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let counters: Arc<TrafficCounters> = Default::default();
         N2rSocket {
             bound_dock,
             recv_incoming,
+            packets_dropped_backpressure,
 
             send_outgoing,
             incoming_queue: Arc::new(ConcurrentQueue::unbounded()),
+            recently_received: Cache::builder().time_to_live(DEDUP_WINDOW).build(),
+            duplicates_dropped: Default::default(),
+            counters: counters.clone(),
+            in_flight: in_flight.clone(),
+            reserved: Default::default(),
 
             _send_batcher: Immortal::respawn(
                 RespawnStrategy::Immediate,
-                clone!([ctx, recv_outgoing], move || send_batcher_loop(
-                    ctx.clone(),
-                    idsk,
-                    dock,
-                    recv_outgoing.clone()
-                )
-                .map_err(log_error("send_batcher"))),
+                clone!([ctx, recv_outgoing, in_flight, counters], move || {
+                    send_batcher_loop(
+                        ctx.clone(),
+                        idsk,
+                        dock,
+                        recv_outgoing.clone(),
+                        in_flight.clone(),
+                        counters.clone(),
+                    )
+                    .map_err(log_error("send_batcher"))
+                }),
             )
             .into(),
         }
     }
 
     pub async fn send_to(&self, body: Bytes, endpoint: Endpoint) -> Result<(), SocketSendError> {
-        let _ = self.send_outgoing.try_send((body, endpoint));
+        // like before reservations existed, messages are silently dropped when the queue is full
+        if let Some(permit) = self.try_reserve() {
+            permit.send(body, endpoint);
+        } else {
+            self.counters.send_errors.fetch_add(1, Ordering::Relaxed);
+        }
         Ok(())
     }
 
+    /// Sends several messages at once, returning the outcome of each send in order. The messages are queued together, so that the ones to the same destination share packets, and reply blocks, up to the packet size. Unlike with `send_to`, messages that don't fit in the outgoing queue fail instead of being dropped silently.
+    pub async fn send_batch(
+        &self,
+        messages: Vec<(Bytes, Endpoint)>,
+    ) -> Vec<Result<(), SocketSendError>> {
+        // reserve everything first, so that the send batcher can't pick up only part of the batch
+        let permits: Vec<Option<SendPermit>> =
+            messages.iter().map(|_| self.try_reserve()).collect();
+        messages
+            .into_iter()
+            .zip(permits)
+            .map(|((body, endpoint), permit)| {
+                let Some(permit) = permit else {
+                    self.counters.send_errors.fetch_add(1, Ordering::Relaxed);
+                    return Err(SocketSendError::QueueFull);
+                };
+                permit.send(body, endpoint);
+                Ok(())
+            })
+            .collect()
+    }
+
+    /// Reserves a slot in the outgoing queue, returning `None` if the queue is full. Lets callers check for backpressure before building a message.
+    pub fn try_reserve(&self) -> Option<SendPermit> {
+        self.reserved
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |reserved| {
+                (reserved + self.send_outgoing.len() < OUTGOING_QUEUE_CAPACITY)
+                    .then_some(reserved + 1)
+            })
+            .ok()?;
+        Some(SendPermit {
+            send_outgoing: self.send_outgoing.clone(),
+            in_flight: self.in_flight.clone(),
+            reserved: self.reserved.clone(),
+        })
+    }
+
+    /// Returns how many messages passed to `send_to` have not been handed to the network yet.
+    pub fn pending_outgoing(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
     pub async fn recv_from(&self) -> Result<(Bytes, Endpoint), SocketRecvError> {
         loop {
             if let Ok(retval) = self.incoming_queue.pop() {
                 return Ok(retval);
             }
 
-            let (message, fingerprint) = self.recv_incoming.recv().await.map_err(|e| {
-                log::debug!("N2rSocket RecvError: {e}");
-                SocketRecvError::N2rRecvError
-            })?;
-            let endpoint = Endpoint::new(fingerprint, message.source_dock);
-            for batch_member in message.body {
-                self.incoming_queue.push((batch_member, endpoint)).unwrap();
+            let (message, fingerprint, packet_id) =
+                self.recv_incoming.recv().await.map_err(|e| {
+                    self.counters.recv_errors.fetch_add(1, Ordering::Relaxed);
+                    log::debug!("N2rSocket RecvError: {e}");
+                    SocketRecvError::N2rRecvError
+                })?;
+            self.enqueue_incoming(message, fingerprint, packet_id);
+        }
+    }
+
+    /// Waits at most `timeout` for the next incoming message, returning `None` if none arrived.
+    pub async fn recv_from_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<Option<(Bytes, Endpoint)>, SocketRecvError> {
+        self.recv_from().timeout(timeout).await.transpose()
+    }
+
+    /// Whether messages that arrive for this socket are being dropped, because 80% of its receive window is taken by messages not yet received.
+    pub fn is_backpressured(&self) -> bool {
+        is_backpressured(self.recv_incoming.len(), self.recv_incoming.capacity())
+    }
+
+    /// Returns the next incoming message if one is already queued, without waiting.
+    pub fn try_recv_from(&self) -> Option<(Bytes, Endpoint)> {
+        loop {
+            if let Ok(retval) = self.incoming_queue.pop() {
+                return Some(retval);
             }
+
+            let (message, fingerprint, packet_id) = self.recv_incoming.try_recv().ok()?;
+            self.enqueue_incoming(message, fingerprint, packet_id);
+        }
+    }
+
+    /// Queues up the batch members of a received message, unless the packet that carried it was already received within the deduplication window. Messages that the sender sends again travel in new packets, so they aren't dropped even if their contents are the same.
+    fn enqueue_incoming(&self, message: Message, fingerprint: Fingerprint, packet_id: PacketId) {
+        if self.recently_received.contains_key(&packet_id) {
+            self.duplicates_dropped.fetch_add(1, Ordering::Relaxed);
+            log::trace!("dropping duplicate message from {fingerprint}");
+            return;
+        }
+        self.recently_received.insert(packet_id, ());
+        let endpoint = Endpoint::new(fingerprint, message.source_dock);
+        for batch_member in message.body {
+            self.counters
+                .packets_received
+                .fetch_add(1, Ordering::Relaxed);
+            self.counters
+                .bytes_received
+                .fetch_add(batch_member.len() as u64, Ordering::Relaxed);
+            self.incoming_queue.push((batch_member, endpoint)).unwrap();
+        }
+    }
+
+    /// Returns statistics of the messages this socket sent and received.
+    pub fn stats(&self) -> SocketStats {
+        SocketStats {
+            packets_sent: self.counters.packets_sent.load(Ordering::Relaxed),
+            bytes_sent: self.counters.bytes_sent.load(Ordering::Relaxed),
+            packets_received: self.counters.packets_received.load(Ordering::Relaxed),
+            bytes_received: self.counters.bytes_received.load(Ordering::Relaxed),
+            send_errors: self.counters.send_errors.load(Ordering::Relaxed),
+            recv_errors: self.counters.recv_errors.load(Ordering::Relaxed),
+            duplicates_dropped: self.duplicates_dropped.load(Ordering::Relaxed),
+            packets_dropped_backpressure: self.packets_dropped_backpressure.load(Ordering::Relaxed),
         }
     }
 
     pub fn local_endpoint(&self) -> Endpoint {
         Endpoint::new(self.bound_dock.fp, self.bound_dock.dock)
     }
+
+    /// Returns the fingerprint messages from this socket come from. It's that of the identity the socket was bound with: the node's own identity only if the socket was bound with it, and otherwise an identity of its own, such as an anonymous one that no relay can tie to this node.
+    pub fn local_fingerprint(&self) -> Fingerprint {
+        self.bound_dock.fp
+    }
+
+    /// Constrains the routes of the messages this socket sends, or lifts the constraints with `None`.
+    pub fn set_route_profile(&self, profile: Option<RouteProfile>) {
+        let route_profiles = self.bound_dock.ctx.get(ROUTE_PROFILES);
+        match profile {
+            Some(profile) => route_profiles.insert(self.local_endpoint(), profile),
+            None => route_profiles
+                .remove(&self.local_endpoint())
+                .map(|(_, v)| v),
+        };
+    }
+}
+
+/// A reserved slot in an [N2rSocket]'s outgoing queue. Dropping it without sending releases the slot.
+pub struct SendPermit {
+    send_outgoing: Sender<(Bytes, Endpoint)>,
+    in_flight: Arc<AtomicUsize>,
+    reserved: Arc<AtomicUsize>,
+}
+
+impl SendPermit {
+    /// Queues a message into the reserved slot.
+    pub fn send(self, body: Bytes, endpoint: Endpoint) {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        // cannot fail for lack of space, since the slot was reserved
+        if self.send_outgoing.try_send((body, endpoint)).is_err() {
+            self.in_flight.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for SendPermit {
+    fn drop(&mut self) {
+        self.reserved.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 async fn send_batcher_loop(
@@ -126,6 +388,8 @@ async fn send_batcher_loop(
     isk: IdentitySecret,
     dock: Dock,
     recv_outgoing: Receiver<(Bytes, Endpoint)>,
+    in_flight: Arc<AtomicUsize>,
+    counters: Arc<TrafficCounters>,
 ) -> anyhow::Result<()> {
     let mut batches: HashMap<Endpoint, VecDeque<Bytes>> = HashMap::new();
     loop {
@@ -139,6 +403,13 @@ async fn send_batcher_loop(
         while let Ok((msg, dest)) = recv_outgoing.try_recv() {
             batches.entry(dest).or_default().push_back(msg);
         }
+        // the messages stop being in flight once sent, or once sending them fails
+        let _in_flight_guard = scopeguard::guard(
+            batches.values().map(|batch| batch.len()).sum::<usize>(),
+            |count| {
+                in_flight.fetch_sub(count, Ordering::Relaxed);
+            },
+        );
         // go through all the batches
         let mut subbatch = vec![];
         for (endpoint, batch) in batches.iter_mut() {
@@ -160,7 +431,7 @@ async fn send_batcher_loop(
                 }
                 log::trace!("subbatch of size {}", subbatch.len());
                 // send the message
-                send_n2r(
+                let sent = send_n2r(
                     &ctx,
                     isk,
                     dock,
@@ -168,7 +439,21 @@ async fn send_batcher_loop(
                     endpoint.dock,
                     subbatch.clone(),
                 )
-                .await?;
+                .await;
+                if sent.is_ok() {
+                    counters
+                        .packets_sent
+                        .fetch_add(subbatch.len() as u64, Ordering::Relaxed);
+                    counters.bytes_sent.fetch_add(
+                        subbatch.iter().map(|msg| msg.len() as u64).sum(),
+                        Ordering::Relaxed,
+                    );
+                } else {
+                    counters
+                        .send_errors
+                        .fetch_add(subbatch.len() as u64, Ordering::Relaxed);
+                }
+                sent?;
             }
         }
     }
@@ -179,5 +464,8 @@ impl Drop for BoundDock {
         self.ctx
             .get(SOCKET_RECV_QUEUES)
             .remove(&Endpoint::new(self.fp, self.dock));
+        self.ctx
+            .get(ROUTE_PROFILES)
+            .remove(&Endpoint::new(self.fp, self.dock));
     }
 }
@@ -1,7 +1,16 @@
+mod half_close;
 mod listener;
+mod mux;
+mod segment_tracker;
 pub use listener::StreamListener;
+pub use mux::{MuxStream, StreamMux};
 
-use std::{pin::Pin, sync::Arc, time::Duration};
+use std::{
+    pin::Pin,
+    sync::{atomic::Ordering, Arc},
+    task::Poll,
+    time::{Duration, Instant},
+};
 
 use bytes::Bytes;
 use clone_macro::clone;
@@ -13,9 +22,19 @@ use stdcode::StdcodeSerializeExt;
 
 use crate::socket::{Endpoint, Socket};
 
+use self::{
+    half_close::{HalfClose, Signal},
+    segment_tracker::SegmentTracker,
+};
+
+/// How long `shutdown_write` waits for the written data, and then its FIN, to be acknowledged.
+const SHUTDOWN_WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+
 #[derive(Clone)]
 pub struct Stream {
     inner_stream: sosistab2::Stream,
+    tracker: Arc<Mutex<SegmentTracker>>,
+    half_close: Arc<HalfClose>,
     _task: Arc<Task<()>>,
 }
 
@@ -79,6 +98,8 @@ impl Stream {
             StreamState::new_established(tick_notify, our_stream_id, "".to_owned());
 
         let wrapped_ss = Arc::new(Mutex::new(s2_state));
+        let tracker = Arc::new(Mutex::new(SegmentTracker::default()));
+        let half_close = Arc::new(HalfClose::default());
         let ticker_task = clone!([wrapped_ss], async move {
             loop {
                 let maybe = wrapped_ss.lock().tick(&outgoing_callback);
@@ -95,10 +116,13 @@ impl Stream {
             }
         });
 
-        let forward_task = clone!([wrapped_ss], async move {
+        let closer = s2_stream.clone();
+        let forward_task = clone!([wrapped_ss, tracker, half_close], async move {
             let up_loop = async {
                 loop {
                     let smsg = recv_outgoing.recv().await?;
+                    tracker.lock().on_outgoing(&smsg);
+                    half_close.progress_waker.wake();
                     socket
                         .send_to(smsg.stdcode().into(), server_endpoint)
                         .await?;
@@ -108,6 +132,23 @@ impl Stream {
                 loop {
                     let (msg, _ep) = socket.recv_from().await?;
                     let smsg: StreamMessage = stdcode::deserialize(&msg)?;
+                    if let Some((stream_id, signal)) = Signal::parse(&smsg) {
+                        // whichever side's signal completes the shutdown, this side closes too
+                        if half_close.on_signal(signal) {
+                            close_detached(closer.clone());
+                        }
+                        if signal == Signal::Fin {
+                            socket
+                                .send_to(
+                                    Signal::FinAck.to_message(stream_id).stdcode().into(),
+                                    server_endpoint,
+                                )
+                                .await?;
+                        }
+                        continue;
+                    }
+                    tracker.lock().on_incoming(&smsg);
+                    half_close.progress_waker.wake();
                     wrapped_ss.lock().inject_incoming(smsg);
                 }
             };
@@ -122,10 +163,75 @@ impl Stream {
 
         Ok(Self {
             inner_stream: s2_stream,
+            tracker,
+            half_close,
             _task: Arc::new(task),
         })
     }
 
+    /// Shuts down the writing half of the stream once everything written so far is delivered, without closing the reading half. The peer reads up to the end of the data, then EOF, and can keep writing to us. Once both sides shut down writing, the stream closes.
+    pub async fn shutdown_write(&mut self) -> anyhow::Result<()> {
+        if self.half_close.write_finished.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let deadline = Instant::now() + SHUTDOWN_WRITE_TIMEOUT;
+        // the FIN travels outside the reliable stream, so it must not overtake the data
+        if !self.wait_until(deadline, Self::all_written_acked).await {
+            anyhow::bail!("timed out waiting for the written data to be acknowledged");
+        }
+        let mut resend_interval = Duration::from_secs(1);
+        loop {
+            if Instant::now() >= deadline {
+                anyhow::bail!("timed out waiting for the FIN to be acknowledged");
+            }
+            self.inner_stream.send_urel(Signal::Fin.payload()).await?;
+            let resend_at = (Instant::now() + resend_interval).min(deadline);
+            if self
+                .wait_until(resend_at, |stream| {
+                    stream.half_close.fin_acked.load(Ordering::SeqCst)
+                })
+                .await
+            {
+                // the stream is closed by whichever signal completes the shutdown of both sides
+                return Ok(());
+            }
+            resend_interval *= 2;
+        }
+    }
+
+    /// Waits until `cond` holds, checking it again whenever a segment goes out or a signal or acknowledgement comes in. Returns false if `deadline` passes first.
+    async fn wait_until(&self, deadline: Instant, cond: impl Fn(&Self) -> bool) -> bool {
+        let wait = futures_util::future::poll_fn(|cx| {
+            self.half_close.progress_waker.register(cx.waker());
+            if cond(self) {
+                Poll::Ready(true)
+            } else {
+                Poll::Pending
+            }
+        });
+        wait.or(async {
+            Timer::at(deadline).await;
+            false
+        })
+        .await
+    }
+
+    fn all_written_acked(&self) -> bool {
+        let tracker = self.tracker.lock();
+        tracker.bytes_sent() == self.half_close.bytes_written.load(Ordering::SeqCst)
+            && tracker.in_flight() == 0
+    }
+
+    /// Returns the smoothed round-trip time of the data this stream sent, or zero before any of it was acknowledged.
+    pub fn rtt_estimate(&self) -> Duration {
+        self.tracker.lock().rtt_estimate()
+    }
+
+    /// Returns how many segments of data are in flight, unacknowledged. The stream sends more only while its congestion window is bigger than this.
+    pub fn window_size(&self) -> u32 {
+        self.tracker.lock().in_flight()
+    }
+
     fn pin_project_inner(self: std::pin::Pin<&mut Self>) -> Pin<&mut sosistab2::Stream> {
         // SAFETY: this is a safe pin-projection, since we never get a &mut sosistab2::Stream from a Pin<&mut Stream> elsewhere.
         // Safety requires that we either consistently lose Pin or keep it.
@@ -134,14 +240,31 @@ impl Stream {
     }
 }
 
+/// Closes a stream from a task that handles its incoming messages, without holding them up while it closes.
+fn close_detached(mut stream: sosistab2::Stream) {
+    smolscale::spawn(async move { stream.shutdown().await }).detach();
+}
+
 impl AsyncRead for Stream {
     fn poll_read(
         self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &mut [u8],
     ) -> std::task::Poll<std::io::Result<usize>> {
+        let half_close = self.half_close.clone();
         let inner = self.pin_project_inner();
-        inner.poll_read(cx, buf)
+        match inner.poll_read(cx, buf) {
+            Poll::Pending => {
+                // everything the peer wrote was acknowledged before its FIN, so there's nothing more to wait for
+                half_close.read_waker.register(cx.waker());
+                if half_close.peer_write_finished.load(Ordering::SeqCst) {
+                    Poll::Ready(Ok(0))
+                } else {
+                    Poll::Pending
+                }
+            }
+            ready => ready,
+        }
     }
 }
 
@@ -151,8 +274,21 @@ impl AsyncWrite for Stream {
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<std::io::Result<usize>> {
+        let half_close = self.half_close.clone();
+        if half_close.write_finished.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "writing was shut down",
+            )));
+        }
         let inner = self.pin_project_inner();
-        inner.poll_write(cx, buf)
+        let result = inner.poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            half_close
+                .bytes_written
+                .fetch_add(n as u64, Ordering::SeqCst);
+        }
+        result
     }
 
     fn poll_flush(
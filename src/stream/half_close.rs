@@ -0,0 +1,81 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use bytes::Bytes;
+use futures_util::task::AtomicWaker;
+use sosistab2::StreamMessage;
+
+/// Payloads of the unreliable messages that signal half-closes. Streams send no other unreliable messages.
+const FIN: &[u8] = b"FIN";
+const FIN_ACK: &[u8] = b"FINACK";
+
+/// A message that signals a half-close, outside of sosistab2's reliable stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Signal {
+    /// the sender shut down writing, and everything it wrote was acknowledged
+    Fin,
+    FinAck,
+}
+
+impl Signal {
+    pub fn parse(msg: &StreamMessage) -> Option<(u16, Signal)> {
+        match msg {
+            StreamMessage::Unreliable { stream_id, payload } if payload == FIN => {
+                Some((*stream_id, Signal::Fin))
+            }
+            StreamMessage::Unreliable { stream_id, payload } if payload == FIN_ACK => {
+                Some((*stream_id, Signal::FinAck))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn payload(self) -> Bytes {
+        match self {
+            Signal::Fin => Bytes::from_static(FIN),
+            Signal::FinAck => Bytes::from_static(FIN_ACK),
+        }
+    }
+
+    pub fn to_message(self, stream_id: u16) -> StreamMessage {
+        StreamMessage::Unreliable {
+            stream_id,
+            payload: self.payload(),
+        }
+    }
+}
+
+/// The half-close state of a [super::Stream], shared with the task that handles its incoming messages.
+#[derive(Default)]
+pub(super) struct HalfClose {
+    /// bytes written to the stream, to tell when all of them were sent
+    pub bytes_written: AtomicU64,
+    /// whether we shut down writing
+    pub write_finished: AtomicBool,
+    /// whether the peer acknowledged our FIN
+    pub fin_acked: AtomicBool,
+    /// whether the peer shut down writing
+    pub peer_write_finished: AtomicBool,
+    /// whether the stream was closed, after both sides shut down writing
+    closed: AtomicBool,
+    /// the reader waiting for more data, to wake up when the peer's FIN turns its wait into EOF
+    pub read_waker: AtomicWaker,
+    /// `shutdown_write` waiting for its data or its FIN to be acknowledged, to wake up whenever a segment goes out or a signal or acknowledgement comes in
+    pub progress_waker: AtomicWaker,
+}
+
+impl HalfClose {
+    /// Records a signal from the peer, returning whether the stream should now close because both sides shut down writing and our FIN was acknowledged. That's returned only once.
+    pub fn on_signal(&self, signal: Signal) -> bool {
+        match signal {
+            Signal::Fin => {
+                self.peer_write_finished.store(true, Ordering::SeqCst);
+                self.read_waker.wake();
+            }
+            Signal::FinAck => self.fin_acked.store(true, Ordering::SeqCst),
+        }
+        self.progress_waker.wake();
+        self.fin_acked.load(Ordering::SeqCst)
+            && self.peer_write_finished.load(Ordering::SeqCst)
+            && !self.closed.swap(true, Ordering::SeqCst)
+    }
+}
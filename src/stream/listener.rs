@@ -8,11 +8,24 @@ use stdcode::StdcodeSerializeExt;
 
 use crate::socket::{Endpoint, Socket};
 
-use super::Stream;
+use super::{
+    close_detached,
+    half_close::{HalfClose, Signal},
+    segment_tracker::SegmentTracker,
+    Stream,
+};
+
+/// What the listener keeps of an accepted stream, to hand it the messages for it.
+struct AcceptedStream {
+    state: Arc<Mutex<StreamState>>,
+    stream: sosistab2::Stream,
+    tracker: Arc<Mutex<SegmentTracker>>,
+    half_close: Arc<HalfClose>,
+}
 
 pub struct StreamListener {
     socket: Arc<Socket>,
-    table: Arc<DashMap<Endpoint, Arc<Mutex<sosistab2::StreamState>>>>,
+    table: Arc<DashMap<Endpoint, AcceptedStream>>,
 }
 
 impl StreamListener {
@@ -45,6 +58,8 @@ impl StreamListener {
                     let (s2_state, s2_stream) =
                         StreamState::new_established(tick_notify, stream_id, "".into());
                     let s2_state = Arc::new(Mutex::new(s2_state));
+                    let tracker = Arc::new(Mutex::new(SegmentTracker::default()));
+                    let half_close = Arc::new(HalfClose::default());
 
                     let syn_ack = StreamMessage::Reliable {
                         kind: sosistab2::RelKind::SynAck,
@@ -59,6 +74,8 @@ impl StreamListener {
                         .await?;
 
                     let state = s2_state.clone();
+                    let ticker_tracker = tracker.clone();
+                    let ticker_half_close = half_close.clone();
                     let skt = self.socket.clone();
                     let table = self.table.clone();
                     let ticker = smolscale::spawn(async move {
@@ -73,6 +90,8 @@ impl StreamListener {
                                 future::select(recv_future, timer.fuse()).await;
                                 for msg in outgoing.drain(..) {
                                     log::trace!("listener sending back result of tick {:?}", msg);
+                                    ticker_tracker.lock().on_outgoing(&msg);
+                                    ticker_half_close.progress_waker.wake();
                                     let msg = msg.stdcode().into();
                                     let _ = skt.send_to(msg, client_ep).await;
                                 }
@@ -85,11 +104,21 @@ impl StreamListener {
                     });
 
                     // insert state into table
-                    self.table.insert(client_ep, s2_state);
+                    self.table.insert(
+                        client_ep,
+                        AcceptedStream {
+                            state: s2_state,
+                            stream: s2_stream.clone(),
+                            tracker: tracker.clone(),
+                            half_close: half_close.clone(),
+                        },
+                    );
 
                     // return a Stream
                     return Ok(Stream {
                         inner_stream: s2_stream,
+                        tracker,
+                        half_close,
                         _task: Arc::new(ticker),
                     });
                 }
@@ -110,9 +139,11 @@ impl StreamListener {
                     seqno,
                     payload,
                 } => match self.table.get(&client_ep) {
-                    Some(state) => {
+                    Some(accepted) => {
                         log::trace!("INJECTING into state: {:?}", stream_msg);
-                        state.lock().inject_incoming(stream_msg);
+                        accepted.tracker.lock().on_incoming(&stream_msg);
+                        accepted.half_close.progress_waker.wake();
+                        accepted.state.lock().inject_incoming(stream_msg);
                         continue;
                     }
                     None => {
@@ -129,7 +160,25 @@ impl StreamListener {
                     }
                 },
                 _ => {
-                    log::debug!("unreliable stream messages aren't supported")
+                    let Some((stream_id, signal)) = Signal::parse(&stream_msg) else {
+                        log::debug!("unreliable stream messages aren't supported");
+                        continue;
+                    };
+                    let Some((half_close, stream)) = self
+                        .table
+                        .get(&client_ep)
+                        .map(|accepted| (accepted.half_close.clone(), accepted.stream.clone()))
+                    else {
+                        continue;
+                    };
+                    // whichever side's signal completes the shutdown, this side closes too
+                    if half_close.on_signal(signal) {
+                        close_detached(stream);
+                    }
+                    if signal == Signal::Fin {
+                        let msg = Signal::FinAck.to_message(stream_id).stdcode().into();
+                        self.socket.send_to(msg, client_ep).await?;
+                    }
                 }
             };
         }
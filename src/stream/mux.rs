@@ -0,0 +1,383 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU16, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+};
+
+use anyhow::Context as _;
+use bytes::Bytes;
+use dashmap::DashMap;
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, StreamExt};
+use smol::{
+    channel::{Receiver, Sender},
+    future::FutureExt,
+    Task,
+};
+
+use super::Stream;
+
+/// Flag of the frame that opens a sub-stream.
+const SYN: u8 = 1;
+/// Flag of the frame after which the sender writes nothing more to the sub-stream.
+const FIN: u8 = 2;
+/// Flag of the frame that aborts a sub-stream in both directions.
+const RST: u8 = 4;
+
+/// How many frames can wait to be written to the underlying stream before the writers wait, or to be read from each sub-stream before it's reset.
+const FRAME_QUEUE_CAPACITY: usize = 64;
+
+/// How many sub-streams the peer can open before we accept them, beyond which new ones are reset.
+const ACCEPT_BACKLOG: usize = 64;
+
+/// A frame of a sub-stream, sent over the underlying stream as `[stream_id: u16][flags: u8][length: u16][payload]`, big-endian.
+struct Frame {
+    stream_id: u16,
+    flags: u8,
+    payload: Bytes,
+}
+
+impl Frame {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(5 + self.payload.len());
+        buf.extend_from_slice(&self.stream_id.to_be_bytes());
+        buf.push(self.flags);
+        buf.extend_from_slice(&(self.payload.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+
+    async fn read_from(reader: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Frame> {
+        let mut header = [0u8; 5];
+        reader.read_exact(&mut header).await?;
+        let mut payload = vec![0u8; u16::from_be_bytes([header[3], header[4]]) as usize];
+        reader.read_exact(&mut payload).await?;
+        Ok(Frame {
+            stream_id: u16::from_be_bytes([header[0], header[1]]),
+            flags: header[2],
+            payload: payload.into(),
+        })
+    }
+}
+
+/// Multiplexes many logical [MuxStream]s over one [Stream], so that they share its haven session instead of each setting up its own.
+///
+/// A sub-stream whose reader falls [FRAME_QUEUE_CAPACITY] frames behind is reset, so that it never holds up the others. Sub-streams stop working when the mux is dropped.
+pub struct StreamMux {
+    shared: Arc<MuxShared>,
+    recv_accepted: Receiver<(u16, Substream, Receiver<Bytes>)>,
+    _task: Arc<Task<()>>,
+}
+
+struct MuxShared {
+    send_frame: Sender<Frame>,
+    /// the open sub-streams, until their peer sends a FIN
+    substreams: DashMap<u16, Substream>,
+    next_id: AtomicU16,
+}
+
+impl MuxShared {
+    /// Aborts a sub-stream, telling the peer too.
+    fn reset(&self, stream_id: u16) {
+        if let Some((_, substream)) = self.substreams.remove(&stream_id) {
+            substream.reset.store(true, Ordering::Relaxed);
+        }
+        // best effort, since the read loop can't wait for room in the queue
+        let _ = self.send_frame.try_send(Frame {
+            stream_id,
+            flags: RST,
+            payload: Bytes::new(),
+        });
+    }
+
+    /// Whether a sub-stream with this ID is one that we, rather than the peer, open.
+    fn is_ours(&self, stream_id: u16) -> bool {
+        stream_id % 2 == self.next_id.load(Ordering::Relaxed) % 2
+    }
+}
+
+/// The mux's end of a sub-stream.
+#[derive(Clone)]
+struct Substream {
+    /// where the sub-stream's frames go
+    send_incoming: Sender<Bytes>,
+    /// set when either end resets the sub-stream
+    reset: Arc<AtomicBool>,
+}
+
+impl Substream {
+    fn new() -> (Self, Receiver<Bytes>) {
+        let (send_incoming, recv_incoming) = smol::channel::bounded(FRAME_QUEUE_CAPACITY);
+        let substream = Substream {
+            send_incoming,
+            reset: Default::default(),
+        };
+        (substream, recv_incoming)
+    }
+}
+
+impl StreamMux {
+    /// Starts multiplexing over a stream. The two ends must disagree on `initiator`, which picks whether they number the sub-streams they open with odd or even numbers, so that their numbers never collide.
+    pub fn new(stream: Stream, initiator: bool) -> StreamMux {
+        let (send_frame, recv_frame) = smol::channel::bounded(FRAME_QUEUE_CAPACITY);
+        let (send_accepted, recv_accepted) = smol::channel::bounded(ACCEPT_BACKLOG);
+        let shared = Arc::new(MuxShared {
+            send_frame,
+            substreams: DashMap::new(),
+            next_id: AtomicU16::new(if initiator { 1 } else { 2 }),
+        });
+        let task = smolscale::spawn({
+            let shared = shared.clone();
+            async move {
+                let read_loop = mux_read_loop(stream.clone(), shared, send_accepted);
+                let write_loop = mux_write_loop(stream, recv_frame);
+                if let Err(e) = read_loop.race(write_loop).await {
+                    log::debug!("stream mux stopped: {e}");
+                }
+            }
+        });
+        StreamMux {
+            shared,
+            recv_accepted,
+            _task: Arc::new(task),
+        }
+    }
+
+    /// Opens a new sub-stream.
+    pub async fn open_stream(&self) -> anyhow::Result<MuxStream> {
+        // each end has half of the IDs
+        let stream_id = (0..=u16::MAX / 2)
+            .map(|_| self.shared.next_id.fetch_add(2, Ordering::Relaxed))
+            .find(|id| !self.shared.substreams.contains_key(id))
+            .context("every sub-stream ID is in use")?;
+        let (substream, recv_incoming) = Substream::new();
+        self.shared.substreams.insert(stream_id, substream.clone());
+        self.shared
+            .send_frame
+            .send(Frame {
+                stream_id,
+                flags: SYN,
+                payload: Bytes::new(),
+            })
+            .await?;
+        Ok(self.mux_stream(stream_id, substream, recv_incoming))
+    }
+
+    /// Waits for the peer to open a sub-stream.
+    pub async fn accept_stream(&self) -> anyhow::Result<MuxStream> {
+        let (stream_id, substream, recv_incoming) = self.recv_accepted.recv().await?;
+        Ok(self.mux_stream(stream_id, substream, recv_incoming))
+    }
+
+    fn mux_stream(
+        &self,
+        stream_id: u16,
+        substream: Substream,
+        recv_incoming: Receiver<Bytes>,
+    ) -> MuxStream {
+        MuxStream {
+            stream_id,
+            shared: self.shared.clone(),
+            reset: substream.reset,
+            recv_incoming,
+            read_buf: Bytes::new(),
+            pending_send: None,
+            fin_sent: false,
+        }
+    }
+}
+
+async fn mux_read_loop(
+    mut stream: Stream,
+    shared: Arc<MuxShared>,
+    send_accepted: Sender<(u16, Substream, Receiver<Bytes>)>,
+) -> anyhow::Result<()> {
+    // nothing here waits on a single sub-stream, so that a slow one can't hold up the others
+    loop {
+        let frame = Frame::read_from(&mut stream).await?;
+        if frame.flags & RST != 0 {
+            if let Some((_, substream)) = shared.substreams.remove(&frame.stream_id) {
+                substream.reset.store(true, Ordering::Relaxed);
+            }
+            continue;
+        }
+        if frame.flags & SYN != 0 {
+            if shared.is_ours(frame.stream_id) || shared.substreams.contains_key(&frame.stream_id) {
+                log::debug!(
+                    "ignoring SYN for sub-stream {}, which is open already or ours to open",
+                    frame.stream_id
+                );
+                continue;
+            }
+            let (substream, recv_incoming) = Substream::new();
+            shared.substreams.insert(frame.stream_id, substream.clone());
+            if send_accepted
+                .try_send((frame.stream_id, substream, recv_incoming))
+                .is_err()
+            {
+                log::debug!(
+                    "resetting sub-stream {}, since too many wait to be accepted",
+                    frame.stream_id
+                );
+                shared.reset(frame.stream_id);
+                continue;
+            }
+        }
+        if !frame.payload.is_empty() {
+            let send_incoming = shared
+                .substreams
+                .get(&frame.stream_id)
+                .map(|entry| entry.send_incoming.clone());
+            match send_incoming {
+                Some(send_incoming) => {
+                    if send_incoming.try_send(frame.payload).is_err() {
+                        // either its reader fell too far behind, or it was dropped meanwhile
+                        log::debug!(
+                            "resetting sub-stream {}, which isn't read fast enough",
+                            frame.stream_id
+                        );
+                        shared.reset(frame.stream_id);
+                        continue;
+                    }
+                }
+                None => log::debug!("dropping frame for unknown sub-stream {}", frame.stream_id),
+            }
+        }
+        if frame.flags & FIN != 0 {
+            // dropping the sender lets the sub-stream read EOF once it has read everything
+            shared.substreams.remove(&frame.stream_id);
+        }
+    }
+}
+
+async fn mux_write_loop(mut stream: Stream, recv_frame: Receiver<Frame>) -> anyhow::Result<()> {
+    loop {
+        let frame = recv_frame.recv().await?;
+        stream.write_all(&frame.encode()).await?;
+    }
+}
+
+/// A logical stream of a [StreamMux].
+pub struct MuxStream {
+    stream_id: u16,
+    shared: Arc<MuxShared>,
+    /// set when either end resets the sub-stream
+    reset: Arc<AtomicBool>,
+    recv_incoming: Receiver<Bytes>,
+    /// the part of the latest received frame not read yet
+    read_buf: Bytes,
+    /// the frame being queued for the underlying stream, resolving to false if the mux stopped
+    pending_send: Option<Pin<Box<dyn Future<Output = bool> + Send>>>,
+    fin_sent: bool,
+}
+
+impl MuxStream {
+    /// Starts queueing a frame. Callers must first finish queueing the previous one.
+    fn start_send(&mut self, flags: u8, payload: Bytes) {
+        let send_frame = self.shared.send_frame.clone();
+        let frame = Frame {
+            stream_id: self.stream_id,
+            flags,
+            payload,
+        };
+        self.pending_send = Some(Box::pin(
+            async move { send_frame.send(frame).await.is_ok() },
+        ));
+    }
+
+    fn reset_error(&self) -> std::io::Error {
+        std::io::Error::new(
+            std::io::ErrorKind::ConnectionReset,
+            "the sub-stream was reset",
+        )
+    }
+
+    /// Finishes queueing the frame started last, if any.
+    fn poll_pending_send(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if let Some(pending_send) = self.pending_send.as_mut() {
+            let sent = ready!(pending_send.as_mut().poll(cx));
+            self.pending_send = None;
+            if !sent {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "the stream mux stopped",
+                )));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        while self.read_buf.is_empty() {
+            match ready!(self.recv_incoming.poll_next_unpin(cx)) {
+                Some(payload) => self.read_buf = payload,
+                None if self.reset.load(Ordering::Relaxed) => {
+                    return Poll::Ready(Err(self.reset_error()))
+                }
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+        let n = buf.len().min(self.read_buf.len());
+        buf[..n].copy_from_slice(&self.read_buf.split_to(n));
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        ready!(self.poll_pending_send(cx))?;
+        if self.reset.load(Ordering::Relaxed) {
+            return Poll::Ready(Err(self.reset_error()));
+        }
+        if self.fin_sent {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "the sub-stream was closed",
+            )));
+        }
+        let n = buf.len().min(u16::MAX as usize);
+        self.start_send(0, Bytes::copy_from_slice(&buf[..n]));
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.poll_pending_send(cx)
+    }
+
+    /// Sends a FIN, after which the peer reads EOF. Reading from this end keeps working until the peer closes too.
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        ready!(self.poll_pending_send(cx))?;
+        if !self.fin_sent {
+            self.fin_sent = true;
+            self.start_send(FIN, Bytes::new());
+        }
+        self.poll_pending_send(cx)
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        self.shared.substreams.remove(&self.stream_id);
+        if !self.fin_sent && !self.reset.load(Ordering::Relaxed) {
+            // best effort, since dropping can't wait for room in the queue
+            let _ = self.shared.send_frame.try_send(Frame {
+                stream_id: self.stream_id,
+                flags: FIN,
+                payload: Bytes::new(),
+            });
+        }
+    }
+}
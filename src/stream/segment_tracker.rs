@@ -0,0 +1,118 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    time::{Duration, Instant},
+};
+
+use sosistab2::{RelKind, StreamMessage};
+
+/// Watches the data segments a [super::Stream] sends and the acknowledgements it receives, to estimate what sosistab2 keeps to itself: the round-trip time, and how many segments its window lets be in flight.
+#[derive(Default)]
+pub(super) struct SegmentTracker {
+    /// when each unacknowledged data segment was sent, by sequence number; `None` once retransmitted, since its acknowledgement could then be for either copy
+    unacked: BTreeMap<u64, Option<Instant>>,
+    /// payload bytes of all data segments sent, not counting retransmissions
+    bytes_sent: u64,
+    smoothed_rtt: Option<Duration>,
+}
+
+impl SegmentTracker {
+    pub fn on_outgoing(&mut self, msg: &StreamMessage) {
+        if let StreamMessage::Reliable {
+            kind: RelKind::Data,
+            seqno,
+            payload,
+            ..
+        } = msg
+        {
+            match self.unacked.entry(*seqno) {
+                Entry::Occupied(mut entry) => *entry.get_mut() = None,
+                Entry::Vacant(entry) => {
+                    entry.insert(Some(Instant::now()));
+                    self.bytes_sent += payload.len() as u64;
+                }
+            }
+        }
+    }
+
+    pub fn on_incoming(&mut self, msg: &StreamMessage) {
+        if let StreamMessage::Reliable {
+            kind: RelKind::DataAck,
+            seqno: lowest_unseen,
+            payload: selective_acks,
+            ..
+        } = msg
+        {
+            let mut acked: Vec<u64> = self
+                .unacked
+                .range(..lowest_unseen)
+                .map(|(seqno, _)| *seqno)
+                .collect();
+            if let Ok(sacks) = stdcode::deserialize::<Vec<u64>>(selective_acks) {
+                acked.extend(sacks);
+            }
+            // only the newest segment acknowledged gives a sample; older ones may just have waited for it
+            let newest_sent = acked
+                .into_iter()
+                .filter_map(|seqno| self.unacked.remove(&seqno).map(|sent| (seqno, sent)))
+                .max_by_key(|(seqno, _)| *seqno)
+                .and_then(|(_, sent)| sent);
+            if let Some(sent) = newest_sent {
+                let sample = sent.elapsed();
+                // the usual exponentially weighted moving average, with a gain of 1/8
+                self.smoothed_rtt = Some(match self.smoothed_rtt {
+                    Some(srtt) => srtt * 7 / 8 + sample / 8,
+                    None => sample,
+                });
+            }
+        }
+    }
+
+    /// The smoothed round-trip time, or zero before any segment was acknowledged.
+    pub fn rtt_estimate(&self) -> Duration {
+        self.smoothed_rtt.unwrap_or_default()
+    }
+
+    /// How many payload bytes were sent, not counting retransmissions.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    /// How many data segments are sent but not acknowledged yet.
+    pub fn in_flight(&self) -> u32 {
+        self.unacked.len() as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use stdcode::StdcodeSerializeExt;
+
+    use super::*;
+
+    fn segment(kind: RelKind, seqno: u64, payload: Bytes) -> StreamMessage {
+        StreamMessage::Reliable {
+            kind,
+            stream_id: 0,
+            seqno,
+            payload,
+        }
+    }
+
+    #[test]
+    fn test_acks() {
+        let mut tracker = SegmentTracker::default();
+        for seqno in 0..5 {
+            tracker.on_outgoing(&segment(RelKind::Data, seqno, Bytes::new()));
+        }
+        // a retransmitted segment gives no sample
+        tracker.on_outgoing(&segment(RelKind::Data, 1, Bytes::new()));
+        tracker.on_incoming(&segment(RelKind::DataAck, 2, Bytes::new()));
+        assert_eq!(tracker.in_flight(), 3);
+        assert_eq!(tracker.smoothed_rtt, None);
+
+        tracker.on_incoming(&segment(RelKind::DataAck, 2, vec![4u64].stdcode().into()));
+        assert_eq!(tracker.in_flight(), 2);
+        assert!(tracker.smoothed_rtt.is_some());
+    }
+}
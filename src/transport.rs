@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+pub use sosistab2::Pipe;
+
+/// A way of carrying links between neighbors, which embedders can add to the daemon with [crate::daemon::DaemonBuilder::register_transport]. In_routes and out_routes with `protocol: custom` name the transport they use, and pass their `config` to it as it is.
+#[async_trait]
+pub trait EarendilTransport: Send + Sync + 'static {
+    /// The name that in_routes and out_routes refer to this transport by.
+    fn name(&self) -> &str;
+
+    /// Starts listening for neighbors as configured by an in_route.
+    async fn listen(&self, config: &serde_json::Value) -> anyhow::Result<Box<dyn PipeListener>>;
+
+    /// Connects to a neighbor as configured by an out_route.
+    async fn dial(&self, config: &serde_json::Value) -> anyhow::Result<Box<dyn Pipe>>;
+}
+
+/// The listening side of a transport, which hands over a pipe for each neighbor that connects.
+#[async_trait]
+pub trait PipeListener: Send + Sync + 'static {
+    /// Waits for the next neighbor to connect. An error stops the in_route.
+    async fn accept_pipe(&self) -> anyhow::Result<Box<dyn Pipe>>;
+}
+
+/// The transports registered with a daemon, by name.
+#[derive(Default)]
+pub struct TransportRegistry {
+    transports: HashMap<String, Box<dyn EarendilTransport>>,
+}
+
+impl TransportRegistry {
+    /// Adds a transport, replacing any registered under the same name.
+    pub fn register(&mut self, transport: impl EarendilTransport) {
+        self.transports
+            .insert(transport.name().to_string(), Box::new(transport));
+    }
+
+    /// Looks up a transport by name.
+    pub fn get(&self, name: &str) -> anyhow::Result<&dyn EarendilTransport> {
+        self.transports
+            .get(name)
+            .map(|transport| transport.as_ref())
+            .ok_or_else(|| anyhow::anyhow!("no transport named {name} is registered"))
+    }
+}
@@ -0,0 +1,120 @@
+use std::{net::SocketAddr, time::Duration};
+
+use earendil::{config::ConfigFile, daemon::Daemon};
+use earendil_crypt::IdentitySecret;
+use hickory_proto::{
+    op::{Message, MessageType, Query},
+    rr::{rdata::A, Name, RData, Record, RecordType},
+};
+use smol::net::UdpSocket;
+use smol_timeout::TimeoutExt;
+
+fn daemon_from_json(json: serde_json::Value) -> Daemon {
+    let cfg: ConfigFile = serde_json::from_value(json).unwrap();
+    Daemon::init(cfg).unwrap()
+}
+
+/// Answers every A query with 10.0.0.1, standing in for a real upstream resolver.
+async fn fake_upstream(listen: SocketAddr) {
+    let socket = UdpSocket::bind(listen).await.unwrap();
+    let mut buf = [0u8; 65536];
+    loop {
+        let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+        let query = Message::from_vec(&buf[..n]).unwrap();
+        let mut answer = Message::new();
+        answer
+            .set_id(query.id())
+            .set_message_type(MessageType::Response)
+            .add_queries(query.queries().to_vec())
+            .add_answer(Record::from_rdata(
+                query.queries()[0].name().clone(),
+                60,
+                RData::A(A::new(10, 0, 0, 1)),
+            ));
+        socket
+            .send_to(&answer.to_vec().unwrap(), from)
+            .await
+            .unwrap();
+    }
+}
+
+#[test]
+fn dns_over_earendil() {
+    let _ = env_logger::try_init();
+
+    let relay = daemon_from_json(serde_json::json!({
+        "identity_seed": "dns-relay",
+        "control_listen": "127.0.0.1:11219",
+        "in_routes": {
+            "main_tcp": {"protocol": "tcp", "listen": "127.0.0.1:12206"},
+        },
+    }));
+    let relay_fp = relay.identity().public().fingerprint();
+    let resolver_fp = IdentitySecret::from_seed("dns-resolver-haven")
+        .public()
+        .fingerprint();
+    let out_routes = serde_json::json!({
+        "relay": {
+            "protocol": "tcp",
+            "fingerprint": relay_fp.to_string(),
+            "connect": "127.0.0.1:12206",
+        },
+    });
+    let _resolver = daemon_from_json(serde_json::json!({
+        "identity_seed": "dns-resolver",
+        "control_listen": "127.0.0.1:11220",
+        "out_routes": out_routes,
+        "havens": [{
+            "identity_seed": "dns-resolver-haven",
+            "rendezvous": relay_fp.to_string(),
+            "handler": {"type": "dns_resolver", "upstream": "127.0.0.1:12207"},
+        }],
+    }));
+    let _client = daemon_from_json(serde_json::json!({
+        "identity_seed": "dns-client",
+        "control_listen": "127.0.0.1:11221",
+        "out_routes": out_routes,
+        "havens": [{
+            "identity_seed": "dns-client-haven",
+            "rendezvous": relay_fp.to_string(),
+            "handler": {
+                "type": "dns_proxy",
+                "listen": "127.0.0.1:12208",
+                "resolver_haven": resolver_fp.to_string(),
+            },
+        }],
+    }));
+    smolscale::spawn(fake_upstream("127.0.0.1:12207".parse().unwrap())).detach();
+
+    smolscale::block_on(async {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut query = Message::new();
+        query
+            .set_id(4242)
+            .set_recursion_desired(true)
+            .add_query(Query::query(
+                Name::from_ascii("example.com.").unwrap(),
+                RecordType::A,
+            ));
+        let query = query.to_vec().unwrap();
+        let mut buf = [0u8; 65536];
+        // the resolver haven takes a while to become reachable, so the query is repeated until it is
+        for _ in 0..60 {
+            socket.send_to(&query, "127.0.0.1:12208").await.unwrap();
+            if let Some(Ok((n, _))) = socket
+                .recv_from(&mut buf)
+                .timeout(Duration::from_secs(2))
+                .await
+            {
+                let answer = Message::from_vec(&buf[..n]).unwrap();
+                assert_eq!(answer.id(), 4242);
+                assert_eq!(
+                    answer.answers()[0].data(),
+                    Some(&RData::A(A::new(10, 0, 0, 1)))
+                );
+                return;
+            }
+        }
+        panic!("the DNS proxy never answered")
+    });
+}
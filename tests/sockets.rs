@@ -2,8 +2,14 @@ use std::{env, time::Duration};
 
 use anyhow::Context;
 use bytes::Bytes;
-use earendil::{config::ConfigFile, daemon::Daemon, socket::Socket};
+use earendil::{
+    config::ConfigFile,
+    daemon::Daemon,
+    socket::Socket,
+    stream::{Stream, StreamListener, StreamMux},
+};
 use earendil_crypt::IdentitySecret;
+use futures_util::{AsyncReadExt, AsyncWriteExt};
 use once_cell::sync::Lazy;
 use smol::Timer;
 use smol_timeout::TimeoutExt;
@@ -61,10 +67,10 @@ fn n2r() {
 
         // charlie receives the msg
         let (body, ep) = charlie_skt
-            .recv_from()
-            .timeout(Duration::from_secs(10))
+            .recv_from_timeout(Duration::from_secs(10))
             .await
             .unwrap()
+            .context("timed out")
             .unwrap();
         assert_eq!(body, alice_msg);
         assert_eq!(ep, alice_skt.local_endpoint());
@@ -77,10 +83,10 @@ fn n2r() {
             .context("charlie sending failed!")
             .unwrap();
         let (body, ep) = alice_skt
-            .recv_from()
-            .timeout(Duration::from_secs(10))
+            .recv_from_timeout(Duration::from_secs(10))
             .await
             .unwrap()
+            .context("timed out")
             .unwrap();
         assert_eq!(body, charlie_msg);
         assert_eq!(ep, charlie_skt.local_endpoint());
@@ -117,10 +123,10 @@ fn haven() {
 
         // derek receives the msg
         let (body, ep) = derek_skt
-            .recv_from()
-            .timeout(Duration::from_secs(10))
+            .recv_from_timeout(Duration::from_secs(10))
             .await
             .unwrap()
+            .context("timed out")
             .unwrap();
         assert_eq!(body, alice_msg);
         assert_eq!(ep, alice_skt.local_endpoint());
@@ -133,13 +139,245 @@ fn haven() {
             .context("charlie sending failed!")
             .unwrap();
         let (body, ep) = alice_skt
-            .recv_from()
-            .timeout(Duration::from_secs(10))
+            .recv_from_timeout(Duration::from_secs(10))
             .await
-            .context("timed out")
             .unwrap()
+            .context("timed out")
             .unwrap();
         assert_eq!(body, derek_msg);
         assert_eq!(ep, derek_skt.local_endpoint());
     })
 }
+
+#[test]
+fn haven_close_drains() {
+    let _ = env_logger::try_init();
+    env::set_var("SOSISTAB2_NO_SLEEP", "1");
+    Lazy::force(&START_DAEMONS);
+
+    let alice_isk = IdentitySecret::generate();
+    let alice_skt = Socket::bind_haven(&ALICE_DAEMON, alice_isk, None, None);
+
+    let derek_isk = IdentitySecret::generate();
+    let derek_skt = Socket::bind_haven(
+        &DEREK_DAEMON,
+        derek_isk,
+        None,
+        Some(CHARLIE_DAEMON.identity().public().fingerprint()),
+    );
+
+    smolscale::block_on(async move {
+        // sleep to give the nodes time to connect
+        Timer::after(Duration::from_secs(30)).await;
+        let derek_ep = derek_skt.local_endpoint();
+        for i in 0..50 {
+            alice_skt
+                .send_to(Bytes::from(format!("message {i}")), derek_ep)
+                .await
+                .context("alice sending failed!")
+                .unwrap();
+        }
+        // closing must not lose anything already sent
+        alice_skt
+            .close_with_timeout(Duration::from_secs(60))
+            .await
+            .context("alice closing failed!")
+            .unwrap();
+
+        let mut received = std::collections::BTreeSet::new();
+        while received.len() < 50 {
+            let (body, _) = derek_skt
+                .recv_from_timeout(Duration::from_secs(10))
+                .await
+                .unwrap()
+                .context("timed out")
+                .unwrap();
+            received.insert(body);
+        }
+        for i in 0..50 {
+            assert!(received.contains(format!("message {i}").as_bytes()));
+        }
+    })
+}
+
+#[test]
+fn stream() {
+    let _ = env_logger::try_init();
+    env::set_var("SOSISTAB2_NO_SLEEP", "1");
+    Lazy::force(&START_DAEMONS);
+
+    let alice_isk = IdentitySecret::generate();
+    let alice_skt = Socket::bind_haven(&ALICE_DAEMON, alice_isk, None, None);
+
+    let derek_isk = IdentitySecret::generate();
+    let derek_skt = Socket::bind_haven(
+        &DEREK_DAEMON,
+        derek_isk,
+        None,
+        Some(CHARLIE_DAEMON.identity().public().fingerprint()),
+    );
+    let derek_ep = derek_skt.local_endpoint();
+
+    smolscale::block_on(async move {
+        // sleep to give the nodes time to connect
+        Timer::after(Duration::from_secs(30)).await;
+
+        // derek echoes everything back, uppercased
+        let mut listener = StreamListener::listen(derek_skt);
+        let _server = smolscale::spawn(async move {
+            let mut stream = listener.accept().await?;
+            // the listener only feeds accepted streams while accepting
+            let _feeder = smolscale::spawn(async move { while listener.accept().await.is_ok() {} });
+            let mut buf = [0u8; 1000];
+            loop {
+                let n = stream.read(&mut buf).await?;
+                if n == 0 {
+                    return anyhow::Ok(());
+                }
+                stream.write_all(&buf[..n].to_ascii_uppercase()).await?;
+            }
+        });
+
+        let mut stream = Stream::connect(alice_skt, derek_ep)
+            .timeout(Duration::from_secs(30))
+            .await
+            .context("connecting timed out")
+            .unwrap()
+            .unwrap();
+        // bigger than one segment, so that the stream has to reassemble it in order
+        let request: Vec<u8> = (0..20_000).map(|i| b"abcdefgh"[i % 8]).collect();
+        stream.write_all(&request).await.unwrap();
+        let mut response = vec![0u8; request.len()];
+        stream
+            .read_exact(&mut response)
+            .timeout(Duration::from_secs(30))
+            .await
+            .context("timed out")
+            .unwrap()
+            .unwrap();
+        assert_eq!(response, request.to_ascii_uppercase());
+    })
+}
+
+#[test]
+fn stream_half_close() {
+    let _ = env_logger::try_init();
+    env::set_var("SOSISTAB2_NO_SLEEP", "1");
+    Lazy::force(&START_DAEMONS);
+
+    let alice_isk = IdentitySecret::generate();
+    let alice_skt = Socket::bind_haven(&ALICE_DAEMON, alice_isk, None, None);
+
+    let derek_isk = IdentitySecret::generate();
+    let derek_skt = Socket::bind_haven(
+        &DEREK_DAEMON,
+        derek_isk,
+        None,
+        Some(CHARLIE_DAEMON.identity().public().fingerprint()),
+    );
+    let derek_ep = derek_skt.local_endpoint();
+
+    smolscale::block_on(async move {
+        // sleep to give the nodes time to connect
+        Timer::after(Duration::from_secs(30)).await;
+
+        // derek reads the whole request before answering it
+        let mut listener = StreamListener::listen(derek_skt);
+        let _server = smolscale::spawn(async move {
+            let mut stream = listener.accept().await?;
+            let _feeder = smolscale::spawn(async move { while listener.accept().await.is_ok() {} });
+            let mut request = vec![];
+            stream.read_to_end(&mut request).await?;
+            stream.write_all(&request.to_ascii_uppercase()).await?;
+            stream.shutdown_write().await
+        });
+
+        let mut stream = Stream::connect(alice_skt, derek_ep)
+            .timeout(Duration::from_secs(30))
+            .await
+            .context("connecting timed out")
+            .unwrap()
+            .unwrap();
+        let request: Vec<u8> = (0..20_000).map(|i| b"abcdefgh"[i % 8]).collect();
+        stream.write_all(&request).await.unwrap();
+        stream.shutdown_write().await.unwrap();
+        // writing is over, but reading still works
+        assert!(stream.write_all(b"too late").await.is_err());
+        let mut response = vec![];
+        stream
+            .read_to_end(&mut response)
+            .timeout(Duration::from_secs(30))
+            .await
+            .context("timed out")
+            .unwrap()
+            .unwrap();
+        assert_eq!(response, request.to_ascii_uppercase());
+    })
+}
+
+#[test]
+fn stream_mux() {
+    let _ = env_logger::try_init();
+    env::set_var("SOSISTAB2_NO_SLEEP", "1");
+    Lazy::force(&START_DAEMONS);
+
+    let alice_isk = IdentitySecret::generate();
+    let alice_skt = Socket::bind_haven(&ALICE_DAEMON, alice_isk, None, None);
+
+    let derek_isk = IdentitySecret::generate();
+    let derek_skt = Socket::bind_haven(
+        &DEREK_DAEMON,
+        derek_isk,
+        None,
+        Some(CHARLIE_DAEMON.identity().public().fingerprint()),
+    );
+    let derek_ep = derek_skt.local_endpoint();
+
+    smolscale::block_on(async move {
+        // sleep to give the nodes time to connect
+        Timer::after(Duration::from_secs(30)).await;
+
+        // derek answers every sub-stream with its request, uppercased
+        let mut listener = StreamListener::listen(derek_skt);
+        let _server = smolscale::spawn(async move {
+            let stream = listener.accept().await?;
+            let _feeder = smolscale::spawn(async move { while listener.accept().await.is_ok() {} });
+            let mux = StreamMux::new(stream, false);
+            while let Ok(mut sub_stream) = mux.accept_stream().await {
+                smolscale::spawn(async move {
+                    let mut request = vec![];
+                    sub_stream.read_to_end(&mut request).await?;
+                    sub_stream.write_all(&request.to_ascii_uppercase()).await?;
+                    sub_stream.close().await
+                })
+                .detach();
+            }
+            anyhow::Ok(())
+        });
+
+        let stream = Stream::connect(alice_skt, derek_ep)
+            .timeout(Duration::from_secs(30))
+            .await
+            .context("connecting timed out")
+            .unwrap()
+            .unwrap();
+        let mux = StreamMux::new(stream, true);
+        let requests = (0..3).map(|i| {
+            let mux = &mux;
+            async move {
+                let mut sub_stream = mux.open_stream().await.unwrap();
+                let request = format!("request {i}: ").repeat(1000).into_bytes();
+                sub_stream.write_all(&request).await.unwrap();
+                sub_stream.close().await.unwrap();
+                let mut response = vec![];
+                sub_stream.read_to_end(&mut response).await.unwrap();
+                assert_eq!(response, request.to_ascii_uppercase());
+            }
+        });
+        futures_util::future::join_all(requests)
+            .timeout(Duration::from_secs(60))
+            .await
+            .context("timed out")
+            .unwrap();
+    })
+}
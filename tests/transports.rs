@@ -0,0 +1,310 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use earendil::{
+    config::ConfigFile,
+    control_protocol::{ControlClient, ControlProtocol},
+    daemon::{Daemon, DaemonBuilder},
+    transport::{EarendilTransport, Pipe, PipeListener},
+};
+use earendil_crypt::Fingerprint;
+use nanorpc_http::client::HttpRpcTransport;
+use smol::{
+    channel::{Receiver, Sender},
+    Timer,
+};
+
+fn daemon_from_json(json: serde_json::Value) -> Daemon {
+    let cfg: ConfigFile = serde_json::from_value(json).unwrap();
+    Daemon::init(cfg).unwrap()
+}
+
+/// Waits until the daemon controlled at `control` lists `neighbor` among its neighbors.
+async fn wait_for_neighbor(control: &str, neighbor: Fingerprint) {
+    let client = ControlClient::from(HttpRpcTransport::new(control.parse().unwrap()));
+    for _ in 0..60 {
+        if let Ok(stats) = client.bandwidth_stats().await {
+            if stats
+                .per_neighbor
+                .iter()
+                .any(|neigh| neigh.fingerprint == neighbor)
+            {
+                return;
+            }
+        }
+        Timer::after(Duration::from_secs(1)).await;
+    }
+    panic!("{neighbor} never connected to the daemon at {control}")
+}
+
+#[test]
+fn tcp_link() {
+    let _ = env_logger::try_init();
+
+    let relay = daemon_from_json(serde_json::json!({
+        "identity_seed": "tcp-link-relay",
+        "control_listen": "127.0.0.1:11211",
+        "in_routes": {
+            "main_tcp": {"protocol": "tcp", "listen": "127.0.0.1:12203"},
+        },
+    }));
+    let relay_fp = relay.identity().public().fingerprint();
+    let client = daemon_from_json(serde_json::json!({
+        "identity_seed": "tcp-link-client",
+        "control_listen": "127.0.0.1:11212",
+        "out_routes": {
+            "relay": {
+                "protocol": "tcp",
+                "fingerprint": relay_fp.to_string(),
+                "connect": "localhost:12203",
+            },
+        },
+    }));
+    let client_fp = client.identity().public().fingerprint();
+
+    smolscale::block_on(async {
+        wait_for_neighbor("127.0.0.1:11212", relay_fp).await;
+        wait_for_neighbor("127.0.0.1:11211", client_fp).await;
+    });
+}
+
+#[test]
+fn websocket_link() {
+    let _ = env_logger::try_init();
+
+    let relay = daemon_from_json(serde_json::json!({
+        "identity_seed": "ws-link-relay",
+        "control_listen": "127.0.0.1:11213",
+        "in_routes": {
+            "main_ws": {"protocol": "websocket", "listen": "127.0.0.1:12204", "path": "/earendil"},
+        },
+    }));
+    let relay_fp = relay.identity().public().fingerprint();
+    let client = daemon_from_json(serde_json::json!({
+        "identity_seed": "ws-link-client",
+        "control_listen": "127.0.0.1:11214",
+        "out_routes": {
+            "relay": {
+                "protocol": "websocket",
+                "fingerprint": relay_fp.to_string(),
+                "url": "ws://127.0.0.1:12204/earendil",
+            },
+        },
+    }));
+    let client_fp = client.identity().public().fingerprint();
+
+    smolscale::block_on(async {
+        wait_for_neighbor("127.0.0.1:11214", relay_fp).await;
+        wait_for_neighbor("127.0.0.1:11213", client_fp).await;
+    });
+}
+
+#[test]
+fn quic_link() {
+    let _ = env_logger::try_init();
+    // neighbors don't check the certificate, so a throwaway self-signed one does
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let cert_path =
+        std::env::temp_dir().join(format!("earendil-quic-link-{}.crt", std::process::id()));
+    let key_path =
+        std::env::temp_dir().join(format!("earendil-quic-link-{}.key", std::process::id()));
+    std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+    std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+    let relay = daemon_from_json(serde_json::json!({
+        "identity_seed": "quic-link-relay",
+        "control_listen": "127.0.0.1:11222",
+        "in_routes": {
+            "main_quic": {"protocol": "quic", "listen": "127.0.0.1:12209", "cert": cert_path, "key": key_path},
+        },
+    }));
+    let relay_fp = relay.identity().public().fingerprint();
+    let client = daemon_from_json(serde_json::json!({
+        "identity_seed": "quic-link-client",
+        "control_listen": "127.0.0.1:11223",
+        "out_routes": {
+            "relay": {
+                "protocol": "quic",
+                "fingerprint": relay_fp.to_string(),
+                "connect": "127.0.0.1:12209",
+            },
+        },
+    }));
+    let client_fp = client.identity().public().fingerprint();
+
+    smolscale::block_on(async {
+        wait_for_neighbor("127.0.0.1:11223", relay_fp).await;
+        wait_for_neighbor("127.0.0.1:11222", client_fp).await;
+    });
+    let _ = std::fs::remove_file(cert_path);
+    let _ = std::fs::remove_file(key_path);
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_socket_link() {
+    let _ = env_logger::try_init();
+    let path = std::env::temp_dir().join(format!("earendil-unix-link-{}.sock", std::process::id()));
+
+    let relay = daemon_from_json(serde_json::json!({
+        "identity_seed": "unix-link-relay",
+        "control_listen": "127.0.0.1:11217",
+        "in_routes": {
+            "main_unix": {"protocol": "unix_socket", "path": path},
+        },
+    }));
+    let relay_fp = relay.identity().public().fingerprint();
+    let client = daemon_from_json(serde_json::json!({
+        "identity_seed": "unix-link-client",
+        "control_listen": "127.0.0.1:11218",
+        "out_routes": {
+            "relay": {
+                "protocol": "unix_socket",
+                "fingerprint": relay_fp.to_string(),
+                "path": path,
+            },
+        },
+    }));
+    let client_fp = client.identity().public().fingerprint();
+
+    smolscale::block_on(async {
+        wait_for_neighbor("127.0.0.1:11218", relay_fp).await;
+        wait_for_neighbor("127.0.0.1:11217", client_fp).await;
+    });
+    let _ = std::fs::remove_file(path);
+}
+
+/// A transport between daemons in the same process, whose in_routes are named by `config.name`.
+#[derive(Clone, Default)]
+struct MemoryTransport {
+    listeners: Arc<Mutex<HashMap<String, Sender<MemoryPipe>>>>,
+}
+
+#[async_trait]
+impl EarendilTransport for MemoryTransport {
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    async fn listen(&self, config: &serde_json::Value) -> anyhow::Result<Box<dyn PipeListener>> {
+        let (send, recv) = smol::channel::unbounded();
+        self.listeners
+            .lock()
+            .unwrap()
+            .insert(config["name"].to_string(), send);
+        Ok(Box::new(MemoryListener(recv)))
+    }
+
+    async fn dial(&self, config: &serde_json::Value) -> anyhow::Result<Box<dyn Pipe>> {
+        let listener = self
+            .listeners
+            .lock()
+            .unwrap()
+            .get(&config["name"].to_string())
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("nothing listens at {}", config["name"]))?;
+        let (a_send, a_recv) = smol::channel::unbounded();
+        let (b_send, b_recv) = smol::channel::unbounded();
+        listener
+            .send(MemoryPipe {
+                send: a_send,
+                recv: b_recv,
+            })
+            .await?;
+        Ok(Box::new(MemoryPipe {
+            send: b_send,
+            recv: a_recv,
+        }))
+    }
+}
+
+struct MemoryListener(Receiver<MemoryPipe>);
+
+#[async_trait]
+impl PipeListener for MemoryListener {
+    async fn accept_pipe(&self) -> anyhow::Result<Box<dyn Pipe>> {
+        Ok(Box::new(self.0.recv().await?))
+    }
+}
+
+struct MemoryPipe {
+    send: Sender<Bytes>,
+    recv: Receiver<Bytes>,
+}
+
+#[async_trait]
+impl Pipe for MemoryPipe {
+    fn send(&self, to_send: Bytes) {
+        let _ = self.send.try_send(to_send);
+    }
+
+    async fn recv(&self) -> std::io::Result<Bytes> {
+        self.recv
+            .recv()
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "memory pipe closed"))
+    }
+
+    fn protocol(&self) -> &str {
+        "memory"
+    }
+
+    fn peer_metadata(&self) -> &str {
+        ""
+    }
+
+    fn peer_addr(&self) -> String {
+        "memory".into()
+    }
+}
+
+#[test]
+fn custom_transport_link() {
+    let _ = env_logger::try_init();
+    let transport = MemoryTransport::default();
+
+    let relay = DaemonBuilder::new(
+        serde_json::from_value(serde_json::json!({
+            "identity_seed": "custom-link-relay",
+            "control_listen": "127.0.0.1:11215",
+            "in_routes": {
+                "main_memory": {"protocol": "custom", "transport": "memory", "config": {"name": "relay"}},
+            },
+        }))
+        .unwrap(),
+    )
+    .register_transport(transport.clone())
+    .build()
+    .unwrap();
+    let relay_fp = relay.identity().public().fingerprint();
+    let client = DaemonBuilder::new(
+        serde_json::from_value(serde_json::json!({
+            "identity_seed": "custom-link-client",
+            "control_listen": "127.0.0.1:11216",
+            "out_routes": {
+                "relay": {
+                    "protocol": "custom",
+                    "fingerprint": relay_fp.to_string(),
+                    "transport": "memory",
+                    "config": {"name": "relay"},
+                },
+            },
+        }))
+        .unwrap(),
+    )
+    .register_transport(transport)
+    .build()
+    .unwrap();
+    let client_fp = client.identity().public().fingerprint();
+
+    smolscale::block_on(async {
+        wait_for_neighbor("127.0.0.1:11216", relay_fp).await;
+        wait_for_neighbor("127.0.0.1:11215", client_fp).await;
+    });
+}